@@ -0,0 +1,48 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use return_to_monk::bench_utils;
+use return_to_monk::engine::{Engine, Runner};
+use return_to_monk::lexer;
+
+fn lex(c: &mut Criterion) {
+    let source = bench_utils::program(10_000);
+    c.bench_function("lex 10000 statements", |b| {
+        b.iter(|| lexer::tokenize(black_box(&source)))
+    });
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse nested");
+    for depth in [16, 64, 250] {
+        let source = bench_utils::nested(depth);
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &source, |b, source| {
+            b.iter(|| return_to_monk::parse(black_box(source)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Each run starts from a new runner, so that it doesn't see the globals of
+// the runs before it. Parsing is part of the setup, which isn't timed.
+fn eval(c: &mut Criterion) {
+    let programs = [
+        ("fib 20", bench_utils::fib(20)),
+        ("sum 100000", bench_utils::sum(100_000)),
+        ("strings 10000", bench_utils::strings(10_000)),
+    ];
+    for (name, source) in &programs {
+        let mut group = c.benchmark_group(*name);
+        for engine in Engine::ALL {
+            group.bench_function(engine.name(), |b| {
+                b.iter_batched(
+                    || (Runner::new(engine), return_to_monk::parse(source).unwrap()),
+                    |(mut runner, program)| runner.run(program).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, lex, parse, eval);
+criterion_main!(benches);
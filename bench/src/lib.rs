@@ -0,0 +1,3 @@
+// The Criterion benchmarks of the interpreter are in benches/, run them with
+// `cargo bench` in this directory. The programs they time come from
+// return_to_monk::bench_utils.
@@ -0,0 +1,20 @@
+// With the ffi feature, generates include/monk.h from src/ffi.rs, the way
+// cbindgen.toml says to. The header is checked in, for hosts that build the
+// cdylib without reading the crate, and only written when it changes.
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir))
+            .expect("cbindgen.toml is a cbindgen config");
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{}/src/ffi.rs", dir))
+            .generate()
+            .expect("src/ffi.rs can be parsed")
+            .write_to_file(format!("{}/include/monk.h", dir));
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use return_to_monk::{dump, lexer};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+    lexer::tokenize(source);
+    dump::tokens(source);
+    if let Ok(program) = return_to_monk::parse(source) {
+        dump::source(&program);
+    }
+});
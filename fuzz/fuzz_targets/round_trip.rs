@@ -0,0 +1,179 @@
+#![no_main]
+
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use return_to_monk::ast::{Expression, Infix, Prefix, Program, Slot, Statement};
+use return_to_monk::{dump, Span, Symbol};
+
+// how deep the generated expressions nest, well within the parser's limit
+const MAX_DEPTH: usize = 8;
+
+// none of them are keywords, and identifiers can't have digits
+const NAMES: &[&str] = &["a", "b", "x", "foo", "_bar", "puts", "len"];
+
+// A program the parser could have produced: only names that lex as
+// identifiers, integers that aren't negative, since the minus is a prefix,
+// and strings without the quote that would end them or the NUL the lexer
+// stops at. Blocks are only the bodies of ifs, functions and trys, since a
+// brace at the start of a statement is a hash.
+struct GeneratedProgram(Program);
+
+impl<'a> Arbitrary<'a> for GeneratedProgram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut program = Program::new();
+        for _ in 0..u.int_in_range(0..=8)? {
+            program.statements.push(statement(u, MAX_DEPTH)?);
+        }
+        Ok(GeneratedProgram(program))
+    }
+}
+
+// what libfuzzer prints for a failing input
+impl Debug for GeneratedProgram {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", dump::source(&self.0))
+    }
+}
+
+fn name(u: &mut Unstructured) -> Result<Symbol> {
+    Ok(Symbol::intern(u.choose(NAMES)?))
+}
+
+fn names(u: &mut Unstructured) -> Result<Vec<Symbol>> {
+    (0..u.int_in_range(0..=3)?).map(|_| name(u)).collect()
+}
+
+fn statement(u: &mut Unstructured, depth: usize) -> Result<Statement> {
+    let span = Span::default();
+    Ok(match u.int_in_range(0..=2)? {
+        0 => Statement::LetStatement {
+            name: name(u)?,
+            slot: Slot::default(),
+            value: expression(u, depth)?,
+            span,
+        },
+        1 => Statement::ReturnStatement(expression(u, depth)?, span),
+        _ => Statement::ExpressionStatement(expression(u, depth)?, span),
+    })
+}
+
+fn block(u: &mut Unstructured, depth: usize) -> Result<Statement> {
+    let statements = (0..u.int_in_range(0..=3)?)
+        .map(|_| statement(u, depth))
+        .collect::<Result<_>>()?;
+    Ok(Statement::BlockStatement(statements, Span::default()))
+}
+
+fn expressions(u: &mut Unstructured, depth: usize) -> Result<Vec<Expression>> {
+    (0..u.int_in_range(0..=3)?)
+        .map(|_| expression(u, depth))
+        .collect()
+}
+
+fn literal(u: &mut Unstructured) -> Result<Expression> {
+    Ok(match u.int_in_range(0..=3)? {
+        0 => Expression::Identifier(name(u)?, Slot::default()),
+        1 => Expression::IntegerLiteral(u.int_in_range(0..=i64::MAX)?),
+        2 => Expression::BooleanLiteral(u.arbitrary()?),
+        _ => {
+            let string: String = u.arbitrary()?;
+            Expression::StringLiteral(string.replace(['"', '\0'], ""))
+        }
+    })
+}
+
+fn expression(u: &mut Unstructured, depth: usize) -> Result<Expression> {
+    if depth == 0 {
+        return literal(u);
+    }
+    let depth = depth - 1;
+    let span = Span::default();
+    let no_locals = || Rc::from(Vec::<Symbol>::new());
+    Ok(match u.int_in_range(0..=10)? {
+        0 => Expression::ArrayLiteral(expressions(u, depth)?),
+        1 => {
+            let pairs = (0..u.int_in_range(0..=3)?)
+                .map(|_| Ok((expression(u, depth)?, expression(u, depth)?)))
+                .collect::<Result<_>>()?;
+            Expression::HashLiteral(pairs, span)
+        }
+        2 => Expression::If {
+            condition: Box::new(expression(u, depth)?),
+            consequence: Box::new(block(u, depth)?),
+            alternative: match u.arbitrary()? {
+                true => Some(Box::new(block(u, depth)?)),
+                false => None,
+            },
+            span,
+        },
+        3 => Expression::FunctionLiteral {
+            parameters: names(u)?,
+            body: Rc::new(block(u, depth)?),
+            locals: no_locals(),
+            span,
+        },
+        4 => Expression::MacroLiteral {
+            parameters: names(u)?,
+            body: Rc::new(block(u, depth)?),
+            locals: no_locals(),
+            span,
+        },
+        5 => Expression::Call {
+            function: Box::new(expression(u, depth)?),
+            arguments: expressions(u, depth)?,
+            span,
+        },
+        6 => Expression::Try {
+            body: Box::new(block(u, depth)?),
+            name: name(u)?,
+            handler: Box::new(block(u, depth)?),
+            locals: no_locals(),
+            span,
+        },
+        7 => Expression::Index {
+            left: Box::new(expression(u, depth)?),
+            index: Box::new(expression(u, depth)?),
+            span,
+        },
+        8 => {
+            let operator = u.choose(&[Prefix::BANG, Prefix::MINUS])?.clone();
+            Expression::Prefix(operator, Box::new(expression(u, depth)?))
+        }
+        9 => {
+            let operator = u
+                .choose(&[
+                    Infix::PLUS,
+                    Infix::MINUS,
+                    Infix::ASTERISK,
+                    Infix::SLASH,
+                    Infix::PERCENT,
+                    Infix::EQ,
+                    Infix::NOT_EQ,
+                    Infix::LT,
+                    Infix::GT,
+                ])?
+                .clone();
+            Expression::Infix(
+                operator,
+                Box::new(expression(u, depth)?),
+                Box::new(expression(u, depth)?),
+            )
+        }
+        _ => literal(u)?,
+    })
+}
+
+// Spans aren't compared, the generated ones are all empty, so the trees are
+// compared through their source: printing the parsed program must give the
+// source it was parsed from.
+fuzz_target!(|program: GeneratedProgram| {
+    let source = dump::source(&program.0);
+    let parsed = match return_to_monk::parse(&source) {
+        Ok(parsed) => parsed,
+        Err(errors) => panic!("{} doesn't parse: {:?}", source, errors),
+    };
+    assert_eq!(dump::source(&parsed), source);
+});
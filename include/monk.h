@@ -0,0 +1,72 @@
+/*
+ * The C interface of return_to_monk, built into the cdylib with the ffi
+ * feature:
+ *
+ *   cargo build --release --features ffi
+ *
+ * An interpreter runs code one piece after another, keeping the globals that
+ * one piece defines for the next. It isn't thread-safe: use each one from a
+ * single thread. Strings are UTF-8 and NUL-terminated.
+ *
+ * A bug in the interpreter doesn't unwind into the caller: the functions
+ * fail the way they document instead, with 2 for those that return a status.
+ */
+
+#ifndef MONK_H
+#define MONK_H
+
+/* Generated from src/ffi.rs by build.rs with the ffi feature; don't edit. */
+
+#include <stddef.h>
+
+typedef struct MonkInterpreter MonkInterpreter;
+
+/**
+ * A function of the host that the code can call. It gets the data it was
+ * registered with and its arguments as the strings they display as, which
+ * are only valid during the call. It returns the string the call evaluates
+ * to, which is copied before the function is called again and which the
+ * host keeps owning, or NULL for null.
+ */
+typedef const char *(*monk_fn)(void *data, size_t argc, const char *const *argv);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * A new interpreter, to be freed with monk_free, or NULL if it failed.
+ */
+MonkInterpreter *monk_new(void);
+
+/**
+ * Runs the source, returning 0 if it ran, 1 if it failed to parse or
+ * raised an error and 2 if the interpreter panicked. monk_get_string returns
+ * its value or error message.
+ */
+int monk_eval(MonkInterpreter *monk, const char *source);
+
+/**
+ * The value of the last monk_eval as it displays, or its error message. The
+ * interpreter owns the string, which is valid until the next monk_eval or
+ * monk_free.
+ */
+const char *monk_get_string(const MonkInterpreter *monk);
+
+/**
+ * Defines a global function that calls func with data, returning 0, 1 if
+ * the name isn't a valid string or 2 if the interpreter panicked. data has
+ * to stay valid for as long as the interpreter can call the function.
+ */
+int monk_register_fn(MonkInterpreter *monk, const char *name, monk_fn func, void *data);
+
+/**
+ * Frees the interpreter. NULL is ignored.
+ */
+void monk_free(MonkInterpreter *monk);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* MONK_H */
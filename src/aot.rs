@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use crate::ast::Program;
+use crate::cli::{CompileOptions, Target};
+use crate::compiler;
+use crate::evaluator::{Environment, EvalConfig};
+use crate::macro_expansion;
+use crate::serialize;
+use crate::test_runner;
+use crate::wasm;
+
+// `monk compile`: compiles the file ahead of time, to bytecode that `monk
+// run` runs without parsing it again, or to a WebAssembly module that runs
+// under node with the runtime that's written next to it.
+pub fn run(options: &CompileOptions) -> ExitCode {
+    match compile(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}: {}", options.file, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn compile(options: &CompileOptions) -> Result<(), String> {
+    let source = fs::read_to_string(&options.file)
+        .map_err(|error| format!("can't read the file: {}", error))?;
+    let program = expand(test_runner::parse(&source)?)?;
+    let output = options.output();
+    match options.target {
+        Target::Bytecode => {
+            let bytecode = compiler::compile(&program).map_err(|error| error.message)?;
+            serialize::write_file(&output, &bytecode).map_err(|error| error.message)
+        }
+        Target::Wasm => {
+            let module = wasm::compile(&program).map_err(|error| error.message)?;
+            let write = |path: &Path, contents: &[u8]| {
+                fs::write(path, contents)
+                    .map_err(|error| format!("can't write {}: {}", path.display(), error))
+            };
+            write(&output, &module)?;
+            let runtime = output.with_file_name("monk.js");
+            write(&runtime, wasm::RUNTIME.as_bytes())?;
+            println!(
+                "run it with: node {} {}",
+                runtime.display(),
+                output.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+// the program with its macros expanded, which neither target knows about
+fn expand(mut program: Program) -> Result<Program, String> {
+    let macros = Rc::new(RefCell::new(Environment::new()));
+    let config = EvalConfig::default();
+    macro_expansion::define_macros(&mut program, &macros, &config)
+        .and_then(|_| macro_expansion::expand_macros(&mut program, &macros, &config))
+        .map_err(|error| error.message)?;
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+    use std::env;
+
+    #[test]
+    fn test_compile() {
+        let dir = env::temp_dir().join("return_to_monk_test_compile");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("script.mk");
+        fs::write(
+            &file,
+            "let twice = macro(x) { quote(unquote(x) + unquote(x)) };\n\
+             let f = fn(n) { n * 2 };\n\
+             twice(f(10))",
+        )
+        .unwrap();
+        let options = |target| CompileOptions {
+            file: file.display().to_string(),
+            output: None,
+            target,
+            help: false,
+        };
+
+        compile(&options(Target::Bytecode)).unwrap();
+        let bytecode = serialize::read_file(dir.join("script.monkc")).unwrap();
+        let value = Vm::new().run(bytecode).unwrap();
+        assert_eq!(value.to_string(), "40");
+
+        compile(&options(Target::Wasm)).unwrap();
+        let module = fs::read(dir.join("script.wasm")).unwrap();
+        assert_eq!(&module[..4], b"\0asm");
+        assert!(dir.join("monk.js").exists());
+
+        fs::write(&file, "let x = ;").unwrap();
+        let error = compile(&options(Target::Bytecode)).unwrap_err();
+        assert_eq!(
+            error,
+            "no prefix parse function for SEMICOLON at line 1, column 9"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,21 @@
 use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::dump;
+use crate::symbol::Symbol;
+use crate::token::Span;
 
 #[derive(Debug)]
 pub struct Program {
     pub statements: Vec<Statement>,
 }
 
+impl Default for Program {
+    fn default() -> Self {
+        Program::new()
+    }
+}
+
 impl Program {
     pub fn new() -> Program {
         Program {
@@ -23,63 +34,155 @@ impl Display for Program {
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Statement {
-    LetStatement { name: String, value: Expression },
-    ReturnStatement(Expression),
-    BlockStatement(Vec<Statement>),
-    ExpressionStatement(Expression),
+    LetStatement {
+        name: Symbol,
+        slot: Slot,
+        value: Expression,
+        span: Span,
+    },
+    ReturnStatement(Expression, Span),
+    BlockStatement(Vec<Statement>, Span),
+    ExpressionStatement(Expression, Span),
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::LetStatement { span, .. }
+            | Statement::ReturnStatement(_, span)
+            | Statement::BlockStatement(_, span)
+            | Statement::ExpressionStatement(_, span) => *span,
+        }
+    }
 }
 
 impl Display for Statement {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            Statement::LetStatement { name, value } => {
+            Statement::LetStatement { name, value, .. } => {
                 write!(f, "let {} = {};", name, value)
             }
-            Statement::ReturnStatement(value) => {
+            Statement::ReturnStatement(value, _) => {
                 write!(f, "return {};", value)
             }
-            Statement::BlockStatement(statements) => {
+            Statement::BlockStatement(statements, _) => {
                 let mut result = String::new();
                 for statement in statements {
                     result.push_str(&format!("{}", statement));
                 }
                 write!(f, "{}", result)
             }
-            Statement::ExpressionStatement(expression) => write!(f, "{}", expression),
+            Statement::ExpressionStatement(expression, _) => write!(f, "{}", expression),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expression {
-    Identifier(String),
-    IntegerLiteral(isize),
+    Identifier(Symbol, Slot),
+    IntegerLiteral(i64),
     BooleanLiteral(bool),
+    StringLiteral(String),
+    ArrayLiteral(Vec<Expression>),
+    HashLiteral(Vec<(Expression, Expression)>, Span),
     If {
         condition: Box<Expression>,
         consequence: Box<Statement>,
         alternative: Option<Box<Statement>>,
+        span: Span,
     },
     FunctionLiteral {
-        parameters: Vec<String>,
-        body: Box<Statement>,
+        parameters: Vec<Symbol>,
+        // shared with the function objects created from this literal
+        body: Rc<Statement>,
+        // the names of the call environment's slots, filled in by the resolver
+        locals: Rc<[Symbol]>,
+        span: Span,
+    },
+    // like a function literal, but called at expansion time with the code of
+    // its arguments
+    MacroLiteral {
+        parameters: Vec<Symbol>,
+        body: Rc<Statement>,
+        locals: Rc<[Symbol]>,
+        span: Span,
     },
     Call {
         function: Box<Expression>,
         arguments: Vec<Expression>,
+        span: Span,
+    },
+    Try {
+        body: Box<Statement>,
+        name: Symbol,
+        handler: Box<Statement>,
+        // the names of the handler environment's slots, filled in by the resolver
+        locals: Rc<[Symbol]>,
+        span: Span,
+    },
+    Index {
+        left: Box<Expression>,
+        index: Box<Expression>,
+        span: Span,
+    },
+    // runs the body for each value of the iterable, with the name bound to it
+    For {
+        name: Symbol,
+        iterable: Box<Expression>,
+        body: Box<Statement>,
+        // the names of the body environment's slots, filled in by the resolver
+        locals: Rc<[Symbol]>,
+        span: Span,
     },
     Prefix(Prefix, Box<Expression>),
     Infix(Infix, Box<Expression>, Box<Expression>),
 }
 
+impl Expression {
+    // the parser only keeps the spans of the expressions that errors point at
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expression::HashLiteral(_, span)
+            | Expression::If { span, .. }
+            | Expression::FunctionLiteral { span, .. }
+            | Expression::MacroLiteral { span, .. }
+            | Expression::Call { span, .. }
+            | Expression::Try { span, .. }
+            | Expression::Index { span, .. }
+            | Expression::For { span, .. } => Some(*span),
+            Expression::Identifier(..)
+            | Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_)
+            | Expression::ArrayLiteral(_)
+            | Expression::Prefix(..)
+            | Expression::Infix(..) => None,
+        }
+    }
+}
+
 impl Display for Expression {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            Expression::Identifier(name) => write!(f, "{}", name),
+            Expression::Identifier(name, _) => write!(f, "{}", name),
             Expression::IntegerLiteral(value) => write!(f, "{}", value),
             Expression::BooleanLiteral(value) => write!(f, "{}", value),
+            Expression::StringLiteral(value) => write!(f, "\"{}\"", value),
+            Expression::ArrayLiteral(elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Expression::HashLiteral(pairs, _) => {
+                let pairs: Vec<String> = pairs
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                write!(f, "{{{}}}", pairs.join(", "))
+            }
+            Expression::Index { left, index, .. } => write!(f, "({}[{}])", left, index),
             Expression::Prefix(operator, right) => write!(f, "({}{})", operator, right),
             Expression::Infix(operator, left, right) => {
                 write!(f, "({} {} {})", left, operator, right)
@@ -88,6 +191,7 @@ impl Display for Expression {
                 condition,
                 consequence,
                 alternative,
+                ..
             } => {
                 let mut result = String::new();
                 result.push_str(&format!("if {} {}", condition, consequence));
@@ -96,12 +200,20 @@ impl Display for Expression {
                 }
                 write!(f, "{}", result)
             }
-            Expression::FunctionLiteral { parameters, body } => {
+            Expression::FunctionLiteral {
+                parameters, body, ..
+            }
+            | Expression::MacroLiteral {
+                parameters, body, ..
+            } => {
                 let mut result = String::new();
-                result.push_str("fn(");
+                match self {
+                    Expression::MacroLiteral { .. } => result.push_str("macro("),
+                    _ => result.push_str("fn("),
+                }
                 for (i, parameter) in parameters.iter().enumerate() {
                     if i == 0 {
-                        result.push_str(&format!("{}", parameter));
+                        result.push_str(&parameter.to_string());
                     } else {
                         result.push_str(&format!(", {}", parameter));
                     }
@@ -109,13 +221,26 @@ impl Display for Expression {
                 result.push_str(&format!(") {}", body));
                 write!(f, "{}", result)
             }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => write!(f, "try {} catch ({}) {}", body, name, handler),
+            Expression::For {
+                name,
+                iterable,
+                body,
+                ..
+            } => write!(f, "for ({} in {}) {}", name, iterable, body),
             Expression::Call {
                 function,
                 arguments,
+                ..
             } => {
                 let mut result = String::new();
                 result.push_str(&format!("{}", function));
-                result.push_str("(");
+                result.push('(');
                 for (i, argument) in arguments.iter().enumerate() {
                     if i == 0 {
                         result.push_str(&format!("{}", argument));
@@ -123,20 +248,34 @@ impl Display for Expression {
                         result.push_str(&format!(", {}", argument));
                     }
                 }
-                result.push_str(")");
+                result.push(')');
                 write!(f, "{}", result)
             }
         }
     }
 }
 
-#[allow(non_camel_case_types)]
+// Where a variable lives at runtime, filled in by the resolver. Globals are
+// looked up by name, locals by how many environments out they were declared
+// and their position in that environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Slot {
+    #[default]
+    Global,
+    Local {
+        depth: usize,
+        index: usize,
+    },
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Infix {
     PLUS,
     MINUS,
     ASTERISK,
     SLASH,
+    PERCENT,
     EQ,
     NOT_EQ,
     LT,
@@ -150,6 +289,7 @@ impl Display for Infix {
             Infix::MINUS => write!(f, "-"),
             Infix::ASTERISK => write!(f, "*"),
             Infix::SLASH => write!(f, "/"),
+            Infix::PERCENT => write!(f, "%"),
             Infix::EQ => write!(f, "=="),
             Infix::NOT_EQ => write!(f, "!="),
             Infix::LT => write!(f, "<"),
@@ -158,6 +298,7 @@ impl Display for Infix {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Prefix {
     BANG,
@@ -172,3 +313,343 @@ impl Display for Prefix {
         }
     }
 }
+
+// A difference between two versions of a program, found by `diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AstChange {
+    // a statement that only the new program has
+    Inserted(Span),
+    // a statement that only the old program has
+    Removed(Span),
+    // a node that both have, but changed, with where it is in each
+    Modified { old: Span, new: Span },
+}
+
+impl Display for AstChange {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AstChange::Inserted(span) => write!(f, "inserted at {}", span),
+            AstChange::Removed(span) => write!(f, "removed at {}", span),
+            AstChange::Modified { old, new } => {
+                write!(f, "modified at {} (was at {})", new, old)
+            }
+        }
+    }
+}
+
+// What changed from one version of a program to the next, in the order of
+// the code. Nodes are compared by their code, so neither spans nor the slots
+// the resolver fills in count: code that only moved didn't change. A change
+// is reported at the smallest node around it that has a span, so changing
+// `x + 1` to `x + 2` modifies the statement it's in.
+pub fn diff(old: &Program, new: &Program) -> Vec<AstChange> {
+    let mut changes = Vec::new();
+    diff_statements(&old.statements, &new.statements, &mut changes);
+    changes
+}
+
+// The statements that stayed the same are their longest common subsequence,
+// the ones between them changed.
+fn diff_statements(old: &[Statement], new: &[Statement], changes: &mut Vec<AstChange>) {
+    let old_sources: Vec<String> = old.iter().map(dump::statement_source).collect();
+    let new_sources: Vec<String> = new.iter().map(dump::statement_source).collect();
+    // how many statements old[i..] and new[j..] have in common
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old_sources[i] == new_sources[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let (mut old_start, mut new_start) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old_sources[i] == new_sources[j] {
+            diff_changed(&old[old_start..i], &new[new_start..j], changes);
+            i += 1;
+            j += 1;
+            (old_start, new_start) = (i, j);
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    diff_changed(&old[old_start..], &new[new_start..], changes);
+}
+
+// statements between the same ones took each other's place, in order, the
+// rest were inserted or removed
+fn diff_changed(old: &[Statement], new: &[Statement], changes: &mut Vec<AstChange>) {
+    for k in 0..old.len().max(new.len()) {
+        match (old.get(k), new.get(k)) {
+            (Some(old), Some(new)) => diff_statement(old, new, changes),
+            (Some(old), None) => changes.push(AstChange::Removed(old.span())),
+            (None, Some(new)) => changes.push(AstChange::Inserted(new.span())),
+            (None, None) => {}
+        }
+    }
+}
+
+fn diff_statement(old: &Statement, new: &Statement, changes: &mut Vec<AstChange>) {
+    let found = match (old, new) {
+        (
+            Statement::LetStatement {
+                name: old_name,
+                value: old_value,
+                ..
+            },
+            Statement::LetStatement {
+                name: new_name,
+                value: new_value,
+                ..
+            },
+        ) if old_name == new_name => diff_expression(old_value, new_value, changes),
+        (Statement::ReturnStatement(old, _), Statement::ReturnStatement(new, _))
+        | (Statement::ExpressionStatement(old, _), Statement::ExpressionStatement(new, _)) => {
+            diff_expression(old, new, changes)
+        }
+        (Statement::BlockStatement(old, _), Statement::BlockStatement(new, _)) => {
+            diff_statements(old, new, changes);
+            true
+        }
+        _ => false,
+    };
+    if !found {
+        changes.push(AstChange::Modified {
+            old: old.span(),
+            new: new.span(),
+        });
+    }
+}
+
+// Finds the changes in expressions that differ, and whether it could: if
+// the change is in a node without a span, the expression is modified as a
+// whole, and if it has no span either, so is the node around it.
+fn diff_expression(old: &Expression, new: &Expression, changes: &mut Vec<AstChange>) -> bool {
+    let mut inner = Vec::new();
+    let found = match (old, new) {
+        (Expression::ArrayLiteral(old), Expression::ArrayLiteral(new)) => {
+            diff_expressions(old, new, &mut inner)
+        }
+        (Expression::HashLiteral(old, _), Expression::HashLiteral(new, _)) => {
+            old.len() == new.len()
+                && old
+                    .iter()
+                    .zip(new)
+                    .all(|((old_key, old_value), (new_key, new_value))| {
+                        diff_child(old_key, new_key, &mut inner)
+                            && diff_child(old_value, new_value, &mut inner)
+                    })
+        }
+        (
+            Expression::If {
+                condition: old_condition,
+                consequence: old_consequence,
+                alternative: old_alternative,
+                ..
+            },
+            Expression::If {
+                condition: new_condition,
+                consequence: new_consequence,
+                alternative: new_alternative,
+                ..
+            },
+        ) => {
+            diff_child(old_condition, new_condition, &mut inner)
+                && diff_block(old_consequence, new_consequence, &mut inner)
+                && match (old_alternative, new_alternative) {
+                    (Some(old), Some(new)) => diff_block(old, new, &mut inner),
+                    (None, None) => true,
+                    _ => false,
+                }
+        }
+        (
+            Expression::FunctionLiteral {
+                parameters: old_parameters,
+                body: old_body,
+                ..
+            },
+            Expression::FunctionLiteral {
+                parameters: new_parameters,
+                body: new_body,
+                ..
+            },
+        )
+        | (
+            Expression::MacroLiteral {
+                parameters: old_parameters,
+                body: old_body,
+                ..
+            },
+            Expression::MacroLiteral {
+                parameters: new_parameters,
+                body: new_body,
+                ..
+            },
+        ) => old_parameters == new_parameters && diff_block(old_body, new_body, &mut inner),
+        (
+            Expression::Call {
+                function: old_function,
+                arguments: old_arguments,
+                ..
+            },
+            Expression::Call {
+                function: new_function,
+                arguments: new_arguments,
+                ..
+            },
+        ) => {
+            diff_child(old_function, new_function, &mut inner)
+                && diff_expressions(old_arguments, new_arguments, &mut inner)
+        }
+        (
+            Expression::Try {
+                body: old_body,
+                name: old_name,
+                handler: old_handler,
+                ..
+            },
+            Expression::Try {
+                body: new_body,
+                name: new_name,
+                handler: new_handler,
+                ..
+            },
+        ) => {
+            old_name == new_name
+                && diff_block(old_body, new_body, &mut inner)
+                && diff_block(old_handler, new_handler, &mut inner)
+        }
+        (
+            Expression::For {
+                name: old_name,
+                iterable: old_iterable,
+                body: old_body,
+                ..
+            },
+            Expression::For {
+                name: new_name,
+                iterable: new_iterable,
+                body: new_body,
+                ..
+            },
+        ) => {
+            old_name == new_name
+                && diff_child(old_iterable, new_iterable, &mut inner)
+                && diff_block(old_body, new_body, &mut inner)
+        }
+        (
+            Expression::Index {
+                left: old_left,
+                index: old_index,
+                ..
+            },
+            Expression::Index {
+                left: new_left,
+                index: new_index,
+                ..
+            },
+        ) => {
+            diff_child(old_left, new_left, &mut inner)
+                && diff_child(old_index, new_index, &mut inner)
+        }
+        (Expression::Prefix(old_operator, old), Expression::Prefix(new_operator, new)) => {
+            old_operator == new_operator && diff_child(old, new, &mut inner)
+        }
+        (
+            Expression::Infix(old_operator, old_left, old_right),
+            Expression::Infix(new_operator, new_left, new_right),
+        ) => {
+            old_operator == new_operator
+                && diff_child(old_left, new_left, &mut inner)
+                && diff_child(old_right, new_right, &mut inner)
+        }
+        _ => false,
+    };
+    if found {
+        changes.append(&mut inner);
+        return true;
+    }
+    match (old.span(), new.span()) {
+        (Some(old), Some(new)) => {
+            changes.push(AstChange::Modified { old, new });
+            true
+        }
+        _ => false,
+    }
+}
+
+fn diff_child(old: &Expression, new: &Expression, changes: &mut Vec<AstChange>) -> bool {
+    dump::expression_source(old) == dump::expression_source(new)
+        || diff_expression(old, new, changes)
+}
+
+fn diff_expressions(old: &[Expression], new: &[Expression], changes: &mut Vec<AstChange>) -> bool {
+    old.len() == new.len()
+        && old
+            .iter()
+            .zip(new)
+            .all(|(old, new)| diff_child(old, new, changes))
+}
+
+fn diff_block(old: &Statement, new: &Statement, changes: &mut Vec<AstChange>) -> bool {
+    if dump::statement_source(old) != dump::statement_source(new) {
+        diff_statement(old, new, changes);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_diff() {
+        let old = "let a = 1;\nlet f = fn(x) {\n  x + 1\n};\nputs(f(a));";
+        let tests = vec![
+            // moved, not changed
+            ("let a = 1; let f = fn(x) { x + 1 }; puts(f(a));", vec![]),
+            (
+                "let a = 1;\nlet b = 2;\nlet f = fn(x) {\n  x + 1\n};\nputs(f(a));",
+                vec!["inserted at line 2, column 1"],
+            ),
+            (
+                "let a = 1;\nputs(f(a));",
+                vec!["removed at line 2, column 1"],
+            ),
+            (
+                "let a = 1;\nlet f = fn(x) {\n  x + 2\n};\nputs(f(a));",
+                vec!["modified at line 3, column 3 (was at line 3, column 3)"],
+            ),
+            (
+                "let a = 2;\nlet f = fn(x) {\n  x + 1\n};\nputs(f(a), 1);",
+                vec![
+                    "modified at line 1, column 1 (was at line 1, column 1)",
+                    "modified at line 5, column 1 (was at line 5, column 1)",
+                ],
+            ),
+            (
+                "let a = 1;\nlet f = fn(x) {\n  x + 1\n};\nputs(g(a));",
+                vec!["modified at line 5, column 6 (was at line 5, column 6)"],
+            ),
+            (
+                "let a = 1;\nlet g = fn(x) {\n  x + 1\n};\nputs(f(a));",
+                vec!["modified at line 2, column 1 (was at line 2, column 1)"],
+            ),
+        ];
+
+        let old = parser::parse(old).unwrap();
+        for (new, expected) in tests {
+            let changes: Vec<String> = diff(&old, &parser::parse(new).unwrap())
+                .iter()
+                .map(AstChange::to_string)
+                .collect();
+            assert_eq!(changes, expected, "{}", new);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use crate::cli::BenchOptions;
+use crate::engine::{Engine, Runner};
+use crate::evaluator::EvalError;
+use crate::test_runner;
+
+// what a function has to start with to be a benchmark
+const BENCH_PREFIX: &str = "bench_";
+
+// `monk bench`: times every top-level function named bench_... in the file,
+// called without arguments, on each engine that's asked for. Each is called
+// a few times first so that the ones that are timed don't include any
+// warming up, e.g. of memoized caches.
+pub fn run(options: &BenchOptions) -> ExitCode {
+    let source = match fs::read_to_string(&options.file) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("error: can't read {}: {}", options.file, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let names = match test_runner::parse(&source) {
+        Ok(program) => test_runner::functions(&program, BENCH_PREFIX),
+        Err(error) => {
+            eprintln!("error: {}: {}", options.file, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    if names.is_empty() {
+        eprintln!(
+            "error: {}: no functions named {}...",
+            options.file, BENCH_PREFIX
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    for name in &names {
+        let mut means = Vec::new();
+        for &engine in &options.engines {
+            match bench(&source, name, engine, options) {
+                Ok(stats) => {
+                    println!(
+                        "{:<width$}  {:<11}  mean {:>10.3?}  median {:>10.3?}  stddev {:>10.3?}  ({} runs)",
+                        name,
+                        engine.name(),
+                        stats.mean,
+                        stats.median,
+                        stats.stddev,
+                        options.iterations,
+                    );
+                    means.push(stats.mean);
+                }
+                Err(error) => {
+                    eprintln!("error: {}: {}: {}", options.file, name, error);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        if let [first, second] = means[..] {
+            let ratio = first.as_secs_f64() / second.as_secs_f64();
+            println!(
+                "{:<width$}  {} is {:.2}x as fast as {}",
+                "", options.engines[1], ratio, options.engines[0]
+            );
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    mean: Duration,
+    median: Duration,
+    stddev: Duration,
+}
+
+impl Stats {
+    fn new(times: &[Duration]) -> Stats {
+        let mut sorted = times.to_vec();
+        sorted.sort();
+        let secs: Vec<f64> = times.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = match secs.len() {
+            0 | 1 => 0.0,
+            n => secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1) as f64,
+        };
+        let middle = sorted.len() / 2;
+        let median = match sorted.len() % 2 {
+            0 => (sorted[middle - 1] + sorted[middle]) / 2,
+            _ => sorted[middle],
+        };
+        Stats {
+            mean: Duration::from_secs_f64(mean),
+            median,
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+// the times of the calls of the function, on a runner that has run the top
+// level of the file
+fn bench(
+    source: &str,
+    name: &str,
+    engine: Engine,
+    options: &BenchOptions,
+) -> Result<Stats, EvalError> {
+    let mut runner = Runner::new(engine);
+    runner.run(test_runner::parse(source).unwrap())?;
+    let call = format!("{}()", name);
+    let mut times = Vec::with_capacity(options.iterations);
+    for iteration in 0..options.warmup + options.iterations {
+        let program = test_runner::parse(&call).unwrap();
+        let start = Instant::now();
+        runner.run(program)?;
+        if iteration >= options.warmup {
+            times.push(start.elapsed());
+        }
+    }
+    Ok(Stats::new(&times))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let millis = |times: &[u64]| -> Vec<Duration> {
+            times.iter().map(|&ms| Duration::from_millis(ms)).collect()
+        };
+        let stats = Stats::new(&millis(&[4, 1, 3, 2]));
+        assert_eq!(stats.mean, Duration::from_micros(2500));
+        assert_eq!(stats.median, Duration::from_micros(2500));
+        // the sample standard deviation, sqrt(5 / 3) ms
+        assert_eq!(stats.stddev.as_micros(), 1290);
+
+        let stats = Stats::new(&millis(&[7]));
+        assert_eq!(stats.median, Duration::from_millis(7));
+        assert_eq!(stats.stddev, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bench() {
+        let options = BenchOptions {
+            iterations: 3,
+            warmup: 1,
+            ..BenchOptions::default()
+        };
+        let source = "let n = 10; let bench_sum = fn() { n + 1 }; let bench_fail = fn() { 1 / 0 };";
+        for engine in Engine::ALL {
+            assert!(bench(source, "bench_sum", engine, &options).is_ok());
+            let error = bench(source, "bench_fail", engine, &options).unwrap_err();
+            assert_eq!(error.message, "division by zero: 1 / 0");
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// Synthetic programs for benchmarking the interpreter, with their size as a
+// parameter so that a benchmark can show how the time grows with it. They're
+// valid programs that run on either engine without output. The ones that
+// loop recurse by halving their range rather than use a for loop, so that
+// they measure calls, and stay within the recursion limit however large they
+// get.
+
+// A large file of `count` statements of the kinds real code has: functions
+// with ifs, hashes, arrays, strings and calls of the functions before them.
+pub fn program(count: usize) -> String {
+    let mut source = String::new();
+    for i in 0..count {
+        let statement = match i % 4 {
+            0 => format!(
+                "let {} = fn(x, y) {{ if (x < y) {{ x * 2 + y }} else {{ x - y }} }};\n",
+                name(i)
+            ),
+            1 => format!(
+                "let {} = {{\"name\": \"{}\", \"size\": {}, \"items\": [1, 2, 3]}};\n",
+                name(i),
+                name(i),
+                i
+            ),
+            2 => format!("let {} = [{}, {} + 1, \"text\", true];\n", name(i), i, i),
+            // the function three statements up
+            _ => format!("let {} = {}({}, 2);\n", name(i), name(i - 3), i),
+        };
+        source.push_str(&statement);
+    }
+    source
+}
+
+// identifiers can't have digits, so the statements are named in letters
+fn name(mut i: usize) -> String {
+    let mut name = String::from("v");
+    loop {
+        name.push((b'a' + (i % 26) as u8) as char);
+        i /= 26;
+        if i == 0 {
+            return name;
+        }
+    }
+}
+
+// `1 + 1` nested `depth` deep in parentheses, which can be up to 254, as the
+// additions inside count towards the parser's limit of 256 too:
+// (((1 + 1) + 1) + 1)
+pub fn nested(depth: usize) -> String {
+    format!("{}1{}", "(".repeat(depth), " + 1)".repeat(depth))
+}
+
+// the naive recursive fibonacci, whose time is in the calls
+pub fn fib(n: usize) -> String {
+    format!(
+        "let fib = fn(n) {{ if (n < 2) {{ n }} else {{ fib(n - 1) + fib(n - 2) }} }};\nfib({});",
+        n
+    )
+}
+
+// adds up the numbers below n, the loop of the arithmetic
+pub fn sum(n: usize) -> String {
+    format!(
+        "let sum = fn(lo, hi) {{ if (hi - lo < 2) {{ lo }} else {{ let mid = lo + (hi - lo) / 2; \
+         sum(lo, mid) + sum(mid, hi) }} }};\nsum(0, {});",
+        n
+    )
+}
+
+// builds a string of n characters out of ever longer strings, and gives its
+// length
+pub fn strings(n: usize) -> String {
+    format!(
+        "let build = fn(n) {{ if (n < 2) {{ \"x\" }} else {{ build(n / 2) + build(n - n / 2) }} }};\n\
+         len(build({}));",
+        n
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, Runner};
+    use crate::parser;
+
+    #[test]
+    fn test_programs() {
+        let tests = vec![
+            (nested(200), "201"),
+            (fib(15), "610"),
+            (sum(10_000), "49995000"),
+            (strings(1_000), "1000"),
+        ];
+
+        for engine in Engine::ALL {
+            for (source, expected) in &tests {
+                let result = Runner::new(engine).run(parser::parse(source).unwrap());
+                assert_eq!(
+                    result.unwrap().to_string(),
+                    *expected,
+                    "{} on {}",
+                    source,
+                    engine
+                );
+            }
+
+            let mut runner = Runner::new(engine);
+            runner.run(parser::parse(&program(40)).unwrap()).unwrap();
+            assert_eq!(runner.bindings().len(), 40);
+            assert_eq!(
+                runner.lookup("vmb").unwrap().to_string(),
+                "[38, 39, \"text\", true]"
+            );
+            assert_eq!(runner.lookup("vnb").unwrap().to_string(), "37");
+        }
+    }
+}
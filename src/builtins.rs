@@ -0,0 +1,1172 @@
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::{env, fs, mem};
+
+use crate::compiler;
+use crate::convert::Data;
+use crate::evaluator::{
+    integer_object, native_bool_to_boolean_object, null_object, Env, ErrorKind, EvalError,
+    HashPair, Memoized, Object,
+};
+use crate::iter::{Iter, Source};
+use crate::printer;
+use crate::sandbox::{self, Capability};
+use crate::symbol::Symbol;
+use crate::task::{Channel, Task};
+#[cfg(feature = "time")]
+use crate::time;
+
+thread_local! {
+    // what args() returns: the script and the arguments after it on the
+    // command line, or nothing in the REPL
+    static ARGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    // where puts writes and where input is read from, if not stdout and stdin
+    static OUTPUT: RefCell<Option<Box<dyn Write>>> = const { RefCell::new(None) };
+    static INPUT: RefCell<Option<Box<dyn BufRead>>> = const { RefCell::new(None) };
+    // the tests that test() registers, when they're collected for monk test
+    static TESTS: RefCell<Option<Vec<Test>>> = const { RefCell::new(None) };
+}
+
+// a test that test() registered, by its name
+pub type Test = (String, Rc<Object>);
+
+// Builtins that look at the variables where they're called. The engines
+// answer calls of them by name themselves, as builtins only get their
+// arguments.
+pub const LOCALS: &str = "locals";
+pub const GLOBALS: &str = "globals";
+
+pub type BuiltinFn = fn(&[Rc<Object>]) -> Result<Rc<Object>, EvalError>;
+
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    // how it's called and what it does, as shown by :doc
+    pub signature: &'static str,
+    pub doc: &'static str,
+    pub func: BuiltinFn,
+}
+
+// builtins are unique by name, comparing the function pointers is unreliable
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Builtin) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Debug for Builtin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Builtin({})", self.name)
+    }
+}
+
+impl Display for Builtin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "builtin function {}", self.name)
+    }
+}
+
+pub type NativeFn = dyn Fn(&[Rc<Object>]) -> Result<Rc<Object>, EvalError>;
+
+// A builtin of the program that embeds the interpreter, which unlike the
+// others can keep state, e.g. the callback of a C host and its data.
+#[derive(Clone)]
+pub struct Native {
+    pub name: Symbol,
+    pub func: Rc<NativeFn>,
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        Rc::ptr_eq(&self.func, &other.func)
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+impl Display for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "builtin function {}", self.name)
+    }
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "len",
+        signature: "len(value)",
+        doc: "the number of characters in a string, elements in an array or pairs in a hash",
+        func: len,
+    },
+    Builtin {
+        name: "first",
+        signature: "first(array)",
+        doc: "the first element of the array, or null if it's empty",
+        func: first,
+    },
+    Builtin {
+        name: "last",
+        signature: "last(array)",
+        doc: "the last element of the array, or null if it's empty",
+        func: last,
+    },
+    Builtin {
+        name: "rest",
+        signature: "rest(array)",
+        doc: "a new array of all elements but the first, or null if it's empty",
+        func: rest,
+    },
+    Builtin {
+        name: "push",
+        signature: "push(array, value)",
+        doc: "a new array with the value appended; the array itself is left as is",
+        func: push,
+    },
+    Builtin {
+        name: "memoize",
+        signature: "memoize(function)",
+        doc: "the function, remembering its results by arguments so each is only computed once",
+        func: memoize,
+    },
+    Builtin {
+        name: "puts",
+        signature: "puts(values...)",
+        doc: "prints each value on a line of its own, and returns null",
+        func: puts,
+    },
+    Builtin {
+        name: "error",
+        signature: "error(message)",
+        doc: "raises an error with the message, which try/catch can catch",
+        func: error,
+    },
+    Builtin {
+        name: "message",
+        signature: "message(error)",
+        doc: "the message of a caught error",
+        func: message,
+    },
+    Builtin {
+        name: "kind",
+        signature: "kind(error)",
+        doc: "the kind of a caught error, e.g. \"TYPE_MISMATCH\"",
+        func: kind,
+    },
+    Builtin {
+        name: "trace",
+        signature: "trace(error)",
+        doc: "the calls a caught error unwound through, one per line, innermost first",
+        func: trace,
+    },
+    Builtin {
+        name: "dis",
+        signature: "dis(function)",
+        doc: "the bytecode instructions the VM runs for the function",
+        func: dis,
+    },
+    Builtin {
+        name: "assert",
+        signature: "assert(condition, message)",
+        doc: "raises an assertion error with the optional message unless the condition is truthy",
+        func: assert,
+    },
+    Builtin {
+        name: "assert_eq",
+        signature: "assert_eq(actual, expected)",
+        doc: "raises an assertion error showing both values unless they're equal",
+        func: assert_eq,
+    },
+    Builtin {
+        name: "args",
+        signature: "args()",
+        doc: "the script's path and the command line arguments after it, as strings",
+        func: args,
+    },
+    Builtin {
+        name: "input",
+        signature: "input()",
+        doc: "the next line of stdin without its newline, or null at the end of it",
+        func: input,
+    },
+    Builtin {
+        name: "read_file",
+        signature: "read_file(path)",
+        doc: "the contents of the file as a string",
+        func: read_file,
+    },
+    Builtin {
+        name: "getenv",
+        signature: "getenv(name)",
+        doc: "the value of the environment variable, or null if it isn't set",
+        func: getenv,
+    },
+    Builtin {
+        name: "test",
+        signature: "test(name, function)",
+        doc:
+            "registers the function as a test with the name, for monk test to run, and returns null",
+        func: test,
+    },
+    Builtin {
+        name: "iter",
+        signature: "iter(value)",
+        doc: "an iterator over the elements of an array or the [key, value] pairs of a hash",
+        func: iter,
+    },
+    Builtin {
+        name: "next",
+        signature: "next(iterator)",
+        doc: "the next value of the iterator, or null once it's done",
+        func: next,
+    },
+    Builtin {
+        name: "done",
+        signature: "done(iterator)",
+        doc: "whether the iterator has no values left for next",
+        func: done,
+    },
+    Builtin {
+        name: "range",
+        signature: "range([start,] end)",
+        doc: "an iterator over the integers from start, or 0, up to but not including end",
+        func: range,
+    },
+    Builtin {
+        name: "spawn",
+        signature: "spawn(function, args...)",
+        doc: "a task that calls the function with the args on a thread of its own; \
+              the function gets nothing from outside of it but the args",
+        func: spawn,
+    },
+    Builtin {
+        name: "join",
+        signature: "join(task)",
+        doc: "waits for the task to finish and returns its value, or raises its error",
+        func: join,
+    },
+    Builtin {
+        name: "channel",
+        signature: "channel()",
+        doc: "a channel to send values between tasks through",
+        func: channel,
+    },
+    Builtin {
+        name: "send",
+        signature: "send(channel, value)",
+        doc: "sends the value, which has to be data or a channel, and returns null",
+        func: send,
+    },
+    Builtin {
+        name: "recv",
+        signature: "recv(channel)",
+        doc: "the first value sent to the channel, waiting for one if it's empty",
+        func: recv,
+    },
+    Builtin {
+        name: LOCALS,
+        signature: "locals()",
+        doc: "a hash of the names and values of the local variables where it's called",
+        func: locals,
+    },
+    Builtin {
+        name: GLOBALS,
+        signature: "globals()",
+        doc: "a hash of the names and values of the global variables",
+        func: globals,
+    },
+    // last, so that the positions of the others don't depend on the feature
+    Builtin {
+        name: "map",
+        signature: "map(iterable, function)",
+        doc: "the values of the array passed through the function, or for a hash or iterator an iterator over them, which calls the function as next asks for them",
+        func: map,
+    },
+    Builtin {
+        name: "filter",
+        signature: "filter(iterable, function)",
+        doc: "the values of the array the function gives a truthy value for, or for a hash or iterator an iterator over them, as next asks for them",
+        func: filter,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_now",
+        signature: "time_now()",
+        doc: "the current time, in milliseconds since the Unix epoch",
+        func: time::now,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_parse",
+        signature: "time_parse(format, text)",
+        doc: "the time the text is in the strftime format, taken to be UTC without an offset",
+        func: time::parse,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_format",
+        signature: "time_format(format, time)",
+        doc: "the time as text in the strftime format, in UTC",
+        func: time::format,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_year",
+        signature: "time_year(time)",
+        doc: "the year of the time, in UTC",
+        func: time::year,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_month",
+        signature: "time_month(time)",
+        doc: "the month of the time from 1 for January, in UTC",
+        func: time::month,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_day",
+        signature: "time_day(time)",
+        doc: "the day of the month of the time, in UTC",
+        func: time::day,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_hour",
+        signature: "time_hour(time)",
+        doc: "the hour of the time, in UTC",
+        func: time::hour,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_minute",
+        signature: "time_minute(time)",
+        doc: "the minute of the time",
+        func: time::minute,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_second",
+        signature: "time_second(time)",
+        doc: "the second of the time",
+        func: time::second,
+    },
+    #[cfg(feature = "time")]
+    Builtin {
+        name: "time_weekday",
+        signature: "time_weekday(time)",
+        doc: "the day of the week of the time from 1 for Monday to 7 for Sunday, in UTC",
+        func: time::weekday,
+    },
+];
+
+pub fn all() -> &'static [Builtin] {
+    BUILTINS
+}
+
+pub fn by_position(index: usize) -> Option<Builtin> {
+    BUILTINS.get(index).copied()
+}
+
+pub fn lookup(name: &str) -> Option<Builtin> {
+    BUILTINS
+        .iter()
+        .find(|builtin| builtin.name == name)
+        .copied()
+}
+
+// the index of the builtin, by which compiled code refers to it
+pub fn position(name: &str) -> Option<usize> {
+    BUILTINS.iter().position(|builtin| builtin.name == name)
+}
+
+fn len(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("len", args, 1)?;
+    let len = match &*args[0] {
+        Object::String(value) => value.chars().count(),
+        Object::Array(elements) => elements.len(),
+        Object::Hash(pairs) => pairs.len(),
+        arg => return Err(wrong_type("len", "STRING, ARRAY or HASH", arg)),
+    };
+    Ok(integer_object(len as i64))
+}
+
+fn first(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let elements = array_argument("first", args)?;
+    Ok(elements.first().cloned().unwrap_or_else(null_object))
+}
+
+fn last(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let elements = array_argument("last", args)?;
+    Ok(elements.last().cloned().unwrap_or_else(null_object))
+}
+
+// everything but the first element, or null for an empty array
+fn rest(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let elements = array_argument("rest", args)?;
+    if elements.is_empty() {
+        return Ok(null_object());
+    }
+    Ok(Object::Array(elements[1..].to_vec()).into())
+}
+
+// a copy of the array with the value appended, the original is left as is
+fn push(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("push", args, 2)?;
+    match &*args[0] {
+        Object::Array(elements) => {
+            let mut elements = elements.clone();
+            elements.push(Rc::clone(&args[1]));
+            Ok(Object::Array(elements).into())
+        }
+        arg => Err(wrong_type("push", "ARRAY", arg)),
+    }
+}
+
+// the evaluator does the caching when the wrapper is called
+fn memoize(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("memoize", args, 1)?;
+    match &*args[0] {
+        Object::Function(_) | Object::Closure(_) => {
+            Ok(Object::Memoized(Memoized::new(Rc::clone(&args[0]))).into())
+        }
+        arg => Err(wrong_type("memoize", "FUNCTION", arg)),
+    }
+}
+
+fn puts(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    OUTPUT
+        .with(|output| match &mut *output.borrow_mut() {
+            Some(output) => args.iter().try_for_each(|arg| writeln!(output, "{}", arg)),
+            None => {
+                args.iter().for_each(|arg| println!("{}", arg));
+                Ok(())
+            }
+        })
+        .map_err(|error| {
+            EvalError::new(
+                ErrorKind::User,
+                format!("can't write the output: {}", error),
+            )
+        })?;
+    Ok(null_object())
+}
+
+// raises an error with the given message, to be caught with try/catch
+fn error(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("error", args, 1)?;
+    match &*args[0] {
+        Object::String(message) => Err(EvalError::new(ErrorKind::User, message.clone())),
+        arg => Err(wrong_type("error", "STRING", arg)),
+    }
+}
+
+fn message(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let error = error_argument("message", args)?;
+    Ok(Object::String(error.message.clone()).into())
+}
+
+fn kind(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let error = error_argument("kind", args)?;
+    Ok(Object::String(error.kind.to_string()).into())
+}
+
+// one line per frame, innermost call first
+fn trace(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let error = error_argument("trace", args)?;
+    let frames: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+    Ok(Object::String(frames.join("\n")).into())
+}
+
+// the instructions the VM runs for a function, which is compiled first if
+// the tree-walker created it
+fn dis(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("dis", args, 1)?;
+    let function = match &*args[0] {
+        Object::Closure(closure) => Rc::clone(&closure.function),
+        Object::Function(function) => compiler::compile_function(function)
+            .map_err(|error| EvalError::new(ErrorKind::Compile, error.message))?,
+        Object::Memoized(memoized) => return dis(&[Rc::clone(memoized.function())]),
+        arg => return Err(wrong_type("dis", "FUNCTION", arg)),
+    };
+    let instructions = function.instructions.to_string();
+    Ok(Object::String(instructions.trim_end().to_string()).into())
+}
+
+fn assert(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let message = match args {
+        [_] => "assertion failed".to_string(),
+        [_, message] => match &**message {
+            Object::String(message) => format!("assertion failed: {}", message),
+            arg => return Err(wrong_type("assert", "STRING", arg)),
+        },
+        _ => return check_arity("assert", args, 2).map(|_| null_object()),
+    };
+    match args[0].is_truthy() {
+        true => Ok(null_object()),
+        false => Err(EvalError::new(ErrorKind::Assertion, message)),
+    }
+}
+
+fn assert_eq(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("assert_eq", args, 2)?;
+    if args[0] == args[1] {
+        return Ok(null_object());
+    }
+    let actual = printer::render_line(&args[0]);
+    let expected = printer::render_line(&args[1]);
+    Err(EvalError::new(
+        ErrorKind::Assertion,
+        format!(
+            "assertion failed: {} != {}\n{}",
+            actual,
+            expected,
+            difference(&actual, &expected)
+        ),
+    ))
+}
+
+// both values, with a caret under the first character where they differ
+fn difference(actual: &str, expected: &str) -> String {
+    let same = actual
+        .chars()
+        .zip(expected.chars())
+        .take_while(|(a, e)| a == e)
+        .count();
+    format!(
+        "  expected: {}\n    actual: {}\n            {}^",
+        expected,
+        actual,
+        " ".repeat(same)
+    )
+}
+
+fn args(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("args", args, 0)?;
+    let args = ARGS.with(|args| {
+        args.borrow()
+            .iter()
+            .map(|arg| Object::String(arg.clone()).into())
+            .collect()
+    });
+    Ok(Object::Array(args).into())
+}
+
+// sets what args() returns, before a script runs
+pub fn set_args(args: Vec<String>) {
+    ARGS.with(|current| *current.borrow_mut() = args);
+}
+
+fn input(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("input", args, 0)?;
+    sandbox::require("input", Capability::Stdin)?;
+    let mut line = String::new();
+    INPUT
+        .with(|input| match &mut *input.borrow_mut() {
+            Some(input) => input.read_line(&mut line),
+            None => io::stdin().read_line(&mut line),
+        })
+        .map_err(|error| {
+            EvalError::new(ErrorKind::Io, format!("can't read the input: {}", error))
+        })?;
+    if line.is_empty() {
+        return Ok(null_object());
+    }
+    let end = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(end);
+    Ok(Object::String(line).into())
+}
+
+fn read_file(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let path = string_argument("read_file", args)?;
+    sandbox::require("read_file", Capability::Filesystem)?;
+    fs::read_to_string(path)
+        .map(|contents| Object::String(contents).into())
+        .map_err(|error| EvalError::new(ErrorKind::Io, format!("can't read {}: {}", path, error)))
+}
+
+fn getenv(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let name = string_argument("getenv", args)?;
+    sandbox::require("getenv", Capability::Env)?;
+    Ok(env::var(name).map_or_else(|_| null_object(), |value| Object::String(value).into()))
+}
+
+// registers the test when tests are collected, and otherwise does nothing
+fn test(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("test", args, 2)?;
+    let name = match &*args[0] {
+        Object::String(name) => name,
+        arg => return Err(wrong_type("test", "STRING", arg)),
+    };
+    if !matches!(&*args[1], Object::Function(_) | Object::Closure(_)) {
+        return Err(wrong_type("test", "FUNCTION", &args[1]));
+    }
+    TESTS.with(|tests| {
+        if let Some(tests) = &mut *tests.borrow_mut() {
+            tests.push((name.clone(), Rc::clone(&args[1])));
+        }
+    });
+    Ok(null_object())
+}
+
+// an iterator is its own iterator, so iter() can be given either
+fn iter(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("iter", args, 1)?;
+    match &*args[0] {
+        Object::Array(_) | Object::Hash(_) => {
+            Ok(Object::Iterator(Iter::new(Source::Collection(Rc::clone(&args[0])))).into())
+        }
+        Object::Iterator(_) => Ok(Rc::clone(&args[0])),
+        arg => Err(wrong_type("iter", "ARRAY, HASH or ITERATOR", arg)),
+    }
+}
+
+fn next(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("next", args, 1)?;
+    match &*args[0] {
+        Object::Iterator(iter) => Ok(iter.next().unwrap_or_else(null_object)),
+        arg => Err(wrong_type("next", "ITERATOR", arg)),
+    }
+}
+
+fn done(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("done", args, 1)?;
+    match &*args[0] {
+        Object::Iterator(iter) => Ok(native_bool_to_boolean_object(iter.is_done())),
+        arg => Err(wrong_type("done", "ITERATOR", arg)),
+    }
+}
+
+// the integers are only made as next() asks for them
+fn range(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let (start, end) = match args {
+        [end] => (&Object::Integer(0), &**end),
+        [start, end] => (&**start, &**end),
+        _ => return check_arity("range", args, 2).map(|_| null_object()),
+    };
+    match (start, end) {
+        (Object::Integer(start), Object::Integer(end)) => {
+            Ok(Object::Iterator(Iter::new(Source::Range(*start, *end))).into())
+        }
+        (Object::Integer(_), arg) | (arg, _) => Err(wrong_type("range", "INTEGER", arg)),
+    }
+}
+
+// the engines call the function as the iterator is stepped, or over an array
+// for each element right away, see iter::step
+fn map(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    lazy("map", args, Source::Map)
+}
+
+fn filter(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    lazy("filter", args, Source::Filter)
+}
+
+fn lazy(
+    name: &str,
+    args: &[Rc<Object>],
+    source: fn(Rc<Object>, Rc<Object>) -> Source,
+) -> Result<Rc<Object>, EvalError> {
+    check_arity(name, args, 2)?;
+    let iterator = match &*args[0] {
+        Object::Array(_) | Object::Hash(_) | Object::Iterator(_) => iter(&args[..1])?,
+        arg => return Err(wrong_type(name, "ARRAY, HASH or ITERATOR", arg)),
+    };
+    match &*args[1] {
+        Object::Function(_)
+        | Object::Closure(_)
+        | Object::Builtin(_)
+        | Object::Native(_)
+        | Object::Memoized(_) => {}
+        arg => return Err(wrong_type(name, "FUNCTION", arg)),
+    }
+    Ok(Object::Iterator(Iter::new(source(iterator, Rc::clone(&args[1])))).into())
+}
+
+// The function is compiled again from its body for the thread it runs on,
+// where its arguments are copied to, bound to its name again if it calls
+// itself by it.
+fn spawn(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    let Some((function, args)) = args.split_first() else {
+        return check_arity("spawn", args, 1).map(|_| null_object());
+    };
+    sandbox::require("spawn", Capability::Threads)?;
+    let task = match &**function {
+        Object::Function(inner) => Task::spawn(inner.parameters(), inner.body(), args, |name| {
+            visible(inner.env(), name).is_some_and(|value| Rc::ptr_eq(&value, function))
+        })?,
+        Object::Closure(closure) => match &closure.function.body {
+            Some(body) => Task::spawn(&closure.function.parameters, body, args, |name| {
+                closure.function.name.as_ref() == Some(name)
+            })?,
+            None => return Err(EvalError::new(
+                ErrorKind::WrongArguments,
+                "can't spawn a function without its source, like one of a compiled file or a task",
+            )),
+        },
+        Object::Memoized(memoized) => {
+            let args: Vec<_> = std::iter::once(memoized.function())
+                .chain(args)
+                .cloned()
+                .collect();
+            return spawn(&args);
+        }
+        arg => return Err(wrong_type("spawn", "FUNCTION", arg)),
+    };
+    Ok(Object::Task(task).into())
+}
+
+// the value of the name in the environment, the innermost binding first
+fn visible(env: &Env, name: &Symbol) -> Option<Rc<Object>> {
+    let env = env.borrow();
+    let bindings = env.bindings().into_iter().rev();
+    match bindings.into_iter().find(|(bound, _)| bound == name) {
+        Some((_, value)) => Some(value),
+        None => visible(env.outer()?, name),
+    }
+}
+
+fn join(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("join", args, 1)?;
+    match &*args[0] {
+        Object::Task(task) => task.join(),
+        arg => Err(wrong_type("join", "TASK", arg)),
+    }
+}
+
+fn channel(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("channel", args, 0)?;
+    sandbox::require("channel", Capability::Threads)?;
+    Ok(Object::Channel(Channel::new()).into())
+}
+
+fn send(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("send", args, 2)?;
+    sandbox::require("send", Capability::Threads)?;
+    let Object::Channel(channel) = &*args[0] else {
+        return Err(wrong_type("send", "CHANNEL", &args[0]));
+    };
+    let message = Data::exact(&args[1]).map_err(|type_name| {
+        EvalError::new(
+            ErrorKind::WrongArguments,
+            format!("can't send {} to another thread", type_name),
+        )
+        .with_types(&[type_name])
+    })?;
+    channel.send(message);
+    Ok(null_object())
+}
+
+fn recv(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("recv", args, 1)?;
+    sandbox::require("recv", Capability::Threads)?;
+    match &*args[0] {
+        Object::Channel(channel) => Ok(channel.recv()?.into_object()),
+        arg => Err(wrong_type("recv", "CHANNEL", arg)),
+    }
+}
+
+// only reached when they're called some other way, e.g. under another name
+fn locals(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity(LOCALS, args, 0)?;
+    Err(not_called_by_name(LOCALS))
+}
+
+fn globals(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity(GLOBALS, args, 0)?;
+    Err(not_called_by_name(GLOBALS))
+}
+
+fn not_called_by_name(name: &str) -> EvalError {
+    EvalError::new(
+        ErrorKind::WrongArguments,
+        format!("`{}` has to be called by its name, like {}()", name, name),
+    )
+}
+
+// what locals() and globals() return: a hash of the variables' names to
+// their values, sorted by name so that both engines give the same hash
+pub fn variables(mut bindings: Vec<(Symbol, Rc<Object>)>) -> Rc<Object> {
+    bindings.sort_by(|a, b| str::cmp(&a.0, &b.0));
+    let pairs = bindings
+        .into_iter()
+        .map(|(name, value)| {
+            let key: Rc<Object> = Object::String(name.to_string()).into();
+            (key.hash_key().unwrap(), HashPair { key, value })
+        })
+        .collect();
+    Object::Hash(pairs).into()
+}
+
+// Sets where test() registers tests, none to not collect them, returning
+// the tests registered since it was last set.
+pub fn set_tests(tests: Option<Vec<Test>>) -> Option<Vec<Test>> {
+    TESTS.with(|current| mem::replace(&mut *current.borrow_mut(), tests))
+}
+
+// sets where puts writes, none for stdout, returning where it wrote before
+pub fn set_output(output: Option<Box<dyn Write>>) -> Option<Box<dyn Write>> {
+    OUTPUT.with(|current| mem::replace(&mut *current.borrow_mut(), output))
+}
+
+// whether puts writes somewhere other than stdout
+pub(crate) fn has_output() -> bool {
+    OUTPUT.with(|output| output.borrow().is_some())
+}
+
+// writes what puts wrote elsewhere, like on a task's thread, where puts
+// writes on this thread
+pub(crate) fn write_output(bytes: &[u8]) -> io::Result<()> {
+    OUTPUT.with(|output| match &mut *output.borrow_mut() {
+        Some(output) => output.write_all(bytes),
+        None => io::stdout().write_all(bytes),
+    })
+}
+
+// sets what input is read from, none for stdin, returning the input before
+pub fn set_input(input: Option<Box<dyn BufRead>>) -> Option<Box<dyn BufRead>> {
+    INPUT.with(|current| mem::replace(&mut *current.borrow_mut(), input))
+}
+
+fn error_argument<'a>(name: &str, args: &'a [Rc<Object>]) -> Result<&'a EvalError, EvalError> {
+    check_arity(name, args, 1)?;
+    match &*args[0] {
+        Object::Error(error) => Ok(error),
+        arg => Err(wrong_type(name, "ERROR", arg)),
+    }
+}
+
+fn string_argument<'a>(name: &str, args: &'a [Rc<Object>]) -> Result<&'a str, EvalError> {
+    check_arity(name, args, 1)?;
+    match &*args[0] {
+        Object::String(value) => Ok(value),
+        arg => Err(wrong_type(name, "STRING", arg)),
+    }
+}
+
+fn array_argument<'a>(name: &str, args: &'a [Rc<Object>]) -> Result<&'a [Rc<Object>], EvalError> {
+    check_arity(name, args, 1)?;
+    match &*args[0] {
+        Object::Array(elements) => Ok(elements),
+        arg => Err(wrong_type(name, "ARRAY", arg)),
+    }
+}
+
+pub(crate) fn check_arity(name: &str, args: &[Rc<Object>], want: usize) -> Result<(), EvalError> {
+    if args.len() != want {
+        return Err(EvalError::new(
+            ErrorKind::WrongArguments,
+            format!(
+                "wrong number of arguments to `{}`: got={}, want={}",
+                name,
+                args.len(),
+                want
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn wrong_type(name: &str, want: &str, arg: &Object) -> EvalError {
+    EvalError::new(
+        ErrorKind::WrongArguments,
+        format!(
+            "argument to `{}` must be {}, got {}",
+            name,
+            want,
+            arg.type_of()
+        ),
+    )
+    .with_types(&[arg.type_of()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::assert_outcomes;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("puts").map(|b| b.name), Some("puts"));
+        assert_eq!(lookup("nope"), None);
+
+        assert_eq!(position("len"), Some(0));
+        assert_eq!(by_position(0).map(|b| b.name), Some("len"));
+        assert_eq!(position("nope"), None);
+    }
+
+    #[test]
+    fn test_error_builtins() {
+        let raised = error(&[Object::String("boom".into()).into()]).unwrap_err();
+        assert_eq!(raised.kind, ErrorKind::User);
+        assert_eq!(raised.message, "boom");
+
+        let caught: Rc<Object> = Object::Error(raised).into();
+        let tests = vec![(message as BuiltinFn, "boom"), (kind, "USER"), (trace, "")];
+        for (func, expected) in tests {
+            match &*func(&[Rc::clone(&caught)]).unwrap() {
+                Object::String(value) => assert_eq!(value, expected),
+                obj => panic!("object is not String. got={:?}", obj),
+            }
+        }
+
+        let wrong = message(&[Object::Integer(1).into()]).unwrap_err();
+        assert_eq!(
+            wrong.message,
+            "argument to `message` must be ERROR, got INTEGER"
+        );
+        let wrong = error(&[]).unwrap_err();
+        assert_eq!(
+            wrong.message,
+            "wrong number of arguments to `error`: got=0, want=1"
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        let string = |value: &str| -> Rc<Object> { Object::String(value.into()).into() };
+        let int = |value: i64| -> Rc<Object> { Object::Integer(value).into() };
+        let tests = vec![
+            (assert as BuiltinFn, vec![int(1)], None),
+            (assert, vec![null_object()], Some("assertion failed")),
+            (
+                assert,
+                vec![Object::Boolean(false).into(), string("no")],
+                Some("assertion failed: no"),
+            ),
+            (assert_eq, vec![string("a"), string("a")], None),
+            (
+                assert_eq,
+                vec![string("a"), int(1)],
+                Some(
+                    "assertion failed: \"a\" != 1\n  expected: 1\n    actual: \"a\"\n            ^",
+                ),
+            ),
+            (
+                assert_eq,
+                vec![string("abc"), string("abd")],
+                Some(
+                    "assertion failed: \"abc\" != \"abd\"\n  expected: \"abd\"\n    \
+                     actual: \"abc\"\n               ^",
+                ),
+            ),
+        ];
+        for (func, args, expected) in tests {
+            let result = func(&args).err().map(|error| {
+                assert_eq!(error.kind, ErrorKind::Assertion);
+                error.message
+            });
+            assert_eq!(result.as_deref(), expected, "{:?}", args);
+        }
+
+        let wrong = assert(&[]).unwrap_err();
+        assert_eq!(
+            wrong.message,
+            "wrong number of arguments to `assert`: got=0, want=2"
+        );
+    }
+
+    #[test]
+    fn test_sandboxed_builtins() {
+        let string = |value: &str| -> Rc<Object> { Object::String(value.into()).into() };
+        let path = std::env::temp_dir().join("return_to_monk_test_read_file.txt");
+        fs::write(&path, "contents").unwrap();
+        let path = string(&path.display().to_string());
+
+        let denied = read_file(&[Rc::clone(&path)]).unwrap_err();
+        assert_eq!(denied.kind, ErrorKind::PermissionDenied);
+        let denied = getenv(&[string("PATH")]).unwrap_err();
+        assert_eq!(denied.kind, ErrorKind::PermissionDenied);
+
+        let before = sandbox::set_capabilities(sandbox::Capabilities::all());
+        assert_eq!(read_file(&[path]).unwrap().to_string(), "contents");
+        let missing = read_file(&[string("/no/such/file")]).unwrap_err();
+        assert_eq!(missing.kind, ErrorKind::Io);
+        assert!(missing.message.starts_with("can't read /no/such/file: "));
+        assert_eq!(
+            *getenv(&[string("RETURN_TO_MONK_UNSET")]).unwrap(),
+            Object::Null
+        );
+        let wrong = getenv(&[Object::Integer(1).into()]).unwrap_err();
+        assert_eq!(
+            wrong.message,
+            "argument to `getenv` must be STRING, got INTEGER"
+        );
+        sandbox::set_capabilities(before);
+    }
+
+    #[test]
+    fn test_args() {
+        let strings = |obj: Rc<Object>| -> Vec<String> {
+            match &*obj {
+                Object::Array(elements) => elements.iter().map(|e| e.to_string()).collect(),
+                obj => panic!("object is not Array. got={:?}", obj),
+            }
+        };
+        assert!(strings(args(&[]).unwrap()).is_empty());
+        set_args(vec!["script.mk".to_string(), "--verbose".to_string()]);
+        assert_eq!(strings(args(&[]).unwrap()), ["script.mk", "--verbose"]);
+        set_args(Vec::new());
+
+        let wrong = args(&[Object::Integer(1).into()]).unwrap_err();
+        assert_eq!(
+            wrong.message,
+            "wrong number of arguments to `args`: got=1, want=0"
+        );
+    }
+
+    #[test]
+    fn test_iterators() {
+        let tests = vec![
+            (
+                "let it = iter([1, 2]); [next(it), next(it), next(it)]",
+                "[1, 2, null]",
+            ),
+            (
+                r#"let it = iter({"b": 1, "a": 2}); [next(it), next(it), next(it)]"#,
+                r#"[["b", 1], ["a", 2], null]"#,
+            ),
+            ("let it = iter([]); iter(it) == it", "true"),
+            (
+                "let it = range(3); [next(it), next(it), next(it), next(it)]",
+                "[0, 1, 2, null]",
+            ),
+            (
+                "let it = range(-1, 1); [next(it), next(it), next(it)]",
+                "[-1, 0, null]",
+            ),
+            ("next(range(5, 5))", "null"),
+            (
+                "let it = iter([1]); let before = done(it); next(it); [before, done(it)]",
+                "[false, true]",
+            ),
+            // a range is never materialized
+            (
+                "let it = range(9223372036854775806); next(it); next(it)",
+                "1",
+            ),
+            (
+                "let sum = fn(it, total) { if (done(it)) { total } \
+                 else { sum(it, total + next(it)) } }; sum(range(1, 101), 0)",
+                "5050",
+            ),
+            ("range(2, 4)", "range(2, 4)"),
+            ("iter([1, \"a\"])", "iter([1, \"a\"])"),
+            (
+                "iter(1)",
+                "argument to `iter` must be ARRAY, HASH or ITERATOR, got INTEGER",
+            ),
+            (
+                "next([1])",
+                "argument to `next` must be ITERATOR, got ARRAY",
+            ),
+            (
+                "range(1, true)",
+                "argument to `range` must be INTEGER, got BOOLEAN",
+            ),
+            (
+                "range()",
+                "wrong number of arguments to `range`: got=0, want=2",
+            ),
+            (
+                "let it = map(iter([1, 2]), fn(x) { x * 10 }); [next(it), next(it), next(it)]",
+                "[10, 20, null]",
+            ),
+            // over an array they give an array
+            ("map([1, 2, 3], fn(x) { x * 10 })", "[10, 20, 30]"),
+            ("len(filter([1, 2, 3, 4], fn(x) { x % 2 == 0 }))", "2"),
+            ("filter([1, 2, 3, 4], fn(x) { x > 2 })[0]", "3"),
+            ("map([], fn(x) { 1 / 0 })", "[]"),
+            ("map([1, 0], fn(x) { 1 / x })", "division by zero: 1 / 0"),
+            (
+                "let it = filter(range(10), fn(x) { x % 4 == 0 }); \
+                 [next(it), done(it), next(it), next(it), done(it), next(it)]",
+                "[0, false, 4, 8, true, null]",
+            ),
+            // the function is only called for the values asked for
+            (
+                "next(map(range(100), fn(x) { if (x > 0) { 1 / 0 } else { x } }))",
+                "0",
+            ),
+            (
+                "next(map(filter(range(1000000000), fn(x) { x > 2 }), fn(x) { x * x }))",
+                "9",
+            ),
+            (
+                "map([1], len)",
+                "argument to `len` must be STRING, ARRAY or HASH, got INTEGER",
+            ),
+            (
+                "map([1], 2)",
+                "argument to `map` must be FUNCTION, got INTEGER",
+            ),
+        ];
+        assert_outcomes(&tests);
+    }
+
+    #[test]
+    fn test_variables() {
+        let tests = vec![
+            ("locals()", "{}"),
+            ("let b = 2; let a = 1; globals()", r#"{"a": 1, "b": 2}"#),
+            (
+                "let f = fn(x) { let y = x * 2; locals() }; f(3)",
+                r#"{"x": 3, "y": 6}"#,
+            ),
+            // the variables of the functions around it too, the innermost
+            // of each name
+            (
+                "let f = fn(x, y) { let g = fn(y) { locals() }; g(3) }; f(1, 2)",
+                r#"{"g": fn(y) {...}, "x": 1, "y": 3}"#,
+            ),
+            (
+                "let f = fn() { try { error(\"boom\") } catch (e) { locals() } }; f()",
+                r#"{"e": ERROR: boom}"#,
+            ),
+            (
+                "let f = fn() { let before = locals(); let after = 1; before }; f()",
+                "{}",
+            ),
+            ("let x = 1; let f = fn() { globals() }; len(f())", "2"),
+            // a snapshot, not a view
+            (
+                "let f = fn(x) { let l = locals(); let x = 2; [l[\"x\"], x] }; f(1)",
+                "[1, 2]",
+            ),
+            ("let globals = fn() { 1 }; globals()", "1"),
+            ("let f = fn(locals) { locals() }; f(fn() { 2 })", "2"),
+            (
+                "let l = locals; l()",
+                "`locals` has to be called by its name, like locals()",
+            ),
+            (
+                "globals(1)",
+                "wrong number of arguments to `globals`: got=1, want=0",
+            ),
+        ];
+        assert_outcomes(&tests);
+    }
+
+    #[test]
+    fn test_dis() {
+        let tests = vec![
+            (
+                "let add = fn(a, b) { a + b }; dis(add)",
+                "0000 OpGetLocal 0\n0002 OpGetLocal 1\n0004 OpAdd\n0005 OpReturnValue",
+            ),
+            (
+                "let twice = memoize(fn(x) { x * 2 }); dis(twice)",
+                "0000 OpGetLocal 0\n0002 OpConstant 0\n0005 OpMul\n0006 OpReturnValue",
+            ),
+            (
+                "dis(len)",
+                "argument to `dis` must be FUNCTION, got BUILTIN",
+            ),
+        ];
+        assert_outcomes(&tests);
+    }
+}
@@ -0,0 +1,365 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::process::ExitCode;
+
+use crate::ast::{Expression, Program, Statement};
+use crate::cli::GraphOptions;
+use crate::symbol::Symbol;
+use crate::test_runner;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // the function is called by name
+    Call,
+    // the function's name is used otherwise, e.g. passed to another function
+    Reference,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: Symbol,
+    pub to: Symbol,
+    pub kind: EdgeKind,
+}
+
+// Which named functions of a program call or refer to which. A function is
+// named by the let that binds it, at the top level or inside another
+// function, and functions with the same name are one node. What an
+// anonymous function does counts for the named function it's in, and the
+// code at the top level isn't in any, so it has no edges of its own. Macros
+// aren't functions that run, so they're left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallGraph {
+    // in the order they're defined
+    pub functions: Vec<Symbol>,
+    // in the order they're in the code, each once
+    pub edges: Vec<Edge>,
+}
+
+impl CallGraph {
+    // the graph in the dot language of Graphviz, with references dashed
+    pub fn dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+        for function in &self.functions {
+            dot.push_str(&format!("  \"{}\";\n", function));
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Call => "",
+                EdgeKind::Reference => " [style=dashed]",
+            };
+            dot.push_str(&format!(
+                "  \"{}\" -> \"{}\"{};\n",
+                edge.from, edge.to, style
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+//   fib calls fib
+//   main refers to fib
+impl Display for CallGraph {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for edge in &self.edges {
+            let verb = match edge.kind {
+                EdgeKind::Call => "calls",
+                EdgeKind::Reference => "refers to",
+            };
+            writeln!(f, "{} {} {}", edge.from, verb, edge.to)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn call_graph(program: &Program) -> CallGraph {
+    let mut graph = CallGraph::default();
+    for statement in &program.statements {
+        functions(statement, &mut graph.functions);
+    }
+    let mut walker = Walker {
+        graph,
+        scopes: Vec::new(),
+    };
+    for statement in &program.statements {
+        walker.statement(statement, None);
+    }
+    walker.graph
+}
+
+// the names of the functions the statement binds, inside it too
+fn functions(statement: &Statement, names: &mut Vec<Symbol>) {
+    match statement {
+        Statement::LetStatement { name, value, .. } => {
+            if matches!(value, Expression::FunctionLiteral { .. }) && !names.contains(name) {
+                names.push(name.clone());
+            }
+            expression_functions(value, names);
+        }
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            expression_functions(value, names)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                functions(statement, names);
+            }
+        }
+    }
+}
+
+fn expression_functions(expression: &Expression, names: &mut Vec<Symbol>) {
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_)
+        | Expression::MacroLiteral { .. } => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                expression_functions(element, names);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                expression_functions(key, names);
+                expression_functions(value, names);
+            }
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            expression_functions(condition, names);
+            functions(consequence, names);
+            if let Some(alternative) = alternative {
+                functions(alternative, names);
+            }
+        }
+        Expression::FunctionLiteral { body, .. } => functions(body, names),
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            expression_functions(function, names);
+            for argument in arguments {
+                expression_functions(argument, names);
+            }
+        }
+        Expression::Try { body, handler, .. } => {
+            functions(body, names);
+            functions(handler, names);
+        }
+        Expression::For { iterable, body, .. } => {
+            expression_functions(iterable, names);
+            functions(body, names);
+        }
+        Expression::Index { left, index, .. } => {
+            expression_functions(left, names);
+            expression_functions(index, names);
+        }
+        Expression::Prefix(_, right) => expression_functions(right, names),
+        Expression::Infix(_, left, right) => {
+            expression_functions(left, names);
+            expression_functions(right, names);
+        }
+    }
+}
+
+struct Walker {
+    graph: CallGraph,
+    // the names that functions bind themselves, innermost last, which hide
+    // the functions by those names
+    scopes: Vec<Vec<Symbol>>,
+}
+
+impl Walker {
+    // `from` is the named function the statement is in
+    fn statement(&mut self, statement: &Statement, from: Option<&Symbol>) {
+        match statement {
+            Statement::LetStatement {
+                name,
+                value:
+                    Expression::FunctionLiteral {
+                        parameters, body, ..
+                    },
+                ..
+            } => self.function(parameters, body, Some(name)),
+            Statement::LetStatement { name, value, .. } => {
+                self.expression(value, from);
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.push(name.clone());
+                }
+            }
+            Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+                self.expression(value, from)
+            }
+            Statement::BlockStatement(statements, _) => {
+                for statement in statements {
+                    self.statement(statement, from);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, parameters: &[Symbol], body: &Statement, from: Option<&Symbol>) {
+        self.scopes.push(parameters.to_vec());
+        self.statement(body, from);
+        self.scopes.pop();
+    }
+
+    fn expression(&mut self, expression: &Expression, from: Option<&Symbol>) {
+        match expression {
+            Expression::Identifier(name, _) => self.edge(from, name, EdgeKind::Reference),
+            Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_)
+            | Expression::MacroLiteral { .. } => {}
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.expression(element, from);
+                }
+            }
+            Expression::HashLiteral(pairs, _) => {
+                for (key, value) in pairs {
+                    self.expression(key, from);
+                    self.expression(value, from);
+                }
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.expression(condition, from);
+                self.statement(consequence, from);
+                if let Some(alternative) = alternative {
+                    self.statement(alternative, from);
+                }
+            }
+            Expression::FunctionLiteral {
+                parameters, body, ..
+            } => self.function(parameters, body, from),
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                match &**function {
+                    Expression::Identifier(name, _) => self.edge(from, name, EdgeKind::Call),
+                    function => self.expression(function, from),
+                }
+                for argument in arguments {
+                    self.expression(argument, from);
+                }
+            }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => {
+                self.statement(body, from);
+                self.scopes.push(vec![name.clone()]);
+                self.statement(handler, from);
+                self.scopes.pop();
+            }
+            Expression::For {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.expression(iterable, from);
+                self.scopes.push(vec![name.clone()]);
+                self.statement(body, from);
+                self.scopes.pop();
+            }
+            Expression::Index { left, index, .. } => {
+                self.expression(left, from);
+                self.expression(index, from);
+            }
+            Expression::Prefix(_, right) => self.expression(right, from),
+            Expression::Infix(_, left, right) => {
+                self.expression(left, from);
+                self.expression(right, from);
+            }
+        }
+    }
+
+    fn edge(&mut self, from: Option<&Symbol>, to: &Symbol, kind: EdgeKind) {
+        let Some(from) = from else {
+            return;
+        };
+        let hidden = self.scopes.iter().any(|scope| scope.contains(to));
+        if hidden || !self.graph.functions.contains(to) {
+            return;
+        }
+        let edge = Edge {
+            from: from.clone(),
+            to: to.clone(),
+            kind,
+        };
+        if !self.graph.edges.contains(&edge) {
+            self.graph.edges.push(edge);
+        }
+    }
+}
+
+// `monk graph`: prints which functions of the file call which.
+pub(crate) fn run(options: &GraphOptions) -> ExitCode {
+    let program = fs::read_to_string(&options.file)
+        .map_err(|error| format!("can't read the file: {}", error))
+        .and_then(|source| test_runner::parse(&source));
+    match program {
+        Ok(program) => {
+            let graph = call_graph(&program);
+            match options.dot {
+                true => print!("{}", graph.dot()),
+                false => print!("{}", graph),
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("error: {}: {}", options.file, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_call_graph() {
+        let source = "
+            let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+            let apply = fn(f, x) { f(x) };
+            let main = fn() {
+                let double = fn(x) { x * 2 };
+                puts(apply(fib, 10), double(1));
+                let shadow = fn(fib) { fib(1) };
+                try { later() } catch (apply) { apply }
+            };
+            let later = fn() { main };
+            fib(10);
+        ";
+        let graph = call_graph(&parser::parse(source).unwrap());
+        let names: Vec<&str> = graph.functions.iter().map(|name| &**name).collect();
+        assert_eq!(names, ["fib", "apply", "main", "double", "shadow", "later"]);
+        assert_eq!(
+            graph.to_string(),
+            "fib calls fib\nmain calls apply\nmain refers to fib\nmain calls double\n\
+             main calls later\nlater refers to main\n"
+        );
+        assert!(graph
+            .dot()
+            .ends_with("  \"later\" -> \"main\" [style=dashed];\n}\n"));
+    }
+}
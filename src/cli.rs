@@ -0,0 +1,974 @@
+use std::io::{self, IsTerminal};
+use std::iter;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::engine::Engine;
+use crate::evaluator::EvalConfig;
+use crate::sandbox::{self, Capabilities};
+use crate::warnings::{Severity, WarningKind};
+use crate::{aot, bench, builtins, call_graph, color, lint, repl, server, test_runner};
+
+pub const USAGE: &str = "\
+usage: monk [run] [options] [file [args...]]
+       monk --check [file...]
+       monk compile [options] file
+       monk lint [options] file...
+       monk test [options] [path...]
+       monk bench [options] file
+       monk graph [--dot] file
+       monk serve [options]
+
+Runs the file, or the program piped into it, or else starts the REPL. The
+file can be a script or compiled with monk compile, and - reads it from
+stdin. The arguments after the file are the script's, which args() returns
+with it.
+
+options:
+  -e <code>         run the code instead, printing its value
+  --check           only report syntax errors and warnings
+  --ast[=json]      print the syntax tree instead of running it
+  --tokens          print the tokens instead of running the code
+  --engine <name>   run code on tree-walker, vm or jit (jit feature)
+  --profile         report where the time went, to stderr
+  --error-format=json
+                    report runtime errors as JSON, a line each on stderr
+  --strict-bool     only let booleans be conditions and operands of !
+  --no-color        don't color the output
+  --restore <file>  start the REPL from a saved session
+  -h, --help        show this help";
+
+pub const LINT_USAGE: &str = "\
+usage: monk lint [options] file...
+
+Reports what looks wrong in the files without running them. Each rule is
+allowed, a warning or an error, and the files fail when there are errors.
+
+rules: unused, unreachable, constant-condition, shadowing (allowed unless
+it's asked for), and all for every rule. The [lint] section of the config
+file sets them too, e.g. shadowing = \"deny\", and the options override it.
+
+options:
+  --allow <rule>    don't report it
+  --warn <rule>     report it as a warning
+  --deny <rule>     report it as an error
+  --no-color        don't color the output
+  -h, --help        show this help";
+
+pub const TEST_USAGE: &str = "\
+usage: monk test [options] [path...]
+
+Runs the tests in the files under the paths, the current directory if none
+are given. Directories are searched for files named *_test.mk. Every
+top-level function named test_... is a test, and so is every function the
+file registers with test(\"name\", fn() { ... }). A test passes unless it
+raises an error, e.g. with assert or assert_eq. A file without tests is a
+test itself.
+
+options:
+  --engine <name>   run the tests on tree-walker, vm or jit (jit feature)
+  --coverage        show which statements the tests ran
+  --coverage-output <file>
+                    write the coverage as lcov, or JSON for a .json file
+  --no-color        don't color the output
+  -h, --help        show this help";
+
+pub const BENCH_USAGE: &str = "\
+usage: monk bench [options] file
+
+Times every top-level function named bench_... in the file, called without
+arguments, and reports the mean, median and standard deviation of its runs.
+
+options:
+  --engine <name>       run the benchmarks on tree-walker, vm or jit (jit feature)
+  --compare             run them on both engines and compare them
+  --iterations <count>  how many runs are timed, 100 by default
+  --warmup <count>      how many runs go before them, 10 by default
+  -h, --help            show this help";
+
+pub const COMPILE_USAGE: &str = "\
+usage: monk compile [options] file
+
+Compiles the file ahead of time: to bytecode that monk runs without parsing
+the file again, or to a WebAssembly module that runs under node with the
+monk.js it's written next to. Only integers, booleans, top-level functions
+and puts can be compiled to WebAssembly.
+
+options:
+  -o <file>          where to write it, by default the file with the
+                     extension .monkc or .wasm
+  --target <target>  bytecode, the default, or wasm
+  -h, --help         show this help";
+
+pub const GRAPH_USAGE: &str = "\
+usage: monk graph [options] file
+
+Shows which functions of the file call which, and which refer to which
+otherwise, e.g. by passing them to another function. A function is named by
+the let that binds it.
+
+options:
+  --dot       print the graph for Graphviz, e.g. monk graph --dot a.mk | dot -Tsvg
+  -h, --help  show this help";
+
+pub const SERVE_USAGE: &str = "\
+usage: monk serve [options]
+
+Serves the REPL over TCP, a line of code at a time, with the globals of a
+connection kept until it closes. The code runs in the sandbox, so it can't
+reach the filesystem, network, processes, environment or stdin, or start
+threads, and each line is stopped when it runs out of fuel, time or memory,
+or calls too deep. A line that leaves the connection with more globals than
+--max-globals, or globals that hold more than --memory-limit, is undone.
+
+options:
+  --port <port>       the port to listen on, 7007 by default
+  --host <address>    the address to listen on, 127.0.0.1 by default
+  --token <token>     make clients send the token first, or set MONK_TOKEN
+  --engine <name>     run the code on tree-walker, vm or jit (jit feature)
+  --fuel <count>      the steps a line may take, 10000000 by default
+  --timeout <ms>      the time a line may take, 5000 by default
+  --memory-limit <bytes>
+                      what a line may hold at once, 67108864 (64 MiB) by
+                      default
+  --max-depth <calls> how deep a line may call, 200 by default
+  --max-line <bytes>  the longest line a client may send, 65536 by default
+  --max-reply <bytes> the longest value a reply shows, 65536 by default
+  --max-connections <count>
+                      how many clients may connect at once, 64 by default
+  --max-globals <count>
+                      how many globals a connection may define, 1000 by
+                      default
+  -h, --help          show this help";
+
+// What to do: run code, or one of the subcommands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Lint(LintOptions),
+    Test(TestOptions),
+    Bench(BenchOptions),
+    Compile(CompileOptions),
+    Graph(GraphOptions),
+    Serve(ServeOptions),
+}
+
+impl Command {
+    // the command in the arguments, without the name of the program; an error
+    // comes with the usage of the command
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+        let mut args = args.into_iter().peekable();
+        match args.peek().map(String::as_str) {
+            Some("lint") => {
+                args.next();
+                LintOptions::parse(args)
+                    .map(Command::Lint)
+                    .map_err(|error| format!("{}\n\n{}", error, LINT_USAGE))
+            }
+            Some("test") => {
+                args.next();
+                TestOptions::parse(args)
+                    .map(Command::Test)
+                    .map_err(|error| format!("{}\n\n{}", error, TEST_USAGE))
+            }
+            Some("bench") => {
+                args.next();
+                BenchOptions::parse(args)
+                    .map(Command::Bench)
+                    .map_err(|error| format!("{}\n\n{}", error, BENCH_USAGE))
+            }
+            Some("compile") => {
+                args.next();
+                CompileOptions::parse(args)
+                    .map(Command::Compile)
+                    .map_err(|error| format!("{}\n\n{}", error, COMPILE_USAGE))
+            }
+            Some("graph") => {
+                args.next();
+                GraphOptions::parse(args)
+                    .map(Command::Graph)
+                    .map_err(|error| format!("{}\n\n{}", error, GRAPH_USAGE))
+            }
+            Some("serve") => {
+                args.next();
+                ServeOptions::parse(args)
+                    .map(Command::Serve)
+                    .map_err(|error| format!("{}\n\n{}", error, SERVE_USAGE))
+            }
+            Some("run") => {
+                args.next();
+                Options::parse(args)
+                    .map(Command::Run)
+                    .map_err(|error| format!("{}\n\n{}", error, USAGE))
+            }
+            _ => Options::parse(args)
+                .map(Command::Run)
+                .map_err(|error| format!("{}\n\n{}", error, USAGE)),
+        }
+    }
+}
+
+// What to print about the code instead of running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dump {
+    Tokens,
+    Ast,
+    AstJson,
+}
+
+// How runtime errors are reported: as text with the source and trace, or as
+// JSON for the programs that run monk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// What the command line asks for. Flags that don't apply to what runs, like
+// --restore for a file, are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub file: Option<String>,
+    // the arguments after the file or -e, which are more files to --check
+    pub args: Vec<String>,
+    // the code of -e, which is run instead of a file
+    pub code: Option<String>,
+    pub dump: Option<Dump>,
+    pub check: bool,
+    // the engine of the config file is used when there's none
+    pub engine: Option<Engine>,
+    pub profile: bool,
+    pub error_format: ErrorFormat,
+    pub strict_booleans: bool,
+    pub color: bool,
+    pub restore: Option<String>,
+    pub help: bool,
+}
+
+impl Options {
+    // the options in the arguments, without the name of the program
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            color: true,
+            ..Options::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match arg.as_str() {
+                "-e" => options.code = Some(value(&arg)?),
+                "--check" => options.check = true,
+                "--tokens" => options.dump = Some(Dump::Tokens),
+                "--ast" => options.dump = Some(Dump::Ast),
+                "--ast=json" => options.dump = Some(Dump::AstJson),
+                "--engine" => options.engine = Some(value(&arg)?.parse()?),
+                "--profile" => options.profile = true,
+                "--error-format=text" => options.error_format = ErrorFormat::Text,
+                "--error-format=json" => options.error_format = ErrorFormat::Json,
+                "--strict-bool" => options.strict_booleans = true,
+                "--no-color" => options.color = false,
+                "--restore" => options.restore = Some(value(&arg)?),
+                "-h" | "--help" => options.help = true,
+                // a file of "-" is stdin
+                flag if flag.starts_with('-') && flag != "-" => {
+                    return Err(format!("unknown option: {}", flag))
+                }
+                // the rest is the script's, even if it looks like a flag
+                _ if options.code.is_some() => {
+                    options.args = std::iter::once(arg).chain(args).collect();
+                    break;
+                }
+                _ => {
+                    options.file = Some(arg);
+                    options.args = args.collect();
+                    break;
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    // the config programs run with, without the observers the flags ask for
+    pub fn eval_config(&self) -> EvalConfig {
+        EvalConfig::default().strict_booleans(self.strict_booleans)
+    }
+}
+
+// What `monk lint` is asked for. A rule that's set more than once has the
+// severity it's set to last.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LintOptions {
+    pub files: Vec<String>,
+    pub rules: Vec<(WarningKind, Severity)>,
+    pub color: bool,
+    pub help: bool,
+}
+
+impl LintOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<LintOptions, String> {
+        let mut options = LintOptions {
+            color: true,
+            ..LintOptions::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let severity = match arg.as_str() {
+                "--allow" => Severity::Allow,
+                "--warn" => Severity::Warn,
+                "--deny" => Severity::Deny,
+                "--no-color" => {
+                    options.color = false;
+                    continue;
+                }
+                "-h" | "--help" => {
+                    options.help = true;
+                    continue;
+                }
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ => {
+                    options.files.push(arg);
+                    continue;
+                }
+            };
+            let rule = args.next().ok_or_else(|| format!("{} needs a rule", arg))?;
+            let kinds: Vec<WarningKind> = match rule.as_str() {
+                "all" => WarningKind::ALL.to_vec(),
+                name => match WarningKind::ALL
+                    .into_iter()
+                    .find(|kind| kind.name() == name)
+                {
+                    Some(kind) => vec![kind],
+                    None => return Err(format!("unknown rule: {}", name)),
+                },
+            };
+            options
+                .rules
+                .extend(kinds.into_iter().map(|kind| (kind, severity)));
+        }
+        if options.files.is_empty() && !options.help {
+            return Err("no files to lint".to_string());
+        }
+        Ok(options)
+    }
+
+    // what becomes of warnings of the kind
+    pub fn severity(&self, kind: WarningKind) -> Severity {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(rule, _)| *rule == kind)
+            .map_or(kind.default_severity(), |(_, severity)| *severity)
+    }
+}
+
+// What `monk test` is asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TestOptions {
+    pub paths: Vec<String>,
+    pub engine: Engine,
+    pub coverage: bool,
+    // where the coverage report goes, as lcov or else JSON for a .json file
+    pub coverage_output: Option<String>,
+    pub color: bool,
+    pub help: bool,
+}
+
+impl TestOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<TestOptions, String> {
+        let mut options = TestOptions {
+            color: true,
+            ..TestOptions::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--engine" => {
+                    let name = args.next().ok_or("--engine needs a value")?;
+                    options.engine = name.parse()?;
+                }
+                "--coverage" => options.coverage = true,
+                "--coverage-output" => {
+                    let file = args.next().ok_or("--coverage-output needs a file")?;
+                    options.coverage = true;
+                    options.coverage_output = Some(file);
+                }
+                "--no-color" => options.color = false,
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ => options.paths.push(arg),
+            }
+        }
+        // only the tree-walker tells which statements it runs
+        if options.coverage && options.engine != Engine::TreeWalker {
+            return Err("--coverage needs the tree-walker engine".to_string());
+        }
+        if options.paths.is_empty() {
+            options.paths.push(".".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// What `monk bench` is asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchOptions {
+    pub file: String,
+    // one engine, or both with --compare
+    pub engines: Vec<Engine>,
+    pub iterations: usize,
+    pub warmup: usize,
+    pub help: bool,
+}
+
+impl BenchOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<BenchOptions, String> {
+        let mut options = BenchOptions {
+            engines: vec![Engine::default()],
+            iterations: 100,
+            warmup: 10,
+            ..BenchOptions::default()
+        };
+        let mut file = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+            let count = |value: String| {
+                value
+                    .parse()
+                    .map_err(|_| format!("expected a count, got {}", value))
+            };
+            match arg.as_str() {
+                "--engine" => options.engines = vec![value(&arg)?.parse()?],
+                "--compare" => options.engines = Engine::ALL.to_vec(),
+                "--iterations" => options.iterations = count(value(&arg)?)?,
+                "--warmup" => options.warmup = count(value(&arg)?)?,
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ if file.is_none() => file = Some(arg),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        if options.iterations == 0 {
+            return Err("--iterations has to be at least 1".to_string());
+        }
+        match file {
+            Some(file) => options.file = file,
+            None if options.help => {}
+            None => return Err("no file to bench".to_string()),
+        }
+        Ok(options)
+    }
+}
+
+// What monk compile compiles to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    #[default]
+    Bytecode,
+    Wasm,
+}
+
+// What `monk compile` is asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileOptions {
+    pub file: String,
+    pub output: Option<String>,
+    pub target: Target,
+    pub help: bool,
+}
+
+impl CompileOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<CompileOptions, String> {
+        let mut options = CompileOptions::default();
+        let mut file = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match arg.as_str() {
+                "-o" => options.output = Some(value(&arg)?),
+                "--target" => {
+                    options.target = match value(&arg)?.as_str() {
+                        "bytecode" => Target::Bytecode,
+                        "wasm" => Target::Wasm,
+                        target => {
+                            return Err(format!(
+                                "unknown target: {} (expected bytecode or wasm)",
+                                target
+                            ))
+                        }
+                    }
+                }
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ if file.is_none() => file = Some(arg),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        match file {
+            Some(file) => options.file = file,
+            None if options.help => {}
+            None => return Err("no file to compile".to_string()),
+        }
+        Ok(options)
+    }
+
+    // where the compiled file goes
+    pub fn output(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => PathBuf::from(output),
+            None => PathBuf::from(&self.file).with_extension(match self.target {
+                Target::Bytecode => "monkc",
+                Target::Wasm => "wasm",
+            }),
+        }
+    }
+}
+
+// What `monk graph` is asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphOptions {
+    pub file: String,
+    pub dot: bool,
+    pub help: bool,
+}
+
+impl GraphOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<GraphOptions, String> {
+        let mut options = GraphOptions::default();
+        let mut file = None;
+        for arg in args {
+            match arg.as_str() {
+                "--dot" => options.dot = true,
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ if file.is_none() => file = Some(arg),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        match file {
+            Some(file) => options.file = file,
+            None if options.help => {}
+            None => return Err("no file to graph".to_string()),
+        }
+        Ok(options)
+    }
+}
+
+// What `monk serve` is asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServeOptions {
+    pub host: String,
+    pub port: u16,
+    // what clients have to send before any code, none to let anyone in
+    pub token: Option<String>,
+    pub engine: Engine,
+    pub fuel: u64,
+    pub timeout: Duration,
+    pub memory_limit: usize,
+    pub max_depth: usize,
+    // the longest line a client may send, in bytes
+    pub max_line: usize,
+    // the longest value a reply shows, in bytes
+    pub max_reply: usize,
+    pub max_connections: usize,
+    pub max_globals: usize,
+    pub help: bool,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            host: "127.0.0.1".to_string(),
+            port: 7007,
+            token: None,
+            engine: Engine::default(),
+            fuel: 10_000_000,
+            timeout: Duration::from_millis(5000),
+            memory_limit: 64 * 1024 * 1024,
+            max_depth: 200,
+            max_line: 64 * 1024,
+            max_reply: 64 * 1024,
+            max_connections: 64,
+            max_globals: 1000,
+            help: false,
+        }
+    }
+}
+
+impl ServeOptions {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<ServeOptions, String> {
+        let mut options = ServeOptions {
+            token: std::env::var("MONK_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            ..ServeOptions::default()
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+            fn number<T: std::str::FromStr>(value: String) -> Result<T, String> {
+                value
+                    .parse()
+                    .map_err(|_| format!("expected a number, got {}", value))
+            }
+            match arg.as_str() {
+                "--port" => options.port = number(value(&arg)?)?,
+                "--host" => options.host = value(&arg)?,
+                "--token" => options.token = Some(value(&arg)?),
+                "--engine" => options.engine = value(&arg)?.parse()?,
+                "--fuel" => options.fuel = number(value(&arg)?)?,
+                "--timeout" => options.timeout = Duration::from_millis(number(value(&arg)?)?),
+                "--memory-limit" => options.memory_limit = number(value(&arg)?)?,
+                "--max-depth" => options.max_depth = number(value(&arg)?)?,
+                "--max-line" => options.max_line = number(value(&arg)?)?,
+                "--max-reply" => options.max_reply = number(value(&arg)?)?,
+                "--max-connections" => options.max_connections = number(value(&arg)?)?,
+                "--max-globals" => options.max_globals = number(value(&arg)?)?,
+                "-h" | "--help" => options.help = true,
+                flag if flag.starts_with('-') => return Err(format!("unknown option: {}", flag)),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        if options.token.as_deref() == Some("") {
+            return Err("--token can't be empty".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// What the monk binary runs, for the command line it was given.
+pub fn main() -> ExitCode {
+    // the scripts that are run from the command line may do anything
+    sandbox::set_capabilities(Capabilities::all());
+    match Command::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => run(options),
+        Ok(Command::Lint(options)) if options.help => {
+            println!("{}", LINT_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Lint(options)) => lint::run(&options),
+        Ok(Command::Test(options)) if options.help => {
+            println!("{}", TEST_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Test(options)) => test_runner::run(&options),
+        Ok(Command::Bench(options)) if options.help => {
+            println!("{}", BENCH_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Bench(options)) => bench::run(&options),
+        Ok(Command::Compile(options)) if options.help => {
+            println!("{}", COMPILE_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Compile(options)) => aot::run(&options),
+        Ok(Command::Graph(options)) if options.help => {
+            println!("{}", GRAPH_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Graph(options)) => call_graph::run(&options),
+        Ok(Command::Serve(options)) if options.help => {
+            println!("{}", SERVE_USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(Command::Serve(options)) => server::run(&options),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(options: Options) -> ExitCode {
+    if options.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let colors = color::Colors::new(options.color);
+    if let Some(code) = &options.code {
+        builtins::set_args(
+            iter::once("-e".to_string())
+                .chain(options.args.clone())
+                .collect(),
+        );
+        return repl::run_code(colors, code, &options);
+    }
+    if let Some(file) = &options.file {
+        if options.check {
+            // every file is checked, even after one that fails
+            let failed = iter::once(file)
+                .chain(&options.args)
+                .filter(|file| repl::run_file(colors, file, &options) != ExitCode::SUCCESS)
+                .count();
+            return match failed {
+                0 => ExitCode::SUCCESS,
+                _ => ExitCode::FAILURE,
+            };
+        }
+        builtins::set_args(
+            iter::once(file.clone())
+                .chain(options.args.clone())
+                .collect(),
+        );
+        return repl::run_file(colors, file, &options);
+    }
+    if !io::stdin().is_terminal() {
+        return repl::run_piped(colors, &options);
+    }
+    repl::start_repl(colors, &options);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = parse(&["--engine", "vm", "--strict-bool", "script.mk"]).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                file: Some("script.mk".to_string()),
+                engine: Some(Engine::Vm),
+                strict_booleans: true,
+                color: true,
+                ..Options::default()
+            }
+        );
+
+        let options = parse(&["--no-color", "--restore", "session.mk", "--profile"]).unwrap();
+        assert_eq!(options.file, None);
+        assert_eq!(options.restore.as_deref(), Some("session.mk"));
+        assert!(!options.color);
+        assert!(options.profile);
+        assert!(parse(&["-h"]).unwrap().help);
+        let options = parse(&["--error-format=json", "script.mk"]).unwrap();
+        assert_eq!(options.error_format, ErrorFormat::Json);
+
+        let options = parse(&["-e", "puts(1 + 2)", "--engine", "vm"]).unwrap();
+        assert_eq!(options.code.as_deref(), Some("puts(1 + 2)"));
+        assert_eq!(options.file, None);
+
+        let tests = vec![
+            ("--tokens", Dump::Tokens),
+            ("--ast", Dump::Ast),
+            ("--ast=json", Dump::AstJson),
+        ];
+        for (flag, dump) in tests {
+            let options = parse(&[flag, "script.mk"]).unwrap();
+            assert_eq!(options.dump, Some(dump), "{}", flag);
+        }
+
+        let options = parse(&["--check", "a.mk", "b.mk", "c.mk"]).unwrap();
+        assert!(options.check);
+        assert_eq!(options.file.as_deref(), Some("a.mk"));
+        assert_eq!(options.args, ["b.mk", "c.mk"]);
+
+        let options = parse(&["--engine", "vm", "a.mk", "--engine", "x"]).unwrap();
+        assert_eq!(options.engine, Some(Engine::Vm));
+        assert_eq!(options.args, ["--engine", "x"]);
+        let options = parse(&["-e", "args()", "a", "-b"]).unwrap();
+        assert_eq!(options.file, None);
+        assert_eq!(options.args, ["a", "-b"]);
+        let options = parse(&["-", "a"]).unwrap();
+        assert_eq!(options.file.as_deref(), Some("-"));
+        assert_eq!(options.args, ["a"]);
+    }
+
+    #[test]
+    fn test_parse_lint() {
+        let args = ["lint", "--deny", "all", "a.mk", "--allow", "unused", "b.mk"];
+        let Ok(Command::Lint(options)) = Command::parse(args.map(String::from)) else {
+            panic!("not a lint command");
+        };
+        assert_eq!(options.files, ["a.mk", "b.mk"]);
+        assert_eq!(
+            options.severity(WarningKind::UnusedBinding),
+            Severity::Allow
+        );
+        assert_eq!(options.severity(WarningKind::Shadowing), Severity::Deny);
+
+        let options = LintOptions::parse(["a.mk".to_string()]).unwrap();
+        assert_eq!(options.severity(WarningKind::UnusedBinding), Severity::Warn);
+        assert_eq!(options.severity(WarningKind::Shadowing), Severity::Allow);
+
+        let tests = vec![
+            (vec!["--deny"], "--deny needs a rule"),
+            (vec!["--deny", "typos", "a.mk"], "unknown rule: typos"),
+            (vec!["--fix", "a.mk"], "unknown option: --fix"),
+            (vec!["--no-color"], "no files to lint"),
+        ];
+        for (args, expected) in tests {
+            let args = args.into_iter().map(String::from);
+            assert_eq!(LintOptions::parse(args).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_test() {
+        let args = ["test", "--engine", "vm", "tests", "more_test.mk"];
+        let Ok(Command::Test(options)) = Command::parse(args.map(String::from)) else {
+            panic!("not a test command");
+        };
+        assert_eq!(options.paths, ["tests", "more_test.mk"]);
+        assert_eq!(options.engine, Engine::Vm);
+
+        let options = TestOptions::parse(Vec::new()).unwrap();
+        assert_eq!(options.paths, ["."]);
+        let error = TestOptions::parse(["--engine".to_string()]).unwrap_err();
+        assert_eq!(error, "--engine needs a value");
+
+        let args = ["--coverage-output", "lcov.info"].map(String::from);
+        let options = TestOptions::parse(args).unwrap();
+        assert!(options.coverage);
+        assert_eq!(options.coverage_output.as_deref(), Some("lcov.info"));
+        let args = ["--coverage", "--engine", "vm"].map(String::from);
+        assert_eq!(
+            TestOptions::parse(args).unwrap_err(),
+            "--coverage needs the tree-walker engine"
+        );
+    }
+
+    #[test]
+    fn test_parse_bench() {
+        let args = ["bench", "--compare", "--iterations", "5", "b.mk"];
+        let Ok(Command::Bench(options)) = Command::parse(args.map(String::from)) else {
+            panic!("not a bench command");
+        };
+        assert_eq!(options.file, "b.mk");
+        assert_eq!(options.engines, Engine::ALL);
+        assert_eq!((options.iterations, options.warmup), (5, 10));
+
+        let tests = vec![
+            (vec![], "no file to bench"),
+            (vec!["--warmup", "x", "b.mk"], "expected a count, got x"),
+            (
+                vec!["--iterations", "0", "b.mk"],
+                "--iterations has to be at least 1",
+            ),
+            (vec!["a.mk", "b.mk"], "unexpected argument: b.mk"),
+        ];
+        for (args, expected) in tests {
+            let args = args.into_iter().map(String::from);
+            assert_eq!(BenchOptions::parse(args).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_graph() {
+        let args = ["graph", "--dot", "a.mk"].map(String::from);
+        let Ok(Command::Graph(options)) = Command::parse(args) else {
+            panic!("not a graph command");
+        };
+        assert_eq!(options.file, "a.mk");
+        assert!(options.dot);
+
+        let tests = vec![
+            (vec!["graph"], "no file to graph"),
+            (vec!["graph", "a.mk", "b.mk"], "unexpected argument: b.mk"),
+        ];
+        for (args, expected) in tests {
+            let error = Command::parse(args.into_iter().map(String::from)).unwrap_err();
+            assert!(error.starts_with(expected), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        let args = [
+            "serve",
+            "--port",
+            "8000",
+            "--token",
+            "secret",
+            "--timeout",
+            "100",
+            "--memory-limit",
+            "1048576",
+            "--max-connections",
+            "2",
+        ];
+        let Ok(Command::Serve(options)) = Command::parse(args.map(String::from)) else {
+            panic!("not a serve command");
+        };
+        assert_eq!(options.port, 8000);
+        assert_eq!(options.host, "127.0.0.1");
+        assert_eq!(options.token.as_deref(), Some("secret"));
+        assert_eq!(options.timeout, Duration::from_millis(100));
+        assert_eq!(options.memory_limit, 1048576);
+        assert_eq!(options.max_depth, 200);
+        assert_eq!(options.max_connections, 2);
+        assert_eq!(options.max_line, 64 * 1024);
+
+        let tests = vec![
+            (vec!["serve", "--port", "x"], "expected a number, got x"),
+            (vec!["serve", "--token", ""], "--token can't be empty"),
+            (vec!["serve", "a.mk"], "unexpected argument: a.mk"),
+        ];
+        for (args, expected) in tests {
+            let error = Command::parse(args.into_iter().map(String::from)).unwrap_err();
+            assert!(error.starts_with(expected), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_parse_compile() {
+        let args = ["compile", "dir/script.mk", "--target", "wasm"];
+        let Ok(Command::Compile(options)) = Command::parse(args.map(String::from)) else {
+            panic!("not a compile command");
+        };
+        assert_eq!(options.target, Target::Wasm);
+        assert_eq!(options.output(), PathBuf::from("dir/script.wasm"));
+
+        let args = ["script.mk", "-o", "out.monkc"].map(String::from);
+        let options = CompileOptions::parse(args).unwrap();
+        assert_eq!(options.target, Target::Bytecode);
+        assert_eq!(options.output(), PathBuf::from("out.monkc"));
+
+        let args = ["run", "script.monkc", "x"].map(String::from);
+        let Ok(Command::Run(options)) = Command::parse(args) else {
+            panic!("not a run command");
+        };
+        assert_eq!(options.file.as_deref(), Some("script.monkc"));
+        assert_eq!(options.args, ["x"]);
+
+        let tests = vec![
+            (vec![], "no file to compile"),
+            (
+                vec!["--target", "jvm", "a.mk"],
+                "unknown target: jvm (expected bytecode or wasm)",
+            ),
+            (vec!["-o"], "-o needs a value"),
+        ];
+        for (args, expected) in tests {
+            let args = args.into_iter().map(String::from);
+            assert_eq!(CompileOptions::parse(args).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let unknown = "llvm".parse::<Engine>().unwrap_err();
+        let tests = vec![
+            (vec!["--engine"], "--engine needs a value"),
+            (vec!["--engine", "llvm"], unknown.as_str()),
+            (vec!["--fast"], "unknown option: --fast"),
+            (vec!["--ast=xml"], "unknown option: --ast=xml"),
+            (vec!["-e"], "-e needs a value"),
+        ];
+        for (args, expected) in tests {
+            assert_eq!(parse(&args).unwrap_err(), expected, "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt::{self, Display, Formatter};
+
+// Compiled code: every instruction is an opcode byte followed by its operands,
+// each a big-endian integer of the width given by the opcode's definition.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Instructions(pub Vec<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Opcode {
+    Constant,
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    True,
+    False,
+    Null,
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    Minus,
+    Bang,
+    JumpNotTruthy,
+    Jump,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetBuiltin,
+    GetFree,
+    // the closure of the function being run, for a function that calls itself
+    CurrentClosure,
+    Array,
+    Hash,
+    Index,
+    Call,
+    ReturnValue,
+    Return,
+    Closure,
+    // the start of a try body, with the address of its handler
+    Try,
+    // the try body finished without an error
+    EndTry,
+    // a hash of the globals that are set, for globals()
+    Globals,
+}
+
+// in the order of their byte values
+const OPCODES: &[Opcode] = &[
+    Opcode::Constant,
+    Opcode::Pop,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Mod,
+    Opcode::True,
+    Opcode::False,
+    Opcode::Null,
+    Opcode::Equal,
+    Opcode::NotEqual,
+    Opcode::LessThan,
+    Opcode::GreaterThan,
+    Opcode::Minus,
+    Opcode::Bang,
+    Opcode::JumpNotTruthy,
+    Opcode::Jump,
+    Opcode::GetGlobal,
+    Opcode::SetGlobal,
+    Opcode::GetLocal,
+    Opcode::SetLocal,
+    Opcode::GetBuiltin,
+    Opcode::GetFree,
+    Opcode::CurrentClosure,
+    Opcode::Array,
+    Opcode::Hash,
+    Opcode::Index,
+    Opcode::Call,
+    Opcode::ReturnValue,
+    Opcode::Return,
+    Opcode::Closure,
+    Opcode::Try,
+    Opcode::EndTry,
+    Opcode::Globals,
+];
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Constant => "OpConstant",
+            Opcode::Pop => "OpPop",
+            Opcode::Add => "OpAdd",
+            Opcode::Sub => "OpSub",
+            Opcode::Mul => "OpMul",
+            Opcode::Div => "OpDiv",
+            Opcode::Mod => "OpMod",
+            Opcode::True => "OpTrue",
+            Opcode::False => "OpFalse",
+            Opcode::Null => "OpNull",
+            Opcode::Equal => "OpEqual",
+            Opcode::NotEqual => "OpNotEqual",
+            Opcode::LessThan => "OpLessThan",
+            Opcode::GreaterThan => "OpGreaterThan",
+            Opcode::Minus => "OpMinus",
+            Opcode::Bang => "OpBang",
+            Opcode::JumpNotTruthy => "OpJumpNotTruthy",
+            Opcode::Jump => "OpJump",
+            Opcode::GetGlobal => "OpGetGlobal",
+            Opcode::SetGlobal => "OpSetGlobal",
+            Opcode::GetLocal => "OpGetLocal",
+            Opcode::SetLocal => "OpSetLocal",
+            Opcode::GetBuiltin => "OpGetBuiltin",
+            Opcode::GetFree => "OpGetFree",
+            Opcode::CurrentClosure => "OpCurrentClosure",
+            Opcode::Array => "OpArray",
+            Opcode::Hash => "OpHash",
+            Opcode::Index => "OpIndex",
+            Opcode::Call => "OpCall",
+            Opcode::ReturnValue => "OpReturnValue",
+            Opcode::Return => "OpReturn",
+            Opcode::Closure => "OpClosure",
+            Opcode::Try => "OpTry",
+            Opcode::EndTry => "OpEndTry",
+            Opcode::Globals => "OpGlobals",
+        }
+    }
+
+    // the width in bytes of each operand
+    pub fn operand_widths(self) -> &'static [usize] {
+        match self {
+            Opcode::Constant
+            | Opcode::JumpNotTruthy
+            | Opcode::Jump
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::Array
+            | Opcode::Hash
+            | Opcode::Try => &[2],
+            Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::GetBuiltin
+            | Opcode::GetFree
+            | Opcode::Call => &[1],
+            // the function's constant and how many free variables it captures
+            Opcode::Closure => &[2, 1],
+            _ => &[],
+        }
+    }
+
+    // the width in bytes of the whole instruction
+    pub fn width(self) -> usize {
+        1 + self.operand_widths().iter().sum::<usize>()
+    }
+}
+
+// Encodes an instruction. Operands that don't fit their width are a bug in
+// the caller, which has to check the limits of what it compiles.
+pub fn make(op: Opcode, operands: &[usize]) -> Vec<u8> {
+    let widths = op.operand_widths();
+    debug_assert_eq!(widths.len(), operands.len(), "operands of {}", op.name());
+    let mut instruction = Vec::with_capacity(op.width());
+    instruction.push(op as u8);
+    for (&operand, &width) in operands.iter().zip(widths) {
+        debug_assert!(operand < 1 << (8 * width), "operand of {}", op.name());
+        match width {
+            2 => instruction.extend_from_slice(&(operand as u16).to_be_bytes()),
+            1 => instruction.push(operand as u8),
+            _ => unreachable!(),
+        }
+    }
+    instruction
+}
+
+// decodes the operands of an instruction starting right after its opcode
+pub fn read_operands(op: Opcode, bytes: &[u8]) -> Vec<usize> {
+    let mut offset = 0;
+    let mut operands = Vec::with_capacity(op.operand_widths().len());
+    for &width in op.operand_widths() {
+        operands.push(match width {
+            2 => read_u16(&bytes[offset..]),
+            1 => bytes[offset] as usize,
+            _ => unreachable!(),
+        });
+        offset += width;
+    }
+    operands
+}
+
+pub fn read_u16(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+impl Instructions {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // the instructions in order with their offsets, stopping at the first
+    // byte that isn't an opcode
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Opcode, Vec<usize>)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let op = Opcode::from_byte(*self.0.get(offset)?)?;
+            let operands = read_operands(op, &self.0[offset + 1..]);
+            let instruction = (offset, op, operands);
+            offset += op.width();
+            Some(instruction)
+        })
+    }
+}
+
+// one instruction per line, prefixed with its offset
+impl Display for Instructions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (offset, op, operands) in self.iter() {
+            write!(f, "{:04} {}", offset, op.name())?;
+            for operand in operands {
+                write!(f, " {}", operand)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make() {
+        let tests = vec![
+            (
+                Opcode::Constant,
+                vec![65534],
+                vec![Opcode::Constant as u8, 255, 254],
+            ),
+            (Opcode::Add, vec![], vec![Opcode::Add as u8]),
+            (
+                Opcode::GetLocal,
+                vec![255],
+                vec![Opcode::GetLocal as u8, 255],
+            ),
+            (
+                Opcode::Closure,
+                vec![65534, 255],
+                vec![Opcode::Closure as u8, 255, 254, 255],
+            ),
+        ];
+
+        for (op, operands, expected) in tests {
+            assert_eq!(make(op, &operands), expected);
+            assert_eq!(read_operands(op, &expected[1..]), operands);
+        }
+    }
+
+    #[test]
+    fn test_opcodes_round_trip() {
+        for (byte, &op) in OPCODES.iter().enumerate() {
+            assert_eq!(op as usize, byte);
+            assert_eq!(Opcode::from_byte(byte as u8), Some(op));
+        }
+        assert_eq!(Opcode::from_byte(OPCODES.len() as u8), None);
+    }
+
+    #[test]
+    fn test_instructions_display() {
+        let instructions = Instructions(
+            [
+                make(Opcode::Add, &[]),
+                make(Opcode::GetLocal, &[1]),
+                make(Opcode::Constant, &[2]),
+                make(Opcode::Constant, &[65535]),
+                make(Opcode::Closure, &[65535, 255]),
+            ]
+            .concat(),
+        );
+        let expected = "\
+0000 OpAdd
+0001 OpGetLocal 1
+0003 OpConstant 2
+0006 OpConstant 65535
+0009 OpClosure 65535 255
+";
+        assert_eq!(instructions.to_string(), expected);
+    }
+}
@@ -0,0 +1,171 @@
+use std::env;
+
+use crate::highlight::{self, TokenClass};
+
+// What a piece of REPL input or output is, and so how it's colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Keyword,
+    Literal,
+    String,
+    Result,
+    Warning,
+    Error,
+}
+
+impl Style {
+    pub const ALL: [Style; 6] = [
+        Style::Keyword,
+        Style::Literal,
+        Style::String,
+        Style::Result,
+        Style::Warning,
+        Style::Error,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Style::Keyword => "keyword",
+            Style::Literal => "literal",
+            Style::String => "string",
+            Style::Result => "result",
+            Style::Warning => "warning",
+            Style::Error => "error",
+        }
+    }
+
+    fn default_color(self) -> &'static str {
+        match self {
+            Style::Keyword => "magenta",
+            Style::Literal => "yellow",
+            Style::String => "green",
+            Style::Result => "cyan",
+            Style::Warning => "bold yellow",
+            Style::Error => "bold red",
+        }
+    }
+}
+
+// the colors a style can have, with their ANSI codes
+const COLORS: [(&str, &str); 16] = [
+    ("black", "30"),
+    ("red", "31"),
+    ("green", "32"),
+    ("yellow", "33"),
+    ("blue", "34"),
+    ("magenta", "35"),
+    ("cyan", "36"),
+    ("white", "37"),
+    ("bold black", "1;30"),
+    ("bold red", "1;31"),
+    ("bold green", "1;32"),
+    ("bold yellow", "1;33"),
+    ("bold blue", "1;34"),
+    ("bold magenta", "1;35"),
+    ("bold cyan", "1;36"),
+    ("bold white", "1;37"),
+];
+
+// the ANSI code of a color by its name, e.g. "bold red"
+pub fn code(color: &str) -> Option<&'static str> {
+    COLORS
+        .iter()
+        .find(|(name, _)| *name == color)
+        .map(|(_, code)| *code)
+}
+
+// Colors text with ANSI escapes, or leaves it alone when colors are off.
+// They are off when NO_COLOR is set to anything (see no-color.org).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colors {
+    enabled: bool,
+    codes: [&'static str; Style::ALL.len()],
+}
+
+impl Colors {
+    pub fn new(enabled: bool) -> Colors {
+        Colors {
+            enabled: enabled && env::var_os("NO_COLOR").is_none(),
+            codes: Style::ALL.map(|style| code(style.default_color()).unwrap()),
+        }
+    }
+
+    pub fn off() -> Colors {
+        Colors::new(false)
+    }
+
+    // changes the ANSI code the style is colored with
+    pub fn set(mut self, style: Style, code: &'static str) -> Colors {
+        self.codes[style as usize] = code;
+        self
+    }
+
+    pub fn paint(self, style: Style, text: &str) -> String {
+        if !self.enabled || text.is_empty() {
+            return text.to_string();
+        }
+        format!("\x1b[{}m{}\x1b[0m", self.codes[style as usize], text)
+    }
+
+    // the source with its keywords and literals colored
+    pub fn highlight(self, source: &str) -> String {
+        if !self.enabled {
+            return source.to_string();
+        }
+        let mut highlighted = String::new();
+        let mut end = 0;
+        for (span, class) in highlight::highlight(source) {
+            highlighted.push_str(&source[end..span.start]);
+            let text = &source[span.start..span.end];
+            match style(class) {
+                Some(style) => highlighted.push_str(&self.paint(style, text)),
+                None => highlighted.push_str(text),
+            }
+            end = span.end;
+        }
+        highlighted.push_str(&source[end..]);
+        highlighted
+    }
+}
+
+fn style(class: TokenClass) -> Option<Style> {
+    match class {
+        TokenClass::Keyword => Some(Style::Keyword),
+        TokenClass::Number | TokenClass::Boolean => Some(Style::Literal),
+        TokenClass::String => Some(Style::String),
+        TokenClass::Invalid => Some(Style::Error),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let colors = Colors {
+            enabled: true,
+            ..Colors::off()
+        };
+        let tests = vec![
+            ("x + y", "x + y"),
+            ("let x = 5;", "\x1b[35mlet\x1b[0m x = \x1b[33m5\x1b[0m;"),
+            (
+                "if (true) { \"yes\" }\n",
+                "\x1b[35mif\x1b[0m (\x1b[33mtrue\x1b[0m) { \x1b[32m\"yes\"\x1b[0m }\n",
+            ),
+            ("\"open", "\x1b[32m\"open\x1b[0m"),
+            ("1 @", "\x1b[33m1\x1b[0m \x1b[1;31m@\x1b[0m"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(colors.highlight(input), expected, "{}", input);
+        }
+        assert_eq!(Colors::off().highlight("let x = 5;"), "let x = 5;");
+        assert_eq!(Colors::off().paint(Style::Error, "oops"), "oops");
+
+        let colors = colors.set(Style::Keyword, code("bold blue").unwrap());
+        assert_eq!(colors.highlight("fn"), "\x1b[1;34mfn\x1b[0m");
+        assert_eq!(code("purple"), None);
+    }
+}
@@ -0,0 +1,1123 @@
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::builtins;
+use crate::code::{make, Instructions, Opcode};
+use crate::evaluator::{integer_object, CompiledFunction, Function, Object};
+use crate::fold;
+use crate::inline;
+use crate::peephole;
+use crate::quote;
+use crate::source_map::SourceMap;
+use crate::symbol::Symbol;
+use crate::symbol_table::{Binding, SymbolScope, SymbolTable};
+use crate::token::Span;
+
+// A compiled program: the instructions of the top level, which run in a call
+// frame of their own with `num_locals` slots, and the constants they refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bytecode {
+    pub instructions: Instructions,
+    pub constants: Vec<Rc<Object>>,
+    pub num_locals: usize,
+    // the names of the global slots, for error messages
+    pub globals: Vec<Symbol>,
+    pub source_map: SourceMap,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// the instructions of the top level, then those of each function constant
+impl Display for Bytecode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.instructions)?;
+        for (index, constant) in self.constants.iter().enumerate() {
+            if let Object::CompiledFunction(function) = &**constant {
+                writeln!(f, "\nconstant {}: {}", index, function)?;
+                write!(f, "{}", function.instructions)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Compiles programs to bytecode for the VM. A compiler keeps its symbol
+// table and constants between programs, so that a REPL can compile one input
+// at a time and have later ones see the globals of earlier ones.
+//
+// Names are bound the way the tree-walker binds them, with one difference:
+// closures capture the values of the locals of enclosing functions when they
+// are created, so a local function can't refer to a local defined after it.
+// Names that aren't defined anywhere yet are globals, checked at runtime.
+//
+// Calls to small functions are inlined and constant expressions are folded
+// before they're compiled, and an if expression with a constant condition
+// only compiles the branch it takes.
+// The instructions of each function then go through the peephole passes.
+#[derive(Debug)]
+pub struct Compiler {
+    constants: Vec<Rc<Object>>,
+    symbols: SymbolTable,
+    // the function being compiled is last, the top level first
+    scopes: Vec<Scope>,
+    fold: bool,
+    inline: bool,
+    peephole: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler {
+            constants: Vec::new(),
+            symbols: SymbolTable::new(),
+            scopes: Vec::new(),
+            fold: true,
+            inline: true,
+            peephole: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Scope {
+    instructions: Vec<u8>,
+    source_map: SourceMap,
+    // the offsets of the last two instructions emitted
+    last: Option<(Opcode, usize)>,
+    previous: Option<(Opcode, usize)>,
+}
+
+// a jump whose target is patched in once it's known
+const PLACEHOLDER: usize = u16::MAX as usize;
+
+pub fn compile(program: &Program) -> Result<Bytecode, CompileError> {
+    Compiler::new().compile(program)
+}
+
+// Compiles a function the tree-walker created, e.g. to show its instructions.
+// The names it captured from its environment are compiled as globals.
+pub fn compile_function(function: &Function) -> Result<Rc<CompiledFunction>, CompileError> {
+    let literal = Expression::FunctionLiteral {
+        parameters: function.parameters().to_vec(),
+        body: Rc::clone(function.body()),
+        locals: Rc::default(),
+        span: function.body().span(),
+    };
+    let program = Program {
+        statements: vec![Statement::ExpressionStatement(literal, Span::default())],
+    };
+    let bytecode = Compiler::new().inline_functions(false).compile(&program)?;
+    // the functions nested in it are compiled before it
+    match bytecode.constants.last().map(|constant| &**constant) {
+        Some(Object::CompiledFunction(function)) => Ok(Rc::clone(function)),
+        _ => unreachable!("a function literal compiles to a function constant"),
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    // whether to fold constants, on by default; turning it off keeps the
+    // instructions close to the source, e.g. to debug the compiler
+    #[cfg(test)]
+    pub fn fold_constants(mut self, fold: bool) -> Compiler {
+        self.fold = fold;
+        self
+    }
+
+    // whether to inline calls to small functions, on by default; turning it
+    // off keeps every call in stack traces and profiles
+    pub fn inline_functions(mut self, inline: bool) -> Compiler {
+        self.inline = inline;
+        self
+    }
+
+    // whether to rewrite the instructions of each function once it's
+    // compiled, on by default
+    #[cfg(test)]
+    pub fn peephole(mut self, peephole: bool) -> Compiler {
+        self.peephole = peephole;
+        self
+    }
+
+    // the slot of a global that's set from outside of the programs, e.g. by
+    // the REPL
+    pub fn define_global(&mut self, name: &Symbol) -> usize {
+        self.symbols.define(name).index
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<Bytecode, CompileError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("compile").entered();
+        let result = self.compile_program(program);
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(bytecode) => tracing::debug!(
+                instructions = bytecode.instructions.len(),
+                constants = bytecode.constants.len(),
+                "compiled"
+            ),
+            Err(error) => tracing::debug!(error = %error.message, "compile failed"),
+        }
+        result
+    }
+
+    fn compile_program(&mut self, program: &Program) -> Result<Bytecode, CompileError> {
+        let mut statements = program.statements.clone();
+        if self.inline {
+            inline::inline_program(&mut statements);
+        }
+        if self.fold {
+            for statement in &mut statements {
+                fold::fold_statement(statement);
+            }
+        }
+
+        self.scopes = vec![Scope::default()];
+        for statement in &statements {
+            self.compile_statement(statement)?;
+        }
+        self.finish_body()?;
+
+        let scope = self.scopes.pop().unwrap();
+        let (instructions, source_map) = self.finish_instructions(scope);
+        Ok(Bytecode {
+            instructions,
+            constants: self.constants.clone(),
+            num_locals: self.symbols.num_locals(),
+            globals: self.symbols.globals().to_vec(),
+            source_map,
+        })
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        if !matches!(statement, Statement::BlockStatement(..)) {
+            let scope = self.scope();
+            scope
+                .source_map
+                .add(scope.instructions.len(), statement.span());
+        }
+        match statement {
+            Statement::LetStatement { name, value, .. } => {
+                self.compile_value(value, Some(name))?;
+                let binding = self.symbols.define(name);
+                let op = match binding.scope {
+                    SymbolScope::Global => Opcode::SetGlobal,
+                    _ => Opcode::SetLocal,
+                };
+                self.emit(op, &[binding.index])?;
+                self.emit(Opcode::Pop, &[])?;
+            }
+            Statement::ReturnStatement(value, _) => {
+                self.compile_expression(value)?;
+                self.emit(Opcode::ReturnValue, &[])?;
+            }
+            Statement::ExpressionStatement(expression, _) => {
+                self.compile_expression(expression)?;
+                self.emit(Opcode::Pop, &[])?;
+            }
+            Statement::BlockStatement(statements, _) => {
+                for statement in statements {
+                    self.compile_statement(statement)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a block that leaves the value of its last statement, or null
+    fn compile_block(&mut self, block: &Statement) -> Result<(), CompileError> {
+        let start = self.scope().instructions.len();
+        self.compile_statement(block)?;
+        if self.scope().instructions.len() == start {
+            self.emit(Opcode::Null, &[])?;
+        } else if self.last_is(Opcode::Pop) {
+            self.remove_last();
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        self.compile_value(expression, None)
+    }
+
+    // compiles an expression, naming it if it's a function bound by let
+    fn compile_value(
+        &mut self,
+        expression: &Expression,
+        name: Option<&Symbol>,
+    ) -> Result<(), CompileError> {
+        match expression {
+            Expression::IntegerLiteral(value) => {
+                let index = self.add_constant(integer_object(*value));
+                self.emit(Opcode::Constant, &[index])?;
+            }
+            Expression::StringLiteral(value) => {
+                let index = self.add_constant(Object::String(value.clone()).into());
+                self.emit(Opcode::Constant, &[index])?;
+            }
+            Expression::BooleanLiteral(value) => {
+                self.emit(if *value { Opcode::True } else { Opcode::False }, &[])?;
+            }
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Opcode::Array, &[elements.len()])?;
+            }
+            Expression::HashLiteral(pairs, _) => {
+                for (key, value) in pairs {
+                    self.compile_expression(key)?;
+                    self.compile_expression(value)?;
+                }
+                self.emit(Opcode::Hash, &[pairs.len()])?;
+            }
+            Expression::Index { left, index, .. } => {
+                self.compile_expression(left)?;
+                self.compile_expression(index)?;
+                self.emit(Opcode::Index, &[])?;
+            }
+            Expression::Prefix(operator, right) => {
+                self.compile_expression(right)?;
+                let op = match operator {
+                    Prefix::BANG => Opcode::Bang,
+                    Prefix::MINUS => Opcode::Minus,
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::Infix(operator, left, right) => {
+                self.compile_expression(left)?;
+                self.compile_expression(right)?;
+                let op = match operator {
+                    Infix::PLUS => Opcode::Add,
+                    Infix::MINUS => Opcode::Sub,
+                    Infix::ASTERISK => Opcode::Mul,
+                    Infix::SLASH => Opcode::Div,
+                    Infix::PERCENT => Opcode::Mod,
+                    Infix::EQ => Opcode::Equal,
+                    Infix::NOT_EQ => Opcode::NotEqual,
+                    Infix::LT => Opcode::LessThan,
+                    Infix::GT => Opcode::GreaterThan,
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                if let (true, Expression::BooleanLiteral(value)) = (self.fold, &**condition) {
+                    return match (value, alternative) {
+                        (true, _) => self.compile_block(consequence),
+                        (false, Some(alternative)) => self.compile_block(alternative),
+                        (false, None) => self.emit(Opcode::Null, &[]).map(|_| ()),
+                    };
+                }
+
+                self.compile_expression(condition)?;
+                let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[PLACEHOLDER])?;
+                self.compile_block(consequence)?;
+                let jump = self.emit(Opcode::Jump, &[PLACEHOLDER])?;
+
+                self.patch_jump(jump_not_truthy)?;
+                match alternative {
+                    Some(alternative) => self.compile_block(alternative)?,
+                    None => {
+                        self.emit(Opcode::Null, &[])?;
+                    }
+                }
+                self.patch_jump(jump)?;
+            }
+            Expression::Identifier(name, _) => {
+                let binding = match self.symbols.resolve(name) {
+                    Some(binding) => binding,
+                    None => self.symbols.declare_global(name),
+                };
+                self.load(&binding)?;
+            }
+            Expression::FunctionLiteral {
+                parameters, body, ..
+            } => self.compile_function(parameters, body, name)?,
+            Expression::MacroLiteral { .. } => {
+                return Err(CompileError {
+                    message: "macro literals are only supported by the tree-walker".to_string(),
+                });
+            }
+            Expression::Call { .. } if quote::special_form(expression, quote::QUOTE).is_some() => {
+                return Err(CompileError {
+                    message: "quote is only supported by the tree-walker".to_string(),
+                });
+            }
+            // builtins don't get the variables where they're called, so the
+            // locals are put in a hash here and the VM gives the globals
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } if arguments.is_empty() && self.names_builtin(function, builtins::LOCALS) => {
+                let names = self.symbols.local_names();
+                for name in &names {
+                    let key = self.add_constant(Object::String(name.to_string()).into());
+                    self.emit(Opcode::Constant, &[key])?;
+                    let binding = self.symbols.resolve(name).unwrap();
+                    self.load(&binding)?;
+                }
+                self.emit(Opcode::Hash, &[names.len()])?;
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } if arguments.is_empty() && self.names_builtin(function, builtins::GLOBALS) => {
+                self.emit(Opcode::Globals, &[])?;
+            }
+            Expression::Call {
+                function,
+                arguments,
+                span,
+            } => {
+                self.compile_expression(function)?;
+                for argument in arguments {
+                    self.compile_expression(argument)?;
+                }
+                let offset = self.emit(Opcode::Call, &[arguments.len()])?;
+                self.scope().source_map.add(offset, *span);
+            }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => {
+                let try_start = self.emit(Opcode::Try, &[PLACEHOLDER])?;
+                self.compile_block(body)?;
+                self.emit(Opcode::EndTry, &[])?;
+                let jump = self.emit(Opcode::Jump, &[PLACEHOLDER])?;
+
+                // the VM pushes the error before it jumps to the handler,
+                // which has a scope of its own in the current call frame
+                self.patch_jump(try_start)?;
+                self.symbols = std::mem::take(&mut self.symbols).enclose_block();
+                let binding = self.symbols.define(name);
+                self.emit(Opcode::SetLocal, &[binding.index])?;
+                self.emit(Opcode::Pop, &[])?;
+                let handled = self.compile_block(handler);
+                self.symbols = self.leave_symbols();
+                handled?;
+                self.patch_jump(jump)?;
+            }
+            Expression::For {
+                name,
+                iterable,
+                body,
+                span,
+                ..
+            } => {
+                // the loop runs on the iterator builtins, with its name and
+                // the iterator in a scope of their own like a catch handler
+                self.symbols = std::mem::take(&mut self.symbols).enclose_block();
+                let compiled = self.compile_for(name, iterable, body, *span);
+                self.symbols = self.leave_symbols();
+                compiled?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_for(
+        &mut self,
+        name: &Symbol,
+        iterable: &Expression,
+        body: &Statement,
+        span: Span,
+    ) -> Result<(), CompileError> {
+        let iterator = self.symbols.define_hidden();
+        self.call_builtin("iter", span, |compiler| {
+            compiler.compile_expression(iterable)
+        })?;
+        self.emit(Opcode::SetLocal, &[iterator])?;
+        self.emit(Opcode::Pop, &[])?;
+
+        let start = self.scope().instructions.len();
+        self.call_builtin("done", span, |compiler| {
+            compiler.emit(Opcode::GetLocal, &[iterator]).map(|_| ())
+        })?;
+        self.emit(Opcode::Bang, &[])?;
+        let exit = self.emit(Opcode::JumpNotTruthy, &[PLACEHOLDER])?;
+        self.call_builtin("next", span, |compiler| {
+            compiler.emit(Opcode::GetLocal, &[iterator]).map(|_| ())
+        })?;
+        let binding = self.symbols.define(name);
+        self.emit(Opcode::SetLocal, &[binding.index])?;
+        self.emit(Opcode::Pop, &[])?;
+        self.compile_statement(body)?;
+        self.emit(Opcode::Jump, &[start])?;
+
+        self.patch_jump(exit)?;
+        self.emit(Opcode::Null, &[])?;
+        Ok(())
+    }
+
+    // calls the builtin on the argument the closure compiles, as a call in
+    // the program at the span would
+    fn call_builtin(
+        &mut self,
+        name: &str,
+        span: Span,
+        argument: impl FnOnce(&mut Compiler) -> Result<(), CompileError>,
+    ) -> Result<(), CompileError> {
+        let builtin = builtins::position(name).expect("not a builtin");
+        self.emit(Opcode::GetBuiltin, &[builtin])?;
+        argument(self)?;
+        let offset = self.emit(Opcode::Call, &[1])?;
+        self.scope().source_map.add(offset, span);
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        parameters: &[Symbol],
+        body: &Rc<Statement>,
+        name: Option<&Symbol>,
+    ) -> Result<(), CompileError> {
+        // globals are looked up when they're used, only a local function
+        // needs to be told about itself
+        let local_name = name.filter(|_| self.symbols.is_local());
+        self.scopes.push(Scope::default());
+        self.symbols = std::mem::take(&mut self.symbols).enclose();
+        if let Some(name) = local_name {
+            self.symbols.define_function_name(name);
+        }
+        for parameter in parameters {
+            self.symbols.define(parameter);
+        }
+        let compiled = self
+            .compile_statement(body)
+            .and_then(|_| self.finish_body());
+
+        let free = self.symbols.free_symbols().to_vec();
+        let num_locals = self.symbols.num_locals();
+        self.symbols = self.leave_symbols();
+        let scope = self.scopes.pop().unwrap();
+        compiled?;
+
+        let (instructions, source_map) = self.finish_instructions(scope);
+        let function = CompiledFunction {
+            instructions,
+            num_locals,
+            parameters: parameters.to_vec(),
+            name: name.cloned(),
+            source_map,
+            body: Some(Rc::clone(body)),
+        };
+        for binding in &free {
+            self.load(binding)?;
+        }
+        let index = self.add_constant(Object::CompiledFunction(function.into()).into());
+        self.emit(Opcode::Closure, &[index, free.len()])?;
+        Ok(())
+    }
+
+    // returns the value of the last statement, or null if there is none
+    fn finish_body(&mut self) -> Result<(), CompileError> {
+        if self.last_is(Opcode::Pop) {
+            self.remove_last();
+            self.emit(Opcode::ReturnValue, &[])?;
+        } else if !self.last_is(Opcode::ReturnValue) {
+            self.emit(Opcode::Return, &[])?;
+        }
+        Ok(())
+    }
+
+    fn finish_instructions(&self, scope: Scope) -> (Instructions, SourceMap) {
+        let instructions = Instructions(scope.instructions);
+        if self.peephole {
+            peephole::optimize_mapped(&instructions, &scope.source_map, &self.constants)
+        } else {
+            (instructions, scope.source_map)
+        }
+    }
+
+    fn leave_symbols(&mut self) -> SymbolTable {
+        std::mem::take(&mut self.symbols)
+            .into_outer()
+            .expect("left the global scope")
+    }
+
+    fn load(&mut self, binding: &Binding) -> Result<(), CompileError> {
+        let op = match binding.scope {
+            SymbolScope::Global => Opcode::GetGlobal,
+            SymbolScope::Local => Opcode::GetLocal,
+            SymbolScope::Builtin => Opcode::GetBuiltin,
+            SymbolScope::Free => Opcode::GetFree,
+            SymbolScope::Function => {
+                self.emit(Opcode::CurrentClosure, &[])?;
+                return Ok(());
+            }
+        };
+        self.emit(op, &[binding.index])?;
+        Ok(())
+    }
+
+    // whether the expression is the name of the builtin, not of a variable
+    // shadowing it
+    fn names_builtin(&mut self, expression: &Expression, builtin: &str) -> bool {
+        match expression {
+            Expression::Identifier(name, _) if *name == builtin => matches!(
+                self.symbols.resolve(name),
+                Some(Binding {
+                    scope: SymbolScope::Builtin,
+                    ..
+                })
+            ),
+            _ => false,
+        }
+    }
+
+    fn add_constant(&mut self, obj: Rc<Object>) -> usize {
+        self.constants.push(obj);
+        self.constants.len() - 1
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    // appends the instruction and returns its offset
+    fn emit(&mut self, op: Opcode, operands: &[usize]) -> Result<usize, CompileError> {
+        check_operands(op, operands)?;
+        let scope = self.scope();
+        let offset = scope.instructions.len();
+        scope.instructions.extend(make(op, operands));
+        scope.previous = scope.last.replace((op, offset));
+        Ok(offset)
+    }
+
+    fn last_is(&mut self, op: Opcode) -> bool {
+        matches!(self.scope().last, Some((last, _)) if last == op)
+    }
+
+    fn remove_last(&mut self) {
+        let scope = self.scope();
+        if let Some((_, offset)) = scope.last.take() {
+            scope.instructions.truncate(offset);
+            scope.source_map.truncate(offset);
+            scope.last = scope.previous.take();
+        }
+    }
+
+    // points the jump at `offset` to the next instruction to be emitted
+    fn patch_jump(&mut self, offset: usize) -> Result<(), CompileError> {
+        let scope = self.scope();
+        let target = scope.instructions.len();
+        let op = Opcode::from_byte(scope.instructions[offset]).unwrap();
+        check_operands(op, &[target])?;
+        let instruction = make(op, &[target]);
+        scope.instructions[offset..offset + instruction.len()].copy_from_slice(&instruction);
+        Ok(())
+    }
+}
+
+fn check_operands(op: Opcode, operands: &[usize]) -> Result<(), CompileError> {
+    for (&operand, &width) in operands.iter().zip(op.operand_widths()) {
+        if operand >= 1 << (8 * width) {
+            return Err(CompileError {
+                message: format!(
+                    "program too large: operand {} of {} doesn't fit in {} bytes",
+                    operand,
+                    op.name(),
+                    width
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    enum Constant {
+        Integer(i64),
+        String(&'static str),
+        Function(Vec<Vec<u8>>),
+    }
+
+    // without optimizations, so that the instructions follow the source
+    fn test_compile(input: &str) -> Bytecode {
+        let compiler = Compiler::new().fold_constants(false).peephole(false);
+        test_compile_with(compiler, input)
+    }
+
+    fn test_compile_with(mut compiler: Compiler, input: &str) -> Bytecode {
+        let program = parser::parse(input).unwrap();
+        compiler.compile(&program).unwrap()
+    }
+
+    fn instructions(instructions: Vec<Vec<u8>>) -> String {
+        Instructions(instructions.concat()).to_string()
+    }
+
+    fn test_constants(actual: &[Rc<Object>], expected: Vec<Constant>) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (actual, expected) in actual.iter().zip(expected) {
+            match (&**actual, expected) {
+                (Object::Integer(value), Constant::Integer(expected)) => {
+                    assert_eq!(*value, expected)
+                }
+                (Object::String(value), Constant::String(expected)) => {
+                    assert_eq!(value, expected)
+                }
+                (Object::CompiledFunction(function), Constant::Function(expected)) => {
+                    assert_eq!(function.instructions.to_string(), instructions(expected))
+                }
+                (actual, _) => panic!("unexpected constant {:?}", actual),
+            }
+        }
+    }
+
+    // an input, the constants it compiles to and the instructions of the top level
+    type Test = (&'static str, Vec<Constant>, Vec<Vec<u8>>);
+
+    fn run_compiler_tests(tests: Vec<Test>) {
+        for (input, constants, expected) in tests {
+            let bytecode = test_compile(input);
+            assert_eq!(
+                bytecode.instructions.to_string(),
+                instructions(expected),
+                "{}",
+                input
+            );
+            test_constants(&bytecode.constants, constants);
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        use Constant::Integer;
+        let tests = vec![
+            (
+                "1 + 2",
+                vec![Integer(1), Integer(2)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Add, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "1; 2 % 3",
+                vec![Integer(1), Integer(2), Integer(3)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Constant, &[2]),
+                    make(Opcode::Mod, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "-1 < 2 == !true",
+                vec![Integer(1), Integer(2)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Minus, &[]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::LessThan, &[]),
+                    make(Opcode::True, &[]),
+                    make(Opcode::Bang, &[]),
+                    make(Opcode::Equal, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            ("", vec![], vec![make(Opcode::Return, &[])]),
+        ];
+        run_compiler_tests(tests);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        use Constant::{Integer, String};
+        let tests = vec![
+            (
+                "2 * 3 + 4",
+                vec![Integer(10)],
+                vec![make(Opcode::Constant, &[0]), make(Opcode::ReturnValue, &[])],
+            ),
+            (
+                r#"!true; "a" + "b"; x * (1 - 3)"#,
+                vec![String("ab"), Integer(-2)],
+                vec![
+                    make(Opcode::False, &[]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::GetGlobal, &[0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Mul, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "if (1 > 2) { 10 } else { let y = 20; y }; if (!false) { 30 }; if (false) { 40 }",
+                vec![Integer(20), Integer(30)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::SetGlobal, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::GetGlobal, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Null, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "fn() { 1 / 0 }",
+                vec![
+                    Integer(1),
+                    Integer(0),
+                    Constant::Function(vec![
+                        make(Opcode::Constant, &[0]),
+                        make(Opcode::Constant, &[1]),
+                        make(Opcode::Div, &[]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                ],
+                vec![
+                    make(Opcode::Closure, &[2, 0]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+        ];
+
+        for (input, constants, expected) in tests {
+            let bytecode = test_compile_with(Compiler::new().peephole(false), input);
+            assert_eq!(
+                bytecode.instructions.to_string(),
+                instructions(expected),
+                "{}",
+                input
+            );
+            test_constants(&bytecode.constants, constants);
+        }
+
+        // with folding off the operations are left to the VM
+        let bytecode = test_compile("2 * 3 + 4");
+        assert_eq!(bytecode.constants.len(), 3);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        use Constant::Integer;
+        let tests = vec![
+            (
+                "if (true) { 10 }; 3333;",
+                vec![Integer(10), Integer(3333)],
+                vec![
+                    // 0000
+                    make(Opcode::True, &[]),
+                    // 0001
+                    make(Opcode::JumpNotTruthy, &[10]),
+                    // 0004
+                    make(Opcode::Constant, &[0]),
+                    // 0007
+                    make(Opcode::Jump, &[11]),
+                    // 0010
+                    make(Opcode::Null, &[]),
+                    // 0011
+                    make(Opcode::Pop, &[]),
+                    // 0012
+                    make(Opcode::Constant, &[1]),
+                    // 0015
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "if (true) { } else { let x = 20; }",
+                vec![Integer(20)],
+                vec![
+                    // 0000
+                    make(Opcode::True, &[]),
+                    // 0001
+                    make(Opcode::JumpNotTruthy, &[8]),
+                    // 0004
+                    make(Opcode::Null, &[]),
+                    // 0005
+                    make(Opcode::Jump, &[14]),
+                    // 0008
+                    make(Opcode::Constant, &[0]),
+                    // 0011
+                    make(Opcode::SetGlobal, &[0]),
+                    // 0014
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+        ];
+        run_compiler_tests(tests);
+    }
+
+    #[test]
+    fn test_bindings() {
+        use Constant::{Integer, String};
+        let tests = vec![
+            (
+                r#"let one = 1; let two = "two"; one; len(two)"#,
+                vec![Integer(1), String("two")],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::SetGlobal, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::SetGlobal, &[1]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::GetGlobal, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::GetBuiltin, &[0]),
+                    make(Opcode::GetGlobal, &[1]),
+                    make(Opcode::Call, &[1]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                // a global used before its let statement keeps its slot
+                "let f = fn() { later }; let later = 1;",
+                vec![
+                    Constant::Function(vec![
+                        make(Opcode::GetGlobal, &[0]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                    Integer(1),
+                ],
+                vec![
+                    make(Opcode::Closure, &[0, 0]),
+                    make(Opcode::SetGlobal, &[1]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::SetGlobal, &[0]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+        ];
+        run_compiler_tests(tests);
+    }
+
+    #[test]
+    fn test_collections() {
+        use Constant::Integer;
+        let tests = vec![
+            (
+                "[1, 2][0]",
+                vec![Integer(1), Integer(2), Integer(0)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Array, &[2]),
+                    make(Opcode::Constant, &[2]),
+                    make(Opcode::Index, &[]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "{1: 2}",
+                vec![Integer(1), Integer(2)],
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Hash, &[1]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+        ];
+        run_compiler_tests(tests);
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        use Constant::{Function, Integer};
+        let tests = vec![
+            (
+                "fn() { }",
+                vec![Function(vec![make(Opcode::Return, &[])])],
+                vec![
+                    make(Opcode::Closure, &[0, 0]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "fn(a) { let b = a; return b; }(1)",
+                vec![
+                    Function(vec![
+                        make(Opcode::GetLocal, &[0]),
+                        make(Opcode::SetLocal, &[1]),
+                        make(Opcode::Pop, &[]),
+                        make(Opcode::GetLocal, &[1]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                    Integer(1),
+                ],
+                vec![
+                    make(Opcode::Closure, &[0, 0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::Call, &[1]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+            (
+                "fn(a) { fn(b) { fn(c) { a + b + c } } }",
+                vec![
+                    Function(vec![
+                        make(Opcode::GetFree, &[0]),
+                        make(Opcode::GetFree, &[1]),
+                        make(Opcode::Add, &[]),
+                        make(Opcode::GetLocal, &[0]),
+                        make(Opcode::Add, &[]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                    Function(vec![
+                        make(Opcode::GetFree, &[0]),
+                        make(Opcode::GetLocal, &[0]),
+                        make(Opcode::Closure, &[0, 2]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                    Function(vec![
+                        make(Opcode::GetLocal, &[0]),
+                        make(Opcode::Closure, &[1, 1]),
+                        make(Opcode::ReturnValue, &[]),
+                    ]),
+                ],
+                vec![
+                    make(Opcode::Closure, &[2, 0]),
+                    make(Opcode::ReturnValue, &[]),
+                ],
+            ),
+        ];
+        run_compiler_tests(tests);
+
+        let bytecode = test_compile("let add = fn(a, b) { a + b };");
+        match &*bytecode.constants[0] {
+            Object::CompiledFunction(function) => {
+                assert_eq!(function.name.as_deref(), Some("add"));
+                assert_eq!(function.num_locals, 2);
+                assert_eq!(function.to_string(), "fn(a, b) {...}");
+            }
+            obj => panic!("object is not CompiledFunction. got={:?}", obj),
+        }
+    }
+
+    #[test]
+    fn test_try() {
+        use Constant::Integer;
+        let tests = vec![(
+            "let e = 1; try { e } catch (e) { e }; e",
+            vec![Integer(1)],
+            vec![
+                // 0000
+                make(Opcode::Constant, &[0]),
+                // 0003
+                make(Opcode::SetGlobal, &[0]),
+                // 0006
+                make(Opcode::Pop, &[]),
+                // 0007
+                make(Opcode::Try, &[17]),
+                // 0010
+                make(Opcode::GetGlobal, &[0]),
+                // 0013
+                make(Opcode::EndTry, &[]),
+                // 0014
+                make(Opcode::Jump, &[22]),
+                // 0017
+                make(Opcode::SetLocal, &[0]),
+                // 0019
+                make(Opcode::Pop, &[]),
+                // 0020
+                make(Opcode::GetLocal, &[0]),
+                // 0022
+                make(Opcode::Pop, &[]),
+                // 0023
+                make(Opcode::GetGlobal, &[0]),
+                // 0026
+                make(Opcode::ReturnValue, &[]),
+            ],
+        )];
+        run_compiler_tests(tests);
+        assert_eq!(test_compile("try { 1 } catch (e) { 2 }").num_locals, 1);
+    }
+
+    #[test]
+    fn test_compiler_keeps_globals_between_programs() {
+        let mut compiler = Compiler::new();
+        for (input, expected) in [("let a = 1;", 0), ("let b = 2;", 1), ("let a = 3;", 0)] {
+            let program = parser::parse(input).unwrap();
+            let bytecode = compiler.compile(&program).unwrap();
+            let setters: Vec<usize> = bytecode
+                .instructions
+                .iter()
+                .filter(|(_, op, _)| *op == Opcode::SetGlobal)
+                .map(|(_, _, operands)| operands[0])
+                .collect();
+            assert_eq!(setters, vec![expected], "{}", input);
+        }
+
+        let program = parser::parse("a + b").unwrap();
+        let bytecode = compiler.compile(&program).unwrap();
+        assert_eq!(
+            bytecode.globals,
+            vec![Symbol::intern("a"), Symbol::intern("b")]
+        );
+        assert_eq!(bytecode.constants.len(), 3);
+    }
+
+    #[test]
+    fn test_program_too_large() {
+        let args = vec!["1"; 256].join(", ");
+        let program = parser::parse(&format!("f({})", args)).unwrap();
+        assert_eq!(
+            compile(&program).unwrap_err().message,
+            "program too large: operand 256 of OpCall doesn't fit in 1 bytes"
+        );
+    }
+
+    #[test]
+    fn test_quote_is_not_compiled() {
+        let program = parser::parse("quote(1 + 2)").unwrap();
+        assert_eq!(
+            compile(&program).unwrap_err().message,
+            "quote is only supported by the tree-walker"
+        );
+    }
+
+    #[test]
+    fn test_bytecode_display() {
+        let bytecode = test_compile("fn(x) { x }");
+        let expected = "\
+0000 OpClosure 0 0
+0004 OpReturnValue
+
+constant 0: fn(x) {...}
+0000 OpGetLocal 0
+0002 OpReturnValue
+";
+        assert_eq!(bytecode.to_string(), expected);
+    }
+}
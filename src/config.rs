@@ -0,0 +1,354 @@
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+use crate::color::{self, Style};
+use crate::engine::Engine;
+use crate::history::HISTORY_SIZE;
+use crate::warnings::{Severity, WarningKind};
+
+pub const BANNER: &str = "Return to Monk REPL (:help for commands, :quit to exit)";
+
+// The settings of the REPL, from a config file in a small subset of TOML:
+//
+//   prompt = "monk> "
+//   banner = ""              # no banner
+//   color = true
+//   history_size = 500
+//   engine = "vm"
+//   load = ["prelude.mk"]
+//
+//   [colors]
+//   keyword = "bold blue"
+//
+//   [lint]                   # for `monk lint`, before its flags
+//   shadowing = "deny"
+//
+// Every setting is optional. Strings, integers, booleans and arrays of them
+// are supported, each on a single line, and so are comments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub prompt: String,
+    pub banner: String,
+    pub color: bool,
+    pub colors: Vec<(Style, &'static str)>,
+    pub history_size: usize,
+    pub engine: Engine,
+    pub load: Vec<String>,
+    pub lint: Vec<(WarningKind, Severity)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prompt: ">> ".to_string(),
+            banner: BANNER.to_string(),
+            color: true,
+            colors: Vec::new(),
+            history_size: HISTORY_SIZE,
+            engine: Engine::default(),
+            load: Vec::new(),
+            lint: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub message: String,
+    pub line: usize,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+// MONK_CONFIG if it's set, else monk/config.toml in the XDG config directory,
+// which is ~/.config by default
+pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("MONK_CONFIG") {
+        return (!path.is_empty()).then(|| path.into());
+    }
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("monk").join("config.toml"))
+}
+
+impl Config {
+    // the config in the file, or the defaults if there is no file
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(source) => Config::parse(&source).map_err(|error| error.to_string()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut section = String::new();
+        for (index, line) in source.lines().enumerate() {
+            let error = |message: String| ConfigError {
+                message,
+                line: index + 1,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let Some((name, rest)) = header.split_once(']') else {
+                    return Err(error("expected ] after the section name".to_string()));
+                };
+                end_of_line(rest).map_err(error)?;
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(error("expected key = value".to_string()));
+            };
+            let key = match section.as_str() {
+                "" => key.trim().to_string(),
+                section => format!("{}.{}", section, key.trim()),
+            };
+            let mut chars = value.chars().peekable();
+            let value = parse_value(&mut chars).map_err(error)?;
+            end_of_line(&chars.collect::<String>()).map_err(error)?;
+            config.set(&key, value).map_err(error)?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "prompt" => self.prompt = string(key, value)?,
+            "banner" => self.banner = string(key, value)?,
+            "color" => match value {
+                Value::Boolean(color) => self.color = color,
+                value => return Err(mismatch(key, "a boolean", &value)),
+            },
+            "history_size" => match value {
+                Value::Integer(size) if size >= 0 => self.history_size = size as usize,
+                value => return Err(mismatch(key, "a positive integer", &value)),
+            },
+            "engine" => self.engine = string(key, value)?.parse()?,
+            "load" => match value {
+                Value::Array(files) => {
+                    self.load = files
+                        .into_iter()
+                        .map(|file| string(key, file))
+                        .collect::<Result<_, _>>()?
+                }
+                value => return Err(mismatch(key, "an array", &value)),
+            },
+            _ if key.starts_with("lint.") => {
+                let kind = WarningKind::ALL
+                    .into_iter()
+                    .find(|kind| key == format!("lint.{}", kind.name()))
+                    .ok_or_else(|| format!("unknown lint rule: {}", &key["lint.".len()..]))?;
+                let severity = string(key, value)?.parse()?;
+                self.lint.push((kind, severity));
+            }
+            _ => {
+                let style = key
+                    .strip_prefix("colors.")
+                    .and_then(|name| Style::ALL.into_iter().find(|style| style.name() == name))
+                    .ok_or_else(|| format!("unknown setting: {}", key))?;
+                let name = string(key, value)?;
+                let code = color::code(&name).ok_or_else(|| format!("unknown color: {}", name))?;
+                self.colors.push((style, code));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        value => Err(mismatch(key, "a string", &value)),
+    }
+}
+
+fn mismatch(key: &str, expected: &str, value: &Value) -> String {
+    format!("{} has to be {}, got {}", key, expected, value.type_name())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => return Ok(Value::String(string)),
+                    Some('\\') => match chars.next() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some(ch @ ('"' | '\\')) => string.push(ch),
+                        _ => return Err("unknown escape in a string".to_string()),
+                    },
+                    Some(ch) => string.push(ch),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut elements = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    return Ok(Value::Array(elements));
+                }
+                elements.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Value::Array(elements)),
+                    _ => return Err("expected , or ] in an array".to_string()),
+                }
+            }
+        }
+        _ => {
+            let mut word = String::new();
+            while let Some(ch) = chars.next_if(|ch| ch.is_alphanumeric() || *ch == '-') {
+                word.push(ch);
+            }
+            match word.as_str() {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                "" => Err("expected a value".to_string()),
+                word => word
+                    .parse()
+                    .map(Value::Integer)
+                    .map_err(|_| format!("unexpected {}", word)),
+            }
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+}
+
+// nothing but a comment can follow a value on its line
+fn end_of_line(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    match rest.is_empty() || rest.starts_with('#') {
+        true => Ok(()),
+        false => Err(format!("unexpected {}", rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let source = r#"
+        # settings
+        prompt = "monk> "   # a comment
+        banner = ""
+        color = false
+        history_size = 50
+        engine = "vm"
+        load = ["a.mk", "b \"c\".mk",]
+
+        [colors]
+        keyword = "bold blue"
+
+        [lint]
+        shadowing = "deny"
+        "#;
+        let config = Config::parse(source).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                prompt: "monk> ".to_string(),
+                banner: String::new(),
+                color: false,
+                colors: vec![(Style::Keyword, "1;34")],
+                history_size: 50,
+                engine: Engine::Vm,
+                load: vec!["a.mk".to_string(), "b \"c\".mk".to_string()],
+                lint: vec![(WarningKind::Shadowing, Severity::Deny)],
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_errors() {
+        let unknown = format!("{} at line 1", "llvm".parse::<Engine>().unwrap_err());
+        let tests = vec![
+            ("prompt", "expected key = value at line 1"),
+            (
+                "prompt = 1",
+                "prompt has to be a string, got an integer at line 1",
+            ),
+            (
+                "\n\ncolor = \"yes\"",
+                "color has to be a boolean, got a string at line 3",
+            ),
+            (
+                "history_size = -1",
+                "history_size has to be a positive integer, got an integer at line 1",
+            ),
+            ("prompt = \"open", "unterminated string at line 1"),
+            ("prompt = \"a\" \"b\"", "unexpected \"b\" at line 1"),
+            (
+                "load = [\"a\" \"b\"]",
+                "expected , or ] in an array at line 1",
+            ),
+            ("engine = \"llvm\"", unknown.as_str()),
+            ("shell = \"zsh\"", "unknown setting: shell at line 1"),
+            (
+                "[colors]\nkeyword = \"purple\"",
+                "unknown color: purple at line 2",
+            ),
+            ("[colors\n", "expected ] after the section name at line 1"),
+            (
+                "[lint]\nstyle = \"deny\"",
+                "unknown lint rule: style at line 2",
+            ),
+            (
+                "[lint]\nunused = \"error\"",
+                "unknown severity: error (expected allow, warn or deny) at line 2",
+            ),
+        ];
+        for (source, expected) in tests {
+            let error = Config::parse(source).unwrap_err();
+            assert_eq!(error.to_string(), expected, "{}", source);
+        }
+    }
+}
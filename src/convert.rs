@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::evaluator::{
+    integer_object, native_bool_to_boolean_object, null_object, EvalError, HashPair, HashPairs,
+    Object,
+};
+use crate::interpreter::Value;
+use crate::task::Channel;
+
+// Conversions between objects and Rust values, for the programs that embed
+// the interpreter. Value is an Rc, so a value is built from a Rust one with
+// `Value::new(42.into())`, and read back with `i64::try_from(&*value)`.
+
+impl From<i64> for Object {
+    fn from(value: i64) -> Object {
+        Object::Integer(value)
+    }
+}
+
+impl From<bool> for Object {
+    fn from(value: bool) -> Object {
+        Object::Boolean(value)
+    }
+}
+
+impl From<&str> for Object {
+    fn from(value: &str) -> Object {
+        Object::String(value.to_string())
+    }
+}
+
+impl From<String> for Object {
+    fn from(value: String) -> Object {
+        Object::String(value)
+    }
+}
+
+impl From<Vec<Value>> for Object {
+    fn from(elements: Vec<Value>) -> Object {
+        Object::Array(elements)
+    }
+}
+
+// none is null
+impl<T: Into<Object>> From<Option<T>> for Object {
+    fn from(value: Option<T>) -> Object {
+        value.map_or(Object::Null, Into::into)
+    }
+}
+
+// A hash of the pairs, or the error of the first key that can't be one. A
+// key that's there twice keeps its last value, like in a hash literal.
+//
+//   convert::hash([("name", Object::from("monk")), ("age", 5.into())])
+pub fn hash<K, V>(pairs: impl IntoIterator<Item = (K, V)>) -> Result<Object, EvalError>
+where
+    K: Into<Object>,
+    V: Into<Object>,
+{
+    let mut hash = HashPairs::new();
+    for (key, value) in pairs {
+        let key = Rc::new(key.into());
+        let value = Rc::new(value.into());
+        hash.insert(key.hash_key()?, HashPair { key, value });
+    }
+    Ok(Object::Hash(hash))
+}
+
+// an object that isn't of the type it's converted to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl ConversionError {
+    fn new(expected: &'static str, found: &Object) -> ConversionError {
+        ConversionError {
+            expected,
+            found: found.type_of().to_string(),
+        }
+    }
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "expected {}, got {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl TryFrom<&Object> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(obj: &Object) -> Result<i64, ConversionError> {
+        match obj {
+            Object::Integer(value) => Ok(*value),
+            _ => Err(ConversionError::new("INTEGER", obj)),
+        }
+    }
+}
+
+impl TryFrom<&Object> for bool {
+    type Error = ConversionError;
+
+    fn try_from(obj: &Object) -> Result<bool, ConversionError> {
+        match obj {
+            Object::Boolean(value) => Ok(*value),
+            _ => Err(ConversionError::new("BOOLEAN", obj)),
+        }
+    }
+}
+
+impl TryFrom<&Object> for String {
+    type Error = ConversionError;
+
+    fn try_from(obj: &Object) -> Result<String, ConversionError> {
+        match obj {
+            Object::String(value) => Ok(value.clone()),
+            _ => Err(ConversionError::new("STRING", obj)),
+        }
+    }
+}
+
+impl TryFrom<&Object> for Vec<Value> {
+    type Error = ConversionError;
+
+    fn try_from(obj: &Object) -> Result<Vec<Value>, ConversionError> {
+        match obj {
+            Object::Array(elements) => Ok(elements.clone()),
+            _ => Err(ConversionError::new("ARRAY", obj)),
+        }
+    }
+}
+
+// the pairs of a hash whose keys are all strings; a hash with another key
+// is an error, as the key 1 and the key "1" would both become "1"
+impl TryFrom<&Object> for HashMap<String, Value> {
+    type Error = ConversionError;
+
+    fn try_from(obj: &Object) -> Result<HashMap<String, Value>, ConversionError> {
+        match obj {
+            Object::Hash(pairs) => pairs
+                .values()
+                .map(|pair| Ok((String::try_from(&*pair.key)?, pair.value.clone())))
+                .collect(),
+            _ => Err(ConversionError::new("HASH", obj)),
+        }
+    }
+}
+
+// A value as plain data, which unlike an object can be sent to another
+// thread, as channels can. The values that aren't data, like functions, are
+// only kept as what they display as. The pairs of a hash are in the order
+// they were inserted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    Null,
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+    Array(Vec<Data>),
+    Hash(Vec<(Data, Data)>),
+    Channel(Channel),
+    Other(String),
+}
+
+impl Data {
+    // the value as data that makes the same value again on another thread,
+    // or the type of the first value in it that isn't data or a channel
+    pub(crate) fn exact(obj: &Object) -> Result<Data, &'static str> {
+        Ok(match obj {
+            Object::Array(elements) => Data::Array(
+                elements
+                    .iter()
+                    .map(|element| Data::exact(element))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Object::Hash(pairs) => Data::Hash(
+                pairs
+                    .values()
+                    .map(|pair| Ok((Data::exact(&pair.key)?, Data::exact(&pair.value)?)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Object::ReturnValue(value) => Data::exact(value)?,
+            obj => match Data::from(obj) {
+                Data::Other(_) => return Err(obj.type_of()),
+                data => data,
+            },
+        })
+    }
+
+    // what's kept of a value that isn't data is a string of what it
+    // displayed as
+    pub(crate) fn into_object(self) -> Rc<Object> {
+        match self {
+            Data::Null => null_object(),
+            Data::Integer(value) => integer_object(value),
+            Data::Boolean(value) => native_bool_to_boolean_object(value),
+            Data::String(value) | Data::Other(value) => Object::String(value).into(),
+            Data::Array(elements) => {
+                Object::Array(elements.into_iter().map(Data::into_object).collect()).into()
+            }
+            Data::Hash(pairs) => {
+                let pairs: HashPairs = pairs
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let (key, value) = (key.into_object(), value.into_object());
+                        // the keys were keys on the other side too
+                        let hash_key = key.hash_key().expect("a data key is hashable");
+                        (hash_key, HashPair { key, value })
+                    })
+                    .collect();
+                Object::Hash(pairs).into()
+            }
+            Data::Channel(channel) => Object::Channel(channel).into(),
+        }
+    }
+}
+
+impl From<&Object> for Data {
+    fn from(obj: &Object) -> Data {
+        match obj {
+            Object::Null => Data::Null,
+            Object::Integer(value) => Data::Integer(*value),
+            Object::Boolean(value) => Data::Boolean(*value),
+            Object::String(value) => Data::String(value.clone()),
+            Object::Array(elements) => Data::Array(
+                elements
+                    .iter()
+                    .map(|element| Data::from(&**element))
+                    .collect(),
+            ),
+            Object::Hash(pairs) => Data::Hash(
+                pairs
+                    .values()
+                    .map(|pair| (Data::from(&*pair.key), Data::from(&*pair.value)))
+                    .collect(),
+            ),
+            Object::Channel(channel) => Data::Channel(channel.clone()),
+            Object::ReturnValue(value) => Data::from(&**value),
+            _ => Data::Other(obj.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_object() {
+        let tests: Vec<(Object, &str)> = vec![
+            (42i64.into(), "42"),
+            ((-1i64).into(), "-1"),
+            (i64::MAX.into(), "9223372036854775807"),
+            (true.into(), "true"),
+            ("monk".into(), "monk"),
+            (String::from("monk").into(), "monk"),
+            (
+                vec![Value::new(1.into()), Value::new("a".into())].into(),
+                "[1, \"a\"]",
+            ),
+            (None::<bool>.into(), "null"),
+            (Some(false).into(), "false"),
+        ];
+        for (obj, expected) in tests {
+            assert_eq!(obj.to_string(), expected);
+        }
+
+        let obj = hash([("b", Object::from(2)), ("a", 1.into()), ("b", 3.into())]);
+        assert_eq!(obj.unwrap().to_string(), "{\"b\": 3, \"a\": 1}");
+        let error = hash([(Object::from(vec![]), Object::Null)]).unwrap_err();
+        assert_eq!(error.message, "unusable as hash key: ARRAY");
+    }
+
+    #[test]
+    fn test_from_object() {
+        assert_eq!(i64::try_from(&Object::Integer(7)), Ok(7));
+        assert_eq!(bool::try_from(&Object::Boolean(true)), Ok(true));
+        assert_eq!(String::try_from(&Object::from("a")), Ok("a".to_string()));
+        let array = Vec::<Value>::try_from(&Object::from(vec![Value::new(Object::Null)]));
+        assert_eq!(array.unwrap().len(), 1);
+        let pairs = HashMap::<String, Value>::try_from(&hash([("1", "one")]).unwrap()).unwrap();
+        assert_eq!(*pairs["1"], Object::from("one"));
+        let mixed = hash([(Object::from("1"), "one"), (1.into(), "two")]).unwrap();
+        let error = HashMap::<String, Value>::try_from(&mixed).unwrap_err();
+        assert_eq!(error.to_string(), "expected STRING, got INTEGER");
+
+        let error = i64::try_from(&Object::from("7")).unwrap_err();
+        assert_eq!(error.to_string(), "expected INTEGER, got STRING");
+        let error = Vec::<Value>::try_from(&Object::Null).unwrap_err();
+        assert_eq!(error.to_string(), "expected ARRAY, got NULL");
+    }
+
+    #[test]
+    fn test_data() {
+        let array = Object::from(vec![Value::new(1.into()), Value::new(Object::Null)]);
+        let obj = hash([("b", array), ("a", true.into())]).unwrap();
+        assert_eq!(
+            Data::from(&obj),
+            Data::Hash(vec![
+                (
+                    Data::String("b".to_string()),
+                    Data::Array(vec![Data::Integer(1), Data::Null])
+                ),
+                (Data::String("a".to_string()), Data::Boolean(true)),
+            ])
+        );
+        let builtin = Object::Builtin(crate::builtins::lookup("len").unwrap());
+        assert_eq!(
+            Data::from(&builtin),
+            Data::Other("builtin function len".to_string())
+        );
+    }
+}
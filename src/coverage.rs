@@ -0,0 +1,299 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+use crate::ast::{Expression, Program, Statement};
+use crate::dump;
+use crate::evaluator::{Env, Frame};
+use crate::observer::{EvalObserver, Node};
+use crate::token::Span;
+
+// Counts how often each statement of the files ran, while the tree-walker
+// runs them. It's a shared handle, like Profiler: add a file before running
+// it with a clone passed to EvalConfig::observe, and read the report from the
+// original once the runs are done. A file is parsed again for every run, so
+// its statements are known by their spans. Blocks aren't counted, only the
+// statements in them, and neither are macros, which never run themselves.
+#[derive(Clone, Default)]
+pub struct Coverage {
+    files: Rc<RefCell<Vec<FileCoverage>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCoverage {
+    pub file: String,
+    hits: HashMap<Span, u64>,
+}
+
+impl FileCoverage {
+    // the statements with how often they ran, in the order they're in
+    pub fn statements(&self) -> Vec<(Span, u64)> {
+        let mut statements: Vec<(Span, u64)> = self
+            .hits
+            .iter()
+            .map(|(span, hits)| (*span, *hits))
+            .collect();
+        statements.sort_by_key(|(span, _)| span.start);
+        statements
+    }
+
+    pub fn covered(&self) -> usize {
+        self.hits.values().filter(|hits| **hits > 0).count()
+    }
+
+    pub fn total(&self) -> usize {
+        self.hits.len()
+    }
+
+    // the lines of the statements that never ran
+    fn missed_lines(&self) -> BTreeSet<usize> {
+        self.hits
+            .iter()
+            .filter(|(_, hits)| **hits == 0)
+            .map(|(span, _)| span.line)
+            .collect()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // the file whose statements the runs after count, until the next one
+    pub fn add_file(&self, file: &str, program: &Program) {
+        let mut hits = HashMap::new();
+        for statement in &program.statements {
+            statement_spans(statement, &mut hits);
+        }
+        self.files.borrow_mut().push(FileCoverage {
+            file: file.to_string(),
+            hits,
+        });
+    }
+
+    #[cfg(test)]
+    pub fn files(&self) -> Vec<FileCoverage> {
+        self.files.borrow().clone()
+    }
+
+    //   coverage:
+    //     math_test.mk: 5/6 statements (83.3%), not run: line 4
+    //   total: 5/6 statements (83.3%)
+    pub fn summary(&self) -> String {
+        let files = self.files.borrow();
+        let mut summary = String::from("coverage:\n");
+        for file in files.iter() {
+            summary.push_str(&format!(
+                "  {}: {}",
+                file.file,
+                percentage(file.covered(), file.total())
+            ));
+            let missed: Vec<String> = file.missed_lines().iter().map(usize::to_string).collect();
+            match missed.len() {
+                0 => {}
+                1 => summary.push_str(&format!(", not run: line {}", missed[0])),
+                _ => summary.push_str(&format!(", not run: lines {}", missed.join(", "))),
+            }
+            summary.push('\n');
+        }
+        let covered = files.iter().map(FileCoverage::covered).sum();
+        let total = files.iter().map(FileCoverage::total).sum();
+        summary.push_str(&format!("total: {}", percentage(covered, total)));
+        summary
+    }
+
+    // The report in the lcov tracefile format, which most coverage tools
+    // read. It counts lines, and a line ran as often as the statements that
+    // start on it together.
+    pub fn lcov(&self) -> String {
+        let mut lcov = String::new();
+        for file in self.files.borrow().iter() {
+            let mut lines: Vec<(usize, u64)> = Vec::new();
+            for (span, hits) in file.statements() {
+                match lines.iter_mut().find(|(line, _)| *line == span.line) {
+                    Some((_, line_hits)) => *line_hits += hits,
+                    None => lines.push((span.line, hits)),
+                }
+            }
+            lines.sort();
+            lcov.push_str(&format!("TN:\nSF:{}\n", file.file));
+            for (line, hits) in &lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            let hit = lines.iter().filter(|(_, hits)| *hits > 0).count();
+            lcov.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        }
+        lcov
+    }
+
+    // the report as JSON, with the statements of every file and their spans
+    pub fn json(&self) -> String {
+        let files = self.files.borrow();
+        let files = files.iter().map(|file| {
+            let statements = file.statements().into_iter().map(|(span, hits)| {
+                dump::object(&[("span", dump::span_json(&span)), ("hits", hits.to_string())])
+            });
+            dump::object(&[
+                ("file", dump::string(&file.file)),
+                ("covered", file.covered().to_string()),
+                ("total", file.total().to_string()),
+                ("statements", dump::array(statements)),
+            ])
+        });
+        dump::object(&[("files", dump::array(files))])
+    }
+}
+
+fn percentage(covered: usize, total: usize) -> String {
+    let percentage = match total {
+        0 => 100.0,
+        _ => covered as f64 * 100.0 / total as f64,
+    };
+    format!("{}/{} statements ({:.1}%)", covered, total, percentage)
+}
+
+impl EvalObserver for Coverage {
+    fn on_enter_node(&mut self, node: Node, _env: &Env, _frames: &[Frame]) {
+        let Node::Statement(statement) = node else {
+            return;
+        };
+        if let Some(file) = self.files.borrow_mut().last_mut() {
+            if let Some(hits) = file.hits.get_mut(&statement.span()) {
+                *hits += 1;
+            }
+        }
+    }
+}
+
+impl Debug for Coverage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Coverage").finish_non_exhaustive()
+    }
+}
+
+fn statement_spans(statement: &Statement, hits: &mut HashMap<Span, u64>) {
+    match statement {
+        // a macro is taken out of the program before it runs
+        Statement::LetStatement {
+            value: Expression::MacroLiteral { .. },
+            ..
+        } => {}
+        Statement::LetStatement { value, span, .. } => {
+            hits.insert(*span, 0);
+            expression_spans(value, hits);
+        }
+        Statement::ReturnStatement(value, span) | Statement::ExpressionStatement(value, span) => {
+            hits.insert(*span, 0);
+            expression_spans(value, hits);
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                statement_spans(statement, hits);
+            }
+        }
+    }
+}
+
+fn expression_spans(expression: &Expression, hits: &mut HashMap<Span, u64>) {
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_)
+        | Expression::MacroLiteral { .. } => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                expression_spans(element, hits);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                expression_spans(key, hits);
+                expression_spans(value, hits);
+            }
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            expression_spans(condition, hits);
+            statement_spans(consequence, hits);
+            if let Some(alternative) = alternative {
+                statement_spans(alternative, hits);
+            }
+        }
+        Expression::FunctionLiteral { body, .. } => statement_spans(body, hits),
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            expression_spans(function, hits);
+            for argument in arguments {
+                expression_spans(argument, hits);
+            }
+        }
+        Expression::Try { body, handler, .. } => {
+            statement_spans(body, hits);
+            statement_spans(handler, hits);
+        }
+        Expression::For { iterable, body, .. } => {
+            expression_spans(iterable, hits);
+            statement_spans(body, hits);
+        }
+        Expression::Index { left, index, .. } => {
+            expression_spans(left, hits);
+            expression_spans(index, hits);
+        }
+        Expression::Prefix(_, right) => expression_spans(right, hits),
+        Expression::Infix(_, left, right) => {
+            expression_spans(left, hits);
+            expression_spans(right, hits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, Runner};
+    use crate::evaluator::EvalConfig;
+    use crate::parser;
+
+    #[test]
+    fn test_coverage() {
+        let source = "let f = fn(x) {\n  if (x > 0) { x } else { -x }\n};\nf(1);\nf(2);";
+        let coverage = Coverage::new();
+        let program = parser::parse(source).unwrap();
+        coverage.add_file("f.mk", &program);
+        let config = EvalConfig::default().observe(coverage.clone());
+        Runner::new(Engine::TreeWalker)
+            .run_with_config(program, config)
+            .unwrap();
+
+        let files = coverage.files();
+        let hits: Vec<(usize, u64)> = files[0]
+            .statements()
+            .iter()
+            .map(|(span, hits)| (span.line, *hits))
+            .collect();
+        assert_eq!(hits, [(1, 1), (2, 2), (2, 2), (2, 0), (4, 1), (5, 1)]);
+        assert_eq!(
+            coverage.summary(),
+            "coverage:\n  f.mk: 5/6 statements (83.3%), not run: line 2\n\
+             total: 5/6 statements (83.3%)"
+        );
+        assert_eq!(
+            coverage.lcov(),
+            "TN:\nSF:f.mk\nDA:1,1\nDA:2,4\nDA:4,1\nDA:5,1\nLF:4\nLH:4\nend_of_record\n"
+        );
+        assert!(coverage.json().starts_with(
+            "{\"files\":[{\"file\":\"f.mk\",\"covered\":5,\"total\":6,\"statements\":\
+             [{\"span\":{\"start\":0,"
+        ));
+    }
+}
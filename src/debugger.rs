@@ -0,0 +1,296 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::ast::Statement;
+use crate::evaluator::{eval_with_config, Env, EvalConfig, Frame};
+use crate::observer::{EvalObserver, Node};
+use crate::parser;
+
+const HELP: &str = "\
+commands:
+  s, step          run until the next statement
+  n, next          run until the next statement in this call or the ones it returns to
+  c, continue      run until a breakpoint
+  b, break LINE    pause at every statement on LINE
+  d, delete LINE   remove the breakpoint on LINE
+  bt, where        show the calls in progress
+  env              show the variables visible here, innermost first
+  p, print EXPR    evaluate EXPR here
+  q, quit          stop debugging and let the program finish
+  h, help          show this message";
+
+// Pauses the evaluator before statements and runs a command loop on the
+// given input and output until told to carry on. It observes the evaluator
+// through EvalConfig::observe and starts out stepping, so it pauses before
+// the first statement.
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Step,
+    // pause once no more than this many calls are in progress
+    Next(usize),
+    Continue,
+}
+
+enum Resume {
+    Pause,
+    Run,
+}
+
+impl Debugger {
+    pub fn new(input: impl BufRead + 'static, output: impl Write + 'static) -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Step,
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+
+    fn pause(&mut self, statement: &Statement, env: &Env, frames: &[Frame]) -> io::Result<()> {
+        writeln!(
+            self.output,
+            "paused at line {}: {}",
+            statement.span().line,
+            statement
+        )?;
+        loop {
+            write!(self.output, "(debug) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // end of input, let the program finish
+                self.breakpoints.clear();
+                self.mode = Mode::Continue;
+                return Ok(());
+            }
+            if let Resume::Run = self.command(line.trim(), env, frames)? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, env: &Env, frames: &[Frame]) -> io::Result<Resume> {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "s" | "step" => self.mode = Mode::Step,
+            "n" | "next" => self.mode = Mode::Next(frames.len()),
+            "c" | "continue" => self.mode = Mode::Continue,
+            "q" | "quit" => {
+                self.breakpoints.clear();
+                self.mode = Mode::Continue;
+            }
+            "b" | "break" | "d" | "delete" => {
+                let Ok(line) = argument.parse::<usize>() else {
+                    writeln!(self.output, "expected a line number, got `{}`", argument)?;
+                    return Ok(Resume::Pause);
+                };
+                if command.starts_with('b') {
+                    self.breakpoints.insert(line);
+                    writeln!(self.output, "breakpoint at line {}", line)?;
+                } else if self.breakpoints.remove(&line) {
+                    writeln!(self.output, "removed breakpoint at line {}", line)?;
+                } else {
+                    writeln!(self.output, "no breakpoint at line {}", line)?;
+                }
+                return Ok(Resume::Pause);
+            }
+            "bt" | "where" => {
+                if frames.is_empty() {
+                    writeln!(self.output, "at top level")?;
+                }
+                for frame in frames.iter().rev() {
+                    writeln!(self.output, "{}", frame)?;
+                }
+                return Ok(Resume::Pause);
+            }
+            "env" => {
+                self.print_env(env)?;
+                return Ok(Resume::Pause);
+            }
+            "p" | "print" => {
+                self.print(argument, env)?;
+                return Ok(Resume::Pause);
+            }
+            "h" | "help" => {
+                writeln!(self.output, "{}", HELP)?;
+                return Ok(Resume::Pause);
+            }
+            "" => return Ok(Resume::Pause),
+            _ => {
+                writeln!(self.output, "unknown command `{}`, try `help`", command)?;
+                return Ok(Resume::Pause);
+            }
+        }
+        Ok(Resume::Run)
+    }
+
+    fn print_env(&mut self, env: &Env) -> io::Result<()> {
+        let mut env = Some(Rc::clone(env));
+        let mut level = 0;
+        while let Some(current) = env {
+            let current = current.borrow();
+            let label = match current.outer() {
+                Some(_) => format!("#{}", level),
+                None => "globals".to_string(),
+            };
+            let bindings: Vec<String> = current
+                .bindings()
+                .into_iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            writeln!(self.output, "{}: {}", label, bindings.join(", "))?;
+            env = current.outer().cloned();
+            level += 1;
+        }
+        Ok(())
+    }
+
+    // evaluates without the debugger, so it doesn't pause inside itself
+    fn print(&mut self, source: &str, env: &Env) -> io::Result<()> {
+        let program = match parser::parse(source) {
+            Ok(program) => program,
+            Err(errors) => return writeln!(self.output, "{}", errors[0]),
+        };
+        match eval_with_config(program, env, EvalConfig::default()) {
+            Ok(value) => writeln!(self.output, "{}", value),
+            Err(error) => writeln!(self.output, "error: {}", error),
+        }
+    }
+}
+
+// only statements other than blocks are places to pause
+impl EvalObserver for Debugger {
+    fn on_enter_node(&mut self, node: Node, env: &Env, frames: &[Frame]) {
+        let statement = match node {
+            Node::Statement(Statement::BlockStatement(..)) | Node::Expression(_) => return,
+            Node::Statement(statement) => statement,
+        };
+        let line = statement.span().line;
+        let pause = match self.mode {
+            Mode::Step => true,
+            Mode::Next(depth) => frames.len() <= depth,
+            Mode::Continue => false,
+        };
+        if !pause && !self.breakpoints.contains(&line) {
+            return;
+        }
+
+        // once the output is gone there is no one left to debug for
+        if self.pause(statement, env, frames).is_err() {
+            self.breakpoints.clear();
+            self.mode = Mode::Continue;
+        }
+    }
+}
+
+impl Debug for Debugger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Debugger")
+            .field("breakpoints", &self.breakpoints)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::Environment;
+    use std::cell::RefCell;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // runs the program under the debugger with the given commands, returning
+    // the debugger's output
+    fn debug(input: &str, commands: &str) -> String {
+        let output = Output::default();
+        let debugger = Debugger::new(io::Cursor::new(commands.to_string()), output.clone());
+        let program = parser::parse(input).unwrap();
+        let env = Rc::new(RefCell::new(Environment::new()));
+        eval_with_config(program, &env, EvalConfig::default().observe(debugger)).unwrap();
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    const PROGRAM: &str = "let add = fn(a, b) {
+    let sum = a + b;
+    sum
+};
+let x = 1;
+add(x, 2);";
+
+    #[test]
+    fn test_step_and_inspect() {
+        let output = debug(PROGRAM, "s\ns\ns\ns\nenv\np sum * 10\nbt\nc\n");
+        let expected = "\
+paused at line 1: let add = fn(a, b) let sum = (a + b);sum;
+(debug) paused at line 5: let x = 1;
+(debug) paused at line 6: add(x, 2)
+(debug) paused at line 2: let sum = (a + b);
+(debug) paused at line 3: sum
+(debug) #0: a = 1, b = 2, sum = 3
+globals: add = fn(a, b) {
+let sum = (a + b);sum
+}, x = 1
+(debug) 30
+(debug) at add (line 6, column 1)
+(debug) ";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let output = debug(PROGRAM, "b 3\nc\np a\nd 3\nd 3\nc\n");
+        assert_eq!(
+            output,
+            "\
+paused at line 1: let add = fn(a, b) let sum = (a + b);sum;
+(debug) breakpoint at line 3
+(debug) paused at line 3: sum
+(debug) 1
+(debug) removed breakpoint at line 3
+(debug) no breakpoint at line 3
+(debug) "
+        );
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let output = debug(PROGRAM, "c\n");
+        assert_eq!(
+            output,
+            "paused at line 1: let add = fn(a, b) let sum = (a + b);sum;\n(debug) "
+        );
+
+        let output = debug(PROGRAM, "n\nn\nn\nn\n");
+        assert!(!output.contains("line 2"), "{}", output);
+        assert!(output.contains("line 6"), "{}", output);
+
+        // running out of commands lets the program finish
+        let output = debug(PROGRAM, "bogus\n");
+        assert!(output.contains("unknown command `bogus`"), "{}", output);
+    }
+}
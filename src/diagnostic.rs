@@ -0,0 +1,76 @@
+use crate::token::Span;
+
+// Shows where in the source a diagnostic is about: the line the span starts
+// on, numbered, with carets under the span. A span that runs over more lines
+// is underlined to the end of its first one.
+//
+//   3 | let y = x +;
+//     |            ^
+pub fn snippet(source: &str, span: Span) -> String {
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let line = source
+        .lines()
+        .nth(span.line.saturating_sub(1))
+        .unwrap_or("");
+
+    let column = span.column.saturating_sub(1);
+    let before: String = line
+        .chars()
+        .take(column)
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+    let rest = line.chars().count().saturating_sub(column);
+    let width = span.end.saturating_sub(span.start).min(rest).max(1);
+
+    format!(
+        "{} | {}\n{} | {}{}",
+        number,
+        line,
+        gutter,
+        before,
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn span(line: usize, column: usize, start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn test_snippet() {
+        let source = "let x = 1;\nlet y = x +;\n\tfoo(bar)\n";
+        let tests = vec![
+            (span(1, 5, 4, 5), "1 | let x = 1;\n  |     ^"),
+            (span(2, 12, 22, 23), "2 | let y = x +;\n  |            ^"),
+            (span(3, 2, 25, 33), "3 | \tfoo(bar)\n  | \t^^^^^^^^"),
+            // runs past the end of its line
+            (span(2, 9, 19, 40), "2 | let y = x +;\n  |         ^^^^"),
+            // the end of the input
+            (span(4, 1, 34, 34), "4 | \n  | ^"),
+        ];
+        for (span, expected) in tests {
+            assert_eq!(snippet(source, span), expected, "{:?}", span);
+        }
+    }
+
+    #[test]
+    fn test_parse_error_snippet() {
+        let source = "let f = fn(x) {\n  x +;\n};";
+        let mut parser = Parser::new(Lexer::new(source));
+        parser.parse_program();
+        let error = &parser.errors[0];
+        assert_eq!(snippet(source, error.span), "2 |   x +;\n  |      ^");
+    }
+}
@@ -0,0 +1,598 @@
+use crate::ast::{Expression, Program, Statement};
+use crate::lexer::Lexer;
+use crate::symbol::Symbol;
+use crate::token::{Span, Token};
+
+// The tokens of the source, one per line with where they are: the line and
+// column they start at, then their bytes.
+//
+//   1:1 0..3 let
+pub fn tokens(source: &str) -> String {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = String::new();
+    loop {
+        let spanned = lexer.next_token();
+        let span = spanned.span;
+        tokens.push_str(&format!(
+            "{}:{} {}..{} {}\n",
+            span.line, span.column, span.start, span.end, spanned.token
+        ));
+        if spanned.token == Token::EOF {
+            return tokens;
+        }
+    }
+}
+
+// The syntax tree of the program, a node per line, indented under its parent.
+pub fn tree(program: &Program) -> String {
+    let mut tree = String::from("Program\n");
+    for statement in &program.statements {
+        statement_tree(statement, 1, &mut tree);
+    }
+    tree
+}
+
+fn line(depth: usize, text: String, tree: &mut String) {
+    tree.push_str(&"  ".repeat(depth));
+    tree.push_str(&text);
+    tree.push('\n');
+}
+
+fn statement_tree(statement: &Statement, depth: usize, tree: &mut String) {
+    match statement {
+        Statement::LetStatement {
+            name, value, span, ..
+        } => {
+            line(depth, format!("Let {} ({})", name, span), tree);
+            expression_tree(value, depth + 1, tree);
+        }
+        Statement::ReturnStatement(value, span) => {
+            line(depth, format!("Return ({})", span), tree);
+            expression_tree(value, depth + 1, tree);
+        }
+        Statement::BlockStatement(statements, span) => {
+            line(depth, format!("Block ({})", span), tree);
+            for statement in statements {
+                statement_tree(statement, depth + 1, tree);
+            }
+        }
+        Statement::ExpressionStatement(expression, span) => {
+            line(depth, format!("Expression ({})", span), tree);
+            expression_tree(expression, depth + 1, tree);
+        }
+    }
+}
+
+fn expression_tree(expression: &Expression, depth: usize, tree: &mut String) {
+    match expression {
+        Expression::Identifier(name, _) => line(depth, format!("Identifier {}", name), tree),
+        Expression::IntegerLiteral(value) => line(depth, format!("Integer {}", value), tree),
+        Expression::BooleanLiteral(value) => line(depth, format!("Boolean {}", value), tree),
+        Expression::StringLiteral(value) => line(depth, format!("String {:?}", value), tree),
+        Expression::ArrayLiteral(elements) => {
+            line(depth, "Array".to_string(), tree);
+            for element in elements {
+                expression_tree(element, depth + 1, tree);
+            }
+        }
+        Expression::HashLiteral(pairs, span) => {
+            line(depth, format!("Hash ({})", span), tree);
+            for (key, value) in pairs {
+                line(depth + 1, "Pair".to_string(), tree);
+                expression_tree(key, depth + 2, tree);
+                expression_tree(value, depth + 2, tree);
+            }
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            span,
+        } => {
+            line(depth, format!("If ({})", span), tree);
+            expression_tree(condition, depth + 1, tree);
+            statement_tree(consequence, depth + 1, tree);
+            if let Some(alternative) = alternative {
+                statement_tree(alternative, depth + 1, tree);
+            }
+        }
+        Expression::FunctionLiteral {
+            parameters,
+            body,
+            span,
+            ..
+        }
+        | Expression::MacroLiteral {
+            parameters,
+            body,
+            span,
+            ..
+        } => {
+            let kind = match expression {
+                Expression::MacroLiteral { .. } => "Macro",
+                _ => "Function",
+            };
+            let parameters: Vec<&str> = parameters.iter().map(|p| &**p).collect();
+            line(
+                depth,
+                format!("{}({}) ({})", kind, parameters.join(", "), span),
+                tree,
+            );
+            statement_tree(body, depth + 1, tree);
+        }
+        Expression::Call {
+            function,
+            arguments,
+            span,
+        } => {
+            line(depth, format!("Call ({})", span), tree);
+            expression_tree(function, depth + 1, tree);
+            for argument in arguments {
+                expression_tree(argument, depth + 1, tree);
+            }
+        }
+        Expression::Try {
+            body,
+            name,
+            handler,
+            span,
+            ..
+        } => {
+            line(depth, format!("Try catch {} ({})", name, span), tree);
+            statement_tree(body, depth + 1, tree);
+            statement_tree(handler, depth + 1, tree);
+        }
+        Expression::For {
+            name,
+            iterable,
+            body,
+            span,
+            ..
+        } => {
+            line(depth, format!("For {} ({})", name, span), tree);
+            expression_tree(iterable, depth + 1, tree);
+            statement_tree(body, depth + 1, tree);
+        }
+        Expression::Index { left, index, span } => {
+            line(depth, format!("Index ({})", span), tree);
+            expression_tree(left, depth + 1, tree);
+            expression_tree(index, depth + 1, tree);
+        }
+        Expression::Prefix(operator, right) => {
+            line(depth, format!("Prefix {}", operator), tree);
+            expression_tree(right, depth + 1, tree);
+        }
+        Expression::Infix(operator, left, right) => {
+            line(depth, format!("Infix {}", operator), tree);
+            expression_tree(left, depth + 1, tree);
+            expression_tree(right, depth + 1, tree);
+        }
+    }
+}
+
+// The program as source that parses back to the same syntax tree, on one
+// line. Unlike Display, it keeps the braces and parentheses the parser needs,
+// and puts every operator in parentheses, so printing what it parses back to
+// gives the same source again.
+//
+//   let f = fn(x) { (x + 1); }; if ((f(1) > 1)) { true; } else { false; };
+#[cfg(any(test, feature = "internals"))]
+pub fn source(program: &Program) -> String {
+    let statements: Vec<String> = program.statements.iter().map(statement_source).collect();
+    statements.join(" ")
+}
+
+pub fn statement_source(statement: &Statement) -> String {
+    match statement {
+        Statement::LetStatement { name, value, .. } => {
+            format!("let {} = {};", name, expression_source(value))
+        }
+        Statement::ReturnStatement(value, _) => format!("return {};", expression_source(value)),
+        Statement::ExpressionStatement(value, _) => format!("{};", expression_source(value)),
+        Statement::BlockStatement(statements, _) => {
+            let statements: Vec<String> = statements.iter().map(statement_source).collect();
+            match statements.len() {
+                0 => String::from("{ }"),
+                _ => format!("{{ {} }}", statements.join(" ")),
+            }
+        }
+    }
+}
+
+pub fn expression_source(expression: &Expression) -> String {
+    let list = |expressions: &[Expression]| {
+        let expressions: Vec<String> = expressions.iter().map(expression_source).collect();
+        expressions.join(", ")
+    };
+    let parameters = |parameters: &[Symbol]| {
+        let parameters: Vec<String> = parameters.iter().map(Symbol::to_string).collect();
+        parameters.join(", ")
+    };
+    match expression {
+        Expression::Identifier(name, _) => name.to_string(),
+        Expression::IntegerLiteral(value) => value.to_string(),
+        Expression::BooleanLiteral(value) => value.to_string(),
+        Expression::StringLiteral(value) => format!("\"{}\"", value),
+        Expression::ArrayLiteral(elements) => format!("[{}]", list(elements)),
+        Expression::HashLiteral(pairs, _) => {
+            let pairs: Vec<String> = pairs
+                .iter()
+                .map(|(key, value)| {
+                    format!("{}: {}", expression_source(key), expression_source(value))
+                })
+                .collect();
+            format!("{{{}}}", pairs.join(", "))
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            let mut source = format!(
+                "if ({}) {}",
+                expression_source(condition),
+                statement_source(consequence)
+            );
+            if let Some(alternative) = alternative {
+                source.push_str(&format!(" else {}", statement_source(alternative)));
+            }
+            source
+        }
+        Expression::FunctionLiteral {
+            parameters: names,
+            body,
+            ..
+        } => format!("fn({}) {}", parameters(names), statement_source(body)),
+        Expression::MacroLiteral {
+            parameters: names,
+            body,
+            ..
+        } => format!("macro({}) {}", parameters(names), statement_source(body)),
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => format!("{}({})", expression_source(function), list(arguments)),
+        Expression::Try {
+            body,
+            name,
+            handler,
+            ..
+        } => format!(
+            "try {} catch ({}) {}",
+            statement_source(body),
+            name,
+            statement_source(handler)
+        ),
+        Expression::For {
+            name,
+            iterable,
+            body,
+            ..
+        } => format!(
+            "for ({} in {}) {}",
+            name,
+            expression_source(iterable),
+            statement_source(body)
+        ),
+        Expression::Index { left, index, .. } => {
+            format!(
+                "({}[{}])",
+                expression_source(left),
+                expression_source(index)
+            )
+        }
+        Expression::Prefix(operator, right) => {
+            format!("({}{})", operator, expression_source(right))
+        }
+        Expression::Infix(operator, left, right) => format!(
+            "({} {} {})",
+            expression_source(left),
+            operator,
+            expression_source(right)
+        ),
+    }
+}
+
+// The syntax tree of the program as JSON, for tools that aren't written in
+// Rust. Every node is an object with its "type", its children and, where the
+// parser keeps one, its "span".
+pub fn json(program: &Program) -> String {
+    let statements: Vec<String> = program.statements.iter().map(statement_json).collect();
+    format!(
+        "{{\"type\":\"Program\",\"statements\":[{}]}}",
+        statements.join(",")
+    )
+}
+
+pub fn object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("\"{}\":{}", name, value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+pub fn array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+pub fn string(value: &str) -> String {
+    let mut string = String::from('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => string.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => string.push(ch),
+        }
+    }
+    string.push('"');
+    string
+}
+
+pub fn span_json(span: &Span) -> String {
+    object(&[
+        ("start", span.start.to_string()),
+        ("end", span.end.to_string()),
+        ("line", span.line.to_string()),
+        ("column", span.column.to_string()),
+    ])
+}
+
+fn kind(name: &str) -> (&'static str, String) {
+    ("type", string(name))
+}
+
+fn statement_json(statement: &Statement) -> String {
+    match statement {
+        Statement::LetStatement {
+            name, value, span, ..
+        } => object(&[
+            kind("Let"),
+            ("name", string(name)),
+            ("value", expression_json(value)),
+            ("span", span_json(span)),
+        ]),
+        Statement::ReturnStatement(value, span) => object(&[
+            kind("Return"),
+            ("value", expression_json(value)),
+            ("span", span_json(span)),
+        ]),
+        Statement::BlockStatement(statements, span) => object(&[
+            kind("Block"),
+            ("statements", array(statements.iter().map(statement_json))),
+            ("span", span_json(span)),
+        ]),
+        Statement::ExpressionStatement(expression, span) => object(&[
+            kind("Expression"),
+            ("expression", expression_json(expression)),
+            ("span", span_json(span)),
+        ]),
+    }
+}
+
+fn expression_json(expression: &Expression) -> String {
+    match expression {
+        Expression::Identifier(name, _) => object(&[kind("Identifier"), ("name", string(name))]),
+        Expression::IntegerLiteral(value) => {
+            object(&[kind("Integer"), ("value", value.to_string())])
+        }
+        Expression::BooleanLiteral(value) => {
+            object(&[kind("Boolean"), ("value", value.to_string())])
+        }
+        Expression::StringLiteral(value) => object(&[kind("String"), ("value", string(value))]),
+        Expression::ArrayLiteral(elements) => object(&[
+            kind("Array"),
+            ("elements", array(elements.iter().map(expression_json))),
+        ]),
+        Expression::HashLiteral(pairs, span) => object(&[
+            kind("Hash"),
+            (
+                "pairs",
+                array(
+                    pairs
+                        .iter()
+                        .map(|(key, value)| array([expression_json(key), expression_json(value)])),
+                ),
+            ),
+            ("span", span_json(span)),
+        ]),
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            span,
+        } => object(&[
+            kind("If"),
+            ("condition", expression_json(condition)),
+            ("consequence", statement_json(consequence)),
+            (
+                "alternative",
+                alternative
+                    .as_ref()
+                    .map_or("null".to_string(), |alternative| {
+                        statement_json(alternative)
+                    }),
+            ),
+            ("span", span_json(span)),
+        ]),
+        Expression::FunctionLiteral {
+            parameters,
+            body,
+            span,
+            ..
+        }
+        | Expression::MacroLiteral {
+            parameters,
+            body,
+            span,
+            ..
+        } => object(&[
+            kind(match expression {
+                Expression::MacroLiteral { .. } => "Macro",
+                _ => "Function",
+            }),
+            (
+                "parameters",
+                array(parameters.iter().map(|parameter| string(parameter))),
+            ),
+            ("body", statement_json(body)),
+            ("span", span_json(span)),
+        ]),
+        Expression::Call {
+            function,
+            arguments,
+            span,
+        } => object(&[
+            kind("Call"),
+            ("function", expression_json(function)),
+            ("arguments", array(arguments.iter().map(expression_json))),
+            ("span", span_json(span)),
+        ]),
+        Expression::Try {
+            body,
+            name,
+            handler,
+            span,
+            ..
+        } => object(&[
+            kind("Try"),
+            ("body", statement_json(body)),
+            ("name", string(name)),
+            ("handler", statement_json(handler)),
+            ("span", span_json(span)),
+        ]),
+        Expression::For {
+            name,
+            iterable,
+            body,
+            span,
+            ..
+        } => object(&[
+            kind("For"),
+            ("name", string(name)),
+            ("iterable", expression_json(iterable)),
+            ("body", statement_json(body)),
+            ("span", span_json(span)),
+        ]),
+        Expression::Index { left, index, span } => object(&[
+            kind("Index"),
+            ("left", expression_json(left)),
+            ("index", expression_json(index)),
+            ("span", span_json(span)),
+        ]),
+        Expression::Prefix(operator, right) => object(&[
+            kind("Prefix"),
+            ("operator", string(&operator.to_string())),
+            ("right", expression_json(right)),
+        ]),
+        Expression::Infix(operator, left, right) => object(&[
+            kind("Infix"),
+            ("operator", string(&operator.to_string())),
+            ("left", expression_json(left)),
+            ("right", expression_json(right)),
+        ]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_tokens() {
+        let tests = vec![
+            ("", "1:1 0..0 EOF\n"),
+            (
+                "let x = 1;",
+                "1:1 0..3 let\n1:5 4..5 IDENT(x)\n1:7 6..7 =\n1:9 8..9 INT(1)\n\
+                 1:10 9..10 ;\n1:11 10..10 EOF\n",
+            ),
+        ];
+        for (source, expected) in tests {
+            assert_eq!(tokens(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_tree() {
+        let program = parser::parse("let f = fn(x) { x + 1 };\nf(2)").unwrap();
+        let expected = "\
+Program
+  Let f (line 1, column 1)
+    Function(x) (line 1, column 9)
+      Block (line 1, column 15)
+        Expression (line 1, column 17)
+          Infix +
+            Identifier x
+            Integer 1
+  Expression (line 2, column 1)
+    Call (line 2, column 1)
+      Identifier f
+      Integer 2
+";
+        assert_eq!(tree(&program), expected);
+    }
+
+    #[test]
+    fn test_source() {
+        let tests = vec![
+            ("", ""),
+            ("let x = 1 + 2 * 3", "let x = (1 + (2 * 3));"),
+            (
+                "let f = fn(x, y) { return x - -y }; f(1)(2)[0]",
+                "let f = fn(x, y) { return (x - (-y)); }; (f(1)(2)[0]);",
+            ),
+            (
+                "if (a) { } else { if (b) { \"é\" } }",
+                "if (a) { } else { if (b) { \"é\"; }; };",
+            ),
+            (
+                "try { {1: [true]}[1] } catch (e) { e }",
+                "try { ({1: [true]}[1]); } catch (e) { e; };",
+            ),
+            (
+                "let m = macro(q) { quote(unquote(q)) }",
+                "let m = macro(q) { quote(unquote(q)); };",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let printed = source(&parser::parse(input).unwrap());
+            assert_eq!(printed, expected, "{}", input);
+            assert_eq!(
+                source(&parser::parse(&printed).unwrap()),
+                printed,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let program = parser::parse("puts(\"a\\b\", -x)").unwrap();
+        let span = |start, end, column| {
+            format!(
+                "{{\"start\":{},\"end\":{},\"line\":1,\"column\":{}}}",
+                start, end, column
+            )
+        };
+        let expected = format!(
+            "{{\"type\":\"Program\",\"statements\":[{{\"type\":\"Expression\",\
+             \"expression\":{{\"type\":\"Call\",\"function\":{{\"type\":\"Identifier\",\"name\":\"puts\"}},\
+             \"arguments\":[{{\"type\":\"String\",\"value\":\"a\\\\b\"}},\
+             {{\"type\":\"Prefix\",\"operator\":\"-\",\"right\":{{\"type\":\"Identifier\",\"name\":\"x\"}}}}],\
+             \"span\":{}}},\"span\":{}}}]}}",
+            span(0, 15, 1),
+            span(0, 15, 1)
+        );
+        assert_eq!(json(&program), expected);
+    }
+}
@@ -0,0 +1,708 @@
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::ast::Program;
+use crate::compiler::Compiler;
+use crate::evaluator::{
+    eval_with_config, Env, Environment, ErrorKind, EvalConfig, EvalError, Object,
+};
+use crate::inspect;
+use crate::macro_expansion;
+use crate::symbol::Symbol;
+use crate::vm::Vm;
+
+// How programs are run: by walking their syntax tree, or by compiling them
+// to bytecode for the VM, which with the jit feature can compile its hot
+// functions on to native code. All give the same results and errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    #[default]
+    TreeWalker,
+    Vm,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl Engine {
+    #[cfg(not(feature = "jit"))]
+    pub const ALL: [Engine; 2] = [Engine::TreeWalker, Engine::Vm];
+    #[cfg(feature = "jit")]
+    pub const ALL: [Engine; 3] = [Engine::TreeWalker, Engine::Vm, Engine::Jit];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::TreeWalker => "tree-walker",
+            Engine::Vm => "vm",
+            #[cfg(feature = "jit")]
+            Engine::Jit => "jit",
+        }
+    }
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Engine, String> {
+        Engine::ALL
+            .into_iter()
+            .find(|engine| engine.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Engine::ALL.iter().map(|engine| engine.name()).collect();
+                let (last, others) = names.split_last().unwrap();
+                format!(
+                    "unknown engine: {} (expected {} or {})",
+                    name,
+                    others.join(", "),
+                    last
+                )
+            })
+    }
+}
+
+// Runs programs one after another on the selected engine, keeping the
+// globals they define for the programs after them. The tree-walker and the
+// VM have globals of their own, so switching between them starts from an
+// empty global scope; the jit engine runs on the VM and shares its. The
+// macros are expanded before either engine sees a program, and are kept
+// across engines.
+//
+// A program that fails keeps what the statements before the failing one did,
+// and the failing statement does nothing: its let doesn't bind, whether the
+// error came from the statement itself, a call deep inside it or the limits
+// of the config. A program that doesn't compile doesn't run at all. Either
+// way the runner is ready for the next program.
+pub struct Runner {
+    engine: Engine,
+    env: Env,
+    macros: Env,
+    compiler: Compiler,
+    vm: Vm,
+}
+
+// The globals and macros of a runner at some point, to go back to later. It
+// holds copies of them, so it can be restored any number of times.
+pub struct Snapshot {
+    env: inspect::Snapshot,
+    macros: inspect::Snapshot,
+    globals: Vec<(Symbol, Rc<Object>)>,
+}
+
+impl Runner {
+    pub fn new(engine: Engine) -> Runner {
+        Runner {
+            engine,
+            env: Rc::new(RefCell::new(Environment::new())),
+            macros: Rc::new(RefCell::new(Environment::new())),
+            // the programs are compiled one at a time, and inlining needs to
+            // see the whole program
+            compiler: Compiler::new().inline_functions(false),
+            vm: Vm::new(),
+        }
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    // the globals of the current engine
+    pub fn bindings(&self) -> Vec<(Symbol, Rc<Object>)> {
+        match self.engine {
+            Engine::TreeWalker => self.env.borrow().bindings(),
+            _ => self.vm.globals(),
+        }
+    }
+
+    // the value of a global of the current engine, or else of a macro
+    pub fn lookup(&self, name: &str) -> Option<Rc<Object>> {
+        let name = Symbol::intern(name);
+        self.bindings()
+            .into_iter()
+            .find(|(binding, _)| *binding == name)
+            .map(|(_, value)| value)
+            .or_else(|| self.macros.borrow().get(&name))
+    }
+
+    // sets a global of the current engine, as if a program had defined it
+    pub fn define(&mut self, name: Symbol, value: Rc<Object>) {
+        match self.engine {
+            Engine::TreeWalker => self.env.borrow_mut().set(&name, value),
+            _ => {
+                let index = self.compiler.define_global(&name);
+                self.vm.set_global(index, name, value);
+            }
+        }
+    }
+
+    // a copy of the globals and macros of every engine, which later
+    // programs don't change
+    pub fn snapshot(&self) -> Snapshot {
+        let (names, values): (Vec<Symbol>, Vec<Rc<Object>>) = self.vm.globals().into_iter().unzip();
+        Snapshot {
+            env: inspect::Snapshot::take(&self.env),
+            macros: inspect::Snapshot::take(&self.macros),
+            globals: names
+                .into_iter()
+                .zip(inspect::copy_values(&values))
+                .collect(),
+        }
+    }
+
+    // puts back the globals and macros of every engine as they were when the
+    // snapshot was taken; the ones defined since are forgotten
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        snapshot.env.restore(&self.env);
+        snapshot.macros.restore(&self.macros);
+        self.vm.clear_globals();
+        let values: Vec<Rc<Object>> = snapshot.globals.iter().map(|(_, v)| Rc::clone(v)).collect();
+        for ((name, _), value) in snapshot.globals.iter().zip(inspect::copy_values(&values)) {
+            let index = self.compiler.define_global(name);
+            self.vm.set_global(index, name.clone(), value);
+        }
+    }
+
+    // forgets the globals and macros of every engine
+    pub fn reset(&mut self) {
+        // functions keep their environment alive, and it them
+        self.env.borrow_mut().clear();
+        *self = Runner::new(self.engine);
+    }
+
+    pub fn run(&mut self, program: Program) -> Result<Rc<Object>, EvalError> {
+        self.run_with_config(program, EvalConfig::default())
+    }
+
+    pub fn run_with_config(
+        &mut self,
+        program: Program,
+        config: EvalConfig,
+    ) -> Result<Rc<Object>, EvalError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("eval", engine = self.engine.name()).entered();
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let result = self.run_program(program, config);
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(_) => tracing::debug!(elapsed = ?start.elapsed(), "evaluated"),
+            Err(error) => tracing::debug!(elapsed = ?start.elapsed(), %error, "eval failed"),
+        }
+        result
+    }
+
+    fn run_program(
+        &mut self,
+        program: Program,
+        config: EvalConfig,
+    ) -> Result<Rc<Object>, EvalError> {
+        let mut program = program;
+        macro_expansion::define_macros(&mut program, &self.macros, &config)?;
+        macro_expansion::expand_macros(&mut program, &self.macros, &config)?;
+        match self.engine {
+            Engine::TreeWalker => eval_with_config(program, &self.env, config),
+            _ => {
+                #[cfg(feature = "jit")]
+                self.vm.use_jit(self.engine == Engine::Jit);
+                let bytecode = self
+                    .compiler
+                    .compile(&program)
+                    .map_err(|error| EvalError::new(ErrorKind::Compile, error.message))?;
+                self.vm.run_with_config(bytecode, config)
+            }
+        }
+    }
+}
+
+// what the input gives on a fresh runner of the engine, its value or its
+// error message, for the tests that check it's the same on every engine
+#[cfg(test)]
+pub(crate) fn outcome(engine: Engine, input: &str) -> String {
+    let program = crate::parser::parse(input).unwrap();
+    match Runner::new(engine).run(program) {
+        Ok(obj) => obj.to_string(),
+        Err(error) => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn assert_outcomes(tests: &[(&str, &str)]) {
+    for engine in Engine::ALL {
+        for (input, expected) in tests {
+            assert_eq!(outcome(engine, input), *expected, "{} on {}", input, engine);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_engine_names() {
+        for engine in Engine::ALL {
+            assert_eq!(engine.to_string().parse::<Engine>(), Ok(engine));
+        }
+        let expected = if cfg!(feature = "jit") {
+            "unknown engine: llvm (expected tree-walker, vm or jit)"
+        } else {
+            "unknown engine: llvm (expected tree-walker or vm)"
+        };
+        assert_eq!("llvm".parse::<Engine>(), Err(expected.to_string()));
+    }
+
+    #[test]
+    fn test_runner() {
+        let inputs = vec![
+            ("let add = fn(a, b) { a + b };", "fn"),
+            ("let x = add(1, 2);", "3"),
+            ("add(x, 10)", "13"),
+            ("y", "error: identifier not found: y"),
+        ];
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            for (input, expected) in &inputs {
+                let result = match runner.run(parser::parse(input).unwrap()) {
+                    Ok(obj) => obj.to_string(),
+                    Err(error) => format!("error: {}", error),
+                };
+                assert!(
+                    result.starts_with(expected),
+                    "{} on {}: {}",
+                    input,
+                    engine,
+                    result
+                );
+            }
+        }
+
+        // the engines don't share their globals
+        let mut runner = Runner::new(Engine::TreeWalker);
+        runner.run(parser::parse("let x = 1;").unwrap()).unwrap();
+        runner.set_engine(Engine::Vm);
+        assert_eq!(runner.engine(), Engine::Vm);
+        assert!(runner.run(parser::parse("x").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_for_loops() {
+        let tests = vec![
+            ("for (x in [1, 2]) { x }", "null"),
+            (
+                "let f = fn(xs) { for (x in xs) { if (x > 1) { return x; } }; 0 }; [f([1, 5, 9]), f([])]",
+                "[5, 0]",
+            ),
+            (
+                r#"let f = fn() { for (pair in {"a": 1, "b": 2}) { if (pair[1] == 2) { return pair[0]; } } }; f()"#,
+                "b",
+            ),
+            (
+                "let f = fn() { for (x in range(3)) { for (y in range(3)) { if (x * y == 4) { return [x, y]; } } } }; f()",
+                "[2, 2]",
+            ),
+            // a lazy pipeline over a large range only makes the values it needs
+            (
+                "let f = fn() { \
+                 for (x in filter(map(range(1000000000), fn(x) { x * 3 }), fn(x) { x % 2 == 1 })) { \
+                 if (x > 100) { return x; } } }; f()",
+                "105",
+            ),
+            (
+                "let f = fn() { for (x in map(range(3), fn(x) { 6 / (2 - x) })) { x } }; f()",
+                "division by zero: 6 / 0\n    at <anonymous> (line 1, column 16)\n    at done (line 1, column 16)\n    at f (line 1, column 73)",
+            ),
+            (
+                "try { for (x in map([0], fn(x) { 1 / x })) { x } } catch (e) { 7 }",
+                "7",
+            ),
+            (
+                "for (x in 1) { x }",
+                "argument to `iter` must be ARRAY, HASH or ITERATOR, got INTEGER\n    at iter (line 1, column 1)",
+            ),
+            ("let x = 5; for (x in [1]) { x }; x", "5"),
+        ];
+        for engine in Engine::ALL {
+            for (input, expected) in &tests {
+                let result = match Runner::new(engine).run(parser::parse(input).unwrap()) {
+                    Ok(obj) => obj.to_string(),
+                    Err(error) => {
+                        let trace = error.trace.iter().map(|frame| format!("\n    {}", frame));
+                        error.to_string() + &trace.collect::<String>()
+                    }
+                };
+                assert_eq!(result, *expected, "{} on {}", input, engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_span() {
+        let tests = vec![
+            ("let f = fn() {\n  1 / 0\n};\nf()", "1 / 0", 2),
+            ("let f = fn(x) { len(x) };\n[1, f(2)]", "len(x)", 1),
+            ("let a = [1];\nlen(a, 2)", "len(a, 2)", 2),
+        ];
+        for engine in Engine::ALL {
+            for (input, expected, line) in &tests {
+                let mut runner = Runner::new(engine);
+                let span = runner
+                    .run(parser::parse(input).unwrap())
+                    .err()
+                    .and_then(|error| error.span)
+                    .unwrap();
+                let text = &input[span.start..span.end];
+                assert_eq!(text, *expected, "{}: {}", engine, input);
+                assert_eq!(span.line, *line, "{}: {}", engine, input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_json_span() {
+        let input = "let f = fn() {\n  1 / 0\n};\nf()";
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            let error = runner.run(parser::parse(input).unwrap()).unwrap_err();
+            let json = error.json();
+            assert!(
+                json.contains(
+                    "\"span\":{\"start\":17,\"end\":22,\"line\":2,\"column\":3},\
+                     \"trace\":[{\"name\":\"f\",\"span\":{\"start\":26,\"end\":29,\"line\":4,\"column\":1}}]"
+                ),
+                "{}: {}",
+                engine,
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_location() {
+        let tests = vec![
+            ("let a = 1; let b = a / 0; let c = 3;", Some("a / 0")),
+            ("let f = fn(x) { x / 0 }; [1, f(2)]", Some("f(2)")),
+            ("let a = 1; try { a / 0 } catch (e) { e }; a", None),
+        ];
+        for engine in Engine::ALL {
+            for (input, expected) in &tests {
+                let mut runner = Runner::new(engine);
+                let location = runner
+                    .run(parser::parse(input).unwrap())
+                    .err()
+                    .and_then(|error| error.location);
+                // the tree-walker knows the statement, the VM the part of it
+                let text = location.map(|span| &input[span.start..span.end]);
+                match expected {
+                    Some(expected) => assert!(
+                        text.is_some_and(|text| text.contains(expected)),
+                        "{}: {}: {:?}",
+                        engine,
+                        input,
+                        text
+                    ),
+                    None => assert_eq!(text, None, "{}: {}", engine, input),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bindings_and_reset() {
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            runner
+                .run(parser::parse("let a = 1; let b = \"two\";").unwrap())
+                .unwrap();
+            let bindings: Vec<String> = runner
+                .bindings()
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            assert_eq!(bindings, vec!["a=1", "b=two"], "{}", engine);
+
+            runner.reset();
+            assert!(runner.bindings().is_empty());
+            assert!(runner.run(parser::parse("a").unwrap()).is_err());
+            assert_eq!(runner.engine(), engine);
+        }
+    }
+
+    #[test]
+    fn test_globals_survive_errors() {
+        let setup = "let a = 1; let f = fn(n) { if (n == 0) { 1 / 0 } else { f(n - 1) } };";
+        let tests = vec![
+            (
+                "let b = a + 1; let c = b + \"x\"; let d = 4;",
+                "type mismatch: INTEGER + STRING",
+            ),
+            (
+                "let b = a + 1; let c = f(3); let d = 4;",
+                "division by zero: 1 / 0",
+            ),
+            (
+                "let b = a + 1; let c = f(5000); let d = 4;",
+                "maximum recursion depth exceeded: 1000",
+            ),
+            ("let b = a + 1; let c = error(\"oops\"); let d = 4;", "oops"),
+        ];
+        for engine in Engine::ALL {
+            for (input, expected) in &tests {
+                let mut runner = Runner::new(engine);
+                runner.run(parser::parse(setup).unwrap()).unwrap();
+                let error = runner.run(parser::parse(input).unwrap()).unwrap_err();
+                assert_eq!(error.message, *expected, "{} {}", engine, input);
+
+                let mut names: Vec<String> = runner
+                    .bindings()
+                    .iter()
+                    .map(|(name, _)| name.to_string())
+                    .collect();
+                names.sort();
+                assert_eq!(names, ["a", "b", "f"], "{} {}", engine, input);
+                for (input, expected) in [
+                    ("c", "identifier not found: c"),
+                    ("d", "identifier not found: d"),
+                ] {
+                    let error = runner.run(parser::parse(input).unwrap()).unwrap_err();
+                    assert_eq!(error.message, expected, "{} {}", engine, input);
+                }
+                let result = runner
+                    .run(parser::parse("let c = f(0) + b; c").unwrap())
+                    .unwrap_err();
+                assert_eq!(result.message, "division by zero: 1 / 0");
+                let result = runner
+                    .run(parser::parse("let c = a + b; c").unwrap())
+                    .unwrap();
+                assert_eq!(result.to_string(), "3", "{}", engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_compile_runs_nothing() {
+        let mut runner = Runner::new(Engine::Vm);
+        runner.run(parser::parse("let a = 1;").unwrap()).unwrap();
+        let error = runner
+            .run(parser::parse("let b = 2; let g = fn() { let k = 1; quote(k) };").unwrap())
+            .unwrap_err();
+        assert_eq!(error.message, "quote is only supported by the tree-walker");
+        assert!(runner.run(parser::parse("b").unwrap()).is_err());
+        let result = runner
+            .run(parser::parse("let h = fn(x) { x + a }; h(2)").unwrap())
+            .unwrap();
+        assert_eq!(result.to_string(), "3");
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            runner
+                .run(
+                    parser::parse("let a = 1; let get = fn() { a }; let xs = iter([1, 2, 3]);")
+                        .unwrap(),
+                )
+                .unwrap();
+            let snapshot = runner.snapshot();
+            runner
+                .run(parser::parse("let a = 2; let b = 3; next(xs);").unwrap())
+                .unwrap();
+
+            runner.restore(&snapshot);
+            let result = runner
+                .run(parser::parse("[a, get(), next(xs)]").unwrap())
+                .unwrap();
+            assert_eq!(result.to_string(), "[1, 1, 1]", "{}", engine);
+            let error = runner.run(parser::parse("b").unwrap()).unwrap_err();
+            assert_eq!(error.message, "identifier not found: b", "{}", engine);
+
+            // restoring doesn't use up the snapshot, and works after a reset
+            runner.reset();
+            runner.restore(&snapshot);
+            let result = runner.run(parser::parse("[a, next(xs)]").unwrap()).unwrap();
+            assert_eq!(result.to_string(), "[1, 1]", "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_define() {
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            runner.run(parser::parse("let a = 1;").unwrap()).unwrap();
+            runner.define(Symbol::intern("b"), Rc::new(Object::Integer(2)));
+            let result = runner
+                .run(parser::parse("let f = fn() { b }; a + f()").unwrap())
+                .unwrap();
+            assert_eq!(result.to_string(), "3", "{}", engine);
+            runner.define(Symbol::intern("a"), Rc::new(Object::Integer(10)));
+            let result = runner.run(parser::parse("a + b").unwrap()).unwrap();
+            assert_eq!(result.to_string(), "12", "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_count_steps() {
+        let input =
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(10)";
+        for engine in Engine::ALL {
+            let steps = Rc::new(Cell::new(0));
+            let config = EvalConfig::default().count_steps(steps.clone());
+            Runner::new(engine)
+                .run_with_config(parser::parse(input).unwrap(), config)
+                .unwrap();
+            let steps = steps.get();
+            assert!(steps > 0, "{}", engine);
+
+            // counted the same way as fuel
+            let enough = EvalConfig::default().fuel(steps);
+            assert!(Runner::new(engine)
+                .run_with_config(parser::parse(input).unwrap(), enough)
+                .is_ok());
+            let too_little = EvalConfig::default().fuel(steps - 1);
+            assert!(Runner::new(engine)
+                .run_with_config(parser::parse(input).unwrap(), too_little)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let config = EvalConfig::default().memory_limit(100_000);
+        let tests = vec![
+            (
+                r#"let build = fn(n, s) { if (n == 0) { len(s) } else { build(n - 1, s + "abcd") } };
+                try { build(400, "") } catch (e) { 0 }"#,
+                Err("memory limit exceeded: 100000 bytes"),
+            ),
+            (
+                "let fill = fn(n, arr) { if (n == 0) { len(arr) } else { fill(n - 1, push(arr, n)) } };
+                fill(200, [])",
+                Err("memory limit exceeded: 100000 bytes"),
+            ),
+            (
+                "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(900)",
+                Err("memory limit exceeded: 100000 bytes"),
+            ),
+            // what's freed again makes room for more, so only what's held
+            // at once counts
+            (
+                "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) + 1 } }; f(500) + f(500) + f(500)",
+                Ok("1500"),
+            ),
+            (
+                "for (i in range(0, 5000)) { let xs = [i, i, i, i]; let s = \"abcdefgh\" + \"ijklmnop\"; }; 1",
+                Ok("1"),
+            ),
+            (
+                r#"let f = fn(n) { if (n == 0) { [n, "a" + "b", {1: n}] } else { f(n - 1) } }; f(10)"#,
+                Ok(r#"[0, "ab", {1: 0}]"#),
+            ),
+        ];
+        for engine in Engine::ALL {
+            for (input, expected) in &tests {
+                let result = Runner::new(engine)
+                    .run_with_config(parser::parse(input).unwrap(), config.clone());
+                let result = result.as_ref().map(|obj| obj.to_string());
+                let result = result.as_deref().map_err(|error| {
+                    assert_eq!(error.kind, ErrorKind::MemoryLimitExceeded);
+                    error.message.as_str()
+                });
+                assert_eq!(result, *expected, "{} on {}", input, engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_macros() {
+        let inputs = vec![
+            (
+                "let unless = macro(condition, consequence, alternative) {
+                    quote(if (!(unquote(condition))) { unquote(consequence) } else { unquote(alternative) });
+                };",
+                "null",
+            ),
+            (r#"unless(10 > 5, "not greater", "greater")"#, "greater"),
+            ("let twice = fn(x) { unless(false, x * 2, 0) }; twice(21)", "42"),
+            ("unless(true, 1 / 0, 2)", "2"),
+        ];
+        for engine in Engine::ALL {
+            let mut runner = Runner::new(engine);
+            for (input, expected) in &inputs {
+                let result = runner.run(parser::parse(input).unwrap()).unwrap();
+                assert_eq!(result.to_string(), *expected, "{} on {}", input, engine);
+            }
+        }
+    }
+
+    const BENCHMARKS: &[(&str, &str)] = &[
+        (
+            "fib",
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(22)",
+        ),
+        (
+            "loop",
+            "let count = fn(n, acc) { if (n == 0) { acc } else { count(n - 1, acc + n % 7) } };
+            count(900, 0)",
+        ),
+        (
+            "strings",
+            r#"let build = fn(n, s) { if (n == 0) { s } else { build(n - 1, s + "ab") } };
+            len(build(900, ""))"#,
+        ),
+        (
+            "arrays",
+            "let fill = fn(n, arr) { if (n == 0) { arr } else { fill(n - 1, push(arr, n)) } };
+            let sum = fn(arr, i, acc) { if (i == len(arr)) { acc } else { sum(arr, i + 1, acc + arr[i]) } };
+            sum(fill(500, []), 0, 0)",
+        ),
+    ];
+
+    // the fastest of a few runs, each on a fresh runner
+    fn time(engine: Engine, input: &str) -> (Duration, String) {
+        let mut best = Duration::MAX;
+        let mut result = String::new();
+        for _ in 0..5 {
+            let program = parser::parse(input).unwrap();
+            let mut runner = Runner::new(engine);
+            let start = Instant::now();
+            result = runner.run(program).unwrap().to_string();
+            best = best.min(start.elapsed());
+        }
+        (best, result)
+    }
+
+    // cargo test --release bench_engines -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_engines() {
+        println!(
+            "{:<10} {:>14} {:>14} {:>8}",
+            "benchmark", "tree-walker", "vm", "speedup"
+        );
+        for (name, input) in BENCHMARKS {
+            let (tree_walker, expected) = time(Engine::TreeWalker, input);
+            let (vm, result) = time(Engine::Vm, input);
+            assert_eq!(result, expected, "{}", name);
+            println!(
+                "{:<10} {:>14?} {:>14?} {:>7.1}x",
+                name,
+                tree_walker,
+                vm,
+                tree_walker.as_secs_f64() / vm.as_secs_f64()
+            );
+        }
+    }
+}
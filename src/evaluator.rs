@@ -1,18 +1,74 @@
 use crate::ast::*;
-use anyhow::{anyhow, Result};
-use std::{collections::HashMap, fmt::Display, rc::Rc};
+use crate::builtins::{self, Builtin, Native};
+use crate::code::Instructions;
+use crate::dump;
+use crate::fold;
+use crate::gc;
+use crate::iter::{self, Iter, Step};
+use crate::observer::{EvalObserver, Node};
+use crate::printer;
+use crate::quote;
+use crate::resolver;
+use crate::source_map::SourceMap;
+use crate::symbol::Symbol;
+use crate::task::{self, Channel};
+use crate::token::Span;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    mem, ptr,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 #[derive(Debug, PartialEq)]
 pub enum Object {
-    Integer(isize),
+    Integer(i64),
     Boolean(bool),
+    String(String),
+    Array(Vec<Rc<Object>>),
+    Hash(HashPairs),
     ReturnValue(Rc<Object>),
+    Error(EvalError),
     Function(Function),
+    Builtin(Builtin),
+    Native(Native),
+    Memoized(Memoized),
+    CompiledFunction(Rc<CompiledFunction>),
+    Closure(Closure),
+    Iterator(Iter),
+    Channel(Channel),
+    Task(task::Task),
+    // code that was quoted instead of evaluated
+    Quote(Expression),
+    Macro(Function),
     Null,
 }
 
+impl Drop for Object {
+    fn drop(&mut self) {
+        if self.size() > 0 {
+            gc::release(self);
+        }
+        // the elements that aren't held anywhere else are emptied here, one
+        // after the other, rather than each dropping its own, so that deeply
+        // nested arrays and hashes don't overflow the stack
+        let mut elements = self.take_elements();
+        while let Some(mut element) = elements.pop() {
+            if let Some(element) = Rc::get_mut(&mut element) {
+                elements.extend(element.take_elements());
+            }
+        }
+    }
+}
+
 impl Object {
-    fn is_truthy(&self) -> bool {
+    pub fn is_truthy(&self) -> bool {
         match self {
             Object::Null => false,
             Object::Boolean(value) => *value,
@@ -20,15 +76,201 @@ impl Object {
         }
     }
 
-    fn type_of(&self) -> &str {
+    pub fn type_of(&self) -> &'static str {
         match self {
             Object::Integer(_) => "INTEGER",
             Object::Boolean(_) => "BOOLEAN",
+            Object::String(_) => "STRING",
+            Object::Array(_) => "ARRAY",
+            Object::Hash(_) => "HASH",
             Object::ReturnValue(value) => value.type_of(),
+            Object::Error(_) => "ERROR",
             Object::Function(_) => "FUNCTION",
+            Object::Builtin(_) | Object::Native(_) => "BUILTIN",
+            Object::Memoized(_) => "FUNCTION",
+            Object::CompiledFunction(_) => "FUNCTION",
+            Object::Closure(_) => "FUNCTION",
+            Object::Iterator(_) => "ITERATOR",
+            Object::Channel(_) => "CHANNEL",
+            Object::Task(_) => "TASK",
+            Object::Quote(_) => "QUOTE",
+            Object::Macro(_) => "MACRO",
             Object::Null => "NULL",
         }
     }
+
+    pub fn hash_key(&self) -> Result<HashKey, EvalError> {
+        match self {
+            Object::Integer(value) => Ok(HashKey::Integer(*value)),
+            Object::Boolean(value) => Ok(HashKey::Boolean(*value)),
+            Object::String(value) => Ok(HashKey::String(value.clone())),
+            _ => Err(EvalError::new(
+                ErrorKind::Unhashable,
+                format!("unusable as hash key: {}", self.type_of()),
+            )
+            .with_types(&[self.type_of()])),
+        }
+    }
+
+    // About how many bytes the object takes on the heap, without the objects
+    // it holds, which are counted when they're made. Only strings, arrays
+    // and hashes count, as the rest are small or shared.
+    fn take_elements(&mut self) -> Vec<Rc<Object>> {
+        match self {
+            Object::Array(elements) => mem::take(elements),
+            Object::Hash(pairs) if !pairs.is_empty() => mem::take(pairs)
+                .pairs
+                .into_iter()
+                .flat_map(|(_, pair)| [pair.key, pair.value])
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let elements = match self {
+            Object::String(value) => value.len(),
+            Object::Array(elements) => elements.len() * mem::size_of::<Rc<Object>>(),
+            Object::Hash(pairs) => {
+                pairs.len() * mem::size_of::<(HashKey, HashPair, (HashKey, usize))>()
+            }
+            _ => return 0,
+        };
+        mem::size_of::<Object>() + elements
+    }
+}
+
+// The objects that can be used as hash keys, compared by value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HashKey {
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+// the original key is kept next to the value for display
+#[derive(Debug, PartialEq)]
+pub struct HashPair {
+    pub key: Rc<Object>,
+    pub value: Rc<Object>,
+}
+
+// The pairs of a hash in the order their keys were first inserted, so that
+// hashes display, convert and serialize the same way every time. Setting a
+// key that's already there replaces its value but keeps its place.
+#[derive(Debug, Default)]
+pub struct HashPairs {
+    pairs: Vec<(HashKey, HashPair)>,
+    // where each key is in the pairs
+    index: HashMap<HashKey, usize>,
+}
+
+impl HashPairs {
+    pub fn new() -> HashPairs {
+        HashPairs::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> HashPairs {
+        HashPairs {
+            pairs: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    // the pair that was replaced, if the key was there
+    pub fn insert(&mut self, key: HashKey, pair: HashPair) -> Option<HashPair> {
+        match self.index.get(&key) {
+            Some(&index) => Some(mem::replace(&mut self.pairs[index].1, pair)),
+            None => {
+                self.index.insert(key.clone(), self.pairs.len());
+                self.pairs.push((key, pair));
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &HashKey) -> Option<&HashPair> {
+        self.index.get(key).map(|&index| &self.pairs[index].1)
+    }
+
+    // the pair inserted at the position, counting from the first
+    pub fn get_index(&self, index: usize) -> Option<(&HashKey, &HashPair)> {
+        self.pairs.get(index).map(|(key, pair)| (key, pair))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&HashKey, &HashPair)> {
+        self.pairs.iter().map(|(key, pair)| (key, pair))
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &HashKey> {
+        self.pairs.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &HashPair> {
+        self.pairs.iter().map(|(_, pair)| pair)
+    }
+}
+
+// hashes with the same pairs are the same whatever order they're in
+impl PartialEq for HashPairs {
+    fn eq(&self, other: &HashPairs) -> bool {
+        self.len() == other.len() && self.iter().all(|(key, pair)| other.get(key) == Some(pair))
+    }
+}
+
+impl FromIterator<(HashKey, HashPair)> for HashPairs {
+    fn from_iter<I: IntoIterator<Item = (HashKey, HashPair)>>(iter: I) -> HashPairs {
+        let mut pairs = HashPairs::new();
+        for (key, pair) in iter {
+            pairs.insert(key, pair);
+        }
+        pairs
+    }
+}
+
+// the range of integers that are allocated once and then shared
+const SMALL_INTEGERS: std::ops::RangeInclusive<i64> = -5..=256;
+
+thread_local! {
+    // the limits of the eval that runs on this thread
+    static LIMITS: RefCell<Limits> = RefCell::new(Limits::default());
+    static TRUE: Rc<Object> = Rc::new(Object::Boolean(true));
+    static FALSE: Rc<Object> = Rc::new(Object::Boolean(false));
+    static NULL: Rc<Object> = Rc::new(Object::Null);
+    static INTEGERS: Vec<Rc<Object>> = SMALL_INTEGERS
+        .map(|value| Rc::new(Object::Integer(value)))
+        .collect();
+}
+
+// booleans and null are shared singletons rather than fresh allocations
+pub fn native_bool_to_boolean_object(value: bool) -> Rc<Object> {
+    if value {
+        TRUE.with(Rc::clone)
+    } else {
+        FALSE.with(Rc::clone)
+    }
+}
+
+pub fn null_object() -> Rc<Object> {
+    NULL.with(Rc::clone)
+}
+
+// small integers are as common as booleans in loop counters and indices
+pub fn integer_object(value: i64) -> Rc<Object> {
+    if SMALL_INTEGERS.contains(&value) {
+        let index = (value - SMALL_INTEGERS.start()) as usize;
+        INTEGERS.with(|integers| Rc::clone(&integers[index]))
+    } else {
+        Rc::new(Object::Integer(value))
+    }
 }
 
 impl Display for Object {
@@ -36,18 +278,74 @@ impl Display for Object {
         match self {
             Object::Integer(value) => write!(f, "{}", value),
             Object::Boolean(value) => write!(f, "{}", value),
+            Object::String(value) => write!(f, "{}", value),
+            Object::Array(_) | Object::Hash(_) => write!(f, "{}", printer::render(self)),
             Object::ReturnValue(value) => write!(f, "{}", value),
+            Object::Error(error) => write!(f, "ERROR: {}", error),
             Object::Function(value) => write!(f, "{}", value),
+            Object::Builtin(value) => write!(f, "{}", value),
+            Object::Native(value) => write!(f, "{}", value),
+            Object::Memoized(value) => write!(f, "memoized {}", value.function),
+            Object::CompiledFunction(value) => write!(f, "{}", value),
+            Object::Closure(value) => write!(f, "{}", value.function),
+            Object::Iterator(value) => write!(f, "{}", value),
+            Object::Channel(value) => write!(f, "{}", value),
+            Object::Task(value) => write!(f, "{}", value),
+            Object::Quote(expression) => write!(f, "QUOTE({})", expression),
+            Object::Macro(value) => write!(f, "macro{}", &value.to_string()["fn".len()..]),
             Object::Null => write!(f, "null"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
 pub struct Function {
-    parameters: Vec<String>,
-    body: Statement,
-    env: Environment,
+    parameters: Vec<Symbol>,
+    body: Rc<Statement>,
+    locals: Rc<[Symbol]>,
+    env: Env,
+}
+
+// The captured environment usually contains the function itself, so it is
+// compared by identity and left out of the Debug output.
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        self.parameters == other.parameters
+            && self.body == other.body
+            && Rc::ptr_eq(&self.env, &other.env)
+    }
+}
+
+impl Function {
+    pub fn parameters(&self) -> &[Symbol] {
+        &self.parameters
+    }
+
+    pub fn body(&self) -> &Rc<Statement> {
+        &self.body
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    // the same function closed over another environment
+    pub fn with_env(&self, env: Env) -> Function {
+        Function {
+            parameters: self.parameters.clone(),
+            body: Rc::clone(&self.body),
+            locals: Rc::clone(&self.locals),
+            env,
+        }
+    }
+}
+
+impl Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("parameters", &self.parameters)
+            .field("body", &self.body)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Display for Function {
@@ -67,441 +365,2833 @@ impl Display for Function {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// A function compiled to bytecode, as it's kept in the constants of the
+// compiled program. Its locals start with the parameters.
+#[derive(Debug)]
+pub struct CompiledFunction {
+    pub instructions: Instructions,
+    pub num_locals: usize,
+    pub parameters: Vec<Symbol>,
+    // the name it was bound to by a let statement, if any
+    pub name: Option<Symbol>,
+    pub source_map: SourceMap,
+    // the body it was compiled from, for spawn to compile it again on another
+    // thread; functions decoded from bytecode, like those of a compiled file
+    // or a task, don't have it
+    pub body: Option<Rc<Statement>>,
+}
+
+// the body isn't part of what was compiled
+impl PartialEq for CompiledFunction {
+    fn eq(&self, other: &CompiledFunction) -> bool {
+        self.instructions == other.instructions
+            && self.num_locals == other.num_locals
+            && self.parameters == other.parameters
+            && self.name == other.name
+            && self.source_map == other.source_map
+    }
+}
+
+impl Display for CompiledFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parameters: Vec<&str> = self.parameters.iter().map(|p| &**p).collect();
+        write!(f, "fn({}) {{...}}", parameters.join(", "))
+    }
+}
+
+// A compiled function as the VM creates it at runtime, with the values of
+// the variables it captured from the functions it's nested in.
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+    pub function: Rc<CompiledFunction>,
+    pub free: Vec<Rc<Object>>,
+}
+
+// A function wrapped by the memoize builtin. Calls with hashable arguments are
+// answered from the cache once the function has returned for them.
+#[derive(Debug, PartialEq)]
+pub struct Memoized {
+    function: Rc<Object>,
+    cache: RefCell<HashMap<Vec<HashKey>, Rc<Object>>>,
+}
+
+impl Memoized {
+    pub fn new(function: Rc<Object>) -> Memoized {
+        Memoized {
+            function,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn function(&self) -> &Rc<Object> {
+        &self.function
+    }
+
+    pub fn cached(&self) -> Vec<Rc<Object>> {
+        self.cache.borrow().values().cloned().collect()
+    }
+
+    pub fn lookup(&self, key: &[HashKey]) -> Option<Rc<Object>> {
+        self.cache.borrow().get(key).cloned()
+    }
+
+    pub fn remember(&self, key: Vec<HashKey>, value: Rc<Object>) {
+        self.cache.borrow_mut().insert(key, value);
+    }
+}
+
+pub type Env = Rc<RefCell<Environment>>;
+
+// Globals are kept by name in the outermost environment. The environments of
+// function calls and catch handlers keep their locals in slots assigned by the
+// resolver; a slot is empty until its let statement has run.
+#[derive(Debug, PartialEq)]
 pub struct Environment {
-    store: HashMap<String, Rc<Object>>,
-    outer: Option<Rc<Environment>>,
+    store: HashMap<Symbol, Rc<Object>>,
+    slots: Vec<Option<Rc<Object>>>,
+    // the names of the slots, only needed to look at the environment by name
+    names: Rc<[Symbol]>,
+    outer: Option<Env>,
+    // the bytes an eval counted the environment as, freed again when it's
+    // dropped
+    charged: usize,
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        gc::free(self.charged);
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Environment {
         Environment {
             store: HashMap::new(),
+            slots: Vec::new(),
+            names: Rc::default(),
             outer: None,
+            charged: 0,
         }
     }
 
-    pub fn new_enclosed(outer: Rc<Environment>) -> Environment {
+    // about how many bytes an environment with the locals takes on the heap,
+    // which is what a call frame of the VM is counted as too
+    pub fn size(locals: usize) -> usize {
+        mem::size_of::<Environment>() + locals * mem::size_of::<Option<Rc<Object>>>()
+    }
+
+    // the environment of a call or block, which the eval has counted as
+    // allocated
+    pub fn new_enclosed(
+        outer: Env,
+        slots: Vec<Option<Rc<Object>>>,
+        names: Rc<[Symbol]>,
+    ) -> Environment {
         Environment {
             store: HashMap::new(),
+            charged: Environment::size(slots.len()),
+            slots,
+            names,
             outer: Some(outer),
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<Rc<Object>> {
+    pub fn get(&self, name: &Symbol) -> Option<Rc<Object>> {
         match self.store.get(name) {
             Some(value) => Some(value.clone()),
             None => match &self.outer {
-                Some(outer) => outer.get(name),
+                Some(outer) => outer.borrow().get(name),
                 None => None,
             },
         }
     }
 
-    pub fn set(&mut self, name: &str, value: Rc<Object>) {
-        self.store.insert(name.to_string(), value);
+    pub fn set(&mut self, name: &Symbol, value: Rc<Object>) {
+        self.store.insert(name.clone(), value);
     }
-}
 
-pub fn eval(program: Program, env: &mut Environment) -> Result<Rc<Object>> {
-    eval_statements(&program.statements, env)
-}
-
-fn eval_statements(
-    statements: &[Statement],
-    env: &mut Environment,
-) -> Result<Rc<Object>, anyhow::Error> {
-    let mut result = Rc::new(Object::Null);
+    pub fn outer(&self) -> Option<&Env> {
+        self.outer.as_ref()
+    }
 
-    for statement in statements {
-        result = eval_statement(statement, env)?;
+    // every value bound in this environment, not including outer ones
+    pub fn values(&self) -> impl Iterator<Item = &Rc<Object>> {
+        self.store.values().chain(self.slots.iter().flatten())
+    }
 
-        match &*result {
-            Object::ReturnValue(obj) => return Ok(obj.clone()),
-            _ => {}
+    // a copy with every value passed through `copy` and the given outer
+    // environment
+    pub fn copy_with(
+        &self,
+        mut copy: impl FnMut(&Rc<Object>) -> Rc<Object>,
+        outer: Option<Env>,
+    ) -> Environment {
+        Environment {
+            store: self
+                .store
+                .iter()
+                .map(|(name, value)| (name.clone(), copy(value)))
+                .collect(),
+            slots: self
+                .slots
+                .iter()
+                .map(|value| value.as_ref().map(&mut copy))
+                .collect(),
+            names: Rc::clone(&self.names),
+            outer,
+            charged: 0,
         }
     }
 
-    Ok(result)
-}
+    // the names and values bound in this environment, globals sorted by name
+    // and locals in slot order
+    pub fn bindings(&self) -> Vec<(Symbol, Rc<Object>)> {
+        let mut globals: Vec<_> = self.store.iter().collect();
+        globals.sort_by(|a, b| str::cmp(a.0, b.0));
+        let locals = self.names.iter().zip(&self.slots);
+        globals
+            .into_iter()
+            .map(|(name, value)| (name.clone(), Rc::clone(value)))
+            .chain(
+                locals.filter_map(|(name, value)| Some((name.clone(), Rc::clone(value.as_ref()?)))),
+            )
+            .collect()
+    }
 
-fn eval_block_statement(
-    statements: &[Statement],
-    env: &mut Environment,
-) -> Result<Rc<Object>, anyhow::Error> {
-    let mut result = Rc::new(Object::Null);
+    // the slot names of every local environment in the chain, outermost first
+    fn scopes(env: &Env) -> Vec<Rc<[Symbol]>> {
+        let mut scopes = Vec::new();
+        let mut env = Rc::clone(env);
+        loop {
+            let outer = match env.borrow().outer() {
+                Some(outer) => Rc::clone(outer),
+                None => break,
+            };
+            scopes.push(Rc::clone(&env.borrow().names));
+            env = outer;
+        }
+        scopes.reverse();
+        scopes
+    }
 
-    for statement in statements {
-        result = eval_statement(statement, env)?;
+    // the locals that are set in every local environment in the chain, the
+    // innermost one of each name
+    fn locals(env: &Env) -> Vec<(Symbol, Rc<Object>)> {
+        let mut locals: Vec<(Symbol, Rc<Object>)> = Vec::new();
+        let mut env = Rc::clone(env);
+        loop {
+            let outer = match env.borrow().outer() {
+                Some(outer) => Rc::clone(outer),
+                None => break,
+            };
+            for (name, value) in env.borrow().bindings() {
+                if !locals.iter().any(|(local, _)| *local == name) {
+                    locals.push((name, value));
+                }
+            }
+            env = outer;
+        }
+        locals
+    }
 
-        match &*result {
-            Object::ReturnValue(_) => return Ok(result.into()),
-            _ => {}
+    // the globals, which are in the outermost environment of the chain
+    fn globals(env: &Env) -> Vec<(Symbol, Rc<Object>)> {
+        match env.borrow().outer() {
+            Some(outer) => Environment::globals(outer),
+            None => env.borrow().bindings(),
         }
     }
 
-    Ok(result.into())
-}
+    // drops everything, used to break reference cycles
+    pub fn clear(&mut self) {
+        self.store.clear();
+        self.slots.clear();
+        self.outer = None;
+    }
 
-fn eval_statement(
-    statement: &Statement,
-    env: &mut Environment,
-) -> Result<Rc<Object>, anyhow::Error> {
-    match statement {
-        Statement::LetStatement { name, value } => {
-            let obj = eval_expression(value, env)?;
-            env.set(name, obj.clone());
-            Ok(obj)
+    fn get_slot(&self, depth: usize, index: usize) -> Option<Rc<Object>> {
+        if depth > 0 {
+            return self.outer.as_ref()?.borrow().get_slot(depth - 1, index);
         }
-        Statement::ExpressionStatement(expression) => eval_expression(expression, env),
-        Statement::BlockStatement(statements) => eval_block_statement(statements, env),
-        Statement::ReturnStatement(value) => {
-            let obj = eval_expression(value, env)?;
-            Ok(Object::ReturnValue(obj).into())
+        self.slots.get(index).cloned().flatten()
+    }
+
+    fn set_slot(&mut self, index: usize, value: Rc<Object>) {
+        if index >= self.slots.len() {
+            self.slots.resize(index + 1, None);
         }
+        self.slots[index] = Some(value);
     }
 }
 
-fn eval_expression(
-    expression: &Expression,
-    env: &mut Environment,
-) -> Result<Rc<Object>, anyhow::Error> {
-    match expression {
-        Expression::IntegerLiteral(value) => Ok(Rc::new(Object::Integer(*value))),
-        Expression::BooleanLiteral(value) => Ok(Rc::new(Object::Boolean(*value))),
-        Expression::Prefix(operator, right) => {
-            let right = eval_expression(right, env)?;
-            eval_prefix_expression(&operator, right)
-        }
-        Expression::Infix(operator, left, right) => {
-            let left = eval_expression(&left, env)?;
-            let right = eval_expression(&right, env)?;
-            eval_infix_expression(&operator, &left, &right)
-        }
-        Expression::If {
-            condition,
-            consequence,
-            alternative,
-        } => {
-            let condition = eval_expression(&condition, env)?;
-            if condition.is_truthy() {
-                eval_statement(&consequence, env)
-            } else if let Some(alternative) = alternative {
-                eval_statement(&alternative, env)
-            } else {
-                Ok(Object::Null.into())
-            }
-        }
-        Expression::Identifier(name) => match env.get(name) {
-            Some(value) => Ok(value),
-            None => Err(anyhow!("identifier not found: {}", name)),
-        },
-        Expression::FunctionLiteral { parameters, body } => {
-            // TODO: Clone is not efficient
-            let func = Function {
-                parameters: parameters.clone(),
-                body: *body.clone(),
-                env: env.clone(),
-            };
-            Ok(Object::Function(func).into())
-        }
-        Expression::Call {
-            function,
-            arguments,
-        } => {
-            let func = eval_expression(function, env)?;
-            let args = eval_expressions(arguments, env)?;
-            apply_function(func, args)
-        }
+// A function call in progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub name: Symbol,
+    pub span: Span,
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {} ({})", self.name, self.span)
     }
 }
 
-fn eval_expressions(
-    expressions: &[Expression],
-    env: &mut Environment,
-) -> Result<Vec<Rc<Object>>, anyhow::Error> {
-    let mut result = Vec::new();
+// A function call that was in progress when a runtime error occurred. The
+// name is text rather than a symbol, which belongs to the thread it was
+// interned on, so that the error can go to other threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub name: String,
+    pub span: Span,
+}
 
-    for expression in expressions {
-        let evaluated = eval_expression(expression, env)?;
-        result.push(evaluated);
+impl From<&Frame> for TraceFrame {
+    fn from(frame: &Frame) -> TraceFrame {
+        TraceFrame {
+            name: frame.name.to_string(),
+            span: frame.span,
+        }
     }
+}
 
-    Ok(result)
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {} ({})", self.name, self.span)
+    }
 }
 
-fn apply_function(func: Rc<Object>, args: Vec<Rc<Object>>) -> Result<Rc<Object>, anyhow::Error> {
-    match &*func {
-        Object::Function(function) => {
-            let mut extended_env = Environment::new_enclosed(function.env.clone().into());
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    IdentifierNotFound,
+    TypeMismatch,
+    UnknownOperator,
+    DivisionByZero,
+    IntegerOverflow,
+    NotAFunction,
+    Unhashable,
+    WrongArguments,
+    RecursionLimit,
+    FuelExhausted,
+    Timeout,
+    // cancelled through a CancelHandle, from outside the program
+    Cancelled,
+    MemoryLimitExceeded,
+    // a builtin needed a capability that the sandbox denies
+    PermissionDenied,
+    // reading or writing outside of the interpreter failed
+    Io,
+    // raised by the program itself through the error builtin
+    User,
+    // a failed assert or assert_eq
+    Assertion,
+    // the bytecode engine couldn't compile the program
+    Compile,
+    // a task that spawn started panicked
+    TaskPanicked,
+    // the thread of a SharedInterpreter stopped, as something on it panicked
+    InterpreterStopped,
+}
 
-            for (param, arg) in function.parameters.iter().zip(args) {
-                extended_env.set(param, arg);
-            }
+impl ErrorKind {
+    // running out of fuel, time or memory, or being cancelled, must stop
+    // the program, so try/catch can't intercept those
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            ErrorKind::FuelExhausted
+                | ErrorKind::Timeout
+                | ErrorKind::Cancelled
+                | ErrorKind::MemoryLimitExceeded
+        )
+    }
 
-            let evaluated = eval_statement(&function.body, &mut extended_env)?;
-            match &*evaluated {
-                Object::ReturnValue(value) => Ok(value.clone()),
-                _ => Ok(evaluated),
-            }
+    // a code for the kind of error that stays the same between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::IdentifierNotFound => "E0201",
+            ErrorKind::TypeMismatch => "E0202",
+            ErrorKind::UnknownOperator => "E0203",
+            ErrorKind::DivisionByZero => "E0204",
+            ErrorKind::IntegerOverflow => "E0205",
+            ErrorKind::NotAFunction => "E0206",
+            ErrorKind::Unhashable => "E0207",
+            ErrorKind::WrongArguments => "E0208",
+            ErrorKind::RecursionLimit => "E0209",
+            ErrorKind::FuelExhausted => "E0210",
+            ErrorKind::Timeout => "E0211",
+            ErrorKind::Cancelled => "E0212",
+            ErrorKind::MemoryLimitExceeded => "E0213",
+            ErrorKind::PermissionDenied => "E0214",
+            ErrorKind::Io => "E0215",
+            ErrorKind::User => "E0216",
+            ErrorKind::Assertion => "E0217",
+            ErrorKind::Compile => "E0218",
+            ErrorKind::TaskPanicked => "E0219",
+            ErrorKind::InterpreterStopped => "E0220",
         }
-        _ => Err(anyhow!("not a function: {}", func)),
     }
 }
 
-fn eval_prefix_expression(
-    operator: &Prefix,
-    right: Rc<Object>,
-) -> Result<Rc<Object>, anyhow::Error> {
-    match operator {
-        Prefix::BANG => eval_bang_operator_expression(right),
-        Prefix::MINUS => eval_minus_prefix_operator_expression(right),
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::IdentifierNotFound => "IDENTIFIER_NOT_FOUND",
+            ErrorKind::TypeMismatch => "TYPE_MISMATCH",
+            ErrorKind::UnknownOperator => "UNKNOWN_OPERATOR",
+            ErrorKind::DivisionByZero => "DIVISION_BY_ZERO",
+            ErrorKind::IntegerOverflow => "INTEGER_OVERFLOW",
+            ErrorKind::NotAFunction => "NOT_A_FUNCTION",
+            ErrorKind::Unhashable => "UNHASHABLE",
+            ErrorKind::WrongArguments => "WRONG_ARGUMENTS",
+            ErrorKind::RecursionLimit => "RECURSION_LIMIT",
+            ErrorKind::FuelExhausted => "FUEL_EXHAUSTED",
+            ErrorKind::Timeout => "TIMEOUT",
+            ErrorKind::Cancelled => "CANCELLED",
+            ErrorKind::MemoryLimitExceeded => "MEMORY_LIMIT_EXCEEDED",
+            ErrorKind::PermissionDenied => "PERMISSION_DENIED",
+            ErrorKind::Io => "IO",
+            ErrorKind::User => "USER",
+            ErrorKind::Assertion => "ASSERTION",
+            ErrorKind::Compile => "COMPILE",
+            ErrorKind::TaskPanicked => "TASK_PANICKED",
+            ErrorKind::InterpreterStopped => "INTERPRETER_STOPPED",
+        };
+        write!(f, "{}", name)
     }
 }
 
-fn eval_bang_operator_expression(right: Rc<Object>) -> Result<Rc<Object>, anyhow::Error> {
-    match &*right {
-        Object::Boolean(value) => Ok(Object::Boolean(!value).into()),
-        Object::Null => Ok(Object::Boolean(true).into()),
-        _ => Ok(Object::Boolean(false).into()),
-    }
+// A runtime error. Inside the program it is a value that try/catch binds to
+// a name; outside it is what eval returns. The trace lists the calls that
+// were in progress when it was raised, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub trace: Vec<TraceFrame>,
+    // the types of the values the error is about, like the operands of a
+    // type mismatch, for hosts that show them apart from the message
+    pub types: Vec<&'static str>,
+    // where it was raised: the innermost call or statement that was running,
+    // which is inside the body of a function when that's where it failed
+    pub span: Option<Span>,
+    // where in the top level of the program it was raised, within the
+    // statement that was running, when nothing caught it
+    pub location: Option<Span>,
 }
 
-fn eval_minus_prefix_operator_expression(right: Rc<Object>) -> Result<Rc<Object>, anyhow::Error> {
-    match &*right {
-        Object::Integer(value) => Ok(Object::Integer(-value).into()),
-        _ => Err(anyhow!("unknown operator: -{}", right.type_of())),
+impl EvalError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        EvalError {
+            kind,
+            message: message.into(),
+            trace: Vec::new(),
+            types: Vec::new(),
+            span: None,
+            location: None,
+        }
     }
-}
 
-fn eval_infix_expression(
-    operator: &Infix,
-    left: &Object,
-    right: &Object,
-) -> Result<Rc<Object>, anyhow::Error> {
-    if left.type_of() != right.type_of() {
-        return Err(anyhow!(
-            "type mismatch: {} {} {}",
-            left.type_of(),
-            operator,
-            right.type_of()
-        ));
+    pub fn with_types(mut self, types: &[&'static str]) -> Self {
+        self.types = types.to_vec();
+        self
     }
 
-    match (operator, left, right) {
-        (_, Object::Integer(left), Object::Integer(right)) => {
-            eval_integer_infix_expression(operator, *left, *right)
-        }
-        (Infix::EQ, left, right) => Ok(Object::Boolean(left == right).into()),
-        (Infix::NOT_EQ, left, right) => Ok(Object::Boolean(left != right).into()),
-        _ => Err(anyhow!(
-            "unknown operator: {} {} {}",
-            left.type_of(),
-            operator,
-            right.type_of()
-        )),
+    // The error as JSON, for hosts that show it in their own way:
+    //
+    //   {"kind":"TYPE_MISMATCH","code":"E0202","message":"type mismatch: ...",
+    //    "span":{...},"trace":[{"name":"f","span":{...}}],"types":["INTEGER","STRING"]}
+    //
+    // The span is null when the error wasn't raised in the program's code.
+    pub fn json(&self) -> String {
+        let span = match self.span {
+            Some(span) => dump::span_json(&span),
+            None => String::from("null"),
+        };
+        let trace = self.trace.iter().map(|frame| {
+            dump::object(&[
+                ("name", dump::string(&frame.name)),
+                ("span", dump::span_json(&frame.span)),
+            ])
+        });
+        let types = self.types.iter().map(|name| dump::string(name));
+        dump::object(&[
+            ("kind", dump::string(&self.kind.to_string())),
+            ("code", dump::string(self.kind.code())),
+            ("message", dump::string(&self.message)),
+            ("span", span),
+            ("trace", dump::array(trace)),
+            ("types", dump::array(types)),
+        ])
     }
 }
 
-fn eval_integer_infix_expression(
-    operator: &Infix,
-    left: isize,
-    right: isize,
-) -> Result<Rc<Object>, anyhow::Error> {
-    match operator {
-        Infix::PLUS => Ok(Object::Integer(left + right).into()),
-        Infix::MINUS => Ok(Object::Integer(left - right).into()),
-        Infix::ASTERISK => Ok(Object::Integer(left * right).into()),
-        Infix::SLASH => Ok(Object::Integer(left / right).into()),
-        Infix::LT => Ok(Object::Boolean(left < right).into()),
-        Infix::GT => Ok(Object::Boolean(left > right).into()),
-        Infix::EQ => Ok(Object::Boolean(left == right).into()),
-        Infix::NOT_EQ => Ok(Object::Boolean(left != right).into()),
+impl Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
+impl std::error::Error for EvalError {}
 
-    #[test]
-    fn test_eval_integer_expression() {
-        let tests = vec![
-            ("5", 5),
-            ("10", 10),
-            ("-5", -5),
-            ("-10", -10),
-            ("5 + 5 + 5 + 5 - 10", 10),
-            ("2 * 2 * 2 * 2 * 2", 32),
-            ("-50 + 100 + -50", 0),
-            ("5 * 2 + 10", 20),
-            ("5 + 2 * 10", 25),
-            ("20 + 2 * -10", 0),
-            ("50 / 2 * 2 + 10", 60),
-            ("2 * (5 + 10)", 30),
-            ("3 * 3 * 3 + 10", 37),
-            ("3 * (3 * 3) + 10", 37),
-            ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
-        ];
+const DEFAULT_MAX_DEPTH: usize = 1000;
+// how many steps are evaluated between two checks of the clock
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
 
-        for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
-            test_integer_object(evaluated, expected);
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalConfig {
+    max_depth: usize,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    // the deadline of the eval a task was spawned by, instead of one from now
+    deadline: Option<Instant>,
+    memory_limit: Option<usize>,
+    strict_booleans: bool,
+    fold_constants: bool,
+    observers: Vec<Observer>,
+    steps: Option<Rc<Cell<u64>>>,
+    cancel: Option<CancelHandle>,
+    metrics: Option<Rc<Cell<Metrics>>>,
+}
+
+impl Default for EvalConfig {
+    fn default() -> Self {
+        EvalConfig {
+            max_depth: DEFAULT_MAX_DEPTH,
+            fuel: None,
+            timeout: None,
+            deadline: None,
+            memory_limit: None,
+            strict_booleans: false,
+            fold_constants: true,
+            observers: Vec::new(),
+            steps: None,
+            cancel: None,
+            metrics: None,
         }
     }
+}
 
-    #[test]
-    fn test_eval_boolean_expression() {
-        let tests = vec![
-            ("true", true),
-            ("false", false),
-            ("1 < 2", true),
-            ("1 > 2", false),
-            ("1 < 1", false),
-            ("1 > 1", false),
-            ("1 == 1", true),
-            ("1 != 1", false),
-            ("1 == 2", false),
-            ("1 != 2", true),
-            ("true == true", true),
-            ("false == false", true),
-            ("true == false", false),
-            ("true != false", true),
-            ("false != true", true),
-            ("(1 < 2) == true", true),
-            ("(1 < 2) == false", false),
-            ("(1 > 2) == true", false),
-            ("(1 > 2) == false", true),
-        ];
+impl EvalConfig {
+    // the number of nested function calls allowed before evaluation is aborted
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
 
-        for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
-            test_boolean_object(evaluated, expected);
-        }
+    // the number of statements and expressions that may be evaluated, unlimited by default
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
     }
 
-    #[test]
-    fn test_bang_operator() {
-        let tests = vec![
-            ("!true", false),
-            ("!false", true),
-            ("!5", false),
-            ("!!true", true),
-        ];
+    // wall-clock time a single eval may take, unlimited by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
-            test_boolean_object(evaluated, expected);
+    // About how many bytes of strings, arrays, hashes and environments a
+    // single eval may hold at once, unlimited by default. What it drops
+    // again doesn't count, so it's the memory in use that's limited rather
+    // than what was allocated over the eval.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    // makes if conditions and the operand of `!` errors unless they're
+    // booleans, instead of treating null and false as falsy and the rest as
+    // truthy
+    pub fn strict_booleans(mut self, strict_booleans: bool) -> Self {
+        self.strict_booleans = strict_booleans;
+        self
+    }
+
+    // whether the tree-walker folds constants before it evaluates a program,
+    // on by default; turning it off evaluates every expression as written
+    pub fn fold_constants(mut self, fold_constants: bool) -> Self {
+        self.fold_constants = fold_constants;
+        self
+    }
+
+    // adds an observer that is told about the progress of every eval made
+    // with this config, e.g. a Profiler or a Debugger
+    pub fn observe(mut self, observer: impl EvalObserver + 'static) -> Self {
+        self.observers
+            .push(Observer(Rc::new(RefCell::new(observer))));
+        self
+    }
+
+    // adds the number of steps every eval made with this config takes, the
+    // same ones that use up fuel, to the counter
+    pub fn count_steps(mut self, steps: Rc<Cell<u64>>) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    // lets another thread stop the evals made with this config through the
+    // handle
+    pub fn cancel_with(mut self, cancel: CancelHandle) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    // sets the metrics to those of every eval made with this config once it
+    // finishes, whether it fails or not
+    pub fn record_metrics(mut self, metrics: Rc<Cell<Metrics>>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+// What one eval did, to tell what code costs. Steps and allocations are
+// counted as the budget counts them, so they differ between the engines.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    // statements and expressions the tree-walker evaluated, or instructions
+    // the VM executed
+    pub steps: u64,
+    // function and builtin calls, including those answered by a memoized
+    // function's cache
+    pub calls: u64,
+    // the objects and environments or call frames that were made, and about
+    // how many bytes they took
+    pub allocations: u64,
+    pub bytes: usize,
+    // the most calls that were in progress at once
+    pub max_depth: usize,
+    pub elapsed: Duration,
+}
+
+// A handle that stops an eval from another thread, e.g. when the user of a
+// server gives up on a script. The eval that runs when it's cancelled fails
+// with a CANCELLED error at its next step, which uses up the cancel. Run
+// through an Interpreter, a cancel while no eval runs does nothing; a config
+// made with the handle leaves it for the next eval that's made with it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl PartialEq for CancelHandle {
+    fn eq(&self, other: &CancelHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    // whether it was cancelled since the last time this was asked
+    pub(crate) fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::Relaxed)
+    }
+
+    // whether it was cancelled, leaving the cancel for the eval to take
+    #[cfg(feature = "jit")]
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// The checks both engines make while they run, so that a config means the
+// same to each of them.
+impl EvalConfig {
+    // starts counting the fuel and time of one eval
+    pub fn budget(&self) -> Budget {
+        Budget {
+            fuel: self.fuel,
+            timeout: self.timeout,
+            deadline: self
+                .deadline
+                .or_else(|| self.timeout.map(|timeout| Instant::now() + timeout)),
+            memory_limit: self.memory_limit,
+            memory: 0,
+            freed: gc::freed(),
+            steps: 0,
+            counter: self.steps.clone(),
+            cancel: self.cancel.clone(),
+            start: Instant::now(),
+            calls: 0,
+            allocations: 0,
+            max_depth: 0,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    // an error if another call would nest deeper than allowed
+    pub fn check_depth(&self, depth: usize) -> Result<(), EvalError> {
+        if depth >= self.max_depth {
+            return Err(EvalError::new(
+                ErrorKind::RecursionLimit,
+                format!("maximum recursion depth exceeded: {}", self.max_depth),
+            ));
+        }
+        Ok(())
+    }
+
+    // in the strict booleans mode, an error unless the value is a boolean
+    pub fn check_boolean(&self, value: &Object, context: &str) -> Result<(), EvalError> {
+        match value {
+            _ if !self.strict_booleans => Ok(()),
+            Object::Boolean(_) => Ok(()),
+            _ => Err(EvalError::new(
+                ErrorKind::TypeMismatch,
+                format!("{} expects a BOOLEAN, got {}", context, value.type_of()),
+            )
+            .with_types(&[value.type_of()])),
+        }
+    }
+
+    // Whether the JIT may run code with the config in its place: nothing
+    // observes the calls, and no allocations are counted against a limit.
+    #[cfg(feature = "jit")]
+    pub(crate) fn allows_native_code(&self) -> bool {
+        self.observers.is_empty() && self.memory_limit.is_none()
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn depth_limit(&self) -> usize {
+        self.max_depth
+    }
+
+    // the limits of an eval with this config and the budget
+    pub fn limits(&self, budget: &Budget) -> Limits {
+        Limits {
+            max_depth: Some(self.max_depth),
+            fuel: self.fuel,
+            timeout: self.timeout,
+            deadline: budget.deadline,
+            memory_limit: self.memory_limit,
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    pub fn notify(&self, event: impl Fn(&mut dyn EvalObserver)) {
+        for observer in &self.observers {
+            event(&mut *observer.0.borrow_mut());
+        }
+    }
+}
+
+// The fuel, time and memory one eval has left, and the metrics of what it
+// did so far.
+#[derive(Debug)]
+pub struct Budget {
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    memory_limit: Option<usize>,
+    // the bytes allocated so far, and what had been freed on the thread when
+    // the eval started
+    memory: usize,
+    freed: usize,
+    steps: u64,
+    counter: Option<Rc<Cell<u64>>>,
+    cancel: Option<CancelHandle>,
+    start: Instant,
+    calls: u64,
+    allocations: u64,
+    max_depth: usize,
+    // where the metrics go once the eval is done with the budget
+    metrics: Option<Rc<Cell<Metrics>>>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        EvalConfig::default().budget()
+    }
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set(self.metrics());
+        }
+    }
+}
+
+impl Budget {
+    // called once for every statement and expression the tree-walker
+    // evaluates, or every instruction the VM executes
+    pub fn step(&mut self) -> Result<(), EvalError> {
+        self.steps += 1;
+        if let Some(counter) = &self.counter {
+            counter.set(counter.get() + 1);
+        }
+
+        match self.fuel {
+            Some(0) => return Err(EvalError::new(ErrorKind::FuelExhausted, "fuel exhausted")),
+            Some(fuel) => self.fuel = Some(fuel - 1),
+            None => {}
+        }
+
+        if self.cancel.as_ref().is_some_and(CancelHandle::take) {
+            return Err(cancelled());
+        }
+
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(timed_out(self.timeout));
+            }
+        }
+
+        Ok(())
+    }
+
+    // counts a call, which makes the given number of calls in progress
+    pub fn call(&mut self, depth: usize) {
+        self.calls += 1;
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    // counts the bytes that were just allocated against the memory limit
+    pub fn allocate(&mut self, bytes: usize) -> Result<(), EvalError> {
+        self.allocations += 1;
+        self.memory += bytes;
+        match self.memory_limit {
+            Some(limit) if self.in_use() > limit => Err(EvalError::new(
+                ErrorKind::MemoryLimitExceeded,
+                format!("memory limit exceeded: {} bytes", limit),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // counts an object that was just made, but not one that was already
+    // there, like an element that a builtin returned
+    pub fn allocate_object(&mut self, obj: &Rc<Object>) -> Result<(), EvalError> {
+        if Rc::strong_count(obj) > 1 {
+            return Ok(());
+        }
+        let bytes = obj.size();
+        if self.memory_limit.is_some() && bytes > 0 {
+            gc::charge(obj, bytes);
+        }
+        self.allocate(bytes)
+    }
+
+    // About the bytes the eval holds: what it allocated less what was freed
+    // since it started. What it frees of the values that earlier evals made
+    // makes room for its own.
+    fn in_use(&self) -> usize {
+        let freed = gc::freed().wrapping_sub(self.freed);
+        self.memory.saturating_sub(freed)
+    }
+
+    // what native code checks itself while it runs: the fuel left, the
+    // deadline and the cancel
+    #[cfg(feature = "jit")]
+    pub(crate) fn fuel_left(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn cancel(&self) -> Option<&CancelHandle> {
+        self.cancel.as_ref()
+    }
+
+    // counts what native code did as the steps and calls it would have
+    // taken on the VM, which it made sure were within the fuel
+    #[cfg(feature = "jit")]
+    pub(crate) fn charge(&mut self, steps: u64, calls: u64, deepest: usize) {
+        self.steps += steps;
+        if let Some(counter) = &self.counter {
+            counter.set(counter.get() + steps);
+        }
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= steps;
+        }
+        self.calls += calls;
+        self.max_depth = self.max_depth.max(deepest);
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            steps: self.steps,
+            calls: self.calls,
+            allocations: self.allocations,
+            bytes: self.memory,
+            max_depth: self.max_depth,
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
+
+fn cancelled() -> EvalError {
+    EvalError::new(ErrorKind::Cancelled, "evaluation cancelled")
+}
+
+fn timed_out(timeout: Option<Duration>) -> EvalError {
+    EvalError::new(
+        ErrorKind::Timeout,
+        format!(
+            "evaluation timed out after {:?}",
+            timeout.unwrap_or_default()
+        ),
+    )
+}
+
+// The limits of an eval, for what its builtins do besides taking steps:
+// waiting for a task or a channel, which gives up once the eval runs out of
+// time or is cancelled, and spawning a task, which gets the same limits.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    max_depth: Option<usize>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    memory_limit: Option<usize>,
+    cancel: Option<CancelHandle>,
+}
+
+impl Limits {
+    // an error if the eval ran out of time or was cancelled
+    pub fn check(&self) -> Result<(), EvalError> {
+        if self.cancel.as_ref().is_some_and(CancelHandle::take) {
+            return Err(cancelled());
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(timed_out(self.timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    // The config of a task, which counts its own fuel and memory with the
+    // same limits, has to finish by the same deadline, and is cancelled
+    // through the handle.
+    pub fn config(&self, cancel: CancelHandle) -> EvalConfig {
+        let mut config = EvalConfig::default().cancel_with(cancel);
+        config.max_depth = self.max_depth.unwrap_or(config.max_depth);
+        config.fuel = self.fuel;
+        config.timeout = self.timeout;
+        config.deadline = self.deadline;
+        config.memory_limit = self.memory_limit;
+        config
+    }
+}
+
+// sets the limits of the eval that runs on this thread, returning those of
+// the one before, e.g. the eval that a macro call is expanded in
+pub fn set_limits(limits: Limits) -> Limits {
+    LIMITS.with(|current| current.replace(limits))
+}
+
+// the limits of the eval that runs on this thread
+pub fn limits() -> Limits {
+    LIMITS.with(|current| current.borrow().clone())
+}
+
+#[derive(Clone)]
+struct Observer(Rc<RefCell<dyn EvalObserver>>);
+
+impl Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+impl PartialEq for Observer {
+    fn eq(&self, other: &Observer) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+pub fn eval(program: Program, env: &Env) -> Result<Rc<Object>, EvalError> {
+    eval_with_config(program, env, EvalConfig::default())
+}
+
+pub fn eval_with_config(
+    program: Program,
+    env: &Env,
+    config: EvalConfig,
+) -> Result<Rc<Object>, EvalError> {
+    let mut program = program;
+    if config.fold_constants {
+        fold::fold_program(&mut program);
+    }
+    resolver::resolve(&mut program, &Environment::scopes(env));
+
+    let bodies = Bodies::default();
+    let mut evaluator = Evaluator::new(config, &bodies);
+    evaluator.run(&program.statements, env)
+}
+
+// calls a function from outside of any program, e.g. a macro while its calls
+// are expanded
+pub fn apply(
+    func: Rc<Object>,
+    args: Vec<Rc<Object>>,
+    config: EvalConfig,
+) -> Result<Rc<Object>, EvalError> {
+    // a macro runs like the function it looks like
+    let func = match &*func {
+        Object::Macro(function) => {
+            Object::Function(function.with_env(Rc::clone(&function.env))).into()
+        }
+        _ => func,
+    };
+    let bodies = Bodies::default();
+    let mut evaluator = Evaluator::new(config, &bodies);
+    evaluator.apply_function(func, args)?;
+    evaluator.finish()
+}
+
+// The bodies of the functions called during one eval. Entries are never
+// removed, so a body borrowed from here stays valid for as long as the store
+// itself is borrowed, even while new bodies are added.
+#[derive(Default)]
+struct Bodies {
+    pinned: RefCell<HashMap<*const Statement, Rc<Statement>>>,
+}
+
+impl Bodies {
+    fn pin(&self, body: &Rc<Statement>) -> &Statement {
+        let ptr = Rc::as_ptr(body);
+        self.pinned
+            .borrow_mut()
+            .entry(ptr)
+            .or_insert_with(|| Rc::clone(body));
+        // SAFETY: the map holds an Rc to the body and never drops it while
+        // self is alive, so the body outlives the returned borrow
+        unsafe { &*ptr }
+    }
+}
+
+// The evaluator doesn't recurse on the native stack. Work that is still to be
+// done is pushed onto `tasks`, and the objects that finished tasks produce are
+// pushed onto `values` for the tasks that need them.
+enum Task<'a> {
+    Statement(&'a Statement, Env),
+    Expression(&'a Expression, Env),
+    // runs the statement at `index` once the previous one left its value
+    Block {
+        statements: &'a [Statement],
+        index: usize,
+        env: Env,
+        top_level: bool,
+    },
+    Let(&'a Symbol, Slot, Env),
+    Return,
+    Prefix(&'a Prefix),
+    Infix(&'a Infix),
+    // collect the given number of elements or key/value pairs
+    Array(usize),
+    Hash(usize),
+    Index,
+    Branch {
+        consequence: &'a Statement,
+        alternative: Option<&'a Statement>,
+        env: Env,
+    },
+    Call {
+        name: Option<&'a Symbol>,
+        argc: usize,
+        span: Span,
+    },
+    // splices the values of the given number of unquoted expressions
+    Quote(&'a Expression, usize),
+    // the body of the innermost function call has been evaluated
+    Leave,
+    // stores the result of a memoized call under its arguments
+    Remember(Rc<Object>, Vec<HashKey>),
+    // passes the result of a call a builtin needed on to what comes after it
+    Resume(Box<dyn FnOnce(Rc<Object>) -> Step<Rc<Object>>>),
+    // a builtin that needed calls to work out its value has it
+    Returned,
+    // the next stage of a for loop, which keeps its iterator on the values
+    For(Loop<'a>, Stage),
+    // where an error raised in the body of a try expression unwinds to
+    // the handler binds the error to its first slot
+    Catch {
+        handler: &'a Statement,
+        locals: &'a Rc<[Symbol]>,
+        env: Env,
+        values: usize,
+        frames: usize,
+        depth: usize,
+    },
+}
+
+struct Loop<'a> {
+    body: &'a Statement,
+    locals: &'a Rc<[Symbol]>,
+    env: Env,
+    span: Span,
+}
+
+// What a for loop does with the value it waited for: the iterator from
+// iter(), whether it's done, its next value, and what the body run with that
+// value left.
+enum Stage {
+    Iterator,
+    Done,
+    Next,
+    Body,
+    Finished,
+}
+
+struct Evaluator<'a> {
+    config: EvalConfig,
+    bodies: &'a Bodies,
+    tasks: Vec<Task<'a>>,
+    values: Vec<Rc<Object>>,
+    depth: usize,
+    budget: Budget,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(config: EvalConfig, bodies: &'a Bodies) -> Evaluator<'a> {
+        Evaluator {
+            budget: config.budget(),
+            config,
+            bodies,
+            tasks: Vec::new(),
+            values: Vec::new(),
+            depth: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    // errors keep the trace of the innermost call they were raised in, and
+    // the span of the call that failed or else of the statement running
+    fn attach_trace(&self, mut error: EvalError, call: Option<Span>) -> EvalError {
+        if error.trace.is_empty() {
+            error.trace = self.frames.iter().rev().map(TraceFrame::from).collect();
+        }
+        if error.span.is_none() {
+            error.span = call.or_else(|| {
+                self.tasks.iter().rev().find_map(|task| match task {
+                    Task::Block {
+                        statements, index, ..
+                    } => Some(statements.get(index.checked_sub(1)?)?.span()),
+                    _ => None,
+                })
+            });
+        }
+        error
+    }
+
+    // the statement of the program's top level that's running
+    fn statement(&self) -> Option<&'a Statement> {
+        self.tasks.iter().find_map(|task| match task {
+            Task::Block {
+                statements,
+                index,
+                top_level: true,
+                ..
+            } => statements.get(index.checked_sub(1)?),
+            _ => None,
+        })
+    }
+
+    fn run(&mut self, statements: &'a [Statement], env: &Env) -> Result<Rc<Object>, EvalError> {
+        self.values.push(null_object());
+        self.tasks.push(Task::Block {
+            statements,
+            index: 0,
+            env: Rc::clone(env),
+            top_level: true,
+        });
+        self.finish()
+    }
+
+    // runs the pending tasks, which leave a single value
+    fn finish(&mut self) -> Result<Rc<Object>, EvalError> {
+        let outer = set_limits(self.config.limits(&self.budget));
+        let result = self.run_tasks();
+        set_limits(outer);
+        result
+    }
+
+    fn run_tasks(&mut self) -> Result<Rc<Object>, EvalError> {
+        while let Some(task) = self.tasks.pop() {
+            let call = match &task {
+                Task::Call { span, .. } => Some(*span),
+                _ => None,
+            };
+            if let Err(error) = self.execute(task) {
+                let location = self.statement().map(Statement::span);
+                let error = self.attach_trace(error, call);
+                self.unwind(error).map_err(|error| EvalError {
+                    location: location.or(error.location),
+                    ..error
+                })?;
+            }
+        }
+
+        Ok(self.values.pop().unwrap())
+    }
+
+    // drops pending work up to the innermost try expression and runs its
+    // handler, or gives the error back if nothing can catch it
+    fn unwind(&mut self, error: EvalError) -> Result<(), EvalError> {
+        if error.kind.is_catchable() {
+            while let Some(task) = self.tasks.pop() {
+                if let Task::Catch {
+                    handler,
+                    locals,
+                    env,
+                    values,
+                    frames,
+                    depth,
+                } = task
+                {
+                    self.values.truncate(values);
+                    self.frames.truncate(frames);
+                    self.notify(|observer| observer.on_error(&error, &self.frames));
+                    self.depth = depth;
+
+                    let error = Some(Object::Error(error).into());
+                    self.budget.allocate(Environment::size(locals.len()))?;
+                    let handler_env = gc::allocate(Environment::new_enclosed(
+                        env,
+                        vec![error],
+                        Rc::clone(locals),
+                    ));
+                    self.tasks.push(Task::Statement(handler, handler_env));
+                    return Ok(());
+                }
+            }
+        }
+
+        self.frames.clear();
+        self.notify(|observer| observer.on_error(&error, &[]));
+        Err(error)
+    }
+
+    fn notify(&self, event: impl Fn(&mut dyn EvalObserver)) {
+        self.config.notify(event);
+    }
+
+    fn enter_frame(&mut self, frame: Frame, args: &[Rc<Object>]) {
+        self.notify(|observer| observer.on_call(&frame, args));
+        self.frames.push(frame);
+    }
+
+    fn leave_frame(&mut self, value: &Rc<Object>) {
+        if let Some(frame) = self.frames.pop() {
+            self.notify(|observer| observer.on_return(&frame, value));
+        }
+    }
+
+    fn pop_value(&mut self) -> Rc<Object> {
+        self.values.pop().unwrap()
+    }
+
+    fn execute(&mut self, task: Task<'a>) -> Result<(), EvalError> {
+        match task {
+            Task::Statement(statement, env) => self.eval_statement(statement, env)?,
+            Task::Expression(expression, env) => self.eval_expression(expression, env)?,
+            Task::Block {
+                statements,
+                index,
+                env,
+                top_level,
+            } => {
+                let result = self.pop_value();
+                match &*result {
+                    Object::ReturnValue(value) if top_level => self.values.push(Rc::clone(value)),
+                    Object::ReturnValue(_) => self.values.push(result),
+                    _ if index == statements.len() => self.values.push(result),
+                    _ => {
+                        self.tasks.push(Task::Block {
+                            statements,
+                            index: index + 1,
+                            env: Rc::clone(&env),
+                            top_level,
+                        });
+                        self.tasks.push(Task::Statement(&statements[index], env));
+                    }
+                }
+            }
+            Task::Let(name, slot, env) => {
+                let obj = self.pop_value();
+                match slot {
+                    Slot::Global => env.borrow_mut().set(name, obj.clone()),
+                    Slot::Local { index, .. } => env.borrow_mut().set_slot(index, obj.clone()),
+                }
+                self.values.push(obj);
+            }
+            Task::Return => {
+                let obj = self.pop_value();
+                self.values.push(Object::ReturnValue(obj).into());
+            }
+            Task::Prefix(operator) => {
+                let right = self.pop_value();
+                if *operator == Prefix::BANG {
+                    self.config.check_boolean(&right, "!")?;
+                }
+                self.values.push(eval_prefix_expression(operator, right)?);
+            }
+            Task::Infix(operator) => {
+                let right = self.pop_value();
+                let left = self.pop_value();
+                let result = eval_infix_expression(operator, &left, &right)?;
+                self.budget.allocate_object(&result)?;
+                self.values.push(result);
+            }
+            Task::Array(len) => {
+                let elements = self.values.split_off(self.values.len() - len);
+                let array = Object::Array(elements).into();
+                self.budget.allocate_object(&array)?;
+                self.values.push(array);
+            }
+            Task::Hash(len) => {
+                let values = self.values.split_off(self.values.len() - 2 * len);
+                let mut pairs = HashPairs::with_capacity(len);
+                for pair in values.chunks_exact(2) {
+                    let (key, value) = (Rc::clone(&pair[0]), Rc::clone(&pair[1]));
+                    pairs.insert(key.hash_key()?, HashPair { key, value });
+                }
+                let hash = Object::Hash(pairs).into();
+                self.budget.allocate_object(&hash)?;
+                self.values.push(hash);
+            }
+            Task::Index => {
+                let index = self.pop_value();
+                let left = self.pop_value();
+                self.values.push(eval_index_expression(&left, &index)?);
+            }
+            Task::Branch {
+                consequence,
+                alternative,
+                env,
+            } => {
+                let condition = self.pop_value();
+                self.config.check_boolean(&condition, "if")?;
+                if condition.is_truthy() {
+                    self.tasks.push(Task::Statement(consequence, env));
+                } else if let Some(alternative) = alternative {
+                    self.tasks.push(Task::Statement(alternative, env));
+                } else {
+                    self.values.push(null_object());
+                }
+            }
+            Task::Call { name, argc, span } => {
+                self.budget.call(self.depth + 1);
+                let args = self.values.split_off(self.values.len() - argc);
+                let func = self.pop_value();
+                let name = match name {
+                    Some(name) => name.clone(),
+                    None => Symbol::intern("<anonymous>"),
+                };
+                self.enter_frame(Frame { name, span }, &args);
+                self.apply_function(func, args)?;
+            }
+            Task::Quote(expression, count) => {
+                let values = self.values.split_off(self.values.len() - count);
+                let code = quote::splice(expression, &values)?;
+                self.values.push(Object::Quote(code).into());
+            }
+            Task::Leave => {
+                self.depth -= 1;
+                let evaluated = self.pop_value();
+                let value = match &*evaluated {
+                    Object::ReturnValue(value) => Rc::clone(value),
+                    _ => evaluated,
+                };
+                self.leave_frame(&value);
+                self.values.push(value);
+            }
+            Task::Remember(memoized, key) => {
+                if let Object::Memoized(memoized) = &*memoized {
+                    let result = Rc::clone(self.values.last().unwrap());
+                    memoized.remember(key, result);
+                }
+            }
+            Task::Resume(then) => {
+                let result = self.pop_value();
+                self.resolve(then(result))?;
+            }
+            Task::Returned => {
+                let result = self.pop_value();
+                self.budget.allocate_object(&result)?;
+                self.leave_frame(&result);
+                self.values.push(result);
+            }
+            Task::For(for_loop, stage) => self.step_loop(for_loop, stage)?,
+            // the body of the try expression finished without an error
+            Task::Catch { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn step_loop(&mut self, for_loop: Loop<'a>, stage: Stage) -> Result<(), EvalError> {
+        let span = for_loop.span;
+        match stage {
+            Stage::Iterator => {
+                let iterable = self.pop_value();
+                self.tasks.push(Task::For(for_loop, Stage::Done));
+                self.call_builtin("iter", iterable, span)
+            }
+            Stage::Done => {
+                let iterator = Rc::clone(self.values.last().unwrap());
+                self.tasks.push(Task::For(for_loop, Stage::Next));
+                self.call_builtin("done", iterator, span)
+            }
+            Stage::Next => {
+                let done = self.pop_value();
+                if done.is_truthy() {
+                    self.pop_value();
+                    self.values.push(null_object());
+                    return Ok(());
+                }
+                let iterator = Rc::clone(self.values.last().unwrap());
+                self.tasks.push(Task::For(for_loop, Stage::Body));
+                self.call_builtin("next", iterator, span)
+            }
+            Stage::Body => {
+                let value = self.pop_value();
+                self.budget
+                    .allocate(Environment::size(for_loop.locals.len()))?;
+                let body_env = gc::allocate(Environment::new_enclosed(
+                    Rc::clone(&for_loop.env),
+                    vec![Some(value)],
+                    Rc::clone(for_loop.locals),
+                ));
+                let body = for_loop.body;
+                self.tasks.push(Task::For(for_loop, Stage::Finished));
+                self.tasks.push(Task::Statement(body, body_env));
+                Ok(())
+            }
+            Stage::Finished => {
+                let value = self.pop_value();
+                if let Object::ReturnValue(_) = &*value {
+                    // a return in the body leaves the loop and what it's in
+                    self.pop_value();
+                    self.values.push(value);
+                    return Ok(());
+                }
+                self.step_loop(for_loop, Stage::Done)
+            }
+        }
+    }
+
+    // calls a builtin the way a call in the program at the span would
+    fn call_builtin(&mut self, name: &str, arg: Rc<Object>, span: Span) -> Result<(), EvalError> {
+        let builtin = builtins::lookup(name).expect("not a builtin");
+        let args = vec![arg];
+        self.budget.call(self.depth + 1);
+        self.enter_frame(
+            Frame {
+                name: Symbol::intern(name),
+                span,
+            },
+            &args,
+        );
+        self.apply_function(Object::Builtin(builtin).into(), args)
+    }
+
+    // works out a value that can take calls of functions, making each call
+    // as if the builtin that needs it was the one calling
+    fn resolve(&mut self, step: Step<Rc<Object>>) -> Result<(), EvalError> {
+        match step {
+            Step::Value(value) => self.values.push(value),
+            Step::Call(function, arg, then) => {
+                self.tasks.push(Task::Resume(then));
+                self.budget.call(self.depth + 1);
+                let span = self
+                    .frames
+                    .last()
+                    .map(|frame| frame.span)
+                    .unwrap_or_default();
+                let args = vec![arg];
+                self.enter_frame(
+                    Frame {
+                        name: Symbol::intern("<anonymous>"),
+                        span,
+                    },
+                    &args,
+                );
+                self.apply_function(function, args)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_statement(&mut self, statement: &'a Statement, env: Env) -> Result<(), EvalError> {
+        self.budget.step()?;
+        self.notify(|observer| {
+            observer.on_enter_node(Node::Statement(statement), &env, &self.frames)
+        });
+        match statement {
+            Statement::LetStatement {
+                name, slot, value, ..
+            } => {
+                self.tasks.push(Task::Let(name, *slot, Rc::clone(&env)));
+                self.tasks.push(Task::Expression(value, env));
+            }
+            Statement::ExpressionStatement(expression, _) => {
+                self.tasks.push(Task::Expression(expression, env));
+            }
+            Statement::BlockStatement(statements, _) => {
+                self.values.push(null_object());
+                self.tasks.push(Task::Block {
+                    statements,
+                    index: 0,
+                    env,
+                    top_level: false,
+                });
+            }
+            Statement::ReturnStatement(value, _) => {
+                self.tasks.push(Task::Return);
+                self.tasks.push(Task::Expression(value, env));
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_expression(&mut self, expression: &'a Expression, env: Env) -> Result<(), EvalError> {
+        self.budget.step()?;
+        self.notify(|observer| {
+            observer.on_enter_node(Node::Expression(expression), &env, &self.frames)
+        });
+        match expression {
+            Expression::IntegerLiteral(value) => {
+                self.values.push(integer_object(*value));
+            }
+            Expression::BooleanLiteral(value) => {
+                self.values.push(native_bool_to_boolean_object(*value));
+            }
+            Expression::StringLiteral(value) => {
+                self.values.push(Object::String(value.clone()).into());
+            }
+            Expression::ArrayLiteral(elements) => {
+                self.tasks.push(Task::Array(elements.len()));
+                for element in elements.iter().rev() {
+                    self.tasks.push(Task::Expression(element, Rc::clone(&env)));
+                }
+            }
+            Expression::HashLiteral(pairs, _) => {
+                self.tasks.push(Task::Hash(pairs.len()));
+                for (key, value) in pairs.iter().rev() {
+                    self.tasks.push(Task::Expression(value, Rc::clone(&env)));
+                    self.tasks.push(Task::Expression(key, Rc::clone(&env)));
+                }
+            }
+            Expression::Index { left, index, .. } => {
+                self.tasks.push(Task::Index);
+                self.tasks.push(Task::Expression(index, Rc::clone(&env)));
+                self.tasks.push(Task::Expression(left, env));
+            }
+            Expression::Prefix(operator, right) => {
+                self.tasks.push(Task::Prefix(operator));
+                self.tasks.push(Task::Expression(right, env));
+            }
+            Expression::Infix(operator, left, right) => {
+                self.tasks.push(Task::Infix(operator));
+                self.tasks.push(Task::Expression(right, Rc::clone(&env)));
+                self.tasks.push(Task::Expression(left, env));
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.tasks.push(Task::Branch {
+                    consequence,
+                    alternative: alternative.as_deref(),
+                    env: Rc::clone(&env),
+                });
+                self.tasks.push(Task::Expression(condition, env));
+            }
+            Expression::Try {
+                body,
+                handler,
+                locals,
+                ..
+            } => {
+                self.tasks.push(Task::Catch {
+                    handler,
+                    locals,
+                    env: Rc::clone(&env),
+                    values: self.values.len(),
+                    frames: self.frames.len(),
+                    depth: self.depth,
+                });
+                self.tasks.push(Task::Statement(body, env));
+            }
+            Expression::For {
+                iterable,
+                body,
+                locals,
+                span,
+                ..
+            } => {
+                let for_loop = Loop {
+                    body,
+                    locals,
+                    env: Rc::clone(&env),
+                    span: *span,
+                };
+                self.tasks.push(Task::For(for_loop, Stage::Iterator));
+                self.tasks.push(Task::Expression(iterable, env));
+            }
+            Expression::Identifier(name, slot) => {
+                self.values.push(eval_identifier(name, *slot, &env)?);
+            }
+            Expression::FunctionLiteral {
+                parameters,
+                body,
+                locals,
+                ..
+            } => {
+                let func = Function {
+                    parameters: parameters.clone(),
+                    body: Rc::clone(body),
+                    locals: Rc::clone(locals),
+                    env,
+                };
+                self.values.push(Object::Function(func).into());
+            }
+            Expression::MacroLiteral {
+                parameters,
+                body,
+                locals,
+                ..
+            } => {
+                let func = Function {
+                    parameters: parameters.clone(),
+                    body: Rc::clone(body),
+                    locals: Rc::clone(locals),
+                    env,
+                };
+                self.values.push(Object::Macro(func).into());
+            }
+            Expression::Call { .. } if quote::special_form(expression, quote::QUOTE).is_some() => {
+                let quoted = quote::special_form(expression, quote::QUOTE).unwrap();
+                let mut unquoted = Vec::new();
+                quote::unquoted(quoted, &mut unquoted);
+                self.tasks.push(Task::Quote(quoted, unquoted.len()));
+                for argument in unquoted.into_iter().rev() {
+                    self.tasks.push(Task::Expression(argument, Rc::clone(&env)));
+                }
+            }
+            // builtins don't get the environment they're called in
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } if arguments.is_empty() && names_builtin(function, builtins::LOCALS, &env) => {
+                let locals = builtins::variables(Environment::locals(&env));
+                self.budget.allocate_object(&locals)?;
+                self.values.push(locals);
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } if arguments.is_empty() && names_builtin(function, builtins::GLOBALS, &env) => {
+                let globals = builtins::variables(Environment::globals(&env));
+                self.budget.allocate_object(&globals)?;
+                self.values.push(globals);
+            }
+            Expression::Call {
+                function,
+                arguments,
+                span,
+            } => {
+                let name = match function.as_ref() {
+                    Expression::Identifier(name, _) => Some(name),
+                    _ => None,
+                };
+                self.tasks.push(Task::Call {
+                    name,
+                    argc: arguments.len(),
+                    span: *span,
+                });
+                for argument in arguments.iter().rev() {
+                    self.tasks.push(Task::Expression(argument, Rc::clone(&env)));
+                }
+                self.tasks.push(Task::Expression(function, env));
+            }
+        }
+        Ok(())
+    }
+
+    // expects the frame of the call to be pushed already
+    fn apply_function(&mut self, func: Rc<Object>, args: Vec<Rc<Object>>) -> Result<(), EvalError> {
+        match &*func {
+            Object::Function(function) => {
+                self.config.check_depth(self.depth)?;
+
+                let slots = args.into_iter().map(Some).collect();
+                self.budget
+                    .allocate(Environment::size(function.locals.len()))?;
+                gc::maybe_collect();
+                let extended_env = gc::allocate(Environment::new_enclosed(
+                    Rc::clone(&function.env),
+                    slots,
+                    Rc::clone(&function.locals),
+                ));
+
+                self.depth += 1;
+                self.tasks.push(Task::Leave);
+                self.tasks.push(Task::Statement(
+                    self.bodies.pin(&function.body),
+                    extended_env,
+                ));
+                Ok(())
+            }
+            Object::Builtin(_) | Object::Native(_) => {
+                if let Object::Builtin(builtin) = &*func {
+                    if let Some(step) = iter::step(builtin.name, &args) {
+                        self.tasks.push(Task::Returned);
+                        return self.resolve(step);
+                    }
+                }
+                let result = match &*func {
+                    Object::Builtin(builtin) => (builtin.func)(&args)?,
+                    Object::Native(native) => (native.func)(&args)?,
+                    _ => unreachable!(),
+                };
+                self.budget.allocate_object(&result)?;
+                self.leave_frame(&result);
+                self.values.push(result);
+                Ok(())
+            }
+            Object::Memoized(memoized) => {
+                let key = args
+                    .iter()
+                    .map(|arg| arg.hash_key())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let cached = memoized.lookup(&key);
+                if let Some(result) = cached {
+                    self.leave_frame(&result);
+                    self.values.push(result);
+                    return Ok(());
+                }
+                self.tasks.push(Task::Remember(Rc::clone(&func), key));
+                self.apply_function(Rc::clone(&memoized.function), args)
+            }
+            _ => Err(
+                EvalError::new(ErrorKind::NotAFunction, format!("not a function: {}", func))
+                    .with_types(&[func.type_of()]),
+            ),
+        }
+    }
+}
+
+// whether the expression is the name of the builtin, not of a variable
+// shadowing it
+fn names_builtin(expression: &Expression, builtin: &str, env: &Env) -> bool {
+    match expression {
+        Expression::Identifier(name, Slot::Global) if *name == builtin => {
+            env.borrow().get(name).is_none()
+        }
+        _ => false,
+    }
+}
+
+fn eval_identifier(name: &Symbol, slot: Slot, env: &Env) -> Result<Rc<Object>, EvalError> {
+    let value = match slot {
+        Slot::Global => env.borrow().get(name),
+        Slot::Local { depth, index } => env.borrow().get_slot(depth, index),
+    };
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    // builtins can be shadowed by locals, but an unset local doesn't fall back
+    // to them
+    match (slot, builtins::lookup(name)) {
+        (Slot::Global, Some(builtin)) => Ok(Object::Builtin(builtin).into()),
+        _ => Err(EvalError::new(
+            ErrorKind::IdentifierNotFound,
+            format!("identifier not found: {}", name),
+        )),
+    }
+}
+
+pub fn eval_prefix_expression(
+    operator: &Prefix,
+    right: Rc<Object>,
+) -> Result<Rc<Object>, EvalError> {
+    match operator {
+        Prefix::BANG => eval_bang_operator_expression(right),
+        Prefix::MINUS => eval_minus_prefix_operator_expression(right),
+    }
+}
+
+fn eval_bang_operator_expression(right: Rc<Object>) -> Result<Rc<Object>, EvalError> {
+    match &*right {
+        Object::Boolean(value) => Ok(native_bool_to_boolean_object(!value)),
+        Object::Null => Ok(native_bool_to_boolean_object(true)),
+        _ => Ok(native_bool_to_boolean_object(false)),
+    }
+}
+
+fn eval_minus_prefix_operator_expression(right: Rc<Object>) -> Result<Rc<Object>, EvalError> {
+    match &*right {
+        Object::Integer(value) => value.checked_neg().map(integer_object).ok_or_else(|| {
+            EvalError::new(
+                ErrorKind::IntegerOverflow,
+                format!("integer overflow: -({})", value),
+            )
+        }),
+        _ => Err(EvalError::new(
+            ErrorKind::UnknownOperator,
+            format!("unknown operator: -{}", right.type_of()),
+        )
+        .with_types(&[right.type_of()])),
+    }
+}
+
+pub fn eval_infix_expression(
+    operator: &Infix,
+    left: &Object,
+    right: &Object,
+) -> Result<Rc<Object>, EvalError> {
+    // values of different types are unequal rather than a mismatch
+    match operator {
+        Infix::EQ => return Ok(native_bool_to_boolean_object(equals(left, right))),
+        Infix::NOT_EQ => return Ok(native_bool_to_boolean_object(!equals(left, right))),
+        _ => {}
+    }
+    if left.type_of() != right.type_of() {
+        return Err(EvalError::new(
+            ErrorKind::TypeMismatch,
+            format!(
+                "type mismatch: {} {} {}",
+                left.type_of(),
+                operator,
+                right.type_of()
+            ),
+        )
+        .with_types(&[left.type_of(), right.type_of()]));
+    }
+
+    match (operator, left, right) {
+        (_, Object::Integer(left), Object::Integer(right)) => {
+            eval_integer_infix_expression(operator, *left, *right)
+        }
+        (Infix::PLUS, Object::String(left), Object::String(right)) => {
+            Ok(Object::String(format!("{}{}", left, right)).into())
+        }
+        (Infix::LT, Object::String(left), Object::String(right)) => {
+            Ok(native_bool_to_boolean_object(left < right))
+        }
+        (Infix::GT, Object::String(left), Object::String(right)) => {
+            Ok(native_bool_to_boolean_object(left > right))
+        }
+        _ => Err(EvalError::new(
+            ErrorKind::UnknownOperator,
+            format!(
+                "unknown operator: {} {} {}",
+                left.type_of(),
+                operator,
+                right.type_of()
+            ),
+        )
+        .with_types(&[left.type_of(), right.type_of()])),
+    }
+}
+
+// What == means for two values of the same type. Integers, booleans,
+// strings and null are equal by value, arrays when their elements are equal
+// in order, and hashes when they have the same keys with equal values.
+// Functions, macros, iterators and tasks are only equal to themselves: two
+// functions with the same code are still different functions, and comparing
+// their bodies and captured environments would be surprising and expensive.
+pub fn equals(left: &Object, right: &Object) -> bool {
+    // the elements still to compare, rather than a call for each, so that
+    // deeply nested collections don't overflow the stack
+    let mut pending = vec![(left, right)];
+    while let Some((left, right)) = pending.pop() {
+        let equal = match (left, right) {
+            (Object::Array(left), Object::Array(right)) => {
+                let equal = left.len() == right.len();
+                if equal {
+                    pending.extend(left.iter().zip(right).map(|(l, r)| (&**l, &**r)));
+                }
+                equal
+            }
+            (Object::Hash(left), Object::Hash(right)) => {
+                left.len() == right.len()
+                    && left.iter().all(|(key, pair)| match right.get(key) {
+                        Some(other) => {
+                            pending.push((&pair.value, &other.value));
+                            true
+                        }
+                        None => false,
+                    })
+            }
+            (
+                Object::Function(_)
+                | Object::Memoized(_)
+                | Object::CompiledFunction(_)
+                | Object::Closure(_)
+                | Object::Macro(_)
+                | Object::Iterator(_)
+                | Object::Task(_),
+                _,
+            ) => ptr::eq(left, right),
+            _ => left == right,
+        };
+        if !equal {
+            return false;
+        }
+    }
+    true
+}
+
+// out of range array indices and missing hash keys give null
+pub fn eval_index_expression(left: &Object, index: &Object) -> Result<Rc<Object>, EvalError> {
+    match (left, index) {
+        (Object::Array(elements), Object::Integer(index)) => Ok(usize::try_from(*index)
+            .ok()
+            .and_then(|index| elements.get(index))
+            .cloned()
+            .unwrap_or_else(null_object)),
+        (Object::Hash(pairs), index) => Ok(pairs
+            .get(&index.hash_key()?)
+            .map(|pair| Rc::clone(&pair.value))
+            .unwrap_or_else(null_object)),
+        _ => Err(EvalError::new(
+            ErrorKind::UnknownOperator,
+            format!(
+                "index operator not supported: {}[{}]",
+                left.type_of(),
+                index.type_of()
+            ),
+        )
+        .with_types(&[left.type_of(), index.type_of()])),
+    }
+}
+
+fn eval_integer_infix_expression(
+    operator: &Infix,
+    left: i64,
+    right: i64,
+) -> Result<Rc<Object>, EvalError> {
+    match operator {
+        Infix::PLUS => checked(left.checked_add(right), left, "+", right),
+        Infix::MINUS => checked(left.checked_sub(right), left, "-", right),
+        Infix::ASTERISK => checked(left.checked_mul(right), left, "*", right),
+        Infix::SLASH => match left.checked_div(right) {
+            Some(value) => Ok(integer_object(value)),
+            None if right == 0 => Err(EvalError::new(
+                ErrorKind::DivisionByZero,
+                format!("division by zero: {} / {}", left, right),
+            )),
+            None => Err(EvalError::new(
+                ErrorKind::IntegerOverflow,
+                format!("integer overflow: {} / {}", left, right),
+            )),
+        },
+        Infix::PERCENT => match left.checked_rem(right) {
+            Some(value) => Ok(integer_object(value)),
+            None if right == 0 => Err(EvalError::new(
+                ErrorKind::DivisionByZero,
+                format!("division by zero: {} % {}", left, right),
+            )),
+            None => Err(EvalError::new(
+                ErrorKind::IntegerOverflow,
+                format!("integer overflow: {} % {}", left, right),
+            )),
+        },
+        Infix::LT => Ok(native_bool_to_boolean_object(left < right)),
+        Infix::GT => Ok(native_bool_to_boolean_object(left > right)),
+        Infix::EQ => Ok(native_bool_to_boolean_object(left == right)),
+        Infix::NOT_EQ => Ok(native_bool_to_boolean_object(left != right)),
+    }
+}
+
+// the result of arithmetic that gives none when it overflows
+fn checked(
+    result: Option<i64>,
+    left: i64,
+    operator: &str,
+    right: i64,
+) -> Result<Rc<Object>, EvalError> {
+    result.map(integer_object).ok_or_else(|| {
+        EvalError::new(
+            ErrorKind::IntegerOverflow,
+            format!("integer overflow: {} {} {}", left, operator, right),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::assert_outcomes;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::profiler::Profiler;
+
+    #[test]
+    fn test_eval_integer_expression() {
+        let tests = vec![
+            ("5", 5),
+            ("10", 10),
+            ("-5", -5),
+            ("-10", -10),
+            ("5 + 5 + 5 + 5 - 10", 10),
+            ("2 * 2 * 2 * 2 * 2", 32),
+            ("-50 + 100 + -50", 0),
+            ("5 * 2 + 10", 20),
+            ("5 + 2 * 10", 25),
+            ("20 + 2 * -10", 0),
+            ("50 / 2 * 2 + 10", 60),
+            ("2 * (5 + 10)", 30),
+            ("3 * 3 * 3 + 10", 37),
+            ("3 * (3 * 3) + 10", 37),
+            ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
+            ("7 % 3", 1),
+            ("-7 % 3", -1),
+            ("10 % 5 + 2 * 3 % 4", 2),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_eval_boolean_expression() {
+        let tests = vec![
+            ("true", true),
+            ("false", false),
+            ("1 < 2", true),
+            ("1 > 2", false),
+            ("1 < 1", false),
+            ("1 > 1", false),
+            ("1 == 1", true),
+            ("1 != 1", false),
+            ("1 == 2", false),
+            ("1 != 2", true),
+            ("true == true", true),
+            ("false == false", true),
+            ("true == false", false),
+            ("true != false", true),
+            ("false != true", true),
+            ("(1 < 2) == true", true),
+            ("(1 < 2) == false", false),
+            ("(1 > 2) == true", false),
+            ("(1 > 2) == false", true),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_boolean_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_equality() {
+        let tests = vec![
+            // arrays, element by element
+            ("[] == []", true),
+            ("[1, [2, \"a\"]] == [1, [2, \"a\"]]", true),
+            ("[1, 2] == [2, 1]", false),
+            ("[1, 2] == [1, 2, 3]", false),
+            ("[1, 2] != [1, 3]", true),
+            ("[if (false) { 1 }] == [if (false) { 2 }]", true),
+            // different types are unequal rather than an error
+            ("[1] == [true]", false),
+            ("1 == \"1\"", false),
+            ("1 != \"1\"", true),
+            ("{\"a\": 1}[\"missing\"] == 1", false),
+            ("if (false) { 1 } == false", false),
+            // hashes, key by key whatever the order they were written in
+            ("{} == {}", true),
+            ("{\"a\": 1, 2: [3]} == {2: [3], \"a\": 1}", true),
+            ("{\"a\": 1} == {\"a\": 2}", false),
+            ("{\"a\": 1} == {\"b\": 1}", false),
+            ("{\"a\": 1} == {\"a\": 1, \"b\": 2}", false),
+            ("{\"a\": 1} != {\"a\": 1}", false),
+            // functions, by identity
+            ("let f = fn(x) { x }; f == f", true),
+            ("let f = fn(x) { x }; let g = f; f != g", false),
+            ("fn(x) { x } == fn(x) { x }", false),
+            ("let f = fn() { fn() { 1 } }; f() == f()", false),
+            ("let f = fn(x) { x }; [f] == [f]", true),
+            ("let f = memoize(fn(x) { x }); f == f", true),
+            ("let f = fn(x) { x }; memoize(f) == memoize(f)", false),
+            // builtins, by name
+            ("len == len", true),
+            ("len == first", false),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_boolean_object(evaluated, expected);
+        }
+        let evaluated = test_eval("fn(x) { x } == len").unwrap();
+        test_boolean_object(evaluated, false);
+    }
+
+    #[test]
+    fn test_bang_operator() {
+        let tests = vec![
+            ("!true", false),
+            ("!false", true),
+            ("!5", false),
+            ("!!true", true),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_boolean_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_if_else_expressions() {
+        let tests = vec![
+            ("if (true) { 10 }", 10),
+            ("if (false) { 10 }", 0),
+            ("if (1) { 10 }", 10),
+            ("if (1 < 2) { 10 }", 10),
+            ("if (1 > 2) { 10 } else { 20 }", 20),
+            ("if (1 < 2) { 10 } else { 20 }", 10),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_return_statements() {
+        let tests = vec![
+            ("return 10;", 10),
+            ("return 10; 9;", 10),
+            ("return 2 * 5; 9;", 10),
+            ("9; return 2 * 5; 9;", 10),
+            (
+                "if (10 > 1) {
+                    if (10 > 1) {
+                        return 10;
+                    }
+                    return 1;
+                }",
+                10,
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_let_statements() {
+        let tests = vec![
+            ("let a = 5; a;", 5),
+            ("let a = 5 * 5; a;", 25),
+            ("let a = 5; let b = a; b;", 5),
+            ("let a = 5; let b = a; let c = a + b + 5; c;", 15),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_function_object() {
+        let tests = vec![("fn(x) { x + 2; };", vec!["x"], "fn(x) {\n(x + 2)\n}")];
+
+        for (input, expected_params, expected_func) in tests {
+            let evaluated = test_eval(input).unwrap();
+
+            match &*evaluated {
+                Object::Function(function) => {
+                    assert_eq!(function.parameters, expected_params);
+                    assert_eq!(format!("{}", function), expected_func);
+                }
+                _ => {
+                    panic!("object is not Function. got={}", evaluated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_function_application() {
+        let tests = vec![
+            ("let identity = fn(x) { x; }; identity(5);", 5),
+            ("let identity = fn(x) { return x; }; identity(5);", 5),
+            ("let double = fn(x) { x * 2; }; double(5);", 10),
+            ("let add = fn(x, y) { x + y; }; add(5, 5);", 10),
+            ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", 20),
+            ("fn(x) { x; }(5)", 5),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_closures() {
+        let input = r#"
+        let newAdder = fn(x) {
+            fn(y) { x + y };
+        };
+        let addTwo = newAdder(2);
+        addTwo(3);
+        "#;
+        let expected = 5;
+
+        let evaluated = test_eval(input).unwrap();
+        test_integer_object(evaluated, expected);
+    }
+
+    #[test]
+    fn test_recursive_functions() {
+        let tests = vec![
+            (
+                "let fact = fn(n) { if (n == 0) { 1 } else { n * fact(n - 1) } }; fact(5);",
+                120,
+            ),
+            (
+                r#"
+                let fibonacci = fn(x) {
+                    if (x == 0) {
+                        0
+                    } else {
+                        if (x == 1) {
+                            1
+                        } else {
+                            fibonacci(x - 1) + fibonacci(x - 2);
+                        }
+                    }
+                };
+                fibonacci(10);
+                "#,
+                55,
+            ),
+            (
+                r#"
+                let isEven = fn(n) { if (n == 0) { true } else { isOdd(n - 1) } };
+                let isOdd = fn(n) { if (n == 0) { false } else { isEven(n - 1) } };
+                if (isEven(10)) { 1 } else { 0 }
+                "#,
+                1,
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let evaluated = test_eval(input).unwrap();
+            test_integer_object(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_closures_see_later_bindings() {
+        let input = "let x = 1; let getX = fn() { x }; let x = 2; getX();";
+
+        let evaluated = test_eval(input).unwrap();
+        test_integer_object(evaluated, 2);
+    }
+
+    #[test]
+    fn test_function_scope_does_not_leak() {
+        let input = "let f = fn() { let inner = 1; inner }; f(); inner;";
+
+        let evaluated = test_eval(input);
+        assert_eq!(
+            evaluated.unwrap_err().to_string(),
+            "identifier not found: inner"
+        );
+    }
+
+    #[test]
+    fn test_local_slots() {
+        let tests = vec![
+            ("let f = fn(a, b) { let c = a * b; c - a }; f(3, 4);", 9),
+            ("let f = fn(x) { let x = x + 1; x }; f(1);", 2),
+            ("let x = 10; let f = fn() { let y = x; let x = 1; x + y }; f();", 11),
+            (
+                "let f = fn(n) { let go = fn(i) { if (i == 0) { 0 } else { n + go(i - 1) } }; go(3) }; f(2);",
+                6,
+            ),
+            (
+                "let f = fn() { let g = fn() { later }; let later = 5; g() }; f();",
+                5,
+            ),
+            ("let f = fn(puts) { puts }; f(7);", 7),
+            ("let f = fn() { try { 1 / 0 } catch (e) { let x = 8; x } }; f();", 8),
+        ];
+
+        for (input, expected) in tests {
+            test_integer_object(test_eval(input).unwrap(), expected);
         }
+
+        let evaluated = test_eval("let f = fn() { let g = fn() { later }; g() }; f();");
+        assert_eq!(
+            evaluated.unwrap_err().to_string(),
+            "identifier not found: later"
+        );
     }
 
     #[test]
-    fn test_if_else_expressions() {
+    fn test_builtin_functions() {
+        let evaluated = test_eval("puts(1, true)").unwrap();
+        test_null_object(evaluated);
+
+        let evaluated = test_eval("let f = puts; f").unwrap();
+        assert_eq!(evaluated.to_string(), "builtin function puts");
+
+        let evaluated = test_eval("let puts = fn(x) { x * 2 }; puts(2)").unwrap();
+        test_integer_object(evaluated, 4);
+    }
+
+    #[test]
+    fn test_boolean_and_null_singletons() {
+        let first = test_eval("1 < 2").unwrap();
+        let second = test_eval("!false").unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+
+        let first = test_eval("1 == 2").unwrap();
+        let second = test_eval("false").unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+
+        let first = test_eval("if (false) { 1 }").unwrap();
+        let second = test_eval("puts()").unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+    }
+
+    // cargo test --release bench_boolean_singletons -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_boolean_singletons() {
+        use std::time::Instant;
+
+        const ITERATIONS: usize = 5_000_000;
+
+        let start = Instant::now();
+        let mut allocated = Vec::with_capacity(ITERATIONS);
+        for i in 0..ITERATIONS {
+            allocated.push(Rc::new(Object::Boolean(i % 2 == 0)));
+        }
+        let allocating = start.elapsed();
+        drop(allocated);
+
+        let start = Instant::now();
+        let mut interned = Vec::with_capacity(ITERATIONS);
+        for i in 0..ITERATIONS {
+            interned.push(native_bool_to_boolean_object(i % 2 == 0));
+        }
+        let sharing = start.elapsed();
+        drop(interned);
+
+        let input = r#"
+            let count = fn(n, acc) {
+                if (n == 0) { acc } else { count(n - 1, acc + (if (n < 50) { 1 } else { 0 })) }
+            };
+            count(300, 0);
+        "#;
+        let start = Instant::now();
+        for _ in 0..500 {
+            test_eval(input).unwrap();
+        }
+        let comparisons = start.elapsed();
+
+        println!("{} fresh booleans:       {:?}", ITERATIONS, allocating);
+        println!("{} singleton booleans:   {:?}", ITERATIONS, sharing);
+        println!("comparison-heavy program x500: {:?}", comparisons);
+    }
+
+    #[test]
+    fn test_stack_traces() {
+        let input = "let inner = fn() { foo };
+let outer = fn() { inner() };
+outer();";
+
+        let error = test_eval(input).unwrap_err();
+        assert_eq!(error.to_string(), "identifier not found: foo");
+        assert_eq!(error.kind, ErrorKind::IdentifierNotFound);
+
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(
+            trace,
+            vec![
+                "at inner (line 2, column 20)",
+                "at outer (line 3, column 1)",
+            ]
+        );
+
+        let error = test_eval("fn() { 1 / 0 }();").unwrap_err();
+        assert_eq!(error.trace[0].name, "<anonymous>");
+
+        let error = test_eval("1 / 0;").unwrap_err();
+        assert!(error.trace.is_empty());
+    }
+
+    #[test]
+    fn test_string_expressions() {
         let tests = vec![
-            ("if (true) { 10 }", 10),
-            ("if (false) { 10 }", 0),
-            ("if (1) { 10 }", 10),
-            ("if (1 < 2) { 10 }", 10),
-            ("if (1 > 2) { 10 } else { 20 }", 20),
-            ("if (1 < 2) { 10 } else { 20 }", 10),
+            (r#""Hello World!""#, "Hello World!"),
+            (r#""Hello" + " " + "World!""#, "Hello World!"),
         ];
 
         for (input, expected) in tests {
             let evaluated = test_eval(input).unwrap();
-            test_integer_object(evaluated, expected);
+            match &*evaluated {
+                Object::String(value) => assert_eq!(value, expected),
+                _ => panic!("object is not String. got={:?}", evaluated),
+            }
         }
+
+        test_boolean_object(test_eval(r#""a" == "a""#).unwrap(), true);
+        test_boolean_object(test_eval(r#""a" != "a""#).unwrap(), false);
+        test_boolean_object(test_eval(r#""apple" < "banana""#).unwrap(), true);
+        test_boolean_object(test_eval(r#""b" > "ab""#).unwrap(), true);
+        assert_eq!(
+            test_eval(r#""a" - "b""#).unwrap_err().to_string(),
+            "unknown operator: STRING - STRING"
+        );
     }
 
     #[test]
-    fn test_return_statements() {
+    fn test_arrays_and_hashes() {
         let tests = vec![
-            ("return 10;", 10),
-            ("return 10; 9;", 10),
-            ("return 2 * 5; 9;", 10),
-            ("9; return 2 * 5; 9;", 10),
+            ("[1, 2 * 2, 3 + 3]", "[1, 4, 6]"),
+            ("[1, 2, 3][0]", "1"),
+            ("let i = 0; [1][i]", "1"),
+            ("let a = [1, 2, 3]; a[0] + a[1] + a[2]", "6"),
+            ("[1, 2, 3][3]", "null"),
+            ("[1, 2, 3][-1]", "null"),
+            (r#"{"b": 2, "a": 1}"#, r#"{"b": 2, "a": 1}"#),
+            (r#"{true: 1, 2: 2, "3": 3}"#, r#"{true: 1, 2: 2, "3": 3}"#),
+            ("{1: 1, 2: 2, 1: 3}", "{1: 3, 2: 2}"),
             (
-                "if (10 > 1) {
-                    if (10 > 1) {
-                        return 10;
-                    }
-                    return 1;
-                }",
-                10,
+                r#"["a", fn(x, y) { x }, memoize(fn() { 1 })]"#,
+                r#"["a", fn(x, y) {...}, memoized fn() {...}]"#,
             ),
+            (r#"let key = "foo"; {"foo": 5}[key]"#, "5"),
+            (r#"{"foo": 5}["bar"]"#, "null"),
+            ("{5: 5}[5]", "5"),
+            ("{true: 5}[true]", "5"),
+            ("{1: 1, 1: 2}[1]", "2"),
+            (r#"len("four") + len([1, 2]) + len({1: 1})"#, "7"),
+            ("first([1, 2])", "1"),
+            ("last([1, 2])", "2"),
+            ("rest([1, 2, 3])", "[2, 3]"),
+            ("let a = [1]; push(a, 2); a", "[1]"),
+            ("push([1], 2)", "[1, 2]"),
         ];
 
         for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
-            test_integer_object(evaluated, expected);
+            assert_eq!(
+                test_eval(input).unwrap().to_string(),
+                expected,
+                "input: {}",
+                input
+            );
+        }
+
+        let tests = vec![
+            (
+                r#"{"name": "Monkey"}[fn(x) { x }];"#,
+                "unusable as hash key: FUNCTION",
+            ),
+            ("{[1]: 1}", "unusable as hash key: ARRAY"),
+            ("{{}: 1}", "unusable as hash key: HASH"),
+            ("1[0]", "index operator not supported: INTEGER[INTEGER]"),
+            (r#"[1]["0"]"#, "index operator not supported: ARRAY[STRING]"),
+        ];
+
+        for (input, expected) in tests {
+            let error = test_eval(input).unwrap_err();
+            assert_eq!(error.to_string(), expected);
         }
+        assert_eq!(
+            test_eval("{len: 1}").unwrap_err().kind,
+            ErrorKind::Unhashable
+        );
+        test_integer_object(test_eval("try { {[]: 1} } catch (e) { 1 }").unwrap(), 1);
     }
 
     #[test]
-    fn test_let_statements() {
+    fn test_memoize() {
+        // without the cache this would take far more than the fuel allows
+        let input = "
+        let fib = memoize(fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } });
+        fib(80)";
+        let evaluated = test_eval_with_config(input, EvalConfig::default().fuel(10_000));
+        test_integer_object(evaluated.unwrap(), 23_416_728_348_467_685);
+
         let tests = vec![
-            ("let a = 5; a;", 5),
-            ("let a = 5 * 5; a;", 25),
-            ("let a = 5; let b = a; b;", 5),
-            ("let a = 5; let b = a; let c = a + b + 5; c;", 15),
+            (
+                "let f = memoize(fn(a, b) { a + b }); f(1, 2) + f(1, 2) + f(2, 1)",
+                9,
+            ),
+            (
+                r#"let f = memoize(fn(s) { len(s) }); f("ab") + f("abc")"#,
+                5,
+            ),
+            ("let f = memoize(fn() { 7 }); f(); f()", 7),
+            ("let f = memoize(fn(x) { return x; 0 }); f(3); f(3)", 3),
         ];
+        for (input, expected) in tests {
+            test_integer_object(test_eval(input).unwrap(), expected);
+        }
 
+        let tests = vec![
+            ("memoize(fn(x) { x })([1])", "unusable as hash key: ARRAY"),
+            (
+                "memoize(1)",
+                "argument to `memoize` must be FUNCTION, got INTEGER",
+            ),
+            // errors aren't cached
+            (
+                "let f = memoize(fn(x) { 1 / x }); try { f(0) } catch (e) { 0 }; f(0)",
+                "division by zero: 1 / 0",
+            ),
+        ];
         for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
-            test_integer_object(evaluated, expected);
+            assert_eq!(test_eval(input).unwrap_err().to_string(), expected);
+        }
+
+        let error = test_eval("let f = memoize(fn(x) { 1 / x }); f(0)").unwrap_err();
+        assert_eq!(error.trace[0].name, "f");
+    }
+
+    // records every event as a line, like a tracer would
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl EvalObserver for Recorder {
+        fn on_enter_node(&mut self, node: Node, _env: &Env, frames: &[Frame]) {
+            // blocks display as the statements in them, so they're left out
+            match node {
+                Node::Statement(Statement::BlockStatement(..)) | Node::Expression(_) => {}
+                Node::Statement(statement) => {
+                    let event = format!("{}statement {}", "  ".repeat(frames.len()), statement);
+                    self.0.borrow_mut().push(event);
+                }
+            }
+        }
+
+        fn on_call(&mut self, frame: &Frame, args: &[Rc<Object>]) {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            let event = format!("call {}({})", frame.name, args.join(", "));
+            self.0.borrow_mut().push(event);
+        }
+
+        fn on_return(&mut self, frame: &Frame, value: &Rc<Object>) {
+            let event = format!("return {} {}", frame.name, value);
+            self.0.borrow_mut().push(event);
+        }
+
+        fn on_error(&mut self, error: &EvalError, frames: &[Frame]) {
+            let event = format!("error {} with {} calls left", error, frames.len());
+            self.0.borrow_mut().push(event);
         }
     }
 
     #[test]
-    fn test_function_object() {
-        let tests = vec![("fn(x) { x + 2; };", vec!["x"], "fn(x) {\n(x + 2)\n}")];
+    fn test_observers() {
+        let recorder = Recorder::default();
+        let input = "let f = fn(x) { x / 0 };
+        let g = fn(x) { return try { f(x) } catch (e) { len(\"ab\") } };
+        g(1)";
+        let config = EvalConfig::default().observe(recorder.clone());
+        test_integer_object(test_eval_with_config(input, config).unwrap(), 2);
 
-        for (input, expected_params, expected_func) in tests {
-            let evaluated = test_eval(input).unwrap();
+        let events = recorder.0.borrow();
+        let expected = vec![
+            "statement let f = fn(x) (x / 0);",
+            "statement let g = fn(x) return try f(x) catch (e) len(\"ab\");;",
+            "statement g(1)",
+            "call g(1)",
+            "  statement return try f(x) catch (e) len(\"ab\");",
+            "  statement f(x)",
+            "call f(1)",
+            "    statement (x / 0)",
+            "error division by zero: 1 / 0 with 1 calls left",
+            "  statement len(\"ab\")",
+            "call len(ab)",
+            "return len 2",
+            "return g 2",
+        ];
+        assert_eq!(*events, expected);
 
-            match &*evaluated {
-                Object::Function(function) => {
-                    assert_eq!(function.parameters, expected_params);
-                    assert_eq!(format!("{}", function), expected_func);
-                }
-                _ => {
-                    panic!("object is not Function. got={}", evaluated);
-                }
+        // errors that end the eval leave no calls behind
+        let recorder = Recorder::default();
+        let config = EvalConfig::default().observe(recorder.clone());
+        test_eval_with_config("let f = fn() { x }; f()", config).unwrap_err();
+        let events = recorder.0.borrow();
+        assert_eq!(
+            events.last().unwrap(),
+            "error identifier not found: x with 0 calls left"
+        );
+    }
+
+    #[test]
+    fn test_profiler() {
+        let profiler = Profiler::default();
+        let input = "
+        let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+        let f = fn(x) { try { error(x) } catch (e) { len(x) } };
+        fib(10) + f(\"boom\");";
+        let config = EvalConfig::default().observe(profiler.clone());
+        test_integer_object(test_eval_with_config(input, config).unwrap(), 59);
+
+        let calls = |name| profiler.stats(name).map(|stats| stats.calls);
+        assert_eq!(calls("fib"), Some(177));
+        assert_eq!(calls("f"), Some(1));
+        assert_eq!(calls("error"), Some(1));
+        assert_eq!(calls("len"), Some(1));
+        let fib = profiler.stats("fib").unwrap();
+        assert!(fib.own <= fib.total);
+
+        // calls that were in progress when the error ended the eval are closed
+        let config = EvalConfig::default().observe(profiler.clone());
+        test_eval_with_config("let g = fn() { 1 / 0 }; g()", config).unwrap_err();
+        assert_eq!(calls("g"), Some(1));
+        assert!(profiler.report().contains("fib"));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let tests = vec![
+            ("try { 1 } catch (e) { 2 }", 1),
+            ("try { 1 / 0 } catch (e) { 2 }", 2),
+            ("let f = fn() { x }; try { f() } catch (e) { 3 }", 3),
+            ("let x = try { 1 / 0 } catch (e) { 4 }; x", 4),
+            (
+                "let f = fn() { try { return 5; } catch (e) { 6 }; 7 }; f()",
+                5,
+            ),
+            ("try { try { x } catch (e) { y } } catch (e) { 8 }", 8),
+        ];
+
+        for (input, expected) in tests {
+            test_integer_object(test_eval(input).unwrap(), expected);
+        }
+
+        let evaluated = test_eval("try { 1 / 0 } catch (e) { e }").unwrap();
+        match &*evaluated {
+            Object::Error(error) => {
+                assert_eq!(error.kind, ErrorKind::DivisionByZero);
+                assert_eq!(error.message, "division by zero: 1 / 0");
             }
+            _ => panic!("object is not Error. got={:?}", evaluated),
         }
+
+        let error = test_eval("try { 1 } catch (e) { 2 }; e").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::IdentifierNotFound);
     }
 
     #[test]
-    fn test_function_application() {
+    fn test_resource_limits_are_not_catchable() {
+        let loop_forever = "let f = fn() { f() }; try { f() } catch (e) { 1 }";
+
+        let config = EvalConfig::default().max_depth(100_000).fuel(500);
+        let error = test_eval_with_config(loop_forever, config).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::FuelExhausted);
+
+        let config = EvalConfig::default().max_depth(10);
+        let evaluated = test_eval_with_config(loop_forever, config).unwrap();
+        test_integer_object(evaluated, 1);
+    }
+
+    #[test]
+    fn test_recursion_depth_limit() {
+        let config = EvalConfig::default().max_depth(20);
         let tests = vec![
-            ("let identity = fn(x) { x; }; identity(5);", 5),
-            ("let identity = fn(x) { return x; }; identity(5);", 5),
-            ("let double = fn(x) { x * 2; }; double(5);", 10),
-            ("let add = fn(x, y) { x + y; }; add(5, 5);", 10),
-            ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", 20),
-            ("fn(x) { x; }(5)", 5),
+            (
+                "let f = fn() { f() }; f();",
+                "maximum recursion depth exceeded: 20",
+            ),
+            (
+                "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(25);",
+                "maximum recursion depth exceeded: 20",
+            ),
+        ];
+
+        for (input, expected_message) in tests {
+            let evaluated = test_eval_with_config(input, config.clone());
+            assert_eq!(evaluated.unwrap_err().to_string(), expected_message);
+        }
+
+        let input = "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(19);";
+        let evaluated = test_eval_with_config(input, config).unwrap();
+        test_integer_object(evaluated, 19);
+    }
+
+    #[test]
+    fn test_fuel() {
+        let fib = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };";
+
+        let config = EvalConfig::default().fuel(1000);
+        let evaluated = test_eval_with_config(&format!("{} fib(20);", fib), config.clone());
+        assert_eq!(evaluated.unwrap_err().to_string(), "fuel exhausted");
+
+        let evaluated = test_eval_with_config(&format!("{} fib(5);", fib), config).unwrap();
+        test_integer_object(evaluated, 5);
+
+        // as written, `1 + 2;` is a statement, an infix expression and two
+        // literals, and folded it's a statement and a literal
+        let unfolded = EvalConfig::default().fold_constants(false);
+        let evaluated = test_eval_with_config("1 + 2;", unfolded.clone().fuel(4)).unwrap();
+        test_integer_object(evaluated, 3);
+        let evaluated = test_eval_with_config("1 + 2;", unfolded.fuel(3));
+        assert_eq!(evaluated.unwrap_err().to_string(), "fuel exhausted");
+        let evaluated = test_eval_with_config("1 + 2;", EvalConfig::default().fuel(2)).unwrap();
+        test_integer_object(evaluated, 3);
+    }
+
+    #[test]
+    fn test_strict_booleans() {
+        let config = EvalConfig::default().strict_booleans(true);
+        let tests = vec![
+            ("if (1) { 10 }", "if expects a BOOLEAN, got INTEGER"),
+            (
+                "let x = if (false) { 1 }; if (x) { 10 }",
+                "if expects a BOOLEAN, got NULL",
+            ),
+            ("!\"\"", "! expects a BOOLEAN, got STRING"),
+            (
+                "let f = fn(x) { !x }; f(0)",
+                "! expects a BOOLEAN, got INTEGER",
+            ),
         ];
+        for (input, expected_message) in tests {
+            let evaluated = test_eval_with_config(input, config.clone());
+            assert_eq!(evaluated.unwrap_err().to_string(), expected_message);
+        }
 
+        let tests = vec![
+            ("if (1 < 2) { 10 } else { 20 }", 10),
+            ("if (!true) { 10 } else { 20 }", 20),
+        ];
         for (input, expected) in tests {
-            let evaluated = test_eval(input).unwrap();
+            let evaluated = test_eval_with_config(input, config.clone()).unwrap();
             test_integer_object(evaluated, expected);
         }
+
+        // truthiness is still the default
+        let evaluated = test_eval_with_config("if (1) { 10 }", EvalConfig::default()).unwrap();
+        test_integer_object(evaluated, 10);
     }
 
     #[test]
-    fn test_closures() {
-        let input = r#"
-        let newAdder = fn(x) {
-            fn(y) { x + y };
+    fn test_timeout() {
+        let fib = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };";
+
+        let config = EvalConfig::default().timeout(Duration::from_millis(1));
+        let evaluated = test_eval_with_config(&format!("{} fib(25);", fib), config);
+        assert_eq!(
+            evaluated.unwrap_err().to_string(),
+            "evaluation timed out after 1ms"
+        );
+
+        let config = EvalConfig::default().timeout(Duration::from_secs(60));
+        let evaluated = test_eval_with_config(&format!("{} fib(10);", fib), config).unwrap();
+        test_integer_object(evaluated, 55);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let fib = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };";
+        let cancel = CancelHandle::new();
+        let config = EvalConfig::default().cancel_with(cancel.clone());
+        let canceller = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(10));
+                cancel.cancel();
+            }
+        });
+        let input = format!("{} try {{ fib(40) }} catch (e) {{ 0 }}", fib);
+        let error = test_eval_with_config(&input, config.clone()).unwrap_err();
+        canceller.join().unwrap();
+        assert_eq!(error.kind, ErrorKind::Cancelled);
+        assert_eq!(error.to_string(), "evaluation cancelled");
+
+        // the cancel is used up
+        let evaluated = test_eval_with_config(&format!("{} fib(10);", fib), config.clone());
+        test_integer_object(evaluated.unwrap(), 55);
+        // and one while nothing runs cancels the next eval
+        cancel.cancel();
+        let evaluated = test_eval_with_config("1", config);
+        assert_eq!(evaluated.unwrap_err().kind, ErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn test_default_recursion_depth_limit() {
+        let evaluated = test_eval("let f = fn(n) { f(n + 1) }; f(0);");
+        assert_eq!(
+            evaluated.unwrap_err().to_string(),
+            "maximum recursion depth exceeded: 1000"
+        );
+    }
+
+    #[test]
+    fn test_deep_recursion_does_not_use_native_stack() {
+        let config = EvalConfig::default().max_depth(200_000);
+        let input =
+            "let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(100000);";
+        let evaluated = test_eval_with_config(input, config).unwrap();
+        test_integer_object(evaluated, 100_000);
+    }
+
+    #[test]
+    fn test_deeply_nested_values() {
+        let config = EvalConfig::default().max_depth(50_000);
+        let input = "
+            let nest = fn(x, n) { if (n == 0) { x } else { nest([x], n - 1) } };
+            let a = nest(1, 20000);
+            [a == nest(1, 20000), a == nest(2, 20000), a]";
+        let evaluated = test_eval_with_config(input, config).unwrap();
+        let rendered = evaluated.to_string();
+        assert!(
+            rendered.starts_with("[\n  true,\n  false,\n  [[["),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("[...]"), "{}", rendered);
+        drop(evaluated);
+    }
+
+    #[test]
+    fn test_deeply_nested_expressions() {
+        // built directly, lexing a source this long would dominate the test
+        let mut expression = Expression::IntegerLiteral(0);
+        for _ in 0..10_000 {
+            expression = Expression::Infix(
+                Infix::PLUS,
+                Box::new(expression),
+                Box::new(Expression::IntegerLiteral(1)),
+            );
+        }
+        let program = Program {
+            statements: vec![Statement::ExpressionStatement(expression, Span::default())],
         };
-        let addTwo = newAdder(2);
-        addTwo(3);
-        "#;
-        let expected = 5;
 
-        let evaluated = test_eval(input).unwrap();
-        test_integer_object(evaluated, expected);
+        let env = Rc::new(RefCell::new(Environment::new()));
+        test_integer_object(eval(program, &env).unwrap(), 10_000);
     }
 
     #[test]
@@ -528,6 +3218,13 @@ mod tests {
                 "unknown operator: BOOLEAN + BOOLEAN",
             ),
             ("foobar", "identifier not found: foobar"),
+            ("5 / 0", "division by zero: 5 / 0"),
+            ("let x = 0; 10 / x;", "division by zero: 10 / 0"),
+            ("5 % 0", "division by zero: 5 % 0"),
+            (
+                "let f = fn(n) { 100 % n }; f(0);",
+                "division by zero: 100 % 0",
+            ),
         ];
 
         for (input, expected_message) in tests {
@@ -544,16 +3241,97 @@ mod tests {
         }
     }
 
-    fn test_eval(input: &str) -> Result<Rc<Object>, anyhow::Error> {
+    #[test]
+    fn test_integer_overflow() {
+        let tests = vec![
+            (
+                "9223372036854775807 + 1",
+                "integer overflow: 9223372036854775807 + 1",
+            ),
+            (
+                "let x = 9223372036854775807; x + 1",
+                "integer overflow: 9223372036854775807 + 1",
+            ),
+            (
+                "-9223372036854775807 - 2",
+                "integer overflow: -9223372036854775807 - 2",
+            ),
+            (
+                "9223372036854775807 * 2",
+                "integer overflow: 9223372036854775807 * 2",
+            ),
+            (
+                "-(-9223372036854775807 - 1)",
+                "integer overflow: -(-9223372036854775808)",
+            ),
+            (
+                "(-9223372036854775807 - 1) / -1",
+                "integer overflow: -9223372036854775808 / -1",
+            ),
+            (
+                "(-9223372036854775807 - 1) % -1",
+                "integer overflow: -9223372036854775808 % -1",
+            ),
+            (
+                "try { 9223372036854775807 + 1 } catch (e) { kind(e) }",
+                "INTEGER_OVERFLOW",
+            ),
+            ("9223372036854775806 + 1", "9223372036854775807"),
+        ];
+        assert_outcomes(&tests);
+    }
+
+    #[test]
+    fn test_error_types() {
+        let tests = vec![
+            ("5 + true;", vec!["INTEGER", "BOOLEAN"]),
+            ("-\"a\"", vec!["STRING"]),
+            ("1[0]", vec!["INTEGER", "INTEGER"]),
+            ("{fn() {}: 1}", vec!["FUNCTION"]),
+            ("let x = 1; x()", vec!["INTEGER"]),
+            ("len(1)", vec!["INTEGER"]),
+            ("5 / 0", vec![]),
+        ];
+
+        for (input, expected) in tests {
+            let error = test_eval(input).unwrap_err();
+            assert_eq!(error.types, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_error_json() {
+        let error = test_eval("let f = fn(x) { x + \"a\" };\nf(1)").unwrap_err();
+        let span = "{\"start\":16,\"end\":23,\"line\":1,\"column\":17}";
+        let call = "{\"start\":27,\"end\":31,\"line\":2,\"column\":1}";
+        assert_eq!(
+            error.json(),
+            format!(
+                "{{\"kind\":\"TYPE_MISMATCH\",\"code\":\"E0202\",\
+                 \"message\":\"type mismatch: INTEGER + STRING\",\"span\":{},\
+                 \"trace\":[{{\"name\":\"f\",\"span\":{}}}],\"types\":[\"INTEGER\",\"STRING\"]}}",
+                span, call
+            )
+        );
+        assert!(test_eval("foobar").unwrap_err().json().contains(
+            "\"span\":{\"start\":0,\"end\":6,\"line\":1,\"column\":1},\"trace\":[],\"types\":[]"
+        ));
+    }
+
+    fn test_eval(input: &str) -> Result<Rc<Object>, EvalError> {
+        test_eval_with_config(input, EvalConfig::default())
+    }
+
+    fn test_eval_with_config(input: &str, config: EvalConfig) -> Result<Rc<Object>, EvalError> {
         let lexer = Lexer::new(input);
         let mut parser = Parser::new(lexer);
         let program = parser.parse_program();
 
-        let mut env = Environment::new();
-        eval(program, &mut env)
+        let env = Rc::new(RefCell::new(Environment::new()));
+        eval_with_config(program, &env, config)
     }
 
-    fn test_integer_object(obj: Rc<Object>, expected: isize) {
+    fn test_integer_object(obj: Rc<Object>, expected: i64) {
         match *obj {
             Object::Integer(value) => {
                 assert_eq!(value, expected);
@@ -0,0 +1,242 @@
+// The C interface of the interpreter, with the ffi feature, for programs
+// that aren't written in Rust. build.rs generates include/monk.h from the
+// declarations here, and the doc comments are what the header says about
+// them, including what the callers have to uphold, which is why there are
+// no separate safety docs.
+#![allow(clippy::missing_safety_doc)]
+
+use std::any::Any;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+
+use crate::evaluator::Object;
+use crate::interpreter::Interpreter;
+
+pub struct MonkInterpreter {
+    interpreter: Interpreter,
+    // the value or error of the last eval, which monk_get_string returns
+    result: CString,
+}
+
+/// A function of the host that the code can call. It gets the data it was
+/// registered with and its arguments as the strings they display as, which
+/// are only valid during the call. It returns the string the call evaluates
+/// to, which is copied before the function is called again and which the
+/// host keeps owning, or NULL for null.
+pub type MonkFn =
+    extern "C" fn(data: *mut c_void, argc: usize, argv: *const *const c_char) -> *const c_char;
+
+// the string up to the first NUL, which C can't see past anyway
+fn c_string(string: String) -> CString {
+    CString::new(string).unwrap_or_else(|error| {
+        let end = error.nul_position();
+        let mut bytes = error.into_vec();
+        bytes.truncate(end);
+        CString::new(bytes).unwrap()
+    })
+}
+
+// Panics can't unwind into C, so each function runs its body with this,
+// which gives back the value for failure instead. The interpreter can be
+// left part way through what it was doing, but it's still safe to use.
+fn guard<T>(failed: impl FnOnce(Box<dyn Any + Send>) -> T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(failed)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown", String::as_str),
+    }
+}
+
+unsafe fn str_argument<'a>(string: *const c_char) -> Option<&'a str> {
+    match string.is_null() {
+        true => None,
+        false => CStr::from_ptr(string).to_str().ok(),
+    }
+}
+
+/// A new interpreter, to be freed with monk_free, or NULL if it failed.
+#[no_mangle]
+pub extern "C" fn monk_new() -> *mut MonkInterpreter {
+    guard(
+        |_| ptr::null_mut(),
+        || {
+            Box::into_raw(Box::new(MonkInterpreter {
+                interpreter: Interpreter::new(),
+                result: CString::default(),
+            }))
+        },
+    )
+}
+
+/// Runs the source, returning 0 if it ran, 1 if it failed to parse or
+/// raised an error and 2 if the interpreter panicked. monk_get_string returns
+/// its value or error message.
+#[no_mangle]
+pub unsafe extern "C" fn monk_eval(monk: *mut MonkInterpreter, source: *const c_char) -> c_int {
+    let Some(monk) = monk.as_mut() else {
+        return 1;
+    };
+    let (status, result) = guard(
+        |payload| (2, format!("panicked: {}", panic_message(&*payload))),
+        || {
+            let result = match str_argument(source) {
+                Some(source) => monk
+                    .interpreter
+                    .eval(source)
+                    .map_err(|error| error.to_string()),
+                None => Err("the source isn't a UTF-8 string".to_string()),
+            };
+            match result {
+                Ok(value) => (0, value.to_string()),
+                Err(error) => (1, error),
+            }
+        },
+    );
+    monk.result = c_string(result);
+    status
+}
+
+/// The value of the last monk_eval as it displays, or its error message. The
+/// interpreter owns the string, which is valid until the next monk_eval or
+/// monk_free.
+#[no_mangle]
+pub unsafe extern "C" fn monk_get_string(monk: *const MonkInterpreter) -> *const c_char {
+    guard(
+        |_| ptr::null(),
+        || match monk.as_ref() {
+            Some(monk) => monk.result.as_ptr(),
+            None => ptr::null(),
+        },
+    )
+}
+
+/// Defines a global function that calls func with data, returning 0, 1 if
+/// the name isn't a valid string or 2 if the interpreter panicked. data has
+/// to stay valid for as long as the interpreter can call the function.
+#[no_mangle]
+pub unsafe extern "C" fn monk_register_fn(
+    monk: *mut MonkInterpreter,
+    name: *const c_char,
+    func: MonkFn,
+    data: *mut c_void,
+) -> c_int {
+    let (Some(monk), Some(name)) = (monk.as_mut(), str_argument(name)) else {
+        return 1;
+    };
+    guard(
+        |_| 2,
+        || {
+            monk.interpreter.register_fn(name, move |args| {
+                let args: Vec<CString> = args.iter().map(|arg| c_string(arg.to_string())).collect();
+                let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+                let result = func(data, argv.len(), argv.as_ptr());
+                let result = match result.is_null() {
+                    true => Object::Null,
+                    false => Object::String(CStr::from_ptr(result).to_string_lossy().into_owned()),
+                };
+                Ok(Rc::new(result))
+            });
+            0
+        },
+    )
+}
+
+/// Frees the interpreter. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn monk_free(monk: *mut MonkInterpreter) {
+    if !monk.is_null() {
+        // a panic while dropping leaks what's left of the interpreter
+        guard(|_| (), || drop(Box::from_raw(monk)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn join(
+        data: *mut c_void,
+        argc: usize,
+        argv: *const *const c_char,
+    ) -> *const c_char {
+        let buffer = unsafe { &mut *(data as *mut CString) };
+        let args = unsafe { std::slice::from_raw_parts(argv, argc) };
+        let args: Vec<&str> = args
+            .iter()
+            .map(|&arg| unsafe { CStr::from_ptr(arg) }.to_str().unwrap())
+            .collect();
+        *buffer = CString::new(args.join("+")).unwrap();
+        buffer.as_ptr()
+    }
+
+    extern "C" fn nothing(_: *mut c_void, _: usize, _: *const *const c_char) -> *const c_char {
+        ptr::null()
+    }
+
+    #[test]
+    fn test_ffi() {
+        let eval = |monk, source: &str| unsafe {
+            let source = CString::new(source).unwrap();
+            let status = monk_eval(monk, source.as_ptr());
+            let result = CStr::from_ptr(monk_get_string(monk));
+            (status, result.to_str().unwrap().to_string())
+        };
+        let monk = monk_new();
+        assert_eq!(eval(monk, "let x = 40;"), (0, "40".to_string()));
+        assert_eq!(eval(monk, "x + 2"), (0, "42".to_string()));
+        assert_eq!(
+            eval(monk, "x / 0"),
+            (1, "division by zero: 40 / 0".to_string())
+        );
+
+        let mut buffer = CString::default();
+        let data = &mut buffer as *mut CString as *mut c_void;
+        unsafe {
+            let name = CString::new("join").unwrap();
+            assert_eq!(monk_register_fn(monk, name.as_ptr(), join, data), 0);
+            let name = CString::new("nothing").unwrap();
+            assert_eq!(
+                monk_register_fn(monk, name.as_ptr(), nothing, ptr::null_mut()),
+                0
+            );
+        }
+        assert_eq!(
+            eval(monk, "join(x, \"a\", [1])"),
+            (0, "40+a+[1]".to_string())
+        );
+        assert_eq!(eval(monk, "nothing()"), (0, "null".to_string()));
+
+        unsafe {
+            assert_eq!(monk_eval(monk, ptr::null()), 1);
+            assert_eq!(monk_eval(ptr::null_mut(), ptr::null()), 1);
+            assert!(monk_get_string(ptr::null()).is_null());
+            monk_free(monk);
+            monk_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_panics() {
+        let monk = monk_new();
+        unsafe {
+            (*monk)
+                .interpreter
+                .register_fn("crash", |_| panic!("host function"));
+            let source = CString::new("crash()").unwrap();
+            assert_eq!(monk_eval(monk, source.as_ptr()), 2);
+            let result = CStr::from_ptr(monk_get_string(monk));
+            assert_eq!(result.to_str().unwrap(), "panicked: host function");
+
+            let source = CString::new("1 + 1").unwrap();
+            assert_eq!(monk_eval(monk, source.as_ptr()), 0);
+            monk_free(monk);
+        }
+    }
+}
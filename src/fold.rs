@@ -0,0 +1,210 @@
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::evaluator::{
+    eval_infix_expression, eval_prefix_expression, integer_object, native_bool_to_boolean_object,
+    Object,
+};
+use crate::quote;
+
+// Replaces prefix and infix expressions whose operands are literals with the
+// literal they evaluate to, innermost first, so `2 * 3 + 4` becomes `10`.
+// Operations are evaluated by the evaluator itself, so a folded expression
+// has the value it would have had at runtime. Ones that would raise an error
+// are left alone to raise it when they run, as are the ones whose result
+// depends on how the program is run, like `!` on a non-boolean in the strict
+// booleans mode.
+//
+// An if expression whose condition folds to a boolean is replaced by the
+// branch it takes, when that branch is a single expression. Quoted code is
+// kept as written.
+pub fn fold_program(program: &mut Program) {
+    for statement in &mut program.statements {
+        fold_statement(statement);
+    }
+}
+
+pub fn fold_statement(statement: &mut Statement) {
+    fold_statement_at(statement, 0);
+}
+
+fn fold_statement_at(statement: &mut Statement, depth: usize) {
+    match statement {
+        Statement::LetStatement { value, .. } => fold_expression_at(value, depth + 1),
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            fold_expression_at(value, depth + 1)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                fold_statement_at(statement, depth + 1);
+            }
+        }
+    }
+}
+
+// the tree-walker doesn't recurse on the native stack, so that it can run
+// deeply nested expressions; folding leaves what's nested deeper alone
+const MAX_DEPTH: usize = 256;
+
+fn fold_expression_at(expression: &mut Expression, depth: usize) {
+    if depth > MAX_DEPTH || quote::special_form(expression, quote::QUOTE).is_some() {
+        return;
+    }
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_) => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                fold_expression_at(element, depth + 1);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                fold_expression_at(key, depth + 1);
+                fold_expression_at(value, depth + 1);
+            }
+        }
+        Expression::Index { left, index, .. } => {
+            fold_expression_at(left, depth + 1);
+            fold_expression_at(index, depth + 1);
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            fold_expression_at(condition, depth + 1);
+            fold_statement_at(consequence, depth + 1);
+            if let Some(alternative) = alternative {
+                fold_statement_at(alternative, depth + 1);
+            }
+            let taken = match **condition {
+                Expression::BooleanLiteral(true) => Some(&**consequence),
+                Expression::BooleanLiteral(false) => alternative.as_deref(),
+                _ => None,
+            };
+            if let Some(taken) = taken.and_then(single_expression) {
+                *expression = taken.clone();
+            }
+        }
+        Expression::FunctionLiteral { body, .. } | Expression::MacroLiteral { body, .. } => {
+            fold_statement_at(Rc::make_mut(body), depth + 1)
+        }
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            fold_expression_at(function, depth + 1);
+            for argument in arguments {
+                fold_expression_at(argument, depth + 1);
+            }
+        }
+        Expression::Try { body, handler, .. } => {
+            fold_statement_at(body, depth + 1);
+            fold_statement_at(handler, depth + 1);
+        }
+        Expression::For { iterable, body, .. } => {
+            fold_expression_at(iterable, depth + 1);
+            fold_statement_at(body, depth + 1);
+        }
+        Expression::Prefix(operator, right) => {
+            fold_expression_at(right, depth + 1);
+            let folded = match (&*operator, &**right) {
+                (Prefix::BANG, Expression::BooleanLiteral(_))
+                | (Prefix::MINUS, Expression::IntegerLiteral(_)) => {
+                    let right = value(right).unwrap();
+                    eval_prefix_expression(operator, right).ok()
+                }
+                _ => None,
+            };
+            if let Some(folded) = folded.and_then(|obj| literal(&obj)) {
+                *expression = folded;
+            }
+        }
+        Expression::Infix(operator, left, right) => {
+            fold_expression_at(left, depth + 1);
+            fold_expression_at(right, depth + 1);
+            let folded = match (value(left), value(right)) {
+                // arithmetic that fails, like an overflow or a division by
+                // zero, is left for the runtime to raise
+                (Some(left), Some(right)) => eval_infix_expression(operator, &left, &right).ok(),
+                _ => None,
+            };
+            if let Some(folded) = folded.and_then(|obj| literal(&obj)) {
+                *expression = folded;
+            }
+        }
+    }
+}
+
+// the expression a block consists of, if that's all it has
+fn single_expression(block: &Statement) -> Option<&Expression> {
+    match block {
+        Statement::BlockStatement(statements, _) => match statements.as_slice() {
+            [statement] => single_expression(statement),
+            _ => None,
+        },
+        Statement::ExpressionStatement(expression, _) => Some(expression),
+        _ => None,
+    }
+}
+
+// the value of a literal
+fn value(expression: &Expression) -> Option<Rc<Object>> {
+    match expression {
+        Expression::IntegerLiteral(value) => Some(integer_object(*value)),
+        Expression::BooleanLiteral(value) => Some(native_bool_to_boolean_object(*value)),
+        Expression::StringLiteral(value) => Some(Object::String(value.clone()).into()),
+        _ => None,
+    }
+}
+
+fn literal(obj: &Object) -> Option<Expression> {
+    match obj {
+        Object::Integer(value) => Some(Expression::IntegerLiteral(*value)),
+        Object::Boolean(value) => Some(Expression::BooleanLiteral(*value)),
+        Object::String(value) => Some(Expression::StringLiteral(value.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_fold() {
+        let tests = vec![
+            ("2 * 3 + 4", "10"),
+            ("-(1 - 3)", "2"),
+            ("!true == false", "true"),
+            (r#""a" + "b" == "ab""#, "true"),
+            ("x + 2 * 3", "(x + 6)"),
+            ("[1 + 1, f(2 * 2)][0 + 1]", "([2, f(4)][1])"),
+            ("fn(x) { x * (2 + 2) }", "fn(x) (x * 4)"),
+            ("if (1 < 2) { 3 } else { 4 }", "3"),
+            ("if (!true) { 3 } else { 2 + 2 }", "4"),
+            ("if (true) { let x = 3; x }", "if true let x = 3;x"),
+            ("if (false) { 3 }", "if false 3"),
+            ("if (x) { 1 + 1 }", "if x 2"),
+            ("quote(1 + 1) + (1 + 1)", "(quote((1 + 1)) + 2)"),
+            // errors are raised at runtime, where they can be caught
+            ("1 / 0", "(1 / 0)"),
+            ("1 + true", "(1 + true)"),
+            ("!1", "(!1)"),
+            ("9223372036854775807 + 1", "(9223372036854775807 + 1)"),
+        ];
+
+        for (input, expected) in tests {
+            let mut program = Parser::new(Lexer::new(input)).parse_program();
+            fold_program(&mut program);
+            assert_eq!(program.to_string(), expected, "{}", input);
+        }
+    }
+}
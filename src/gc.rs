@@ -0,0 +1,348 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::evaluator::{Env, Environment, Object};
+use crate::iter::Source;
+
+// Environments are reference counted, so most of them are freed as soon as
+// the call that created them returns. A closure stored in the environment it
+// captured forms a cycle that reference counting never frees, so every
+// environment the evaluator creates is also tracked here, and a cycle
+// collector finds the ones that are only kept alive by such cycles.
+thread_local! {
+    static HEAP: RefCell<Heap> = const { RefCell::new(Heap {
+        environments: Vec::new(),
+        threshold: INITIAL_THRESHOLD,
+    }) };
+}
+
+// An eval with a memory limit counts what it holds rather than what it made,
+// so the bytes of what it made are counted as freed again once it's dropped:
+// the objects it charged, by their address while they live, and the
+// environments and call frames, which know what they were charged.
+thread_local! {
+    static CHARGED: RefCell<HashMap<*const Object, usize>> = RefCell::new(HashMap::new());
+    static FREED: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn charge(obj: &Rc<Object>, bytes: usize) {
+    CHARGED.with(|charged| charged.borrow_mut().insert(Rc::as_ptr(obj), bytes));
+}
+
+// counts the bytes of an object that's being dropped as freed, if they
+// were charged
+pub fn release(obj: &Object) {
+    let bytes = CHARGED.with(|charged| {
+        let mut charged = charged.borrow_mut();
+        match charged.is_empty() {
+            true => None,
+            false => charged.remove(&(obj as *const Object)),
+        }
+    });
+    if let Some(bytes) = bytes {
+        free(bytes);
+    }
+}
+
+pub fn free(bytes: usize) {
+    FREED.with(|freed| freed.set(freed.get().wrapping_add(bytes)));
+}
+
+// the bytes freed on the thread so far, which an eval compares with what it
+// was when it started
+pub fn freed() -> usize {
+    FREED.with(Cell::get)
+}
+
+// how many environments may be tracked before the first collection
+const INITIAL_THRESHOLD: usize = 1024;
+
+struct Heap {
+    environments: Vec<Weak<RefCell<Environment>>>,
+    threshold: usize,
+}
+
+pub fn allocate(environment: Environment) -> Env {
+    let env = Rc::new(RefCell::new(environment));
+    HEAP.with(|heap| heap.borrow_mut().environments.push(Rc::downgrade(&env)));
+    env
+}
+
+// Collects once the number of tracked environments has doubled since the
+// last collection.
+pub fn maybe_collect() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.environments.len() >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+}
+
+// Frees the tracked environments that are only reachable through cycles and
+// returns how many there were.
+//
+// Nothing has to be told what the roots are: an environment or object whose
+// strong count is higher than the number of references to it from other
+// tracked environments and objects is held from somewhere else (a variable in
+// the host, the evaluator's stacks, an untracked environment), so it and
+// everything it reaches is kept. That makes it safe to collect in the middle
+// of an evaluation, as long as no environment is borrowed at the time.
+pub fn collect() -> usize {
+    let environments: Vec<Env> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.environments.retain(|env| env.strong_count() > 0);
+        heap.environments.iter().filter_map(Weak::upgrade).collect()
+    });
+
+    let mut graph = Graph::default();
+    for env in environments {
+        graph.add(Node::Environment(env));
+    }
+    let mut index = 0;
+    while index < graph.nodes.len() {
+        for child in graph.nodes[index].children() {
+            graph.add(child);
+        }
+        index += 1;
+    }
+
+    // references from inside the graph don't count towards keeping it alive
+    let mut external: Vec<usize> = graph.nodes.iter().map(Node::strong_count).collect();
+    for node in &graph.nodes {
+        for child in node.children() {
+            external[graph.lookup[&child.key()]] -= 1;
+        }
+    }
+
+    let mut reachable = vec![false; graph.nodes.len()];
+    let mut pending: Vec<usize> = (0..graph.nodes.len())
+        .filter(|&i| external[i] > 0)
+        .collect();
+    while let Some(i) = pending.pop() {
+        if reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        for child in graph.nodes[i].children() {
+            pending.push(graph.lookup[&child.key()]);
+        }
+    }
+
+    let mut freed = 0;
+    for (node, reachable) in graph.nodes.iter().zip(reachable) {
+        if let (Node::Environment(env), false) = (node, reachable) {
+            env.borrow_mut().clear();
+            freed += 1;
+        }
+    }
+    // dropping the graph lets go of the last references to the garbage
+    drop(graph);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.environments.retain(|env| env.strong_count() > 0);
+        heap.threshold = INITIAL_THRESHOLD.max(heap.environments.len() * 2);
+    });
+    freed
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    lookup: HashMap<*const (), usize>,
+}
+
+impl Graph {
+    fn add(&mut self, node: Node) {
+        let key = node.key();
+        if !self.lookup.contains_key(&key) {
+            self.lookup.insert(key, self.nodes.len());
+            self.nodes.push(node);
+        }
+    }
+}
+
+// Only environments and the objects that can lead back to one take part in
+// cycles; everything else is left out of the graph.
+enum Node {
+    Environment(Env),
+    Object(Rc<Object>),
+}
+
+impl Node {
+    fn key(&self) -> *const () {
+        match self {
+            Node::Environment(env) => Rc::as_ptr(env).cast(),
+            Node::Object(obj) => Rc::as_ptr(obj).cast(),
+        }
+    }
+
+    // the graph holds one reference to every node itself
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(env) => Rc::strong_count(env) - 1,
+            Node::Object(obj) => Rc::strong_count(obj) - 1,
+        }
+    }
+
+    fn children(&self) -> Vec<Node> {
+        match self {
+            Node::Environment(env) => {
+                let env = env.borrow();
+                let outer = env.outer().map(|outer| Node::Environment(Rc::clone(outer)));
+                let values = env.values().filter(|obj| can_form_cycle(obj));
+                outer
+                    .into_iter()
+                    .chain(values.map(|obj| Node::Object(Rc::clone(obj))))
+                    .collect()
+            }
+            Node::Object(obj) => match &**obj {
+                Object::Function(function) => vec![Node::Environment(Rc::clone(function.env()))],
+                Object::ReturnValue(value) if can_form_cycle(value) => {
+                    vec![Node::Object(Rc::clone(value))]
+                }
+                Object::Memoized(memoized) => std::iter::once(Rc::clone(memoized.function()))
+                    .chain(memoized.cached())
+                    .filter(|obj| can_form_cycle(obj))
+                    .map(Node::Object)
+                    .collect(),
+                Object::Array(elements) => elements
+                    .iter()
+                    .filter(|obj| can_form_cycle(obj))
+                    .map(|obj| Node::Object(Rc::clone(obj)))
+                    .collect(),
+                Object::Hash(pairs) => pairs
+                    .values()
+                    .filter(|pair| can_form_cycle(&pair.value))
+                    .map(|pair| Node::Object(Rc::clone(&pair.value)))
+                    .collect(),
+                Object::Iterator(iter) => match iter.source() {
+                    Source::Collection(collection) => vec![Node::Object(Rc::clone(collection))],
+                    Source::Range(..) => Vec::new(),
+                    Source::Map(iterator, function) | Source::Filter(iterator, function) => {
+                        [iterator, function]
+                            .into_iter()
+                            .filter(|obj| can_form_cycle(obj))
+                            .map(|obj| Node::Object(Rc::clone(obj)))
+                            .collect()
+                    }
+                },
+                _ => Vec::new(),
+            },
+        }
+    }
+}
+
+fn can_form_cycle(obj: &Object) -> bool {
+    match obj {
+        // containers are taken whole rather than searched for functions
+        Object::Function(_) | Object::Memoized(_) | Object::Array(_) | Object::Hash(_) => true,
+        Object::ReturnValue(value) => can_form_cycle(value),
+        Object::Iterator(iter) => !matches!(iter.source(), Source::Range(..)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::eval;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn run(input: &str, env: &Env) -> Rc<Object> {
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        eval(parser.parse_program(), env).unwrap()
+    }
+
+    #[test]
+    fn test_collects_closure_cycles() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        collect();
+
+        // each call leaves behind an environment that holds a closure over itself
+        run(
+            "let f = fn() { let g = fn() { g }; 1 }; f(); f(); f();",
+            &env,
+        );
+        assert_eq!(collect(), 3);
+        assert_eq!(collect(), 0);
+    }
+
+    #[test]
+    fn test_closure_cycle_does_not_leak() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        collect();
+
+        run("let make = fn() { let g = fn() { g }; g };", &env);
+        let closure = run("make()", &env);
+        let captured = match &*closure {
+            Object::Function(function) => Rc::downgrade(function.env()),
+            obj => panic!("object is not Function. got={:?}", obj),
+        };
+        // the closure is held by the host and by the environment it captured
+        assert_eq!(Rc::strong_count(&closure), 2);
+        assert_eq!(captured.strong_count(), 1);
+
+        // reference counting alone can't free the cycle
+        drop(closure);
+        assert_eq!(captured.strong_count(), 1);
+
+        assert_eq!(collect(), 1);
+        assert_eq!(captured.strong_count(), 0);
+    }
+
+    #[test]
+    fn test_collects_cycles_through_containers() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        collect();
+
+        run(
+            r#"let f = fn() { let xs = [fn() { xs }]; let h = {"g": fn() { h }}; 1 }; f(); f();"#,
+            &env,
+        );
+        // one environment per call, kept alive through the array and the hash
+        assert_eq!(collect(), 2);
+        assert_eq!(collect(), 0);
+    }
+
+    #[test]
+    fn test_collects_cycles_through_memoized_functions() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        collect();
+
+        run(
+            "let f = fn() { let m = memoize(fn(x) { m }); m(1); 1 }; f();",
+            &env,
+        );
+        // the memoized function caches itself, keeping the call environment alive
+        assert_eq!(collect(), 1);
+    }
+
+    #[test]
+    fn test_keeps_reachable_environments() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        collect();
+
+        run(
+            "let make = fn(x) { let g = fn() { x + one() }; let one = fn() { 1 }; g }; let h = make(41);",
+            &env,
+        );
+        // a closure held only by the host is kept as well
+        let held = run("make(1)", &env);
+        assert_eq!(collect(), 0);
+
+        assert_eq!(run("h()", &env).to_string(), "42");
+        match &*held {
+            Object::Function(function) => assert!(function.env().borrow().values().count() > 0),
+            obj => panic!("object is not Function. got={:?}", obj),
+        }
+
+        drop(held);
+        assert_eq!(collect(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::lexer::Lexer;
+use crate::token::{Span, Token};
+
+// What a token of the source is, for the tools that color it, like the
+// REPL. The language has no comments, so there's no class for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+    Keyword,
+    Identifier,
+    Number,
+    String,
+    Boolean,
+    Operator,
+    Punctuation,
+    // a character that isn't part of any token
+    Invalid,
+}
+
+impl TokenClass {
+    pub fn of(token: &Token) -> Option<TokenClass> {
+        let class = match token {
+            Token::FUNCTION
+            | Token::MACRO
+            | Token::LET
+            | Token::IF
+            | Token::ELSE
+            | Token::RETURN
+            | Token::TRY
+            | Token::CATCH
+            | Token::FOR
+            | Token::IN => TokenClass::Keyword,
+            Token::IDENT(_) => TokenClass::Identifier,
+            Token::INT(_) | Token::INT_TOO_LARGE(_) => TokenClass::Number,
+            Token::STRING(_) | Token::UNTERMINATED_STRING(_) => TokenClass::String,
+            Token::TRUE | Token::FALSE => TokenClass::Boolean,
+            Token::ASSIGN
+            | Token::PLUS
+            | Token::MINUS
+            | Token::BANG
+            | Token::ASTERISK
+            | Token::SLASH
+            | Token::PERCENT
+            | Token::LT
+            | Token::GT
+            | Token::EQ
+            | Token::NOT_EQ => TokenClass::Operator,
+            Token::COMMA
+            | Token::SEMICOLON
+            | Token::COLON
+            | Token::LPAREN
+            | Token::RPAREN
+            | Token::LBRACE
+            | Token::RBRACE
+            | Token::LBRACKET
+            | Token::RBRACKET => TokenClass::Punctuation,
+            Token::ILLEGAL(_) => TokenClass::Invalid,
+            Token::EOF => return None,
+        };
+        Some(class)
+    }
+}
+
+// The tokens of the source with their classes, in the order they're in.
+// It only lexes, so it works on source that doesn't parse, like the line
+// that's being typed.
+pub fn highlight(source: &str) -> Vec<(Span, TokenClass)> {
+    let mut lexer = Lexer::new(source);
+    let mut classes = Vec::new();
+    loop {
+        let spanned = lexer.next_token();
+        match TokenClass::of(&spanned.token) {
+            Some(class) => classes.push((spanned.span, class)),
+            None => return classes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let source = "let s = \"a\" + f(1, true) @";
+        let tests = vec![
+            ("let", TokenClass::Keyword),
+            ("s", TokenClass::Identifier),
+            ("=", TokenClass::Operator),
+            ("\"a\"", TokenClass::String),
+            ("+", TokenClass::Operator),
+            ("f", TokenClass::Identifier),
+            ("(", TokenClass::Punctuation),
+            ("1", TokenClass::Number),
+            (",", TokenClass::Punctuation),
+            ("true", TokenClass::Boolean),
+            (")", TokenClass::Punctuation),
+            ("@", TokenClass::Invalid),
+        ];
+        let classes: Vec<(&str, TokenClass)> = highlight(source)
+            .into_iter()
+            .map(|(span, class)| (&source[span.start..span.end], class))
+            .collect();
+        assert_eq!(classes, tests);
+        assert!(highlight("").is_empty());
+    }
+}
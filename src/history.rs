@@ -0,0 +1,161 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// how many inputs are kept across sessions by default
+pub const HISTORY_SIZE: usize = 1000;
+
+// The inputs typed into the REPL, oldest first. With a file they are loaded
+// from it at startup and every new one is appended to it, so they survive
+// the session even when the process is killed.
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+    size: usize,
+}
+
+// MONK_HISTORY if it's set, else a file in the XDG state directory if there
+// is one, else ~/.monk_history. An empty MONK_HISTORY turns saving off.
+pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("MONK_HISTORY") {
+        return (!path.is_empty()).then(|| path.into());
+    }
+    if let Some(state) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(state).join("monk").join("history"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".monk_history"))
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+impl History {
+    // a history that's only kept in memory
+    pub fn new() -> History {
+        History {
+            entries: Vec::new(),
+            path: None,
+            size: HISTORY_SIZE,
+        }
+    }
+
+    // the history saved in the file, which doesn't have to exist yet, keeping
+    // at most size inputs
+    pub fn load(path: PathBuf, size: usize) -> io::Result<History> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(String::from).collect(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let mut history = History {
+            entries,
+            path: Some(path),
+            size,
+        };
+        if history.entries.len() > history.size {
+            history.truncate();
+            history.rewrite()?;
+        }
+        Ok(history)
+    }
+
+    // the inputs, oldest first
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    // remembers an input, unless it's blank or the same as the one before
+    pub fn add(&mut self, input: &str) -> io::Result<()> {
+        let input = input.trim();
+        if input.is_empty() || self.entries.last().is_some_and(|last| last == input) {
+            return Ok(());
+        }
+        self.entries.push(input.to_string());
+        if self.entries.len() > self.size {
+            self.truncate();
+            return self.rewrite();
+        }
+        match &self.path {
+            Some(path) => {
+                create_parent(path)?;
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", input)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // the entries containing the text, with their numbers
+    pub fn search<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (usize, &'a str)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(move |(_, entry)| entry.contains(text))
+            .map(|(index, entry)| (index + 1, entry.as_str()))
+    }
+
+    fn truncate(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.size);
+        self.entries.drain(..excess);
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        create_parent(path)?;
+        let mut contents = self.entries.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)
+    }
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_and_search() {
+        let mut history = History::new();
+        for input in ["let x = 1;\n", "", "  ", "x + 1", "x + 1", "let y = x;"] {
+            history.add(input).unwrap();
+        }
+        assert_eq!(history.entries(), ["let x = 1;", "x + 1", "let y = x;"]);
+
+        let found: Vec<_> = history.search("let").collect();
+        assert_eq!(found, [(1, "let x = 1;"), (3, "let y = x;")]);
+        assert_eq!(history.search("").count(), 3);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = env::temp_dir().join("return_to_monk_test_history");
+        let path = dir.join("history");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut history = History::load(path.clone(), 3).unwrap();
+        assert!(history.entries().is_empty());
+        for input in ["1", "2", "3", "4"] {
+            history.add(input).unwrap();
+        }
+        history.add("5").unwrap();
+        assert_eq!(history.entries(), ["3", "4", "5"]);
+
+        let history = History::load(path.clone(), 3).unwrap();
+        assert_eq!(history.entries(), ["3", "4", "5"]);
+        let history = History::load(path, 2).unwrap();
+        assert_eq!(history.entries(), ["4", "5"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,700 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::builtins;
+use crate::symbol::Symbol;
+
+// the largest function body, counted in expressions, that is inlined
+pub const INLINE_THRESHOLD: usize = 16;
+
+// Replaces calls to small functions with their bodies, with the arguments
+// in place of the parameters, so `let double = fn(x) { x * 2 }; double(n)`
+// compiles as if it were `n * 2`.
+//
+// The program is taken as a whole, so a call is only inlined when the
+// result is the same whatever runs before it:
+// - the function is bound once, by a let statement at the top level that
+//   comes before the call, and its body is a single expression that
+//   doesn't define anything, return early, create functions or call the
+//   function itself;
+// - the arguments are literals, or names that are always bound where the
+//   call is: parameters, and globals defined before it. Evaluating them
+//   can't fail, so substituting them for the parameters doesn't change what
+//   the call does;
+// - none of the names the body uses are bound differently where the call is.
+//
+// Inlined calls don't show up in stack traces or profiles, and a function
+// redefined by a later program, e.g. in a REPL, keeps its old body where it
+// was inlined.
+pub fn inline_program(statements: &mut [Statement]) {
+    let mut inliner = Inliner {
+        candidates: HashMap::new(),
+        globals: HashSet::new(),
+        scopes: Vec::new(),
+    };
+    let mut defined = HashSet::new();
+    let mut defined_twice = HashSet::new();
+    for statement in statements.iter() {
+        bound_names(statement, &mut |name| {
+            if !defined.insert(name.clone()) {
+                defined_twice.insert(name.clone());
+            }
+        });
+    }
+
+    for statement in statements {
+        inliner.statement(statement);
+        if let Statement::LetStatement { name, value, .. } = statement {
+            if !defined_twice.contains(name) {
+                if let Some(candidate) = Candidate::new(name, value) {
+                    inliner.candidates.insert(name.clone(), candidate);
+                }
+            }
+            inliner.globals.insert(name.clone());
+        }
+    }
+}
+
+struct Candidate {
+    parameters: Vec<Symbol>,
+    body: Expression,
+    // the names the body uses besides its parameters
+    free: HashSet<Symbol>,
+}
+
+impl Candidate {
+    fn new(name: &Symbol, value: &Expression) -> Option<Candidate> {
+        let Expression::FunctionLiteral {
+            parameters, body, ..
+        } = value
+        else {
+            return None;
+        };
+        let Statement::BlockStatement(statements, _) = &**body else {
+            return None;
+        };
+        let [Statement::ExpressionStatement(body, _)] = statements.as_slice() else {
+            return None;
+        };
+        let unique: HashSet<&Symbol> = parameters.iter().collect();
+        if unique.len() != parameters.len() {
+            return None;
+        }
+
+        let mut names = HashSet::new();
+        let size = simple_size(body, &mut names)?;
+        if size > INLINE_THRESHOLD || names.contains(name) {
+            return None;
+        }
+        let free = names
+            .into_iter()
+            .filter(|name| !parameters.contains(name))
+            .collect();
+        Some(Candidate {
+            parameters: parameters.clone(),
+            body: body.clone(),
+            free,
+        })
+    }
+}
+
+// The number of expressions in one that can be inlined, collecting the
+// names it uses, or none if it can't be.
+fn simple_size(expression: &Expression, names: &mut HashSet<Symbol>) -> Option<usize> {
+    let size = match expression {
+        // locals() gives the variables of where it's called
+        Expression::Identifier(name, _) if *name == builtins::LOCALS => return None,
+        Expression::Identifier(name, _) => {
+            names.insert(name.clone());
+            0
+        }
+        Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_) => 0,
+        Expression::ArrayLiteral(elements) => elements
+            .iter()
+            .map(|element| simple_size(element, names))
+            .sum::<Option<usize>>()?,
+        Expression::HashLiteral(pairs, _) => pairs
+            .iter()
+            .map(|(key, value)| Some(simple_size(key, names)? + simple_size(value, names)?))
+            .sum::<Option<usize>>()?,
+        Expression::Index { left, index, .. } => {
+            simple_size(left, names)? + simple_size(index, names)?
+        }
+        Expression::Prefix(_, right) => simple_size(right, names)?,
+        Expression::Infix(_, left, right) => simple_size(left, names)? + simple_size(right, names)?,
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            let arguments = arguments
+                .iter()
+                .map(|argument| simple_size(argument, names))
+                .sum::<Option<usize>>()?;
+            simple_size(function, names)? + arguments
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            let mut size = simple_size(condition, names)? + simple_block_size(consequence, names)?;
+            if let Some(alternative) = alternative {
+                size += simple_block_size(alternative, names)?;
+            }
+            size
+        }
+        Expression::FunctionLiteral { .. }
+        | Expression::MacroLiteral { .. }
+        | Expression::Try { .. }
+        | Expression::For { .. } => return None,
+    };
+    Some(size + 1)
+}
+
+fn simple_block_size(block: &Statement, names: &mut HashSet<Symbol>) -> Option<usize> {
+    match block {
+        Statement::BlockStatement(statements, _) => statements
+            .iter()
+            .map(|statement| simple_block_size(statement, names))
+            .sum(),
+        Statement::ExpressionStatement(expression, _) => simple_size(expression, names),
+        Statement::LetStatement { .. } | Statement::ReturnStatement(..) => None,
+    }
+}
+
+// Calls the callback with every name a statement binds in the scope it runs
+// in, so not the ones bound inside functions.
+fn bound_names(statement: &Statement, callback: &mut impl FnMut(&Symbol)) {
+    match statement {
+        Statement::LetStatement { name, value, .. } => {
+            callback(name);
+            bound_names_in(value, callback);
+        }
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            bound_names_in(value, callback)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                bound_names(statement, callback);
+            }
+        }
+    }
+}
+
+fn bound_names_in(expression: &Expression, callback: &mut impl FnMut(&Symbol)) {
+    match expression {
+        Expression::If {
+            consequence,
+            alternative,
+            ..
+        } => {
+            bound_names(consequence, callback);
+            if let Some(alternative) = alternative {
+                bound_names(alternative, callback);
+            }
+        }
+        Expression::Try { body, .. } => bound_names(body, callback),
+        _ => {}
+    }
+}
+
+// the names bound by a scope inside the program, and which of them are
+// always bound
+#[derive(Default)]
+struct Scope {
+    bound: HashSet<Symbol>,
+    parameters: HashSet<Symbol>,
+}
+
+impl Scope {
+    fn bind_all(&mut self, statement: &Statement) {
+        let mut names = Vec::new();
+        all_bound_names(statement, &mut names);
+        self.bound.extend(names);
+    }
+}
+
+// every name bound anywhere in a statement, including inside functions
+fn all_bound_names(statement: &Statement, names: &mut Vec<Symbol>) {
+    match statement {
+        Statement::LetStatement { name, value, .. } => {
+            names.push(name.clone());
+            all_bound_names_in(value, names);
+        }
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            all_bound_names_in(value, names)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                all_bound_names(statement, names);
+            }
+        }
+    }
+}
+
+fn all_bound_names_in(expression: &Expression, names: &mut Vec<Symbol>) {
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_) => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                all_bound_names_in(element, names);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                all_bound_names_in(key, names);
+                all_bound_names_in(value, names);
+            }
+        }
+        Expression::Index { left, index, .. } => {
+            all_bound_names_in(left, names);
+            all_bound_names_in(index, names);
+        }
+        Expression::Prefix(_, right) => all_bound_names_in(right, names),
+        Expression::Infix(_, left, right) => {
+            all_bound_names_in(left, names);
+            all_bound_names_in(right, names);
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            all_bound_names_in(condition, names);
+            all_bound_names(consequence, names);
+            if let Some(alternative) = alternative {
+                all_bound_names(alternative, names);
+            }
+        }
+        Expression::FunctionLiteral {
+            parameters, body, ..
+        }
+        | Expression::MacroLiteral {
+            parameters, body, ..
+        } => {
+            names.extend(parameters.iter().cloned());
+            all_bound_names(body, names);
+        }
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            all_bound_names_in(function, names);
+            for argument in arguments {
+                all_bound_names_in(argument, names);
+            }
+        }
+        Expression::Try {
+            body,
+            name,
+            handler,
+            ..
+        } => {
+            all_bound_names(body, names);
+            names.push(name.clone());
+            all_bound_names(handler, names);
+        }
+        Expression::For {
+            name,
+            iterable,
+            body,
+            ..
+        } => {
+            all_bound_names_in(iterable, names);
+            names.push(name.clone());
+            all_bound_names(body, names);
+        }
+    }
+}
+
+struct Inliner {
+    candidates: HashMap<Symbol, Candidate>,
+    // the globals defined so far
+    globals: HashSet<Symbol>,
+    // the functions and catch handlers the expression being looked at is in
+    scopes: Vec<Scope>,
+}
+
+impl Inliner {
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::LetStatement { value, .. } => self.expression(value),
+            Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+                self.expression(value)
+            }
+            Statement::BlockStatement(statements, _) => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &mut Expression) {
+        match expression {
+            Expression::Identifier(..)
+            | Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_) => {}
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            Expression::HashLiteral(pairs, _) => {
+                for (key, value) in pairs {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::Index { left, index, .. } => {
+                self.expression(left);
+                self.expression(index);
+            }
+            Expression::Prefix(_, right) => self.expression(right),
+            Expression::Infix(_, left, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.expression(condition);
+                self.statement(consequence);
+                if let Some(alternative) = alternative {
+                    self.statement(alternative);
+                }
+            }
+            Expression::FunctionLiteral {
+                parameters, body, ..
+            }
+            | Expression::MacroLiteral {
+                parameters, body, ..
+            } => {
+                let mut scope = Scope {
+                    parameters: parameters.iter().cloned().collect(),
+                    ..Scope::default()
+                };
+                scope.bound.extend(parameters.iter().cloned());
+                scope.bind_all(body);
+                self.scopes.push(scope);
+                self.statement(std::rc::Rc::make_mut(body));
+                self.scopes.pop();
+            }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => {
+                self.statement(body);
+                let mut scope = Scope::default();
+                scope.bound.insert(name.clone());
+                scope.parameters.insert(name.clone());
+                scope.bind_all(handler);
+                self.scopes.push(scope);
+                self.statement(handler);
+                self.scopes.pop();
+            }
+            Expression::For {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.expression(iterable);
+                let mut scope = Scope::default();
+                scope.bound.insert(name.clone());
+                scope.parameters.insert(name.clone());
+                scope.bind_all(body);
+                self.scopes.push(scope);
+                self.statement(body);
+                self.scopes.pop();
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                self.expression(function);
+                for argument in arguments.iter_mut() {
+                    self.expression(argument);
+                }
+                if let Some(inlined) = self.inline(function, arguments) {
+                    *expression = inlined;
+                }
+            }
+        }
+    }
+
+    fn inline(&self, function: &Expression, arguments: &[Expression]) -> Option<Expression> {
+        let Expression::Identifier(name, _) = function else {
+            return None;
+        };
+        let candidate = self.candidates.get(name)?;
+        if self.is_shadowed(name)
+            || arguments.len() != candidate.parameters.len()
+            || candidate.free.iter().any(|name| self.is_shadowed(name))
+            || !arguments
+                .iter()
+                .all(|argument| self.is_always_bound(argument))
+        {
+            return None;
+        }
+
+        let arguments: HashMap<&Symbol, &Expression> =
+            candidate.parameters.iter().zip(arguments).collect();
+        let mut body = candidate.body.clone();
+        substitute(&mut body, &arguments);
+        Some(body)
+    }
+
+    fn is_shadowed(&self, name: &Symbol) -> bool {
+        self.scopes.iter().any(|scope| scope.bound.contains(name))
+    }
+
+    // whether evaluating the argument always gives a value
+    fn is_always_bound(&self, argument: &Expression) -> bool {
+        match argument {
+            Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_) => true,
+            Expression::Identifier(name, _) => {
+                match self
+                    .scopes
+                    .iter()
+                    .rev()
+                    .find(|scope| scope.bound.contains(name))
+                {
+                    Some(scope) => scope.parameters.contains(name),
+                    None => self.globals.contains(name),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+// replaces the parameters in an inlined body, which binds no names itself
+fn substitute(expression: &mut Expression, arguments: &HashMap<&Symbol, &Expression>) {
+    match expression {
+        Expression::Identifier(name, _) => {
+            if let Some(argument) = arguments.get(name) {
+                *expression = (*argument).clone();
+            }
+        }
+        Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_)
+        | Expression::FunctionLiteral { .. }
+        | Expression::MacroLiteral { .. }
+        | Expression::Try { .. }
+        | Expression::For { .. } => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                substitute(element, arguments);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                substitute(key, arguments);
+                substitute(value, arguments);
+            }
+        }
+        Expression::Index { left, index, .. } => {
+            substitute(left, arguments);
+            substitute(index, arguments);
+        }
+        Expression::Prefix(_, right) => substitute(right, arguments),
+        Expression::Infix(_, left, right) => {
+            substitute(left, arguments);
+            substitute(right, arguments);
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            substitute(condition, arguments);
+            substitute_block(consequence, arguments);
+            if let Some(alternative) = alternative {
+                substitute_block(alternative, arguments);
+            }
+        }
+        Expression::Call {
+            function,
+            arguments: call_arguments,
+            ..
+        } => {
+            substitute(function, arguments);
+            for argument in call_arguments {
+                substitute(argument, arguments);
+            }
+        }
+    }
+}
+
+fn substitute_block(block: &mut Statement, arguments: &HashMap<&Symbol, &Expression>) {
+    match block {
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                substitute_block(statement, arguments);
+            }
+        }
+        Statement::ExpressionStatement(expression, _) => substitute(expression, arguments),
+        _ => unreachable!("inlined bodies only have expressions"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::engine::{Engine, Runner};
+    use crate::evaluator::{EvalError, Object};
+    use crate::parser;
+    use crate::vm::Vm;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_inline() {
+        let tests = vec![
+            (
+                "let double = fn(x) { x * 2 }; double(3)",
+                "let double = fn(x) (x * 2);(3 * 2)",
+            ),
+            (
+                "let k = 1; let add = fn(a, b) { a + b + k }; let f = fn(n) { add(n, k) }",
+                "let k = 1;let add = fn(a, b) ((a + b) + k);let f = fn(n) ((n + k) + k);",
+            ),
+            (
+                "let max = fn(a, b) { if (a > b) { a } else { b } }; max(1, 2)",
+                "let max = fn(a, b) if (a > b) aelse b;if (1 > 2) 1else 2",
+            ),
+            // an inlined body is no argument that can be substituted
+            (
+                "let sq = fn(x) { x * x }; let quad = fn(x) { sq(sq(x)) }; quad(2)",
+                "let sq = fn(x) (x * x);let quad = fn(x) sq((x * x));sq((2 * 2))",
+            ),
+            // only calls after the function is defined
+            (
+                "let f = fn() { g(1) }; let g = fn(x) { x };",
+                "let f = fn() g(1);let g = fn(x) x;",
+            ),
+            // not recursive functions
+            ("let f = fn(n) { f(n) }; f(1)", "let f = fn(n) f(n);f(1)"),
+            // not ones that are defined twice
+            (
+                "let f = fn(x) { x }; let f = fn(x) { 1 }; f(2)",
+                "let f = fn(x) x;let f = fn(x) 1;f(2)",
+            ),
+            // not when an argument has to be evaluated
+            (
+                "let f = fn(x) { x }; f(1 + 2)",
+                "let f = fn(x) x;f((1 + 2))",
+            ),
+            ("let f = fn(x) { x }; f(y)", "let f = fn(x) x;f(y)"),
+            (
+                "let f = fn(x) { x }; let g = fn() { let y = 1; f(y) }",
+                "let f = fn(x) x;let g = fn() let y = 1;f(y);",
+            ),
+            // not when a name the body uses means something else at the call
+            (
+                "let k = 1; let f = fn() { k }; let g = fn(k) { f() }",
+                "let k = 1;let f = fn() k;let g = fn(k) f();",
+            ),
+            (
+                "let f = fn(x) { len(x) }; let g = fn(len) { f(len) }",
+                "let f = fn(x) len(x);let g = fn(len) f(len);",
+            ),
+            (
+                "let f = fn(x) { x }; let g = fn(f) { f(1) }",
+                "let f = fn(x) x;let g = fn(f) f(1);",
+            ),
+            // not bodies that bind names or are too large
+            (
+                "let f = fn(x) { let y = x; y }; f(1)",
+                "let f = fn(x) let y = x;y;f(1)",
+            ),
+            (
+                "let f = fn() { fn() { 1 } }; f()",
+                "let f = fn() fn() 1;f()",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let mut program = parser::parse(input).unwrap();
+            inline_program(&mut program.statements);
+            assert_eq!(program.to_string(), expected, "{}", input);
+        }
+
+        let body = vec!["x"; INLINE_THRESHOLD + 1].join(" + ");
+        let mut program = parser::parse(&format!("let f = fn(x) {{ {} }}; f(1)", body)).unwrap();
+        inline_program(&mut program.statements);
+        assert_eq!(program.statements[1].to_string(), "f(1)");
+    }
+
+    #[test]
+    fn test_inlined_programs_run_the_same() {
+        let tests = vec![
+            "let double = fn(x) { x * 2 }; let sum = fn(n) { if (n == 0) { 0 } else { double(n) + sum(n - 1) } }; sum(10)",
+            "let max = fn(a, b) { if (a > b) { a } else { b } }; let x = 5; [max(x, 3), max(1, x)]",
+            "let max = fn(a, b) { if (a > b) { a } else { b } }; max(true, false)",
+            r#"let greet = fn(name) { "hello " + name }; let h = {"a": greet("a")}; h["a"]"#,
+            "let sumPair = fn(arr) { arr[0] + arr[1] }; let a = [1, 2]; sumPair(a)",
+            "let div = fn(a, b) { a / b }; try { div(1, 0) } catch (e) { message(e) }",
+        ];
+
+        let display = |result: Result<Rc<Object>, EvalError>| match result {
+            Ok(obj) => obj.to_string(),
+            Err(error) => format!("error: {}", error),
+        };
+        for input in tests {
+            let expected =
+                display(Runner::new(Engine::TreeWalker).run(parser::parse(input).unwrap()));
+            for inline in [false, true] {
+                let mut compiler = Compiler::new().inline_functions(inline);
+                let bytecode = compiler.compile(&parser::parse(input).unwrap()).unwrap();
+                let result = display(Vm::new().run(bytecode));
+                assert_eq!(result, expected, "{} (inlining {})", input, inline);
+            }
+        }
+    }
+
+    // cargo test --release bench_inlining -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_inlining() {
+        use std::time::{Duration, Instant};
+
+        let input = "
+            let square = fn(x) { x * x };
+            let abs = fn(x) { if (x < 0) { -x } else { x } };
+            let loop = fn(n, acc) { if (n == 0) { acc } else { loop(n - 1, acc + square(n) % 7 + abs(n) % 3) } };
+            loop(900, 0)";
+        for inline in [false, true] {
+            let mut best = Duration::MAX;
+            for _ in 0..20 {
+                let mut compiler = Compiler::new().inline_functions(inline);
+                let bytecode = compiler.compile(&parser::parse(input).unwrap()).unwrap();
+                let start = Instant::now();
+                Vm::new().run(bytecode).unwrap();
+                best = best.min(start.elapsed());
+            }
+            println!("inlining {:<5} {:?}", inline, best);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::evaluator::{Closure, Env, Environment, HashPair, Memoized, Object};
+use crate::gc;
+use crate::iter::Source;
+use crate::printer;
+use crate::symbol::Symbol;
+
+// how many characters of a value a binding shows
+const PREVIEW_WIDTH: usize = 60;
+
+// A variable as it's listed by `:env`: its name, type and a one-line preview
+// of its value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: Symbol,
+    pub type_name: String,
+    pub preview: String,
+}
+
+impl Display for Binding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} = {}", self.name, self.type_name, self.preview)
+    }
+}
+
+pub fn binding(name: Symbol, value: &Object) -> Binding {
+    Binding {
+        name,
+        type_name: value.type_of().to_string(),
+        preview: preview(value),
+    }
+}
+
+// the value on one line, cut short if it's long
+pub fn preview(value: &Object) -> String {
+    let line = printer::render_line(value);
+    match line.char_indices().nth(PREVIEW_WIDTH) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line,
+    }
+}
+
+// About how many bytes the values hold, counting every object and
+// environment that's reachable from them once, the way evals count them
+// against their memory limit.
+pub fn held(values: &[Rc<Object>]) -> usize {
+    let mut seen: HashSet<*const ()> = HashSet::new();
+    let mut objects: Vec<Rc<Object>> = values.to_vec();
+    let mut envs: Vec<Env> = Vec::new();
+    let mut bytes = 0;
+    loop {
+        if let Some(obj) = objects.pop() {
+            if !seen.insert(Rc::as_ptr(&obj).cast()) {
+                continue;
+            }
+            bytes += obj.size();
+            match &*obj {
+                Object::Array(elements) => objects.extend(elements.iter().cloned()),
+                Object::Hash(pairs) => {
+                    for pair in pairs.values() {
+                        objects.push(Rc::clone(&pair.key));
+                        objects.push(Rc::clone(&pair.value));
+                    }
+                }
+                Object::ReturnValue(value) => objects.push(Rc::clone(value)),
+                Object::Function(function) | Object::Macro(function) => {
+                    envs.push(Rc::clone(function.env()))
+                }
+                Object::Closure(closure) => objects.extend(closure.free.iter().cloned()),
+                Object::Memoized(memoized) => objects.push(Rc::clone(memoized.function())),
+                Object::Iterator(iter) => match iter.source() {
+                    Source::Collection(collection) => objects.push(Rc::clone(collection)),
+                    Source::Range(..) => {}
+                    Source::Map(iterator, function) | Source::Filter(iterator, function) => {
+                        objects.push(Rc::clone(iterator));
+                        objects.push(Rc::clone(function));
+                    }
+                },
+                _ => {}
+            }
+        } else if let Some(env) = envs.pop() {
+            if !seen.insert(Rc::as_ptr(&env).cast()) {
+                continue;
+            }
+            let env = env.borrow();
+            let values: Vec<Rc<Object>> = env.values().cloned().collect();
+            bytes += Environment::size(values.len());
+            objects.extend(values);
+            envs.extend(env.outer().cloned());
+        } else {
+            return bytes;
+        }
+    }
+}
+
+// A copy of an environment and everything reachable from it that later
+// evaluation could change: the environments it encloses in and the ones
+// captured by the functions in it. Objects are immutable and shared with the
+// original, except for the ones that lead to a copied environment or an
+// iterator. Memoized functions start over with an empty cache.
+pub struct Snapshot {
+    env: Env,
+}
+
+impl Snapshot {
+    pub fn take(env: &Env) -> Snapshot {
+        let copy = gc::allocate(Environment::new());
+        let contents = Copier::new(env, &copy).run(env);
+        *copy.borrow_mut() = contents;
+        Snapshot { env: copy }
+    }
+
+    // replaces what the environment holds with a copy of the snapshot, so
+    // that the snapshot can be restored again later
+    pub fn restore(&self, env: &Env) {
+        let contents = Copier::new(&self.env, env).run(&self.env);
+        *env.borrow_mut() = contents;
+    }
+}
+
+// a copy of values that aren't in an environment, e.g. the globals of the
+// VM, in the same way as a snapshot copies the values of one
+pub fn copy_values(values: &[Rc<Object>]) -> Vec<Rc<Object>> {
+    let mut copier = Copier {
+        envs: HashMap::new(),
+        objects: HashMap::new(),
+        pending: Vec::new(),
+    };
+    let values = values.iter().map(|value| copier.object(value)).collect();
+    copier.finish();
+    values
+}
+
+// Copies the environments reachable from a root, mapping the root itself to
+// a given target. Environments are copied from a work list; objects are
+// copied recursively, but only the ones that can reach an environment.
+struct Copier {
+    envs: HashMap<*const (), Env>,
+    objects: HashMap<*const Object, Rc<Object>>,
+    pending: Vec<(Env, Env)>,
+}
+
+impl Copier {
+    fn new(root: &Env, target: &Env) -> Copier {
+        let mut envs = HashMap::new();
+        envs.insert(Rc::as_ptr(root).cast(), Rc::clone(target));
+        Copier {
+            envs,
+            objects: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // returns the contents of the root's copy, which the caller puts into
+    // the target
+    fn run(mut self, root: &Env) -> Environment {
+        let contents = self.copy_contents(root);
+        self.finish();
+        contents
+    }
+
+    fn finish(&mut self) {
+        while let Some((original, copy)) = self.pending.pop() {
+            let contents = self.copy_contents(&original);
+            *copy.borrow_mut() = contents;
+        }
+    }
+
+    fn copy_contents(&mut self, env: &Env) -> Environment {
+        let env = env.borrow();
+        let outer = env.outer().map(|outer| self.env(outer));
+        env.copy_with(|value| self.object(value), outer)
+    }
+
+    fn env(&mut self, env: &Env) -> Env {
+        let key = Rc::as_ptr(env).cast();
+        if let Some(copy) = self.envs.get(&key) {
+            return Rc::clone(copy);
+        }
+        let copy = gc::allocate(Environment::new());
+        self.envs.insert(key, Rc::clone(&copy));
+        self.pending.push((Rc::clone(env), Rc::clone(&copy)));
+        copy
+    }
+
+    fn object(&mut self, obj: &Rc<Object>) -> Rc<Object> {
+        let key = Rc::as_ptr(obj);
+        if let Some(copy) = self.objects.get(&key) {
+            return Rc::clone(copy);
+        }
+        let copy: Rc<Object> = match &**obj {
+            Object::Function(function) => {
+                let env = self.env(function.env());
+                Object::Function(function.with_env(env)).into()
+            }
+            Object::Macro(function) => {
+                let env = self.env(function.env());
+                Object::Macro(function.with_env(env)).into()
+            }
+            Object::Closure(closure) => Object::Closure(Closure {
+                function: Rc::clone(&closure.function),
+                free: closure
+                    .free
+                    .iter()
+                    .map(|value| self.object(value))
+                    .collect(),
+            })
+            .into(),
+            Object::Memoized(memoized) => {
+                let function = self.object(memoized.function());
+                Object::Memoized(Memoized::new(function)).into()
+            }
+            Object::ReturnValue(value) => Object::ReturnValue(self.object(value)).into(),
+            Object::Array(elements) => {
+                let elements = elements.iter().map(|e| self.object(e)).collect();
+                Object::Array(elements).into()
+            }
+            Object::Hash(pairs) => {
+                let pairs = pairs
+                    .iter()
+                    .map(|(key, pair)| {
+                        let pair = HashPair {
+                            key: Rc::clone(&pair.key),
+                            value: self.object(&pair.value),
+                        };
+                        (key.clone(), pair)
+                    })
+                    .collect();
+                Object::Hash(pairs).into()
+            }
+            Object::Iterator(iter) => match iter.source() {
+                Source::Collection(collection) => {
+                    let collection = self.object(collection);
+                    Object::Iterator(iter.with_source(Source::Collection(collection))).into()
+                }
+                Source::Range(..) => {
+                    Object::Iterator(iter.with_source(iter.source().clone())).into()
+                }
+                Source::Map(iterator, function) => {
+                    let source = Source::Map(self.object(iterator), self.object(function));
+                    Object::Iterator(iter.with_source(source)).into()
+                }
+                Source::Filter(iterator, function) => {
+                    let source = Source::Filter(self.object(iterator), self.object(function));
+                    Object::Iterator(iter.with_source(source)).into()
+                }
+            },
+            _ => Rc::clone(obj),
+        };
+        self.objects.insert(key, Rc::clone(&copy));
+        copy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::eval;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+
+    // the bindings of the environment itself, not including outer ones
+    fn bindings(env: &Env) -> Vec<Binding> {
+        env.borrow()
+            .bindings()
+            .into_iter()
+            .map(|(name, value)| binding(name, &value))
+            .collect()
+    }
+
+    fn run(input: &str, env: &Env) -> String {
+        let mut parser = Parser::new(Lexer::new(input));
+        eval(parser.parse_program(), env).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_bindings() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        run(
+            r#"let s = "hi"; let xs = [1, [2, 3]]; let f = fn(x) { x }; let long = "abcdefghij";"#,
+            &env,
+        );
+        run(
+            r#"let long = long + long + long + long + long + long + long;"#,
+            &env,
+        );
+
+        let bindings: Vec<String> = bindings(&env).iter().map(|b| b.to_string()).collect();
+        assert_eq!(
+            bindings,
+            vec![
+                "f: FUNCTION = fn(x) {...}".to_string(),
+                format!("long: STRING = \"{}...", &"abcdefghij".repeat(6)[..59]),
+                "s: STRING = \"hi\"".to_string(),
+                "xs: ARRAY = [1, [2, 3]]".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_held() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        run(
+            r#"let s = "abcd"; let xs = [s, s, [s]]; let f = fn() { xs };"#,
+            &env,
+        );
+        let value = |name: &str| env.borrow().get(&Symbol::intern(name)).unwrap();
+        let string = value("s").size();
+        let array = |len: usize| Object::Array(vec![value("s"); len]).size();
+        assert_eq!(held(&[value("s")]), string);
+        assert_eq!(
+            held(&[value("xs"), value("s")]),
+            string + array(3) + array(1)
+        );
+        // a function holds what its environment holds
+        assert!(held(&[value("f")]) > held(&[value("xs")]));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        run(
+            "let x = 1; let get = fn() { x }; let counter = fn() { let n = 1; fn() { n } }();",
+            &env,
+        );
+        let snapshot = Snapshot::take(&env);
+
+        run("let x = 2; let y = 3;", &env);
+        assert_eq!(run("get()", &env), "2");
+
+        snapshot.restore(&env);
+        assert_eq!(run("x", &env), "1");
+        // closures see the restored globals, not the ones they were copied from
+        assert_eq!(run("get()", &env), "1");
+        assert_eq!(run("counter()", &env), "1");
+        let mut parser = Parser::new(Lexer::new("y"));
+        assert!(eval(parser.parse_program(), &env).is_err());
+
+        // restoring doesn't use up the snapshot
+        run("let x = 5;", &env);
+        snapshot.restore(&env);
+        assert_eq!(run("get()", &env), "1");
+    }
+}
@@ -0,0 +1,618 @@
+use std::cell::Cell;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::ast::Program;
+use crate::builtins::{self, Native};
+use crate::engine::{Engine, Runner, Snapshot};
+use crate::evaluator::{CancelHandle, EvalConfig, EvalError, Metrics, Object};
+use crate::lexer::{LexError, Lexer};
+use crate::parser::{ParseError, Parser};
+use crate::sandbox::{self, Capabilities};
+use crate::symbol::Symbol;
+
+// what the code evaluates to
+pub type Value = Rc<Object>;
+
+// the error code raises while it runs
+pub type RuntimeError = EvalError;
+
+// Why code given to the interpreter didn't give a value. Each error has a
+// kind to match on, with a code that stays the same between versions, and
+// the span it's at. Nothing ran unless it's a runtime error.
+#[derive(Debug)]
+pub enum MonkError {
+    // the first character of the source that isn't part of any token
+    Lex(LexError),
+    // the syntax errors of the source, in the order they're in
+    Parse(Vec<ParseError>),
+    // the error the code raised while it ran, with its trace
+    Runtime(RuntimeError),
+    // the file couldn't be read
+    Io(io::Error),
+}
+
+impl Display for MonkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MonkError::Lex(error) => write!(f, "{}", error),
+            MonkError::Parse(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            MonkError::Runtime(error) => write!(f, "{}", error),
+            MonkError::Io(error) => write!(f, "can't read the file: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for MonkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MonkError::Lex(error) => Some(error),
+            MonkError::Parse(errors) => errors.first().map(|e| e as _),
+            MonkError::Runtime(error) => Some(error),
+            MonkError::Io(error) => Some(error),
+        }
+    }
+}
+
+// An interpreter to embed in other programs. It runs source one piece after
+// another, like the REPL, so the globals that one piece defines are there
+// for the next, until it's reset.
+//
+//   let mut interpreter = Interpreter::new();
+//   interpreter.eval("let double = fn(x) { x * 2 };")?;
+//   interpreter.eval("double(21)")?; // 42
+pub struct Interpreter {
+    runner: Runner,
+    config: EvalConfig,
+    // where puts writes and input is read from, none for stdout and stdin
+    output: Option<Box<dyn Write>>,
+    input: Option<Box<dyn BufRead>>,
+    cancel: CancelHandle,
+    capabilities: Capabilities,
+    metrics: Rc<Cell<Metrics>>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            runner: Runner::new(Engine::default()),
+            config: EvalConfig::default(),
+            output: None,
+            input: None,
+            cancel: CancelHandle::new(),
+            capabilities: Capabilities::none(),
+            metrics: Rc::default(),
+        }
+    }
+
+    // runs the code on the engine, which starts from empty globals
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.runner.set_engine(engine);
+        self
+    }
+
+    // runs the code with the limits and observers of the config
+    pub fn config(mut self, config: EvalConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // writes what the code puts to the output instead of stdout
+    pub fn output(mut self, output: impl Write + 'static) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    // reads the input of the code from the input instead of stdin
+    pub fn input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    // lets the code do what the capabilities allow, e.g. read files, which
+    // it may not do by default
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    // a handle that other threads can cancel the code that runs with
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    // what the code of the last eval that ran did, e.g. how many calls it
+    // made; an eval with syntax errors doesn't run
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.get()
+    }
+
+    // The value of the source, or why it has none: the first error of the
+    // lexer, the syntax errors or the error it raised. Like a failing program
+    // on the runner, the statements before the failing one keep what they
+    // did.
+    pub fn eval(&mut self, source: &str) -> Result<Value, MonkError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("monk").entered();
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.parse_program();
+        if let Some(error) = parser.lex_errors().first() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%error, "lex failed");
+            return Err(MonkError::Lex(error.clone()));
+        }
+        if !parser.errors.is_empty() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(errors = parser.errors.len(), "parse failed");
+            return Err(MonkError::Parse(parser.errors));
+        }
+        self.run(program)
+    }
+
+    // the same as eval, for hosts that run both strings and files
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, MonkError> {
+        self.eval(source)
+    }
+
+    fn run(&mut self, program: Program) -> Result<Value, MonkError> {
+        // a cancel from while nothing ran was meant for code that's done
+        self.cancel.take();
+        let _lent = Lent::new(&mut self.output, &mut self.input, self.capabilities);
+        let config = self
+            .config
+            .clone()
+            .cancel_with(self.cancel.clone())
+            .record_metrics(Rc::clone(&self.metrics));
+        self.runner
+            .run_with_config(program, config)
+            .map_err(MonkError::Runtime)
+    }
+
+    // runs the script in the file, like eval
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<Value, MonkError> {
+        let source = fs::read_to_string(path).map_err(MonkError::Io)?;
+        self.eval(&source)
+    }
+
+    // Defines a global function that calls back into the host. Like the
+    // globals the code defines, it's forgotten on a reset or a switch of
+    // engines.
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        func: impl Fn(&[Value]) -> Result<Value, EvalError> + 'static,
+    ) {
+        let name = Symbol::intern(name);
+        let native = Native {
+            name: name.clone(),
+            func: Rc::new(func),
+        };
+        self.runner.define(name, Rc::new(Object::Native(native)));
+    }
+
+    // the globals that the code defined so far
+    pub(crate) fn globals(&self) -> Vec<(Symbol, Value)> {
+        self.runner.bindings()
+    }
+
+    // A copy of the globals and macros that the code defined so far, to
+    // restore later, e.g. as a checkpoint before running code that may leave
+    // them in a bad state.
+    pub fn snapshot(&self) -> Snapshot {
+        self.runner.snapshot()
+    }
+
+    // puts back the globals and macros as they were when the snapshot was
+    // taken, forgetting the ones defined since
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.runner.restore(snapshot);
+    }
+
+    // forgets every global and macro that the code defined
+    pub fn reset(&mut self) {
+        self.runner.reset();
+    }
+}
+
+// The streams and capabilities of an interpreter, which are the builtins'
+// while its code runs. They're handed back when it's dropped, even when the
+// code panics and the host catches it, as other interpreters on the thread
+// may have their own.
+struct Lent<'a> {
+    output: &'a mut Option<Box<dyn Write>>,
+    input: &'a mut Option<Box<dyn BufRead>>,
+    // what the builtins had before, to give back to them
+    outer_output: Option<Box<dyn Write>>,
+    outer_input: Option<Box<dyn BufRead>>,
+    outer_capabilities: Capabilities,
+}
+
+impl<'a> Lent<'a> {
+    fn new(
+        output: &'a mut Option<Box<dyn Write>>,
+        input: &'a mut Option<Box<dyn BufRead>>,
+        capabilities: Capabilities,
+    ) -> Self {
+        Lent {
+            outer_output: builtins::set_output(output.take()),
+            outer_input: builtins::set_input(input.take()),
+            outer_capabilities: sandbox::set_capabilities(capabilities),
+            output,
+            input,
+        }
+    }
+}
+
+impl Drop for Lent<'_> {
+    fn drop(&mut self) {
+        *self.output = builtins::set_output(self.outer_output.take());
+        *self.input = builtins::set_input(self.outer_input.take());
+        sandbox::set_capabilities(self.outer_capabilities);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::ErrorKind;
+    use crate::lexer::LexErrorKind;
+    use crate::parser::ParseErrorKind;
+    use crate::sandbox::Capability;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_eval() {
+        let inputs = vec![
+            ("let double = fn(x) { x * 2 }; 1", "1"),
+            ("double(21)", "42"),
+            (
+                "let x = ;",
+                "error: no prefix parse function for SEMICOLON at line 1, column 9",
+            ),
+            ("let y = 1; 1 / 0", "error: division by zero: 1 / 0"),
+            ("y", "1"),
+        ];
+        for engine in Engine::ALL {
+            let mut interpreter = Interpreter::new().engine(engine);
+            for (input, expected) in &inputs {
+                let result = match interpreter.eval(input) {
+                    Ok(obj) => obj.to_string(),
+                    Err(error) => format!("error: {}", error),
+                };
+                assert_eq!(result, *expected, "{} on {}", input, engine);
+            }
+
+            interpreter.reset();
+            assert_eq!(
+                interpreter.eval("double(1)").unwrap_err().to_string(),
+                "identifier not found: double"
+            );
+        }
+
+        let mut interpreter = Interpreter::new().config(EvalConfig::default().fuel(10));
+        assert!(interpreter.eval("let f = fn(n) { f(n) }; f(1)").is_err());
+    }
+
+    #[test]
+    fn test_errors_and_files() {
+        let mut interpreter = Interpreter::new();
+        let Err(MonkError::Lex(error)) = interpreter.eval("let x = 1 @ 2;") else {
+            panic!("not a lex error");
+        };
+        assert_eq!(error.kind, LexErrorKind::UnexpectedCharacter);
+        assert_eq!(error.kind.code(), "E0001");
+        assert_eq!(
+            error.to_string(),
+            "unexpected character '@' at line 1, column 11"
+        );
+
+        let Err(MonkError::Parse(errors)) = interpreter.eval("let = 1; let x 2;") else {
+            panic!("not a parse error");
+        };
+        let kinds: Vec<ParseErrorKind> = errors.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ParseErrorKind::UnexpectedToken,
+                ParseErrorKind::ExpectedToken
+            ]
+        );
+        assert_eq!(errors[1].kind.code(), "E0102");
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "no prefix parse function for ASSIGN at line 1, column 5",
+                "expected next token to be ASSIGN, got INT(2) instead at line 1, column 16",
+            ]
+        );
+
+        let Err(MonkError::Runtime(error)) = interpreter.eval("let f = fn() { 1 / 0 }; f()") else {
+            panic!("not a runtime error");
+        };
+        assert_eq!(error.kind, ErrorKind::DivisionByZero);
+        assert_eq!(error.kind.code(), "E0204");
+        assert_eq!(error.trace.len(), 1);
+        assert_eq!(
+            error.span.map(|span| span.to_string()),
+            Some("line 1, column 16".to_string())
+        );
+
+        assert_eq!(*interpreter.eval_str("f; 2").unwrap(), Object::Integer(2));
+        let path = std::env::temp_dir().join("return_to_monk_test_eval_file.mk");
+        fs::write(&path, "let n = 20;\nn + 22").unwrap();
+        assert_eq!(*interpreter.eval_file(&path).unwrap(), Object::Integer(42));
+        assert_eq!(*interpreter.eval("n").unwrap(), Object::Integer(20));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            interpreter.eval_file(&path),
+            Err(MonkError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        for engine in Engine::ALL {
+            let mut interpreter = Interpreter::new().engine(engine);
+            interpreter
+                .eval("let counter = fn() { let n = 1; fn() { n } }(); let x = 1;")
+                .unwrap();
+            let snapshot = interpreter.snapshot();
+            interpreter.eval("let x = 2; let y = 3;").unwrap();
+            interpreter.restore(&snapshot);
+            assert_eq!(
+                interpreter.eval("[x, counter()]").unwrap().to_string(),
+                "[1, 1]"
+            );
+            assert!(interpreter.eval("y").is_err(), "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_cancel_handle() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval("let x = 1;").unwrap();
+        interpreter.cancel_handle().cancel();
+        assert_eq!(*interpreter.eval("x + 1").unwrap(), Object::Integer(2));
+
+        let cancel = interpreter.cancel_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let Err(MonkError::Runtime(error)) =
+            interpreter.eval("for (i in range(0, 1000000000)) { i }")
+        else {
+            panic!("not cancelled");
+        };
+        canceller.join().unwrap();
+        assert_eq!(error.kind, ErrorKind::Cancelled);
+        assert_eq!(*interpreter.eval("x").unwrap(), Object::Integer(1));
+    }
+
+    #[test]
+    fn test_metrics() {
+        for engine in Engine::ALL {
+            let mut interpreter = Interpreter::new().engine(engine);
+            assert_eq!(interpreter.metrics(), Metrics::default());
+
+            interpreter
+                .eval("let f = fn(n) { if (n > 0) { f(n - 1) } else { [n] } }; f(3)")
+                .unwrap();
+            let metrics = interpreter.metrics();
+            assert!(metrics.steps > 0, "{}", engine);
+            assert_eq!(metrics.calls, 4, "{}", engine);
+            assert_eq!(metrics.max_depth, 4, "{}", engine);
+            assert!(metrics.allocations >= 5, "{}", engine);
+            assert!(metrics.bytes > 0, "{}", engine);
+
+            // the metrics are those of the last eval, even when it fails
+            interpreter.eval("len(1, 2)").unwrap_err();
+            let metrics = interpreter.metrics();
+            assert_eq!((metrics.calls, metrics.max_depth), (1, 1), "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let input = || io::Cursor::new("first\nsecond\n");
+        let mut interpreter = Interpreter::new().input(input());
+        assert_eq!(
+            interpreter.eval("input()").unwrap_err().to_string(),
+            "`input` needs reading stdin, which the sandbox denies"
+        );
+        let mut interpreter = Interpreter::new()
+            .input(input())
+            .capabilities(Capabilities::none().allow(Capability::Stdin));
+        let result = interpreter.eval("[input(), input(), input()]").unwrap();
+        assert_eq!(result.to_string(), "[\"first\", \"second\", null]");
+        assert!(interpreter.eval("getenv(\"HOME\")").is_err());
+    }
+
+    #[test]
+    fn test_register_fn() {
+        for engine in Engine::ALL {
+            let mut interpreter = Interpreter::new().engine(engine);
+            let calls = Rc::new(std::cell::Cell::new(0));
+            interpreter.register_fn("count", {
+                let calls = Rc::clone(&calls);
+                move |args| {
+                    calls.set(calls.get() + 1);
+                    Ok(Value::new((args.len() as i64).into()))
+                }
+            });
+            interpreter.register_fn("fail", |_| {
+                Err(EvalError::new(ErrorKind::User, "failed in the host"))
+            });
+            let result = interpreter.eval("let f = fn(x) { count(x, x) }; f(1) + count()");
+            assert_eq!(result.unwrap().to_string(), "2", "{}", engine);
+            assert_eq!(calls.get(), 2);
+            assert_eq!(
+                interpreter.eval("count").unwrap().to_string(),
+                "builtin function count"
+            );
+            let Err(MonkError::Runtime(error)) = interpreter.eval("fail()") else {
+                panic!("fail didn't fail");
+            };
+            assert_eq!(error.message, "failed in the host");
+            assert_eq!(error.trace.len(), 1, "{}", engine);
+        }
+    }
+
+    // a buffer that the test can read after the interpreter wrote to it
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output() {
+        for engine in Engine::ALL {
+            let (first, second) = (Buffer::default(), Buffer::default());
+            let mut interpreter = Interpreter::new().engine(engine).output(first.clone());
+            let mut other = Interpreter::new().engine(engine).output(second.clone());
+            interpreter.eval("puts(1, \"two\")").unwrap();
+            other.eval("let f = fn() { puts([3]) }; f()").unwrap();
+            interpreter.eval("puts(true)").unwrap();
+            assert_eq!(*first.0.borrow(), b"1\ntwo\ntrue\n", "{}", engine);
+            assert_eq!(*second.0.borrow(), b"[3]\n", "{}", engine);
+        }
+        // nothing is left behind for the code that runs without an interpreter
+        assert!(builtins::set_output(None).is_none());
+    }
+
+    #[test]
+    fn test_task_output() {
+        for engine in Engine::ALL {
+            let buffer = Buffer::default();
+            let mut interpreter = Interpreter::new()
+                .engine(engine)
+                .output(buffer.clone())
+                .capabilities(Capabilities::all());
+            let source = "let task = spawn(fn(n) { puts(n, n + 1) }, 1); puts(0); join(task)";
+            interpreter.eval(source).unwrap();
+            assert_eq!(*buffer.0.borrow(), b"0\n1\n2\n", "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_output_after_panic() {
+        let buffer = Buffer::default();
+        let mut interpreter = Interpreter::new()
+            .output(buffer.clone())
+            .capabilities(Capabilities::all());
+        interpreter.register_fn("crash", |_| panic!("host function"));
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            interpreter.eval("puts(1); crash()")
+        }));
+        assert!(crashed.is_err());
+        // the thread's builtins have what they had before, and the
+        // interpreter its output back
+        assert!(builtins::set_output(None).is_none());
+        assert_eq!(sandbox::capabilities(), Capabilities::none());
+        interpreter.eval("puts(2)").unwrap();
+        assert_eq!(*buffer.0.borrow(), b"1\n2\n");
+    }
+
+    // the names of the spans that were entered and the messages of the
+    // events, in order
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Traced(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Traced {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut traced = self.0.lock().unwrap();
+            traced.push(span.metadata().name().to_string());
+            tracing::span::Id::from_u64(traced.len() as u64)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Message(String);
+
+            impl tracing::field::Visit for Message {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        let tests = vec![
+            (
+                Engine::TreeWalker,
+                "1 + 2",
+                vec!["monk", "eval", "evaluated"],
+            ),
+            (
+                Engine::Vm,
+                "1 + 2",
+                vec!["monk", "eval", "compile", "compiled", "evaluated"],
+            ),
+            (Engine::Vm, "1 @", vec!["monk", "lex failed"]),
+            (Engine::Vm, "let = @", vec!["monk", "lex failed"]),
+            (Engine::Vm, "let = 1", vec!["monk", "parse failed"]),
+            (
+                Engine::TreeWalker,
+                "1 / 0",
+                vec!["monk", "eval", "eval failed"],
+            ),
+        ];
+        for (engine, source, expected) in tests {
+            let traced = Traced::default();
+            let mut interpreter = Interpreter::new().engine(engine);
+            tracing::subscriber::with_default(traced.clone(), || {
+                let _ = interpreter.eval(source);
+            });
+            assert_eq!(
+                *traced.0.lock().unwrap(),
+                expected,
+                "{} on {}",
+                source,
+                engine
+            );
+        }
+    }
+}
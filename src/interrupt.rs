@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::evaluator::CancelHandle;
+
+// Ctrl+C. While the REPL waits for input it cancels the line that's being
+// typed: the signal is only recorded, and the read it interrupts returns so
+// the REPL can check for it. While the REPL runs code it cancels the code
+// through its handle, which stops it at its next step with a CANCELLED
+// error. At any other time it ends the process like it would without a
+// handler.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static WAITING_FOR_INPUT: AtomicBool = AtomicBool::new(false);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static CANCEL: OnceLock<CancelHandle> = OnceLock::new();
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+
+    extern "C" {
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        // makes the signal interrupt the system call it arrives in, instead of
+        // restarting it
+        pub fn siginterrupt(signum: c_int, flag: c_int) -> c_int;
+        pub fn _exit(status: c_int) -> !;
+        #[cfg(test)]
+        pub fn raise(signum: c_int) -> c_int;
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: std::os::raw::c_int) {
+    if WAITING_FOR_INPUT.load(Ordering::SeqCst) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        return;
+    }
+    match CANCEL.get() {
+        Some(cancel) if RUNNING.load(Ordering::SeqCst) => cancel.cancel(),
+        // the exit status of a process killed by SIGINT
+        _ => unsafe { sys::_exit(130) },
+    }
+}
+
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGINT, on_interrupt);
+        sys::siginterrupt(sys::SIGINT, 1);
+    }
+}
+
+// whether Ctrl+C was pressed since the last time this was asked
+pub fn take() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+pub fn set_waiting_for_input(waiting: bool) {
+    WAITING_FOR_INPUT.store(waiting, Ordering::SeqCst);
+}
+
+// Sets the handle that Ctrl+C cancels while code runs. There's one for the
+// process, so only the first one that's set counts.
+pub fn cancel_with(cancel: CancelHandle) {
+    let _ = CANCEL.set(cancel);
+}
+
+// Runs code that Ctrl+C cancels rather than ending the process. A cancel
+// that nothing ran to see, e.g. one while a value was being printed, is
+// dropped rather than left to cancel the next input.
+pub fn cancellable<T>(run: impl FnOnce() -> T) -> T {
+    RUNNING.store(true, Ordering::SeqCst);
+    let result = run();
+    RUNNING.store(false, Ordering::SeqCst);
+    if let Some(cancel) = CANCEL.get() {
+        cancel.take();
+    }
+    result
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, Runner};
+    use crate::evaluator::{ErrorKind, EvalConfig};
+    use crate::parser;
+
+    // one test, as the signal goes to the whole process
+    #[test]
+    fn test_interrupt() {
+        install();
+        set_waiting_for_input(true);
+        assert!(!take());
+        unsafe { sys::raise(sys::SIGINT) };
+        assert!(take());
+        assert!(!take());
+        set_waiting_for_input(false);
+
+        let cancel = CancelHandle::new();
+        cancel_with(cancel.clone());
+        let program = parser::parse("let f = fn(n) { f(n + 1) }; f(0)").unwrap();
+        let evaluated = cancellable(|| {
+            unsafe { sys::raise(sys::SIGINT) };
+            Runner::new(Engine::default())
+                .run_with_config(program, EvalConfig::default().cancel_with(cancel))
+        });
+        assert_eq!(evaluated.unwrap_err().kind, ErrorKind::Cancelled);
+        assert!(!take());
+    }
+}
@@ -0,0 +1,242 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::builtins;
+use crate::evaluator::{integer_object, native_bool_to_boolean_object, null_object, Object};
+use crate::printer;
+
+// What next() steps through, one value at a time: the elements of an array,
+// the [key, value] pairs of a hash in the order they were inserted, or the
+// integers of a range, which are only made as they're asked for.
+//
+// map() and filter() make lazy iterators over another one, which call their
+// function on a value only once it's asked for, so a pipeline over a large
+// range never holds more than one value at a time. Over an array they give
+// an array, as they did before there were iterators.
+#[derive(Debug, PartialEq)]
+pub struct Iter {
+    source: Source,
+    // how many values it has given
+    position: Cell<usize>,
+    // the value, or the end, that done() looked ahead at in a lazy iterator,
+    // for next() to give
+    peeked: RefCell<Option<Option<Rc<Object>>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    // an array or a hash
+    Collection(Rc<Object>),
+    // from the start up to but not including the end
+    Range(i64, i64),
+    // the values of the iterator, each passed through the function
+    Map(Rc<Object>, Rc<Object>),
+    // the values of the iterator that the function gives a truthy value for
+    Filter(Rc<Object>, Rc<Object>),
+}
+
+impl Iter {
+    pub fn new(source: Source) -> Iter {
+        Iter {
+            source,
+            position: Cell::new(0),
+            peeked: RefCell::default(),
+        }
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    // the same iterator over another source, as far along as this one
+    pub fn with_source(&self, source: Source) -> Iter {
+        Iter {
+            source,
+            position: self.position.clone(),
+            peeked: self.peeked.clone(),
+        }
+    }
+
+    // whether it calls functions for its values, which only the engines
+    // can do, through step
+    pub fn is_lazy(&self) -> bool {
+        matches!(self.source, Source::Map(..) | Source::Filter(..))
+    }
+
+    // the next value, or none once they've all been given; a lazy iterator
+    // gives none
+    pub fn next(&self) -> Option<Rc<Object>> {
+        let position = self.position.get();
+        let value = match &self.source {
+            Source::Collection(collection) => match &**collection {
+                Object::Array(elements) => elements.get(position).cloned(),
+                Object::Hash(pairs) => pairs.get_index(position).map(|(_, pair)| {
+                    Object::Array(vec![Rc::clone(&pair.key), Rc::clone(&pair.value)]).into()
+                }),
+                _ => None,
+            },
+            Source::Range(start, end) => range_value(*start, *end, position).map(integer_object),
+            Source::Map(..) | Source::Filter(..) => None,
+        };
+        if value.is_some() {
+            self.position.set(position + 1);
+        }
+        value
+    }
+
+    // whether next() has nothing left to give
+    pub fn is_done(&self) -> bool {
+        let position = self.position.get();
+        match &self.source {
+            Source::Collection(collection) => match &**collection {
+                Object::Array(elements) => position >= elements.len(),
+                Object::Hash(pairs) => position >= pairs.len(),
+                _ => true,
+            },
+            Source::Range(start, end) => range_value(*start, *end, position).is_none(),
+            Source::Map(..) | Source::Filter(..) => matches!(*self.peeked.borrow(), Some(None)),
+        }
+    }
+}
+
+impl Display for Iter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Source::Collection(collection) => {
+                write!(f, "iter({})", printer::render_line(collection))
+            }
+            Source::Range(start, end) => write!(f, "range({}, {})", start, end),
+            Source::Map(iterator, function) => write!(f, "map({}, {})", iterator, function),
+            Source::Filter(iterator, function) => {
+                write!(f, "filter({}, {})", iterator, function)
+            }
+        }
+    }
+}
+
+fn range_value(start: i64, end: i64, position: usize) -> Option<i64> {
+    i64::try_from(position)
+        .ok()
+        .and_then(|position| start.checked_add(position))
+        .filter(|value| *value < end)
+}
+
+// A value that can take calls of monk functions to work out. Builtins can't
+// call functions, so they give this to the engine, which makes each call the
+// way a call in the program would and passes the result on to what comes
+// after it.
+pub enum Step<T> {
+    Value(T),
+    // the function, its argument, and what to do with its result
+    Call(
+        Rc<Object>,
+        Rc<Object>,
+        Box<dyn FnOnce(Rc<Object>) -> Step<T>>,
+    ),
+}
+
+impl<T: 'static> Step<T> {
+    fn and_then<U: 'static>(self, then: impl FnOnce(T) -> Step<U> + 'static) -> Step<U> {
+        match self {
+            Step::Value(value) => then(value),
+            Step::Call(function, arg, resume) => Step::Call(
+                function,
+                arg,
+                Box::new(move |result| resume(result).and_then(then)),
+            ),
+        }
+    }
+}
+
+// What next() or done() give for a lazy iterator, or map() or filter() for
+// an array, which the engines work out in place of the builtin; none for
+// any other call.
+pub fn step(builtin: &str, args: &[Rc<Object>]) -> Option<Step<Rc<Object>>> {
+    if let ("map" | "filter", [array, _]) = (builtin, args) {
+        if !matches!(**array, Object::Array(_)) {
+            return None;
+        }
+        // the builtin checks the arguments, and gives its error when the
+        // engine calls it in turn
+        let iterator = (builtins::lookup(builtin)?.func)(args).ok()?;
+        return Some(collect(iterator, Vec::new()));
+    }
+    let [iterator] = args else {
+        return None;
+    };
+    match &**iterator {
+        Object::Iterator(iter) if iter.is_lazy() => {}
+        _ => return None,
+    }
+    match builtin {
+        "next" => {
+            Some(pull(iterator).and_then(|value| Step::Value(value.unwrap_or_else(null_object))))
+        }
+        "done" => {
+            let iterator = Rc::clone(iterator);
+            Some(pull(&iterator).and_then(move |value| {
+                let done = value.is_none();
+                if let Object::Iterator(iter) = &*iterator {
+                    iter.peeked.replace(Some(value));
+                }
+                Step::Value(native_bool_to_boolean_object(done))
+            }))
+        }
+        _ => None,
+    }
+}
+
+// the values the iterator has left, in an array
+fn collect(iterator: Rc<Object>, mut values: Vec<Rc<Object>>) -> Step<Rc<Object>> {
+    pull(&iterator).and_then(move |value| match value {
+        Some(value) => {
+            values.push(value);
+            collect(iterator, values)
+        }
+        None => Step::Value(Object::Array(values).into()),
+    })
+}
+
+// the next value of the iterator, or none at its end
+fn pull(iterator: &Rc<Object>) -> Step<Option<Rc<Object>>> {
+    let Object::Iterator(iter) = &**iterator else {
+        return Step::Value(None);
+    };
+    if let Some(peeked) = iter.peeked.take() {
+        return Step::Value(peeked);
+    }
+    match &iter.source {
+        Source::Map(inner, function) => {
+            let function = Rc::clone(function);
+            pull(inner).and_then(move |value| match value {
+                Some(value) => Step::Call(
+                    function,
+                    value,
+                    Box::new(|result| Step::Value(Some(result))),
+                ),
+                None => Step::Value(None),
+            })
+        }
+        Source::Filter(inner, function) => filter(Rc::clone(inner), Rc::clone(function)),
+        _ => Step::Value(iter.next()),
+    }
+}
+
+// the next value of the inner iterator that the function keeps
+fn filter(inner: Rc<Object>, function: Rc<Object>) -> Step<Option<Rc<Object>>> {
+    pull(&inner).and_then(move |value| match value {
+        Some(value) => Step::Call(
+            Rc::clone(&function),
+            Rc::clone(&value),
+            Box::new(move |keep| {
+                if keep.is_truthy() {
+                    Step::Value(Some(value))
+                } else {
+                    filter(inner, function)
+                }
+            }),
+        ),
+        None => Step::Value(None),
+    })
+}
@@ -0,0 +1,998 @@
+use std::collections::{BTreeSet, HashMap};
+use std::mem::{self, offset_of};
+use std::rc::Rc;
+use std::time::Instant;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature, StackSlotData,
+    StackSlotKind, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use crate::code::{read_u16, Opcode};
+use crate::evaluator::{
+    integer_object, native_bool_to_boolean_object, Budget, CancelHandle, CompiledFunction,
+    EvalConfig, Object,
+};
+
+// The JIT of the jit engine, which is the VM with hot functions compiled to
+// native code by Cranelift. A function is hot once the VM has called it
+// HOT_CALLS times, and is compiled if native code can do all of it: integer
+// arithmetic and comparisons on its parameters and locals, ifs, and calls
+// to itself. It takes integers and gives back an integer or a boolean.
+// Anything else, like a string, a builtin or another function, leaves the
+// function to the VM.
+//
+// Such a function has no side effects, so native code that can't go on, on
+// an overflow, a division by zero, a call too deep or the end of the fuel or
+// time, gives up and the VM runs the call again from its start, raising the
+// error with its trace just as it would have without the JIT. The function
+// is left to the VM from then on. Native code counts the steps and calls
+// the VM would have made, so the fuel and those metrics come out the same,
+// but it doesn't count its allocations, and doesn't run under observers or
+// a memory limit.
+
+// calls to a function before it's compiled
+#[cfg(not(test))]
+const HOT_CALLS: usize = 100;
+// the tests run every function they can as native code
+#[cfg(test)]
+const HOT_CALLS: usize = 1;
+
+// how deep native code calls itself before it leaves the call to the VM,
+// which keeps its frames on the heap rather than on the thread's stack
+const NATIVE_DEPTH: i64 = 4000;
+
+// steps between checks of the deadline and the cancel
+const POLL_INTERVAL: i64 = 1024;
+
+// the function native code calls to check the deadline and the cancel
+const POLL: &str = "monk_jit_poll";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Integer,
+    Boolean,
+}
+
+// What native code reads and updates while it runs. Native code knows the
+// fields up to bailed by their offsets; poll reads the rest.
+#[repr(C)]
+struct Context {
+    // the steps taken, and the count at which poll is called next
+    steps: i64,
+    next_poll: i64,
+    // the calls in progress, counting those of the VM, and how many there
+    // may be before native code gives up
+    depth: i64,
+    depth_limit: i64,
+    deepest: i64,
+    calls: i64,
+    // set once native code gave up
+    bailed: i64,
+    // the steps there's fuel for
+    fuel: i64,
+    deadline: Option<Instant>,
+    cancel: Option<CancelHandle>,
+}
+
+// gives up once the fuel or time runs out or the eval is cancelled, or else
+// sets when to be called next
+extern "C" fn poll(context: *mut Context) -> i64 {
+    // SAFETY: native code passes on the context that Jit::call gave it
+    let context = unsafe { &mut *context };
+    let expired = context.steps > context.fuel
+        || context
+            .cancel
+            .as_ref()
+            .is_some_and(CancelHandle::is_cancelled)
+        || context
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+    if expired {
+        context.bailed = 1;
+        return 1;
+    }
+    context.next_poll =
+        (context.steps.saturating_add(POLL_INTERVAL)).min(context.fuel.saturating_add(1));
+    0
+}
+
+// a compiled function, which takes the context and its arguments
+type NativeFn = unsafe extern "C" fn(*mut Context, *const i64) -> i64;
+
+struct Native {
+    code: NativeFn,
+    returns: Type,
+    // the globals the function calls itself through, which have to still
+    // hold it when it's called
+    globals: Vec<usize>,
+}
+
+enum State {
+    // called this many times, and not compiled yet
+    Counting(usize),
+    Compiled(Native),
+    // does what native code can't, or its native code gave up once
+    Interpreted,
+}
+
+pub struct Jit {
+    // none only while it's dropped
+    module: Option<JITModule>,
+    context: cranelift_codegen::Context,
+    poll: FuncId,
+    // by the address of the function, which the Rc keeps from being reused
+    functions: HashMap<*const CompiledFunction, (Rc<CompiledFunction>, State)>,
+}
+
+impl Jit {
+    // the JIT for the machine it runs on, none if Cranelift can't generate
+    // code for it
+    pub fn new() -> Option<Jit> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        flags.set("is_pic", "false").ok()?;
+        flags.set("use_colocated_libcalls", "false").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol(POLL, poll as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(types::I64));
+        signature.returns.push(AbiParam::new(types::I64));
+        let poll = module
+            .declare_function(POLL, Linkage::Import, &signature)
+            .ok()?;
+        Some(Jit {
+            context: module.make_context(),
+            module: Some(module),
+            poll,
+            functions: HashMap::new(),
+        })
+    }
+
+    // Runs the call of the function on the arguments as native code, if the
+    // function is hot and native code can do it, and gives back its value.
+    // None leaves the call to the VM. Depth is the number of calls in
+    // progress with this one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn call(
+        &mut self,
+        function: &Rc<CompiledFunction>,
+        args: &[Rc<Object>],
+        globals: &[Option<Rc<Object>>],
+        constants: &[Rc<Object>],
+        config: &EvalConfig,
+        budget: &mut Budget,
+        depth: usize,
+    ) -> Option<Rc<Object>> {
+        if !config.allows_native_code() {
+            return None;
+        }
+        let key = Rc::as_ptr(function);
+        let (_, state) = self
+            .functions
+            .entry(key)
+            .or_insert_with(|| (Rc::clone(function), State::Counting(0)));
+        if let State::Counting(calls) = state {
+            *calls += 1;
+            if *calls < HOT_CALLS {
+                return None;
+            }
+            let compiled = match self.compile(function, globals, constants) {
+                Some(native) => State::Compiled(native),
+                None => State::Interpreted,
+            };
+            self.functions.get_mut(&key)?.1 = compiled;
+        }
+        let Some((_, State::Compiled(native))) = self.functions.get(&key) else {
+            return None;
+        };
+
+        // a call that isn't like the ones the function was compiled for is
+        // the VM's
+        if args.len() != function.parameters.len()
+            || !native
+                .globals
+                .iter()
+                .all(|&index| calls_itself(globals.get(index), function))
+        {
+            return None;
+        }
+        let args = args
+            .iter()
+            .map(|arg| match **arg {
+                Object::Integer(value) => Some(value),
+                _ => None,
+            })
+            .collect::<Option<Vec<i64>>>()?;
+
+        let fuel = budget
+            .fuel_left()
+            .map_or(i64::MAX, |fuel| fuel.min(i64::MAX as u64) as i64);
+        let depth = depth as i64;
+        let mut context = Context {
+            steps: 0,
+            next_poll: POLL_INTERVAL.min(fuel.saturating_add(1)),
+            depth,
+            depth_limit: (config.depth_limit() as i64 + 1).min(depth + NATIVE_DEPTH),
+            deepest: 0,
+            calls: 0,
+            bailed: 0,
+            fuel,
+            deadline: budget.deadline(),
+            cancel: budget.cancel().cloned(),
+        };
+        // SAFETY: the code was compiled for the arguments, which there are
+        // as many of as it has parameters, and it only reads the context
+        let value = unsafe { (native.code)(&mut context, args.as_ptr()) };
+        let returns = native.returns;
+        if context.bailed != 0 {
+            self.functions.get_mut(&key)?.1 = State::Interpreted;
+            return None;
+        }
+        budget.charge(
+            context.steps as u64,
+            context.calls as u64,
+            context.deepest as usize,
+        );
+        Some(match returns {
+            Type::Integer => integer_object(value),
+            Type::Boolean => native_bool_to_boolean_object(value != 0),
+        })
+    }
+
+    // compiles the function to native code, or none if it does something
+    // native code can't
+    fn compile(
+        &mut self,
+        function: &CompiledFunction,
+        globals: &[Option<Rc<Object>>],
+        constants: &[Rc<Object>],
+    ) -> Option<Native> {
+        let instructions = decode(&function.instructions.0)?;
+        let module = self.module.as_mut()?;
+        let signature = signature(module);
+        let id = module.declare_anonymous_function(&signature).ok()?;
+
+        // what it gives back is whatever it can be compiled for
+        let (returns, self_globals) =
+            [Type::Integer, Type::Boolean]
+                .into_iter()
+                .find_map(|returns| {
+                    self.context.func.signature = signature.clone();
+                    let mut builder_context = FunctionBuilderContext::new();
+                    let builder =
+                        FunctionBuilder::new(&mut self.context.func, &mut builder_context);
+                    let translation = Translation {
+                        module,
+                        builder,
+                        function,
+                        globals,
+                        constants,
+                        returns,
+                        id,
+                        poll: self.poll,
+                    };
+                    let translated = translation.translate(&instructions);
+                    if translated.is_none() {
+                        module.clear_context(&mut self.context);
+                    }
+                    translated.map(|self_globals| (returns, self_globals))
+                })?;
+
+        let defined = module.define_function(id, &mut self.context);
+        module.clear_context(&mut self.context);
+        defined.ok()?;
+        module.finalize_definitions().ok()?;
+        // SAFETY: the code was compiled with the signature of a NativeFn
+        let code =
+            unsafe { mem::transmute::<*const u8, NativeFn>(module.get_finalized_function(id)) };
+        Some(Native {
+            code,
+            returns,
+            globals: self_globals,
+        })
+    }
+
+    // how many functions run as native code
+    #[cfg(test)]
+    fn compiled(&self) -> usize {
+        self.functions
+            .values()
+            .filter(|(_, state)| matches!(state, State::Compiled(_)))
+            .count()
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: no native code runs anymore, as only call runs it
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+// whether the global holds the function, so a call through it is a call of
+// the function to itself
+fn calls_itself(global: Option<&Option<Rc<Object>>>, function: &Rc<CompiledFunction>) -> bool {
+    match global.and_then(Option::as_deref) {
+        Some(Object::Closure(closure)) => Rc::ptr_eq(&closure.function, function),
+        _ => false,
+    }
+}
+
+// the signature of every compiled function: the context and the arguments
+fn signature(module: &JITModule) -> Signature {
+    let mut signature = module.make_signature();
+    signature.params.push(AbiParam::new(types::I64));
+    signature.params.push(AbiParam::new(types::I64));
+    signature.returns.push(AbiParam::new(types::I64));
+    signature
+}
+
+// the instructions with their offset and operand, none if one of them has
+// more than one operand, which native code doesn't do anyway
+fn decode(code: &[u8]) -> Option<Vec<(usize, Opcode, usize)>> {
+    let mut instructions = Vec::new();
+    let mut ip = 0;
+    while ip < code.len() {
+        let op = Opcode::from_byte(code[ip])?;
+        let operand = match op.operand_widths() {
+            [] => 0,
+            [1] => *code.get(ip + 1)? as usize,
+            [2] => read_u16(code.get(ip + 1..ip + 3)?),
+            _ => return None,
+        };
+        instructions.push((ip, op, operand));
+        ip += op.width();
+    }
+    Some(instructions)
+}
+
+// what's on the operand stack: a value native code computed, or the
+// function itself, which is about to be called
+#[derive(Clone, Copy)]
+enum Item {
+    Value(Value, Type),
+    Callee,
+}
+
+// a place in the code that a jump goes to, with the types of the stack and
+// the locals there once a jump to it has been compiled
+struct Target {
+    block: Block,
+    stack: Option<Vec<Type>>,
+    locals: Vec<Option<Type>>,
+}
+
+// The translation of one function to Cranelift IR, which gives up with none
+// at the first thing native code can't do. The VM only jumps forward, so
+// everything that jumps to an instruction is translated before it is.
+struct Translation<'a, 'b> {
+    module: &'a mut JITModule,
+    builder: FunctionBuilder<'b>,
+    function: &'a CompiledFunction,
+    globals: &'a [Option<Rc<Object>>],
+    constants: &'a [Rc<Object>],
+    returns: Type,
+    id: FuncId,
+    poll: FuncId,
+}
+
+impl Translation<'_, '_> {
+    // translates the instructions, giving back the globals the function
+    // calls itself through
+    fn translate(mut self, instructions: &[(usize, Opcode, usize)]) -> Option<Vec<usize>> {
+        let b = &mut self.builder;
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let context = b.block_params(entry)[0];
+        let args = b.block_params(entry)[1];
+        let bail = b.create_block();
+        let itself = self.module.declare_func_in_func(self.id, b.func);
+        let poll = self.module.declare_func_in_func(self.poll, b.func);
+
+        let parameters = self.function.parameters.len();
+        let mut locals = vec![None; self.function.num_locals];
+        for (index, local) in locals.iter_mut().enumerate() {
+            b.declare_var(variable(index), types::I64);
+            if index < parameters {
+                let arg = b
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), args, 8 * index as i32);
+                b.def_var(variable(index), arg);
+                *local = Some(Type::Integer);
+            }
+        }
+        if locals.len() < parameters {
+            return None;
+        }
+
+        // the blocks that jumps go to, and that follow a conditional jump
+        let mut starts = BTreeSet::new();
+        for &(ip, op, operand) in instructions {
+            match op {
+                Opcode::JumpNotTruthy | Opcode::Jump if operand <= ip => return None,
+                Opcode::JumpNotTruthy => {
+                    starts.insert(operand);
+                    starts.insert(ip + op.width());
+                }
+                Opcode::Jump => {
+                    starts.insert(operand);
+                }
+                _ => {}
+            }
+        }
+        let mut targets: HashMap<usize, Target> = starts
+            .into_iter()
+            .map(|ip| {
+                let target = Target {
+                    block: b.create_block(),
+                    stack: None,
+                    locals: Vec::new(),
+                };
+                (ip, target)
+            })
+            .collect();
+
+        let mut stack: Vec<Item> = Vec::new();
+        let mut reachable = true;
+        // the steps since they were last counted in the context
+        let mut pending = 0;
+        let mut self_globals = Vec::new();
+        for &(ip, op, operand) in instructions {
+            if targets.contains_key(&ip) {
+                if reachable {
+                    self.count(context, poll, bail, &mut pending);
+                    let (block, values) = self.jump_to(&mut targets, ip, &stack, &locals)?;
+                    self.builder.ins().jump(block, &values);
+                }
+                let target = &targets[&ip];
+                let Some(types) = &target.stack else {
+                    // nothing jumps here
+                    reachable = false;
+                    continue;
+                };
+                self.builder.switch_to_block(target.block);
+                let params = self.builder.block_params(target.block);
+                stack = params
+                    .iter()
+                    .zip(types)
+                    .map(|(&value, &ty)| Item::Value(value, ty))
+                    .collect();
+                locals.clone_from(&target.locals);
+                reachable = true;
+            }
+            if !reachable {
+                continue;
+            }
+
+            pending += 1;
+            let b = &mut self.builder;
+            match op {
+                Opcode::Constant => match self.constants.get(operand).map(|obj| &**obj) {
+                    Some(Object::Integer(value)) => {
+                        let value = b.ins().iconst(types::I64, *value);
+                        stack.push(Item::Value(value, Type::Integer));
+                    }
+                    _ => return None,
+                },
+                Opcode::Pop => {
+                    stack.pop()?;
+                }
+                Opcode::True | Opcode::False => {
+                    let value = b.ins().iconst(types::I64, (op == Opcode::True) as i64);
+                    stack.push(Item::Value(value, Type::Boolean));
+                }
+                Opcode::Add | Opcode::Sub | Opcode::Mul => {
+                    let right = integer(stack.pop()?)?;
+                    let left = integer(stack.pop()?)?;
+                    let (value, overflow) = match op {
+                        Opcode::Add => b.ins().sadd_overflow(left, right),
+                        Opcode::Sub => b.ins().ssub_overflow(left, right),
+                        _ => b.ins().smul_overflow(left, right),
+                    };
+                    self.bail_if(overflow, bail);
+                    stack.push(Item::Value(value, Type::Integer));
+                }
+                Opcode::Div | Opcode::Mod => {
+                    let right = integer(stack.pop()?)?;
+                    let left = integer(stack.pop()?)?;
+                    // by zero, or the one quotient that doesn't fit
+                    let zero = b.ins().icmp_imm(IntCC::Equal, right, 0);
+                    let min = b.ins().icmp_imm(IntCC::Equal, left, i64::MIN);
+                    let minus_one = b.ins().icmp_imm(IntCC::Equal, right, -1);
+                    let overflow = b.ins().band(min, minus_one);
+                    let invalid = b.ins().bor(zero, overflow);
+                    self.bail_if(invalid, bail);
+                    let b = &mut self.builder;
+                    let value = match op {
+                        Opcode::Div => b.ins().sdiv(left, right),
+                        _ => b.ins().srem(left, right),
+                    };
+                    stack.push(Item::Value(value, Type::Integer));
+                }
+                Opcode::Equal | Opcode::NotEqual | Opcode::LessThan | Opcode::GreaterThan => {
+                    let (Item::Value(right, right_type), Item::Value(left, left_type)) =
+                        (stack.pop()?, stack.pop()?)
+                    else {
+                        return None;
+                    };
+                    let comparison = match op {
+                        Opcode::Equal => IntCC::Equal,
+                        Opcode::NotEqual => IntCC::NotEqual,
+                        Opcode::LessThan => IntCC::SignedLessThan,
+                        _ => IntCC::SignedGreaterThan,
+                    };
+                    let ordered = matches!(op, Opcode::LessThan | Opcode::GreaterThan);
+                    if left_type != right_type || (ordered && left_type != Type::Integer) {
+                        return None;
+                    }
+                    let result = b.ins().icmp(comparison, left, right);
+                    let value = b.ins().uextend(types::I64, result);
+                    stack.push(Item::Value(value, Type::Boolean));
+                }
+                Opcode::Minus => {
+                    let right = integer(stack.pop()?)?;
+                    let overflow = b.ins().icmp_imm(IntCC::Equal, right, i64::MIN);
+                    self.bail_if(overflow, bail);
+                    let value = self.builder.ins().ineg(right);
+                    stack.push(Item::Value(value, Type::Integer));
+                }
+                Opcode::Bang => {
+                    let right = boolean(stack.pop()?)?;
+                    let value = b.ins().bxor_imm(right, 1);
+                    stack.push(Item::Value(value, Type::Boolean));
+                }
+                Opcode::JumpNotTruthy => {
+                    let condition = boolean(stack.pop()?)?;
+                    self.count(context, poll, bail, &mut pending);
+                    let next = ip + op.width();
+                    let (then, then_values) = self.jump_to(&mut targets, next, &stack, &locals)?;
+                    let (otherwise, otherwise_values) =
+                        self.jump_to(&mut targets, operand, &stack, &locals)?;
+                    self.builder.ins().brif(
+                        condition,
+                        then,
+                        &then_values,
+                        otherwise,
+                        &otherwise_values,
+                    );
+                    reachable = false;
+                }
+                Opcode::Jump => {
+                    self.count(context, poll, bail, &mut pending);
+                    let (block, values) = self.jump_to(&mut targets, operand, &stack, &locals)?;
+                    self.builder.ins().jump(block, &values);
+                    reachable = false;
+                }
+                Opcode::GetGlobal => {
+                    let global = self.globals.get(operand)?.as_deref();
+                    match global {
+                        Some(Object::Closure(closure))
+                            if ptr_eq(&closure.function, self.function) =>
+                        {
+                            self_globals.push(operand);
+                            stack.push(Item::Callee);
+                        }
+                        _ => return None,
+                    }
+                }
+                Opcode::CurrentClosure => stack.push(Item::Callee),
+                Opcode::GetLocal => {
+                    let ty = (*locals.get(operand)?)?;
+                    let value = b.use_var(variable(operand));
+                    stack.push(Item::Value(value, ty));
+                }
+                Opcode::SetLocal => {
+                    let Some(&Item::Value(value, ty)) = stack.last() else {
+                        return None;
+                    };
+                    *locals.get_mut(operand)? = Some(ty);
+                    b.def_var(variable(operand), value);
+                }
+                Opcode::Call => {
+                    if operand != parameters || stack.len() < operand + 1 {
+                        return None;
+                    }
+                    let args = stack
+                        .split_off(stack.len() - operand)
+                        .into_iter()
+                        .map(integer)
+                        .collect::<Option<Vec<Value>>>()?;
+                    let Some(Item::Callee) = stack.pop() else {
+                        return None;
+                    };
+                    self.count(context, poll, bail, &mut pending);
+                    let value = self.call(context, itself, bail, &args);
+                    stack.push(Item::Value(value, self.returns));
+                }
+                Opcode::ReturnValue => {
+                    let Item::Value(value, ty) = stack.pop()? else {
+                        return None;
+                    };
+                    if ty != self.returns {
+                        return None;
+                    }
+                    self.count(context, poll, bail, &mut pending);
+                    self.builder.ins().return_(&[value]);
+                    reachable = false;
+                }
+                _ => return None,
+            }
+        }
+        if reachable {
+            return None;
+        }
+
+        // gives up, for the VM to run the call again
+        let b = &mut self.builder;
+        b.switch_to_block(bail);
+        let one = b.ins().iconst(types::I64, 1);
+        b.ins().store(
+            MemFlags::trusted(),
+            one,
+            context,
+            offset(offset_of!(Context, bailed)),
+        );
+        let zero = b.ins().iconst(types::I64, 0);
+        b.ins().return_(&[zero]);
+        b.seal_all_blocks();
+        self.builder.finalize();
+        Some(self_globals)
+    }
+
+    // the block at the offset and the values to pass it, with the types of
+    // the stack and locals checked against the other jumps to it
+    fn jump_to(
+        &mut self,
+        targets: &mut HashMap<usize, Target>,
+        ip: usize,
+        stack: &[Item],
+        locals: &[Option<Type>],
+    ) -> Option<(Block, Vec<Value>)> {
+        let target = targets.get_mut(&ip)?;
+        let (values, types): (Vec<Value>, Vec<Type>) = stack
+            .iter()
+            .map(|item| match *item {
+                Item::Value(value, ty) => Some((value, ty)),
+                Item::Callee => None,
+            })
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        match &target.stack {
+            None => {
+                for _ in &types {
+                    self.builder.append_block_param(target.block, types::I64);
+                }
+                target.stack = Some(types);
+                target.locals = locals.to_vec();
+            }
+            Some(expected) if *expected == types => {
+                // a local is only read where it has the same type on every
+                // way there
+                for (merged, local) in target.locals.iter_mut().zip(locals) {
+                    if merged != local {
+                        *merged = None;
+                    }
+                }
+            }
+            Some(_) => return None,
+        }
+        Some((target.block, values))
+    }
+
+    // continues in a new block, or gives up if the condition holds
+    fn bail_if(&mut self, condition: Value, bail: Block) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(condition, bail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    // adds the steps since the last count to the context, and polls once
+    // it's time to
+    fn count(&mut self, context: Value, poll: FuncRef, bail: Block, pending: &mut i64) {
+        if *pending == 0 {
+            return;
+        }
+        let b = &mut self.builder;
+        let flags = MemFlags::trusted();
+        let steps_offset = offset(offset_of!(Context, steps));
+        let steps = b.ins().load(types::I64, flags, context, steps_offset);
+        let steps = b.ins().iadd_imm(steps, *pending);
+        b.ins().store(flags, steps, context, steps_offset);
+        let next_poll = b.ins().load(
+            types::I64,
+            flags,
+            context,
+            offset(offset_of!(Context, next_poll)),
+        );
+        let due = b
+            .ins()
+            .icmp(IntCC::SignedGreaterThanOrEqual, steps, next_poll);
+        let polling = b.create_block();
+        let next = b.create_block();
+        b.ins().brif(due, polling, &[], next, &[]);
+        b.switch_to_block(polling);
+        let call = b.ins().call(poll, &[context]);
+        let expired = b.inst_results(call)[0];
+        b.ins().brif(expired, bail, &[], next, &[]);
+        b.switch_to_block(next);
+        *pending = 0;
+    }
+
+    // calls the function itself, counting the call like the VM does
+    fn call(&mut self, context: Value, itself: FuncRef, bail: Block, args: &[Value]) -> Value {
+        let b = &mut self.builder;
+        let flags = MemFlags::trusted();
+        let depth = b.ins().load(
+            types::I64,
+            flags,
+            context,
+            offset(offset_of!(Context, depth)),
+        );
+        let limit = b.ins().load(
+            types::I64,
+            flags,
+            context,
+            offset(offset_of!(Context, depth_limit)),
+        );
+        let too_deep = b.ins().icmp(IntCC::SignedGreaterThanOrEqual, depth, limit);
+        self.bail_if(too_deep, bail);
+
+        let b = &mut self.builder;
+        let calls_offset = offset(offset_of!(Context, calls));
+        let calls = b.ins().load(types::I64, flags, context, calls_offset);
+        let calls = b.ins().iadd_imm(calls, 1);
+        b.ins().store(flags, calls, context, calls_offset);
+        let deepest_offset = offset(offset_of!(Context, deepest));
+        let deepest = b.ins().load(types::I64, flags, context, deepest_offset);
+        let deepest = b.ins().smax(deepest, depth);
+        b.ins().store(flags, deepest, context, deepest_offset);
+        let deeper = b.ins().iadd_imm(depth, 1);
+        let depth_offset = offset(offset_of!(Context, depth));
+        b.ins().store(flags, deeper, context, depth_offset);
+
+        let slot = b.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            8 * args.len().max(1) as u32,
+            3,
+        ));
+        for (index, &arg) in args.iter().enumerate() {
+            b.ins().stack_store(arg, slot, 8 * index as i32);
+        }
+        let args = b.ins().stack_addr(types::I64, slot, 0);
+        let call = b.ins().call(itself, &[context, args]);
+        let value = b.inst_results(call)[0];
+        b.ins().store(flags, depth, context, depth_offset);
+
+        // the callee gave up, and so does its caller
+        let bailed = b.ins().load(
+            types::I64,
+            flags,
+            context,
+            offset(offset_of!(Context, bailed)),
+        );
+        self.bail_if(bailed, bail);
+        value
+    }
+}
+
+fn offset(offset_of: usize) -> i32 {
+    offset_of as i32
+}
+
+fn ptr_eq(function: &Rc<CompiledFunction>, other: &CompiledFunction) -> bool {
+    std::ptr::eq(Rc::as_ptr(function), other)
+}
+
+fn integer(item: Item) -> Option<Value> {
+    match item {
+        Item::Value(value, Type::Integer) => Some(value),
+        _ => None,
+    }
+}
+
+fn boolean(item: Item) -> Option<Value> {
+    match item {
+        Item::Value(value, Type::Boolean) => Some(value),
+        _ => None,
+    }
+}
+
+fn variable(local: usize) -> Variable {
+    Variable::from_u32(local as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::engine::{Engine, Runner};
+    use crate::evaluator::{CancelHandle, EvalError, Metrics};
+    use crate::parser;
+    use crate::vm::Vm;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    // the result, and how many functions were compiled on the way
+    fn run_native(input: &str, config: EvalConfig) -> (Result<Rc<Object>, EvalError>, usize) {
+        let bytecode = Compiler::new()
+            .inline_functions(false)
+            .compile(&parser::parse(input).unwrap())
+            .unwrap();
+        let mut vm = Vm::new();
+        vm.use_jit(true);
+        let result = vm.run_with_config(bytecode, config);
+        (result, vm.jit().unwrap().compiled())
+    }
+
+    fn show(result: Result<Rc<Object>, EvalError>) -> String {
+        match result {
+            Ok(value) => value.to_string(),
+            Err(error) => format!("error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_native_code() {
+        let tests = vec![
+            (
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(20)",
+                "6765",
+                1,
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { !even(n - 1) } }; even(11)",
+                "false",
+                1,
+            ),
+            (
+                "let gcd = fn(a, b) { if (b == 0) { a } else { gcd(b, a % b) } }; gcd(1071, 462)",
+                "21",
+                1,
+            ),
+            (
+                "let f = fn(n) { let m = n * 2; let k = -m / 3; k != 0 }; f(5)",
+                "true",
+                1,
+            ),
+            (
+                "let h = fn(n) { let g = fn(x) { if (x > 0) { g(x - 1) + 2 } else { 0 } }; g(n) }; h(50)",
+                "100",
+                1,
+            ),
+            // integer overflow and division by zero are left to the VM
+            (
+                "let f = fn(n) { if (n == 0) { 1 } else { n * f(n - 1) } }; f(25)",
+                "error: integer overflow: 21 * 2432902008176640000",
+                0,
+            ),
+            (
+                "let f = fn(n) { if (n == 0) { 1 / n } else { f(n - 1) } }; try { f(5) } catch (e) { e }",
+                "ERROR: division by zero: 1 / 0",
+                0,
+            ),
+            // as is anything that isn't an integer or a boolean
+            ("let f = fn(s) { s + \"!\" }; f(\"hi\")", "hi!", 0),
+            ("let f = fn(n) { if (n > 0) { n } }; f(0)", "null", 0),
+            ("let f = fn(n) { puts(n); n }; f(1)", "1", 0),
+            // or a function that isn't the one being called
+            (
+                "let g = fn(n) { n + 1 }; let f = fn(n) { g(n) * 2 }; f(2)",
+                "6",
+                1,
+            ),
+        ];
+        for (input, expected, compiled) in tests {
+            let (result, native) = run_native(input, EvalConfig::default());
+            let result = show(result);
+            assert!(result.starts_with(expected), "{}: {}", input, result);
+            assert_eq!(native, compiled, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        let inputs = vec![
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            "let f = fn(n) { if (n == 0) { 1 } else { n * f(n - 1) } }; f(30)",
+            "let sum = fn(n) { if (n == 0) { 0 } else { n + sum(n - 1) } }; sum(2000)",
+            "let f = fn(a, b) { a % b + a / b }; [f(-7, 2), f(7, -2), f(1, 0)]",
+            "let f = fn(n) { -n }; f(-9223372036854775807 - 1)",
+            // a function that was rebound isn't called through the old name
+            "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(3); let g = f; \
+             let f = fn(n) { 100 }; g(5)",
+        ];
+        for input in inputs {
+            let results: Vec<(String, u64, u64, usize)> = Engine::ALL
+                .into_iter()
+                .map(|engine| {
+                    let metrics = Rc::new(Cell::new(Metrics::default()));
+                    let config = EvalConfig::default().record_metrics(metrics.clone());
+                    let result =
+                        Runner::new(engine).run_with_config(parser::parse(input).unwrap(), config);
+                    let metrics = metrics.get();
+                    (
+                        show(result),
+                        metrics.steps,
+                        metrics.calls,
+                        metrics.max_depth,
+                    )
+                })
+                .collect();
+            let (tree_walker, vm, jit) = (&results[0], &results[1], &results[2]);
+            assert_eq!(tree_walker.0, jit.0, "{}", input);
+            // native code counts the steps and calls the VM would have
+            assert_eq!(vm, jit, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let fib = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };";
+        // cancelled while native code runs
+        let cancel = CancelHandle::new();
+        let handle = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
+        let tests = vec![
+            (
+                EvalConfig::default().fuel(10_000),
+                "fib(30)",
+                "fuel exhausted",
+            ),
+            (
+                EvalConfig::default().max_depth(50),
+                "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(100)",
+                "maximum recursion depth exceeded: 50",
+            ),
+            (
+                EvalConfig::default().timeout(Duration::from_millis(50)),
+                "fib(60)",
+                "evaluation timed out after 50ms",
+            ),
+            (
+                EvalConfig::default().cancel_with(cancel),
+                "fib(60)",
+                "evaluation cancelled",
+            ),
+        ];
+        for (config, input, expected) in tests {
+            let (result, _) = run_native(&format!("{} {}", fib, input), config);
+            assert_eq!(show(result), format!("error: {}", expected), "{}", input);
+        }
+
+        // fuel that's enough is used up as on the VM
+        let metrics = Rc::new(Cell::new(Metrics::default()));
+        let config = EvalConfig::default()
+            .fuel(100_000)
+            .record_metrics(metrics.clone());
+        let (result, compiled) = run_native(&format!("{} fib(15)", fib), config);
+        assert_eq!(show(result), "610");
+        assert_eq!(compiled, 1);
+        let steps = metrics.get().steps;
+        let config = EvalConfig::default().record_metrics(metrics.clone());
+        let bytecode = Compiler::new()
+            .inline_functions(false)
+            .compile(&parser::parse(&format!("{} fib(15)", fib)).unwrap())
+            .unwrap();
+        Vm::new().run_with_config(bytecode, config).unwrap();
+        assert_eq!(metrics.get().steps, steps);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::token::{lookup_ident, Token};
+use std::fmt::{self, Display, Formatter};
+
+use crate::token::{lookup_ident, Span, SpannedToken, Token};
 
 pub struct Lexer<'a> {
     input: &'a str,
@@ -6,41 +8,91 @@ pub struct Lexer<'a> {
     position: usize,
     read_position: usize,
     ch: char,
+    line: usize,
+    column: usize,
+    // the errors of the tokens made so far
+    errors: Vec<LexError>,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &'a str) -> Lexer {
+    pub fn new(input: &'a str) -> Lexer<'a> {
         let mut lexer = Lexer {
             input,
             input_length: input.len(),
             position: 0,
             read_position: 0,
             ch: '\0',
+            line: 1,
+            column: 0,
+            errors: Vec::new(),
         };
         lexer.read_char();
+        // a shebang line, so scripts can be run directly: #!/usr/bin/env monk
+        if input.starts_with("#!") {
+            while lexer.ch != '\n' && lexer.ch != '\0' {
+                lexer.read_char();
+            }
+        }
         lexer
     }
 
     fn read_char(&mut self) {
-        if self.read_position >= self.input_length {
-            self.ch = '\0';
+        if self.ch == '\n' {
+            self.line += 1;
+            self.column = 1;
         } else {
-            self.ch = self.input.chars().nth(self.read_position).unwrap();
+            self.column += 1;
         }
+        // the positions are byte offsets, so they can slice the input
+        self.ch = self.char_at(self.read_position);
         self.position = self.read_position;
-        self.read_position += 1;
+        self.read_position += self.ch.len_utf8();
     }
 
     fn peek_char(&self) -> char {
-        if self.read_position >= self.input_length {
-            '\0'
-        } else {
-            self.input.chars().nth(self.read_position).unwrap()
+        self.char_at(self.read_position)
+    }
+
+    fn char_at(&self, position: usize) -> char {
+        match self.input.get(position..) {
+            Some(rest) => rest.chars().next().unwrap_or('\0'),
+            None => '\0',
         }
     }
 
-    pub fn next_token(&mut self) -> Token {
+    pub fn next_token(&mut self) -> SpannedToken {
         self.skip_whitespace();
+        let start = self.position.min(self.input_length);
+        let (line, column) = (self.line, self.column);
+        let token = self.read_token();
+        let span = Span {
+            start,
+            end: self.position.min(self.input_length),
+            line,
+            column,
+        };
+        let error = match &token {
+            Token::ILLEGAL(ch) => Some((LexErrorKind::UnexpectedCharacter, *ch)),
+            Token::INT_TOO_LARGE(digits) => Some((
+                LexErrorKind::IntegerTooLarge,
+                digits.chars().next().unwrap_or('0'),
+            )),
+            Token::UNTERMINATED_STRING(_) => Some((LexErrorKind::UnterminatedString, '"')),
+            _ => None,
+        };
+        if let Some((kind, ch)) = error {
+            self.errors.push(LexError { kind, ch, span });
+        }
+        SpannedToken { token, span }
+    }
+
+    // ILLEGAL, INT_TOO_LARGE and UNTERMINATED_STRING tokens are made like
+    // any other, and also reported here as errors
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    fn read_token(&mut self) -> Token {
         let token = match self.ch {
             '=' => {
                 if self.peek_char() == '=' {
@@ -62,47 +114,75 @@ impl<'a> Lexer<'a> {
             }
             '*' => Token::ASTERISK,
             '/' => Token::SLASH,
+            '%' => Token::PERCENT,
             '<' => Token::LT,
             '>' => Token::GT,
             ',' => Token::COMMA,
             ';' => Token::SEMICOLON,
+            ':' => Token::COLON,
             '(' => Token::LPAREN,
             ')' => Token::RPAREN,
             '{' => Token::LBRACE,
             '}' => Token::RBRACE,
+            '[' => Token::LBRACKET,
+            ']' => Token::RBRACKET,
+            '"' => {
+                let start = self.position + 1;
+                match self.read_string() {
+                    Some(string) => Token::STRING(string),
+                    None => Token::UNTERMINATED_STRING(self.input[start..].to_string()),
+                }
+            }
             '\0' => Token::EOF,
             _ => {
                 if is_letter(self.ch) {
                     let ident = self.read_identifier();
-                    return lookup_ident(&ident);
+                    return lookup_ident(ident);
                 }
                 if is_digit(self.ch) {
-                    let digit = self.read_digit();
-                    return Token::INT(digit);
+                    return self.read_digit();
                 }
-                Token::ILLEGAL
+                Token::ILLEGAL(self.ch)
             }
         };
         self.read_char();
         token
     }
 
-    fn read_identifier(&mut self) -> String {
+    fn read_identifier(&mut self) -> &'a str {
         let ident_start = self.position;
         while is_letter(self.ch) {
             self.read_char();
         }
 
-        self.input[ident_start..self.position].to_string()
+        &self.input[ident_start..self.position.min(self.input_length)]
     }
 
-    fn read_digit(&mut self) -> isize {
+    fn read_digit(&mut self) -> Token {
         let digit_start = self.position;
         while is_digit(self.ch) {
             self.read_char();
         }
 
-        self.input[digit_start..self.position].parse().unwrap()
+        let digits = &self.input[digit_start..self.position.min(self.input_length)];
+        match digits.parse() {
+            Ok(i) => Token::INT(i),
+            Err(_) => Token::INT_TOO_LARGE(digits.to_string()),
+        }
+    }
+
+    // the characters up to the closing quote, none if the string runs until
+    // the end of the input
+    fn read_string(&mut self) -> Option<String> {
+        let string_start = self.position + 1;
+        loop {
+            self.read_char();
+            match self.ch {
+                '"' => return Some(self.input[string_start..self.position].to_string()),
+                '\0' if self.position >= self.input_length => return None,
+                _ => {}
+            }
+        }
     }
 
     fn skip_whitespace(&mut self) {
@@ -112,12 +192,75 @@ impl<'a> Lexer<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexErrorKind {
+    // a character that no token starts with
+    UnexpectedCharacter,
+    // an integer literal that doesn't fit in an integer
+    IntegerTooLarge,
+    // a string literal without its closing quote
+    UnterminatedString,
+}
+
+impl LexErrorKind {
+    // a code for the kind of error that stays the same between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            LexErrorKind::UnexpectedCharacter => "E0001",
+            LexErrorKind::IntegerTooLarge => "E0002",
+            LexErrorKind::UnterminatedString => "E0003",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub ch: char,
+    pub span: Span,
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            LexErrorKind::UnexpectedCharacter => {
+                write!(f, "unexpected character '{}' at {}", self.ch, self.span)
+            }
+            LexErrorKind::IntegerTooLarge => {
+                write!(f, "integer literal too large at {}", self.span)
+            }
+            LexErrorKind::UnterminatedString => {
+                write!(f, "unterminated string at {}", self.span)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+// Lexes the whole input up to and including EOF, with the errors of the
+// lexer.
+#[cfg(any(test, feature = "internals"))]
+pub fn tokenize(input: &str) -> (Vec<SpannedToken>, Vec<LexError>) {
+    let mut lexer = Lexer::new(input);
+    let mut tokens = Vec::new();
+
+    loop {
+        let spanned = lexer.next_token();
+        let done = spanned.token == Token::EOF;
+        tokens.push(spanned);
+        if done {
+            return (tokens, lexer.errors);
+        }
+    }
+}
+
 fn is_letter(ch: char) -> bool {
     ch.is_ascii() && ch.is_alphabetic() || ch == '_'
 }
 
 fn is_digit(ch: char) -> bool {
-    ch.is_ascii() && ch.is_digit(10)
+    ch.is_ascii() && ch.is_ascii_digit()
 }
 
 #[cfg(test)]
@@ -126,7 +269,7 @@ mod tests {
 
     #[test]
     fn test_next_simple_token() {
-        let input = "=+(){},;";
+        let input = "=+(){},;%";
 
         let tests = vec![
             Token::ASSIGN,
@@ -137,13 +280,14 @@ mod tests {
             Token::RBRACE,
             Token::COMMA,
             Token::SEMICOLON,
+            Token::PERCENT,
             Token::EOF,
         ];
 
         let mut l = Lexer::new(input);
 
         for expected in tests {
-            let token = l.next_token();
+            let token = l.next_token().token;
             assert_eq!(expected, token);
         }
     }
@@ -246,8 +390,226 @@ if (5 < 10) {
         let mut l = Lexer::new(input);
 
         for expected in tests {
-            let token = l.next_token();
+            let token = l.next_token().token;
             assert_eq!(expected, token);
         }
     }
+
+    #[test]
+    fn test_token_spans() {
+        let input = "let five = 5;\n  five == 10;";
+
+        let tests = vec![
+            (Token::LET, 0, 3, 1, 1),
+            (Token::IDENT("five".into()), 4, 8, 1, 5),
+            (Token::ASSIGN, 9, 10, 1, 10),
+            (Token::INT(5), 11, 12, 1, 12),
+            (Token::SEMICOLON, 12, 13, 1, 13),
+            (Token::IDENT("five".into()), 16, 20, 2, 3),
+            (Token::EQ, 21, 23, 2, 8),
+            (Token::INT(10), 24, 26, 2, 11),
+            (Token::SEMICOLON, 26, 27, 2, 13),
+            (Token::EOF, 27, 27, 2, 14),
+        ];
+
+        let mut l = Lexer::new(input);
+
+        for (token, start, end, line, column) in tests {
+            let spanned = l.next_token();
+            assert_eq!(spanned.token, token);
+            assert_eq!(
+                spanned.span,
+                Span {
+                    start,
+                    end,
+                    line,
+                    column
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_shebang() {
+        let tests = vec![
+            ("#!/usr/bin/env monk\nlet", Token::LET, 20, 2, 1),
+            ("#!/usr/bin/env monk", Token::EOF, 19, 1, 20),
+            (" #!x", Token::ILLEGAL('#'), 1, 1, 2),
+        ];
+
+        for (input, token, start, line, column) in tests {
+            let spanned = Lexer::new(input).next_token();
+            assert_eq!(spanned.token, token, "{}", input);
+            assert_eq!(
+                (spanned.span.start, spanned.span.line, spanned.span.column),
+                (start, line, column),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_illegal_characters() {
+        let input = "5 @ #";
+
+        let tests = vec![
+            Token::INT(5),
+            Token::ILLEGAL('@'),
+            Token::ILLEGAL('#'),
+            Token::EOF,
+        ];
+
+        let mut l = Lexer::new(input);
+
+        for expected in tests {
+            let token = l.next_token().token;
+            assert_eq!(expected, token);
+        }
+    }
+
+    #[test]
+    fn test_non_ascii_characters() {
+        let input = "\"héllo\" é 1";
+
+        let tests = vec![
+            (Token::STRING("héllo".into()), 0, 8, 1),
+            (Token::ILLEGAL('é'), 9, 11, 9),
+            (Token::INT(1), 12, 13, 11),
+            (Token::EOF, 13, 13, 12),
+        ];
+
+        let mut l = Lexer::new(input);
+
+        for (token, start, end, column) in tests {
+            let spanned = l.next_token();
+            assert_eq!(spanned.token, token);
+            assert_eq!(
+                (spanned.span.start, spanned.span.end, spanned.span.column),
+                (start, end, column)
+            );
+        }
+    }
+
+    #[test]
+    fn test_integer_too_large() {
+        let (tokens, errors) = tokenize("9223372036854775807 9223372036854775808");
+
+        assert_eq!(tokens[0].token, Token::INT(i64::MAX));
+        assert_eq!(
+            tokens[1].token,
+            Token::INT_TOO_LARGE("9223372036854775808".into())
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, LexErrorKind::IntegerTooLarge);
+        assert_eq!(
+            errors[0].to_string(),
+            "integer literal too large at line 1, column 21"
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let input = "let s = \"ab\ncd \\";
+        let (tokens, errors) = tokenize(input);
+
+        assert_eq!(
+            tokens[3].token,
+            Token::UNTERMINATED_STRING("ab\ncd \\".into())
+        );
+        assert_eq!(tokens[4].token, Token::EOF);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, LexErrorKind::UnterminatedString);
+        assert_eq!(errors[0].kind.code(), "E0003");
+        let span = errors[0].span;
+        assert_eq!((span.start, span.end), (8, input.len()));
+        assert_eq!(
+            errors[0].to_string(),
+            "unterminated string at line 1, column 9"
+        );
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let input = r#""foobar" "foo bar" "" "C:\dir\n" "open"#;
+
+        let tests = vec![
+            Token::STRING("foobar".into()),
+            Token::STRING("foo bar".into()),
+            Token::STRING("".into()),
+            Token::STRING("C:\\dir\\n".into()),
+            Token::UNTERMINATED_STRING("open".into()),
+            Token::EOF,
+        ];
+
+        let mut l = Lexer::new(input);
+
+        for expected in tests {
+            let token = l.next_token().token;
+            assert_eq!(expected, token);
+        }
+    }
+
+    #[test]
+    fn test_brackets_and_colons() {
+        let input = r#"[1, 2]; {"foo": "bar"}"#;
+
+        let tests = vec![
+            Token::LBRACKET,
+            Token::INT(1),
+            Token::COMMA,
+            Token::INT(2),
+            Token::RBRACKET,
+            Token::SEMICOLON,
+            Token::LBRACE,
+            Token::STRING("foo".into()),
+            Token::COLON,
+            Token::STRING("bar".into()),
+            Token::RBRACE,
+            Token::EOF,
+        ];
+
+        let mut l = Lexer::new(input);
+
+        for expected in tests {
+            let token = l.next_token().token;
+            assert_eq!(expected, token);
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let (tokens, errors) = tokenize("let x = 5;");
+
+        let expected = vec![
+            Token::LET,
+            Token::IDENT("x".into()),
+            Token::ASSIGN,
+            Token::INT(5),
+            Token::SEMICOLON,
+            Token::EOF,
+        ];
+        let actual: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+        assert_eq!(actual, expected);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let (tokens, errors) = tokenize("1 @\n$");
+
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[1].token, Token::ILLEGAL('@'));
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].kind, LexErrorKind::UnexpectedCharacter);
+        assert_eq!(errors[0].kind.code(), "E0001");
+        assert_eq!(
+            errors[0].to_string(),
+            "unexpected character '@' at line 1, column 3"
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "unexpected character '$' at line 2, column 1"
+        );
+    }
 }
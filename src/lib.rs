@@ -0,0 +1,97 @@
+// The interpreter as a library, for embedding it in other programs through
+// Interpreter and the values, errors and config it takes and gives, and the
+// syntax tree, lint rules and call graph for tools. The rest are the modules
+// of the monk binary, which only calls cli_main. The internals feature makes
+// the ones the benchmarks and fuzz targets use public, which isn't a stable
+// interface.
+
+mod aot;
+pub mod ast;
+mod bench;
+#[cfg(feature = "internals")]
+pub mod bench_utils;
+#[cfg(all(test, not(feature = "internals")))]
+mod bench_utils;
+mod builtins;
+pub mod call_graph;
+mod cli;
+mod code;
+mod color;
+mod compiler;
+mod config;
+pub mod convert;
+mod coverage;
+mod debugger;
+mod diagnostic;
+#[cfg(feature = "internals")]
+pub mod dump;
+#[cfg(not(feature = "internals"))]
+mod dump;
+#[cfg(feature = "internals")]
+pub mod engine;
+#[cfg(not(feature = "internals"))]
+mod engine;
+mod evaluator;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod fold;
+mod gc;
+mod highlight;
+mod history;
+mod inline;
+mod inspect;
+mod interpreter;
+mod interrupt;
+mod iter;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "internals")]
+pub mod lexer;
+#[cfg(not(feature = "internals"))]
+mod lexer;
+mod lint;
+mod macro_expansion;
+mod observer;
+mod parser;
+mod peephole;
+mod printer;
+mod profiler;
+mod quote;
+mod repl;
+mod resolver;
+mod sandbox;
+#[cfg(feature = "serde")]
+mod serde_value;
+mod serialize;
+mod server;
+mod shared;
+mod source_map;
+mod symbol;
+mod symbol_table;
+mod task;
+mod test_runner;
+#[cfg(feature = "time")]
+mod time;
+mod token;
+mod vm;
+pub mod warnings;
+mod wasm;
+
+#[doc(hidden)]
+pub use cli::main as cli_main;
+pub use convert::{ConversionError, Data};
+pub use engine::{Engine, Snapshot};
+pub use evaluator::{
+    CancelHandle, Env, ErrorKind, EvalConfig, EvalError, Frame, HashKey, HashPair, HashPairs,
+    Metrics, Object, TraceFrame,
+};
+pub use highlight::{highlight, TokenClass};
+pub use interpreter::{Interpreter, MonkError, RuntimeError, Value};
+pub use lexer::{LexError, LexErrorKind};
+pub use observer::{EvalObserver, Node};
+pub use parser::{parse, ParseError, ParseErrorKind};
+pub use sandbox::{Capabilities, Capability};
+pub use shared::SharedInterpreter;
+pub use symbol::Symbol;
+pub use token::Span;
+pub use warnings::LintRule;
@@ -0,0 +1,168 @@
+use std::fs;
+use std::io::{stderr, IsTerminal};
+use std::process::ExitCode;
+
+use crate::cli::LintOptions;
+use crate::color::{Colors, Style};
+use crate::config::{self, Config};
+use crate::diagnostic;
+use crate::parser;
+use crate::warnings::{self, Severity};
+
+// `monk lint`: reports the warnings of every file with the severity their
+// rule has, to stderr, and fails if any file has an error. Syntax errors are
+// always errors. The severities of the config file's [lint] section come
+// before the ones of the flags, which override them.
+pub fn run(options: &LintOptions) -> ExitCode {
+    let mut options = options.clone();
+    match config::default_path().map(|path| Config::load(&path)) {
+        Some(Ok(config)) => {
+            options.rules.splice(0..0, config.lint);
+        }
+        Some(Err(error)) => {
+            eprintln!("error: can't load the config: {}", error);
+            return ExitCode::FAILURE;
+        }
+        None => {}
+    }
+    let colors = match stderr().is_terminal() {
+        true => Colors::new(options.color),
+        false => Colors::off(),
+    };
+    let mut failed = false;
+    for file in &options.files {
+        let diagnostics = match fs::read_to_string(file) {
+            Ok(source) => lint(&source, &options),
+            Err(error) => vec![Diagnostic {
+                severity: Severity::Deny,
+                rule: None,
+                message: format!("can't read the file: {}", error),
+                snippet: None,
+            }],
+        };
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(file, colors));
+        }
+        failed |= diagnostics.iter().any(|d| d.severity == Severity::Deny);
+    }
+    match failed {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Diagnostic {
+    severity: Severity,
+    // the rule it's reported by, none for syntax errors
+    rule: Option<&'static str>,
+    message: String,
+    snippet: Option<String>,
+}
+
+impl Diagnostic {
+    //   warning[unused]: a.mk: unused variable: x at line 1, column 1
+    fn render(&self, file: &str, colors: Colors) -> String {
+        let (label, style) = match self.severity {
+            Severity::Deny => ("error", Style::Error),
+            _ => ("warning", Style::Warning),
+        };
+        let label = match self.rule {
+            Some(rule) => format!("{}[{}]", label, rule),
+            None => label.to_string(),
+        };
+        let mut rendered = format!(
+            "{}: {}: {}",
+            colors.paint(style, &label),
+            file,
+            self.message
+        );
+        if let Some(snippet) = &self.snippet {
+            rendered.push('\n');
+            rendered.push_str(snippet);
+        }
+        rendered
+    }
+}
+
+// the diagnostics of the source that aren't allowed, in the order of the
+// warnings pass
+fn lint(source: &str, options: &LintOptions) -> Vec<Diagnostic> {
+    let program = match parser::parse(source) {
+        Ok(program) => program,
+        Err(errors) => {
+            return errors
+                .iter()
+                .map(|error| Diagnostic {
+                    severity: Severity::Deny,
+                    rule: None,
+                    message: error.to_string(),
+                    snippet: Some(diagnostic::snippet(source, error.span)),
+                })
+                .collect()
+        }
+    };
+    // the rules that are allowed don't run at all
+    let rules = warnings::rules(true)
+        .into_iter()
+        .filter(|rule| options.severity(rule.kind()) != Severity::Allow)
+        .collect();
+    warnings::run(&program, rules)
+        .into_iter()
+        .map(|warning| Diagnostic {
+            severity: options.severity(warning.kind),
+            rule: Some(warning.kind.name()),
+            message: warning.to_string(),
+            snippet: Some(diagnostic::snippet(source, warning.span)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warnings::WarningKind;
+
+    fn options(rules: Vec<(WarningKind, Severity)>) -> LintOptions {
+        LintOptions {
+            rules,
+            ..LintOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        let source = "let x = 1;\nlet f = fn() { let x = 2; x };\nf();";
+        let rendered = |options: &LintOptions| -> Vec<String> {
+            lint(source, options)
+                .iter()
+                .map(|d| d.render("a.mk", Colors::off()))
+                .collect()
+        };
+
+        assert_eq!(
+            rendered(&options(vec![])),
+            [
+                "warning[unused]: a.mk: unused variable: x at line 1, column 1\n\
+              1 | let x = 1;\n  | ^^^^^^^^^^"
+            ]
+        );
+        let denied = options(vec![
+            (WarningKind::UnusedBinding, Severity::Allow),
+            (WarningKind::Shadowing, Severity::Deny),
+        ]);
+        assert_eq!(
+            rendered(&denied),
+            ["error[shadowing]: a.mk: x shadows a binding of an outer scope at line 2, column 16\n\
+              2 | let f = fn() { let x = 2; x };\n  |                ^^^^^^^^^^"]
+        );
+
+        let errors = lint("let = 1;", &options(vec![]));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Deny);
+        assert_eq!(
+            errors[0].render("a.mk", Colors::off()).lines().next(),
+            Some("error: a.mk: no prefix parse function for ASSIGN at line 1, column 5")
+        );
+    }
+}
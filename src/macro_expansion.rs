@@ -0,0 +1,202 @@
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::evaluator::{apply, eval_with_config, Env, ErrorKind, EvalConfig, EvalError, Object};
+use crate::quote;
+
+// Macros are expanded before a program runs, in two steps. Their definitions,
+// let statements at the top level that bind macro literals, are taken out of
+// the program and evaluated in an environment of their own. Then every call
+// to one of them is replaced by what it returns when it's called with the
+// code of its arguments, which has to be quoted code itself.
+
+// takes the macro definitions out of the program, and defines them in env
+pub fn define_macros(
+    program: &mut Program,
+    env: &Env,
+    config: &EvalConfig,
+) -> Result<(), EvalError> {
+    let (definitions, statements): (Vec<_>, Vec<_>) = std::mem::take(&mut program.statements)
+        .into_iter()
+        .partition(is_macro_definition);
+    program.statements = statements;
+    if !definitions.is_empty() {
+        let definitions = Program {
+            statements: definitions,
+        };
+        eval_with_config(definitions, env, config.clone())?;
+    }
+    Ok(())
+}
+
+fn is_macro_definition(statement: &Statement) -> bool {
+    matches!(
+        statement,
+        Statement::LetStatement {
+            value: Expression::MacroLiteral { .. },
+            ..
+        }
+    )
+}
+
+// replaces the calls to the macros defined in env with their expansions
+pub fn expand_macros(
+    program: &mut Program,
+    env: &Env,
+    config: &EvalConfig,
+) -> Result<(), EvalError> {
+    let mut error = None;
+    for statement in &mut program.statements {
+        quote::modify_statement(statement, &mut |expression| {
+            let Some((function, arguments)) = macro_call(expression, env) else {
+                return false;
+            };
+            if error.is_none() {
+                match expand(function, arguments, config) {
+                    Ok(expansion) => *expression = expansion,
+                    Err(e) => error = Some(e),
+                }
+            }
+            true
+        });
+    }
+    match error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+// the macro a call is to, and its arguments
+fn macro_call<'a>(expression: &'a Expression, env: &Env) -> Option<(Rc<Object>, &'a [Expression])> {
+    let Expression::Call {
+        function,
+        arguments,
+        ..
+    } = expression
+    else {
+        return None;
+    };
+    let Expression::Identifier(name, _) = function.as_ref() else {
+        return None;
+    };
+    let function = env.borrow().get(name)?;
+    match *function {
+        Object::Macro(_) => Some((function, arguments)),
+        _ => None,
+    }
+}
+
+fn expand(
+    function: Rc<Object>,
+    arguments: &[Expression],
+    config: &EvalConfig,
+) -> Result<Expression, EvalError> {
+    let arguments = arguments
+        .iter()
+        .map(|argument| Object::Quote(argument.clone()).into())
+        .collect();
+    let expansion = apply(function, arguments, config.clone())?;
+    match &*expansion {
+        Object::Quote(expression) => Ok(expression.clone()),
+        value => Err(EvalError::new(
+            ErrorKind::TypeMismatch,
+            format!("macros have to return quoted code, got {}", value.type_of()),
+        )
+        .with_types(&[value.type_of()])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::Environment;
+    use crate::parser;
+    use crate::symbol::Symbol;
+    use std::cell::RefCell;
+
+    fn new_env() -> Env {
+        Rc::new(RefCell::new(Environment::new()))
+    }
+
+    #[test]
+    fn test_define_macros() {
+        let input = "
+        let number = 1;
+        let function = fn(x, y) { x + y };
+        let mymacro = macro(x, y) { x + y; };
+        ";
+        let mut program = parser::parse(input).unwrap();
+        let env = new_env();
+        define_macros(&mut program, &env, &EvalConfig::default()).unwrap();
+
+        assert_eq!(program.statements.len(), 2);
+        assert!(env.borrow().get(&"number".into()).is_none());
+        assert!(env.borrow().get(&"function".into()).is_none());
+
+        let obj = env.borrow().get(&"mymacro".into()).unwrap();
+        let Object::Macro(function) = &*obj else {
+            panic!("not a macro: {}", obj);
+        };
+        assert_eq!(
+            function.parameters(),
+            [Symbol::intern("x"), Symbol::intern("y")]
+        );
+        assert_eq!(obj.to_string(), "macro(x, y) {\n(x + y)\n}");
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let tests = vec![
+            (
+                "let infixExpression = macro() { quote(1 + 2); }; infixExpression();",
+                "(1 + 2)",
+            ),
+            (
+                "let reverse = macro(a, b) { quote(unquote(b) - unquote(a)); }; reverse(2 + 2, 10 - 5);",
+                "((10 - 5) - (2 + 2))",
+            ),
+            (
+                r#"
+                let unless = macro(condition, consequence, alternative) {
+                    quote(if (!(unquote(condition))) {
+                        unquote(consequence);
+                    } else {
+                        unquote(alternative);
+                    });
+                };
+
+                unless(10 > 5, puts("not greater"), puts("greater"));
+                "#,
+                r#"if (!(10 > 5)) puts("not greater")else puts("greater")"#,
+            ),
+        ];
+        for (input, expected) in tests {
+            let mut program = parser::parse(input).unwrap();
+            let env = new_env();
+            define_macros(&mut program, &env, &EvalConfig::default()).unwrap();
+            expand_macros(&mut program, &env, &EvalConfig::default()).unwrap();
+            assert_eq!(program.to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_expansion_errors() {
+        let tests = vec![
+            (
+                "let m = macro() { 1 }; m()",
+                "macros have to return quoted code, got INTEGER",
+            ),
+            (
+                "let m = macro() { missing }; m()",
+                "identifier not found: missing",
+            ),
+        ];
+        for (input, expected) in tests {
+            let mut program = parser::parse(input).unwrap();
+            let env = new_env();
+            define_macros(&mut program, &env, &EvalConfig::default()).unwrap();
+            let error = expand_macros(&mut program, &env, &EvalConfig::default()).unwrap_err();
+            assert_eq!(error.message, expected, "{}", input);
+        }
+    }
+}
@@ -1,10 +1,5 @@
-mod ast;
-mod evaluator;
-mod lexer;
-mod parser;
-mod repl;
-mod token;
+use std::process::ExitCode;
 
-fn main() {
-    repl::start_repl();
+fn main() -> ExitCode {
+    return_to_monk::cli_main()
 }
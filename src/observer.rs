@@ -0,0 +1,29 @@
+use std::rc::Rc;
+
+use crate::ast::{Expression, Statement};
+use crate::evaluator::{Env, EvalError, Frame, Object};
+
+// Something the evaluator tells about its progress, registered with
+// EvalConfig::observe. Every method does nothing by default, so an observer
+// only implements the events it cares about.
+pub trait EvalObserver {
+    // before a statement or expression is evaluated in the given environment,
+    // with the calls in progress innermost last
+    fn on_enter_node(&mut self, _node: Node, _env: &Env, _frames: &[Frame]) {}
+
+    // a function or builtin is about to be applied to the arguments
+    fn on_call(&mut self, _frame: &Frame, _args: &[Rc<Object>]) {}
+
+    // a call finished normally with the value
+    fn on_return(&mut self, _frame: &Frame, _value: &Rc<Object>) {}
+
+    // an error was raised and has unwound to a try expression, or out of the
+    // program when nothing caught it; frames are the calls still in progress
+    fn on_error(&mut self, _error: &EvalError, _frames: &[Frame]) {}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Node<'a> {
+    Statement(&'a Statement),
+    Expression(&'a Expression),
+}
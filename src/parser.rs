@@ -1,17 +1,85 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
 use crate::ast::*;
-use crate::lexer::Lexer;
-use crate::token::Token;
+use crate::lexer::{LexError, Lexer};
+use crate::symbol::Symbol;
+use crate::token::{Span, SpannedToken, Token};
+
+// how many tokens past the current one a parser created with `new` can see
+const DEFAULT_LOOKAHEAD: usize = 2;
+
+// how deep expressions can nest, so that deeply nested input is an error
+// instead of overflowing the stack
+const MAX_NESTING: usize = 256;
 
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
-    current_token: Token,
-    peek_token: Token,
-    pub errors: Vec<String>,
+    // the current token followed by `lookahead` upcoming tokens
+    tokens: VecDeque<SpannedToken>,
+    lookahead: usize,
+    // how many expressions the current token is nested in
+    depth: usize,
+    // how tall the tallest expression inside the one being parsed is so
+    // far, which counts the chains that `depth` doesn't, like `1 + 1 + 1`
+    height: usize,
+    // set once expressions nest too deeply, after which the rest of the
+    // input is skipped and nothing more is reported
+    stopped: bool,
+    pub errors: Vec<ParseError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    // a token that no expression starts with
+    UnexpectedToken,
+    // a token other than the one the syntax needs next
+    ExpectedToken,
+    // a character that the lexer couldn't make a token of
+    UnexpectedCharacter,
+    // an integer literal that doesn't fit in an integer
+    IntegerTooLarge,
+    // expressions nested deeper than the parser goes
+    NestedTooDeeply,
+    // a string literal without its closing quote
+    UnterminatedString,
+}
+
+impl ParseErrorKind {
+    // a code for the kind of error that stays the same between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseErrorKind::UnexpectedToken => "E0101",
+            ParseErrorKind::ExpectedToken => "E0102",
+            ParseErrorKind::UnexpectedCharacter => "E0103",
+            ParseErrorKind::IntegerTooLarge => "E0104",
+            ParseErrorKind::NestedTooDeeply => "E0105",
+            ParseErrorKind::UnterminatedString => "E0106",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub span: Span,
 }
 
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 type PrefixParseFn = fn(p: &mut Parser) -> Option<Expression>;
-type InfixParseFn = fn(p: &mut Parser, e: Expression) -> Option<Expression>;
+// the span is where the left-hand side started
+type InfixParseFn = fn(p: &mut Parser, e: Expression, start: Span) -> Option<Expression>;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 enum Precedence {
     LOWEST = 0,
@@ -21,26 +89,49 @@ enum Precedence {
     PRODUCT,
     PREFIX,
     CALL,
+    INDEX,
+}
+
+// The program in the source, or all of its syntax errors, in the order
+// they're in. Unlike parse_program, the errors can't go unchecked.
+pub fn parse(source: &str) -> Result<Program, Vec<ParseError>> {
+    let mut parser = Parser::new(Lexer::new(source));
+    let program = parser.parse_program();
+    match parser.errors.is_empty() {
+        true => Ok(program),
+        false => Err(parser.errors),
+    }
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a>) -> Self {
-        let mut parser = Parser {
+        Parser::with_lookahead(lexer, DEFAULT_LOOKAHEAD)
+    }
+
+    pub fn with_lookahead(mut lexer: Lexer<'a>, lookahead: usize) -> Self {
+        let lookahead = lookahead.max(1);
+        let tokens = (0..=lookahead).map(|_| lexer.next_token()).collect();
+        Parser {
             lexer,
-            current_token: Token::EOF,
-            peek_token: Token::EOF,
+            tokens,
+            lookahead,
+            depth: 0,
+            height: 0,
+            stopped: false,
             errors: Vec::new(),
-        };
+        }
+    }
 
-        parser.next_token();
-        parser.next_token();
-        parser
+    // the errors of the tokens that the parser has read, which is all of
+    // them once it has parsed the program
+    pub fn lex_errors(&self) -> &[LexError] {
+        self.lexer.errors()
     }
 
     pub fn parse_program(&mut self) -> Program {
         let mut program = Program::new();
 
-        while self.current_token != Token::EOF {
+        while !self.current_token_is(&Token::EOF) {
             let statement = self.parse_statement();
             if let Some(statement) = statement {
                 program.statements.push(statement);
@@ -56,9 +147,15 @@ impl<'a> Parser<'a> {
         match token {
             Token::IDENT(_) => Some(Parser::parse_identifier),
             Token::INT(_) => Some(Parser::parse_integer_literal),
+            Token::STRING(_) => Some(Parser::parse_string_literal),
             Token::LPAREN => Some(Parser::parse_grouped_expression),
+            Token::LBRACKET => Some(Parser::parse_array_literal),
+            Token::LBRACE => Some(Parser::parse_hash_literal),
             Token::IF => Some(Parser::parse_if_expression),
             Token::FUNCTION => Some(Parser::parse_function_literal),
+            Token::MACRO => Some(Parser::parse_macro_literal),
+            Token::TRY => Some(Parser::parse_try_expression),
+            Token::FOR => Some(Parser::parse_for_expression),
             Token::TRUE | Token::FALSE => Some(Parser::parse_boolean_literal),
             Token::BANG | Token::MINUS => Some(Parser::parse_prefix),
             _ => None,
@@ -73,17 +170,19 @@ impl<'a> Parser<'a> {
             | Token::MINUS
             | Token::ASTERISK
             | Token::SLASH
+            | Token::PERCENT
             | Token::EQ
             | Token::NOT_EQ
             | Token::LT
             | Token::GT => Some(Parser::parse_infix),
             Token::LPAREN => Some(Parser::parse_call_expression),
+            Token::LBRACKET => Some(Parser::parse_index_expression),
             _ => None,
         }
     }
 
     fn parse_statement(&mut self) -> Option<Statement> {
-        match self.current_token {
+        match self.current_token() {
             Token::LET => self.parse_let_statement(),
             Token::RETURN => self.parse_return_statement(),
             _ => self.parse_expression_statement(),
@@ -91,7 +190,8 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_let_statement(&mut self) -> Option<Statement> {
-        let name = match &self.peek_token {
+        let start = self.current_span();
+        let name = match self.peek_token() {
             Token::IDENT(s) => s.clone(),
             _ => return None,
         };
@@ -101,34 +201,38 @@ impl<'a> Parser<'a> {
         }
 
         self.next_token();
-        let value = match self.parse_expression(Precedence::LOWEST) {
-            Some(value) => value,
-            None => return None,
-        };
+        let value = self.parse_expression(Precedence::LOWEST)?;
 
         if self.peek_token_is(&Token::SEMICOLON) {
             self.next_token();
         }
 
-        Some(Statement::LetStatement { name, value })
+        Some(Statement::LetStatement {
+            name,
+            slot: Slot::default(),
+            value,
+            span: start.to(self.current_span()),
+        })
     }
 
     fn parse_return_statement(&mut self) -> Option<Statement> {
+        let start = self.current_span();
         self.next_token();
 
-        let value = match self.parse_expression(Precedence::LOWEST) {
-            Some(value) => value,
-            None => return None,
-        };
+        let value = self.parse_expression(Precedence::LOWEST)?;
 
         if self.peek_token_is(&Token::SEMICOLON) {
             self.next_token();
         }
 
-        Some(Statement::ReturnStatement(value))
+        Some(Statement::ReturnStatement(
+            value,
+            start.to(self.current_span()),
+        ))
     }
 
     fn parse_block_statement(&mut self) -> Option<Statement> {
+        let start = self.current_span();
         self.next_token();
         let mut statements = Vec::new();
 
@@ -138,36 +242,95 @@ impl<'a> Parser<'a> {
             }
             self.next_token();
         }
-        Some(Statement::BlockStatement(statements))
+        Some(Statement::BlockStatement(
+            statements,
+            start.to(self.current_span()),
+        ))
     }
 
     fn parse_expression_statement(&mut self) -> Option<Statement> {
-        let expression = match self.parse_expression(Precedence::LOWEST) {
-            Some(expression) => expression,
-            None => return None,
-        };
-
-        let statement = Statement::ExpressionStatement(expression);
+        let start = self.current_span();
+        let expression = self.parse_expression(Precedence::LOWEST)?;
 
         if self.peek_token_is(&Token::SEMICOLON) {
             self.next_token();
         }
 
-        Some(statement)
+        Some(Statement::ExpressionStatement(
+            expression,
+            start.to(self.current_span()),
+        ))
     }
 
     fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
-        let mut left = match Parser::prefix_parse_fns(&self.current_token) {
+        if self.depth == MAX_NESTING {
+            self.nested_too_deeply();
+            return None;
+        }
+        self.depth += 1;
+        let enclosing = std::mem::take(&mut self.height);
+        let expression = self.parse_nested_expression(precedence);
+        self.height = self.height.max(enclosing);
+        self.depth -= 1;
+        expression
+    }
+
+    fn nested_too_deeply(&mut self) {
+        self.errors.push(ParseError {
+            kind: ParseErrorKind::NestedTooDeeply,
+            message: format!("expressions nested more than {} deep", MAX_NESTING),
+            span: self.current_span(),
+        });
+        // where the statement ends is further than the parser can go, so
+        // it stops rather than report each of the closing tokens
+        self.stopped = true;
+        while !self.current_token_is(&Token::EOF) {
+            self.next_token();
+        }
+    }
+
+    fn parse_nested_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        let start = self.current_span();
+        // parentheses only group, so they don't make the expression taller
+        let grouped = self.current_token_is(&Token::LPAREN);
+        let mut left = match Parser::prefix_parse_fns(self.current_token()) {
             Some(prefix) => prefix(self),
             None => {
-                self.errors.push(format!(
-                    "no prefix parse function for {:?}",
-                    self.current_token
-                ));
+                let (kind, message) = match self.current_token() {
+                    Token::ILLEGAL(c) => (
+                        ParseErrorKind::UnexpectedCharacter,
+                        format!("unexpected character '{}'", c),
+                    ),
+                    Token::INT_TOO_LARGE(digits) => (
+                        ParseErrorKind::IntegerTooLarge,
+                        format!("integer literal {} is too large", digits),
+                    ),
+                    Token::UNTERMINATED_STRING(_) => (
+                        ParseErrorKind::UnterminatedString,
+                        "unterminated string".to_string(),
+                    ),
+                    token => (
+                        ParseErrorKind::UnexpectedToken,
+                        format!("no prefix parse function for {:?}", token),
+                    ),
+                };
+                if !self.stopped {
+                    self.errors.push(ParseError {
+                        kind,
+                        message,
+                        span: self.current_span(),
+                    });
+                }
                 return None;
             }
         };
 
+        let mut height = self.height + !grouped as usize;
+        if height > MAX_NESTING {
+            self.nested_too_deeply();
+            return None;
+        }
+
         // check if there are any infix parse functions for the current token
         // and if the precedence of the infix parse function is greater than the
         // current precedence
@@ -175,38 +338,54 @@ impl<'a> Parser<'a> {
             if precedence as i32 >= self.peek_precedence() as i32 {
                 break;
             }
-            let infix = match Parser::infix_parse_fns(&self.peek_token) {
+            let infix = match Parser::infix_parse_fns(self.peek_token()) {
                 Some(infix) => infix,
-                None => return left,
+                None => break,
             };
 
             self.next_token();
 
+            // each infix expression holds the one before it, so a chain like
+            // `1 + 1 + 1` is as tall as it's long
+            self.height = 0;
             left = match left {
-                Some(left) => infix(self, left),
+                Some(left) => infix(self, left, start),
                 None => return None,
             };
+            height = height.max(self.height) + 1;
+            if height > MAX_NESTING {
+                self.nested_too_deeply();
+                return None;
+            }
         }
 
+        self.height = height;
         left
     }
 
     fn parse_identifier(p: &mut Parser) -> Option<Expression> {
-        match &p.current_token {
-            Token::IDENT(s) => Some(Expression::Identifier(s.into())),
+        match p.current_token() {
+            Token::IDENT(s) => Some(Expression::Identifier(s.clone(), Slot::default())),
             _ => None,
         }
     }
 
     fn parse_integer_literal(p: &mut Parser) -> Option<Expression> {
-        match &p.current_token {
-            Token::INT(i) => Some(Expression::IntegerLiteral(i.clone())),
+        match p.current_token() {
+            Token::INT(i) => Some(Expression::IntegerLiteral(*i)),
+            _ => None,
+        }
+    }
+
+    fn parse_string_literal(p: &mut Parser) -> Option<Expression> {
+        match p.current_token() {
+            Token::STRING(s) => Some(Expression::StringLiteral(s.clone())),
             _ => None,
         }
     }
 
     fn parse_boolean_literal(p: &mut Parser) -> Option<Expression> {
-        match &p.current_token {
+        match p.current_token() {
             Token::TRUE => Some(Expression::BooleanLiteral(true)),
             Token::FALSE => Some(Expression::BooleanLiteral(false)),
             _ => None,
@@ -225,15 +404,13 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_if_expression(p: &mut Parser) -> Option<Expression> {
+        let start = p.current_span();
         if !p.expect_peek(&Token::LPAREN) {
             return None;
         }
 
         p.next_token();
-        let condition = match p.parse_expression(Precedence::LOWEST) {
-            Some(condition) => condition,
-            None => return None,
-        };
+        let condition = p.parse_expression(Precedence::LOWEST)?;
 
         if !p.expect_peek(&Token::RPAREN) {
             return None;
@@ -243,10 +420,7 @@ impl<'a> Parser<'a> {
             return None;
         }
 
-        let consequence = match p.parse_block_statement() {
-            Some(consequence) => consequence,
-            None => return None,
-        };
+        let consequence = p.parse_block_statement()?;
 
         let alternative = if p.peek_token_is(&Token::ELSE) {
             p.next_token();
@@ -265,35 +439,134 @@ impl<'a> Parser<'a> {
             condition: Box::new(condition),
             consequence: Box::new(consequence),
             alternative: alternative.map(Box::new),
+            span: start.to(p.current_span()),
         })
     }
 
-    fn parse_function_literal(p: &mut Parser) -> Option<Expression> {
+    fn parse_try_expression(p: &mut Parser) -> Option<Expression> {
+        let start = p.current_span();
+        if !p.expect_peek(&Token::LBRACE) {
+            return None;
+        }
+
+        let body = p.parse_block_statement()?;
+
+        if !p.expect_peek(&Token::CATCH) {
+            return None;
+        }
+
         if !p.expect_peek(&Token::LPAREN) {
             return None;
         }
 
-        let parameters = match p.parse_function_parameters() {
-            Some(parameters) => parameters,
+        let name = match p.peek_token() {
+            Token::IDENT(s) => s.clone(),
             _ => return None,
         };
+        p.next_token();
+
+        if !p.expect_peek(&Token::RPAREN) {
+            return None;
+        }
 
         if !p.expect_peek(&Token::LBRACE) {
             return None;
         }
 
-        let body = match p.parse_block_statement() {
-            Some(body) => body,
+        let handler = p.parse_block_statement()?;
+
+        Some(Expression::Try {
+            body: Box::new(body),
+            name,
+            handler: Box::new(handler),
+            locals: Rc::default(),
+            span: start.to(p.current_span()),
+        })
+    }
+
+    fn parse_for_expression(p: &mut Parser) -> Option<Expression> {
+        let start = p.current_span();
+        if !p.expect_peek(&Token::LPAREN) {
+            return None;
+        }
+
+        let name = match p.peek_token() {
+            Token::IDENT(s) => s.clone(),
             _ => return None,
         };
+        p.next_token();
+
+        if !p.expect_peek(&Token::IN) {
+            return None;
+        }
+
+        p.next_token();
+        let iterable = p.parse_expression(Precedence::LOWEST)?;
+
+        if !p.expect_peek(&Token::RPAREN) {
+            return None;
+        }
 
+        if !p.expect_peek(&Token::LBRACE) {
+            return None;
+        }
+
+        let body = p.parse_block_statement()?;
+
+        Some(Expression::For {
+            name,
+            iterable: Box::new(iterable),
+            body: Box::new(body),
+            locals: Rc::default(),
+            span: start.to(p.current_span()),
+        })
+    }
+
+    fn parse_function_literal(p: &mut Parser) -> Option<Expression> {
+        let (parameters, body, span) = p.parse_parameters_and_body()?;
         Some(Expression::FunctionLiteral {
             parameters,
-            body: Box::new(body),
+            body: Rc::new(body),
+            locals: Rc::default(),
+            span,
+        })
+    }
+
+    fn parse_macro_literal(p: &mut Parser) -> Option<Expression> {
+        let (parameters, body, span) = p.parse_parameters_and_body()?;
+        Some(Expression::MacroLiteral {
+            parameters,
+            body: Rc::new(body),
+            locals: Rc::default(),
+            span,
         })
     }
 
-    fn parse_function_parameters(&mut self) -> Option<Vec<String>> {
+    // what follows the fn or macro keyword, and the span of the whole literal
+    fn parse_parameters_and_body(&mut self) -> Option<(Vec<Symbol>, Statement, Span)> {
+        let start = self.current_span();
+        if !self.expect_peek(&Token::LPAREN) {
+            return None;
+        }
+
+        let parameters = match self.parse_function_parameters() {
+            Some(parameters) => parameters,
+            _ => return None,
+        };
+
+        if !self.expect_peek(&Token::LBRACE) {
+            return None;
+        }
+
+        let body = match self.parse_block_statement() {
+            Some(body) => body,
+            _ => return None,
+        };
+
+        Some((parameters, body, start.to(self.current_span())))
+    }
+
+    fn parse_function_parameters(&mut self) -> Option<Vec<Symbol>> {
         let mut identifiers = vec![];
 
         // no function parameters
@@ -304,7 +577,7 @@ impl<'a> Parser<'a> {
 
         self.next_token();
         // first parameter
-        identifiers.push(match &self.current_token {
+        identifiers.push(match self.current_token() {
             Token::IDENT(s) => s.clone(),
             _ => return None,
         });
@@ -313,7 +586,7 @@ impl<'a> Parser<'a> {
         while self.peek_token_is(&Token::COMMA) {
             self.next_token();
             self.next_token();
-            identifiers.push(match &self.current_token {
+            identifiers.push(match self.current_token() {
                 Token::IDENT(s) => s.clone(),
                 _ => return None,
             });
@@ -327,7 +600,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_prefix(p: &mut Parser) -> Option<Expression> {
-        let operator = match &p.current_token {
+        let operator = match p.current_token() {
             Token::BANG => Prefix::BANG,
             Token::MINUS => Prefix::MINUS,
             _ => return None,
@@ -335,20 +608,18 @@ impl<'a> Parser<'a> {
 
         p.next_token();
 
-        let right = match p.parse_expression(Precedence::PREFIX) {
-            Some(right) => right,
-            None => return None,
-        };
+        let right = p.parse_expression(Precedence::PREFIX)?;
 
         Some(Expression::Prefix(operator, Box::new(right)))
     }
 
-    fn parse_infix(p: &mut Parser, left: Expression) -> Option<Expression> {
-        let operator = match &p.current_token {
+    fn parse_infix(p: &mut Parser, left: Expression, _start: Span) -> Option<Expression> {
+        let operator = match p.current_token() {
             Token::PLUS => Infix::PLUS,
             Token::MINUS => Infix::MINUS,
             Token::ASTERISK => Infix::ASTERISK,
             Token::SLASH => Infix::SLASH,
+            Token::PERCENT => Infix::PERCENT,
             Token::EQ => Infix::EQ,
             Token::NOT_EQ => Infix::NOT_EQ,
             Token::LT => Infix::LT,
@@ -359,37 +630,40 @@ impl<'a> Parser<'a> {
         let precedence = p.current_precedence();
         p.next_token();
 
-        if let Some(right) = p.parse_expression(precedence) {
-            Some(Expression::Infix(operator, Box::new(left), Box::new(right)))
-        } else {
-            None
-        }
+        p.parse_expression(precedence)
+            .map(|right| Expression::Infix(operator, Box::new(left), Box::new(right)))
     }
 
     fn current_precedence(&self) -> Precedence {
-        match &self.current_token {
+        match self.current_token() {
             Token::EQ | Token::NOT_EQ => Precedence::EQUALS,
             Token::LT | Token::GT => Precedence::LESSGREATER,
             Token::PLUS | Token::MINUS => Precedence::SUM,
-            Token::ASTERISK | Token::SLASH => Precedence::PRODUCT,
+            Token::ASTERISK | Token::SLASH | Token::PERCENT => Precedence::PRODUCT,
             Token::LPAREN => Precedence::CALL,
+            Token::LBRACKET => Precedence::INDEX,
             _ => Precedence::LOWEST,
         }
     }
 
     fn peek_precedence(&self) -> Precedence {
-        match &self.peek_token {
+        match self.peek_token() {
             Token::EQ | Token::NOT_EQ => Precedence::EQUALS,
             Token::LT | Token::GT => Precedence::LESSGREATER,
             Token::PLUS | Token::MINUS => Precedence::SUM,
-            Token::ASTERISK | Token::SLASH => Precedence::PRODUCT,
+            Token::ASTERISK | Token::SLASH | Token::PERCENT => Precedence::PRODUCT,
             Token::LPAREN => Precedence::CALL,
+            Token::LBRACKET => Precedence::INDEX,
             _ => Precedence::LOWEST,
         }
     }
 
-    fn parse_call_expression(p: &mut Parser, function: Expression) -> Option<Expression> {
-        let arguments = match p.parse_call_arguments() {
+    fn parse_call_expression(
+        p: &mut Parser,
+        function: Expression,
+        start: Span,
+    ) -> Option<Expression> {
+        let arguments = match p.parse_expression_list(&Token::RPAREN) {
             Some(arguments) => arguments,
             _ => return None,
         };
@@ -397,58 +671,135 @@ impl<'a> Parser<'a> {
         Some(Expression::Call {
             function: Box::new(function),
             arguments,
+            span: start.to(p.current_span()),
         })
     }
 
-    fn parse_call_arguments(&mut self) -> Option<Vec<Expression>> {
-        let mut arguments = vec![];
+    // a comma separated list of expressions, up to the given closing token
+    fn parse_expression_list(&mut self, end: &Token) -> Option<Vec<Expression>> {
+        let mut list = vec![];
 
-        // no arguments
-        if self.peek_token_is(&Token::RPAREN) {
+        // empty list
+        if self.peek_token_is(end) {
             self.next_token();
-            return Some(arguments);
+            return Some(list);
         }
 
-        // first argument
+        // first element
         self.next_token();
-        if let Some(argument) = self.parse_expression(Precedence::LOWEST) {
-            arguments.push(argument);
-        } else {
-            return None;
-        }
+        list.push(self.parse_expression(Precedence::LOWEST)?);
 
-        // optional additional arguments
+        // optional additional elements
         while self.peek_token_is(&Token::COMMA) {
             self.next_token();
             self.next_token();
-            if let Some(argument) = self.parse_expression(Precedence::LOWEST) {
-                arguments.push(argument);
-            } else {
+            list.push(self.parse_expression(Precedence::LOWEST)?);
+        }
+
+        if !self.expect_peek(end) {
+            return None;
+        }
+
+        Some(list)
+    }
+
+    fn parse_array_literal(p: &mut Parser) -> Option<Expression> {
+        let elements = p.parse_expression_list(&Token::RBRACKET)?;
+        Some(Expression::ArrayLiteral(elements))
+    }
+
+    fn parse_hash_literal(p: &mut Parser) -> Option<Expression> {
+        let start = p.current_span();
+        let mut pairs = vec![];
+
+        while !p.peek_token_is(&Token::RBRACE) {
+            p.next_token();
+            let key = p.parse_expression(Precedence::LOWEST)?;
+
+            if !p.expect_peek(&Token::COLON) {
+                return None;
+            }
+
+            p.next_token();
+            let value = p.parse_expression(Precedence::LOWEST)?;
+            pairs.push((key, value));
+
+            if !p.peek_token_is(&Token::RBRACE) && !p.expect_peek(&Token::COMMA) {
                 return None;
             }
         }
 
-        if !self.expect_peek(&Token::RPAREN) {
+        if !p.expect_peek(&Token::RBRACE) {
             return None;
         }
 
-        Some(arguments)
+        Some(Expression::HashLiteral(pairs, start.to(p.current_span())))
+    }
+
+    fn parse_index_expression(p: &mut Parser, left: Expression, start: Span) -> Option<Expression> {
+        p.next_token();
+        let index = p.parse_expression(Precedence::LOWEST)?;
+
+        if !p.expect_peek(&Token::RBRACKET) {
+            return None;
+        }
+
+        Some(Expression::Index {
+            left: Box::new(left),
+            index: Box::new(index),
+            span: start.to(p.current_span()),
+        })
+    }
+
+    fn current_token(&self) -> &Token {
+        &self.tokens[0].token
+    }
+
+    fn current_span(&self) -> Span {
+        self.tokens[0].span
+    }
+
+    fn peek_token(&self) -> &Token {
+        self.peek_nth(1)
+    }
+
+    fn peek_span(&self) -> Span {
+        self.tokens[1].span
+    }
+
+    // peek_nth(1) is the token right after the current one
+    pub fn peek_nth(&self, n: usize) -> &Token {
+        assert!(
+            n <= self.lookahead,
+            "peek_nth({}) exceeds the parser lookahead of {}",
+            n,
+            self.lookahead
+        );
+        &self.tokens[n].token
     }
 
     fn current_token_is(&self, token: &Token) -> bool {
-        self.current_token == *token
+        self.current_token() == token
     }
 
     fn peek_token_is(&self, token: &Token) -> bool {
-        self.peek_token == *token
+        self.peek_token() == token
     }
 
     fn peek_error(&mut self, token: &Token) {
-        let msg = format!(
+        if self.stopped {
+            return;
+        }
+        let message = format!(
             "expected next token to be {:?}, got {:?} instead",
-            token, self.peek_token
+            token,
+            self.peek_token()
         );
-        self.errors.push(msg);
+        self.errors.push(ParseError {
+            kind: ParseErrorKind::ExpectedToken,
+            message,
+            span: self.peek_span(),
+        });
     }
 
     fn expect_peek(&mut self, token: &Token) -> bool {
@@ -462,8 +813,8 @@ impl<'a> Parser<'a> {
     }
 
     fn next_token(&mut self) {
-        self.current_token = self.peek_token.clone();
-        self.peek_token = self.lexer.next_token();
+        self.tokens.pop_front();
+        self.tokens.push_back(self.lexer.next_token());
     }
 }
 
@@ -486,7 +837,9 @@ mod tests {
 
             for statement in program.statements {
                 match statement {
-                    Statement::LetStatement { name: n, value: v } => {
+                    Statement::LetStatement {
+                        name: n, value: v, ..
+                    } => {
                         assert_eq!(n, name);
                         assert_eq!(v.to_string(), value.to_string());
                     }
@@ -511,7 +864,7 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ReturnStatement(_) => {}
+                Statement::ReturnStatement(..) => {}
                 _ => panic!("Expected ReturnStatement, got {:?}", statement),
             }
         }
@@ -530,7 +883,7 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ExpressionStatement(Expression::Identifier(s)) => {
+                Statement::ExpressionStatement(Expression::Identifier(s, _), _) => {
                     assert_eq!(s, "foobar");
                 }
                 _ => panic!("Expected ExpressionStatement, got {:?}", statement),
@@ -551,7 +904,7 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ExpressionStatement(Expression::IntegerLiteral(i)) => {
+                Statement::ExpressionStatement(Expression::IntegerLiteral(i), _) => {
                     assert_eq!(i, 5);
                 }
                 _ => panic!("Expected ExpressionStatement, got {:?}", statement),
@@ -574,7 +927,7 @@ return 993322;"#;
 
             for statement in program.statements {
                 match statement {
-                    Statement::ExpressionStatement(Expression::BooleanLiteral(b)) => {
+                    Statement::ExpressionStatement(Expression::BooleanLiteral(b), _) => {
                         assert_eq!(b, value);
                     }
                     _ => panic!("Expected ExpressionStatement, got {:?}", statement),
@@ -596,11 +949,15 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ExpressionStatement(Expression::If {
-                    condition,
-                    consequence,
-                    alternative,
-                }) => {
+                Statement::ExpressionStatement(
+                    Expression::If {
+                        condition,
+                        consequence,
+                        alternative,
+                        ..
+                    },
+                    _,
+                ) => {
                     assert_eq!(condition.to_string(), "(x < y)");
                     assert_eq!(consequence.to_string(), "x");
                     assert_eq!(alternative, None);
@@ -623,11 +980,15 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ExpressionStatement(Expression::If {
-                    condition,
-                    consequence,
-                    alternative,
-                }) => {
+                Statement::ExpressionStatement(
+                    Expression::If {
+                        condition,
+                        consequence,
+                        alternative,
+                        ..
+                    },
+                    _,
+                ) => {
                     assert_eq!(condition.to_string(), "(x < y)");
                     assert_eq!(consequence.to_string(), "x");
                     assert_eq!(alternative.unwrap().to_string(), "y");
@@ -637,6 +998,114 @@ return 993322;"#;
         }
     }
 
+    #[test]
+    fn test_string_literal_expression() {
+        let input = r#""hello world";"#;
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(program.statements.len(), 1);
+        match &program.statements[0] {
+            Statement::ExpressionStatement(Expression::StringLiteral(value), _) => {
+                assert_eq!(value, "hello world");
+            }
+            statement => panic!("Expected StringLiteral, got {:?}", statement),
+        }
+    }
+
+    #[test]
+    fn test_array_and_hash_literals() {
+        let tests = vec![
+            ("[]", "[]"),
+            ("[1, 2 * 2, 3 + 3]", "[1, (2 * 2), (3 + 3)]"),
+            ("myArray[1 + 1]", "(myArray[(1 + 1)])"),
+            ("{}", "{}"),
+            (
+                r#"{"one": 1, "two": 2, "three": 3}"#,
+                r#"{"one": 1, "two": 2, "three": 3}"#,
+            ),
+            (
+                r#"{"one": 0 + 1, true: 10 - 8, 3: 15 / 5,}"#,
+                r#"{"one": (0 + 1), true: (10 - 8), 3: (15 / 5)}"#,
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut parser = Parser::new(lexer);
+            let program = parser.parse_program();
+
+            assert_eq!(parser.errors.len(), 0, "input: {}", input);
+            assert_eq!(program.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_try_expression() {
+        let input = "try { x } catch (e) { y }";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(program.statements.len(), 1);
+        match &program.statements[0] {
+            Statement::ExpressionStatement(
+                Expression::Try {
+                    body,
+                    name,
+                    handler,
+                    ..
+                },
+                _,
+            ) => {
+                assert_eq!(body.to_string(), "x");
+                assert_eq!(name, "e");
+                assert_eq!(handler.to_string(), "y");
+            }
+            statement => panic!("Expected Try, got {:?}", statement),
+        }
+    }
+
+    #[test]
+    fn test_for_expression() {
+        let input = "for (x in range(3)) { puts(x) }";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(program.statements.len(), 1);
+        match &program.statements[0] {
+            Statement::ExpressionStatement(
+                Expression::For {
+                    name,
+                    iterable,
+                    body,
+                    ..
+                },
+                _,
+            ) => {
+                assert_eq!(name, "x");
+                assert_eq!(iterable.to_string(), "range(3)");
+                assert_eq!(body.to_string(), "puts(x)");
+            }
+            statement => panic!("Expected For, got {:?}", statement),
+        }
+
+        let mut parser = Parser::new(Lexer::new("for (x, y) { x }"));
+        parser.parse_program();
+        assert_eq!(
+            parser.errors[0].to_string(),
+            "expected next token to be IN, got COMMA instead at line 1, column 7"
+        );
+    }
+
     #[test]
     fn test_function_literal_parsing() {
         let input = "fn(x, y) { x + y; }";
@@ -650,10 +1119,12 @@ return 993322;"#;
 
         for statement in program.statements {
             match statement {
-                Statement::ExpressionStatement(Expression::FunctionLiteral {
-                    parameters,
-                    body,
-                }) => {
+                Statement::ExpressionStatement(
+                    Expression::FunctionLiteral {
+                        parameters, body, ..
+                    },
+                    _,
+                ) => {
                     assert_eq!(parameters.len(), 2);
                     assert_eq!(parameters[0], "x");
                     assert_eq!(parameters[1], "y");
@@ -664,6 +1135,34 @@ return 993322;"#;
         }
     }
 
+    #[test]
+    fn test_macro_literal_parsing() {
+        let input = "macro(x, y) { x + y; }";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+
+        assert_eq!(program.statements.len(), 1);
+        assert_eq!(parser.errors.len(), 0);
+
+        match &program.statements[0] {
+            Statement::ExpressionStatement(
+                Expression::MacroLiteral {
+                    parameters, body, ..
+                },
+                _,
+            ) => {
+                assert_eq!(parameters.len(), 2);
+                assert_eq!(parameters[0], "x");
+                assert_eq!(parameters[1], "y");
+                assert_eq!(body.to_string(), "(x + y)");
+            }
+            statement => panic!("Expected MacroLiteral, got {:?}", statement),
+        }
+        assert_eq!(program.to_string(), "macro(x, y) (x + y)");
+    }
+
     #[test]
     fn test_function_parameter_parsing() {
         let tests = vec![
@@ -683,10 +1182,10 @@ return 993322;"#;
 
             for statement in program.statements {
                 match statement {
-                    Statement::ExpressionStatement(Expression::FunctionLiteral {
-                        parameters,
-                        body: _,
-                    }) => {
+                    Statement::ExpressionStatement(
+                        Expression::FunctionLiteral { parameters, .. },
+                        _,
+                    ) => {
                         assert_eq!(parameters, expected);
                     }
                     _ => panic!("Expected ExpressionStatement, got {:?}", statement),
@@ -711,10 +1210,10 @@ return 993322;"#;
             for statement in &program.statements {
                 println!("{:?}", statement);
                 match statement {
-                    Statement::ExpressionStatement(exp) => match exp {
+                    Statement::ExpressionStatement(exp, _) => match exp {
                         Expression::Prefix(p, r) => {
                             assert_eq!(p.to_string(), prefix);
-                            assert_eq!(is_literal_expression(r, &int.to_string()), true);
+                            assert!(is_literal_expression(r, &int.to_string()));
                         }
                         _ => panic!("Expected PrefixExpression, got {:?}", exp),
                     },
@@ -739,10 +1238,10 @@ return 993322;"#;
 
             for statement in &program.statements {
                 match statement {
-                    Statement::ExpressionStatement(exp) => match exp {
+                    Statement::ExpressionStatement(exp, _) => match exp {
                         Expression::Prefix(p, r) => {
                             assert_eq!(p.to_string(), prefix);
-                            assert_eq!(is_literal_expression(r, value), true);
+                            assert!(is_literal_expression(r, value));
                         }
                         _ => panic!("Expected PrefixExpression, got {:?}", exp),
                     },
@@ -765,10 +1264,11 @@ return 993322;"#;
 
         for statement in &program.statements {
             match statement {
-                Statement::ExpressionStatement(exp) => match exp {
+                Statement::ExpressionStatement(exp, _) => match exp {
                     Expression::Call {
                         function,
                         arguments,
+                        ..
                     } => {
                         assert_eq!(function.to_string(), "add");
                         assert_eq!(arguments.len(), 3);
@@ -790,6 +1290,7 @@ return 993322;"#;
             ("5 - 4;", 5, "-", 4),
             ("5 * 5;", 5, "*", 5),
             ("5 / 5;", 5, "/", 5),
+            ("5 % 5;", 5, "%", 5),
             ("5 > 5;", 5, ">", 5),
             ("5 < 5;", 5, "<", 5),
             ("5 == 5;", 5, "==", 5),
@@ -807,11 +1308,13 @@ return 993322;"#;
 
             for statement in &program.statements {
                 match statement {
-                    Statement::ExpressionStatement(exp) => {
-                        assert_eq!(
-                            is_infix_expression(exp, &left.to_string(), op, &right.to_string()),
-                            true
-                        );
+                    Statement::ExpressionStatement(exp, _) => {
+                        assert!(is_infix_expression(
+                            exp,
+                            &left.to_string(),
+                            op,
+                            &right.to_string()
+                        ));
                     }
                     _ => panic!("Expected ExpressionStatement, got {:?}", statement),
                 }
@@ -838,11 +1341,13 @@ return 993322;"#;
 
             for statement in &program.statements {
                 match statement {
-                    Statement::ExpressionStatement(exp) => {
-                        assert_eq!(
-                            is_infix_expression(exp, &left.to_string(), op, &right.to_string()),
-                            true
-                        );
+                    Statement::ExpressionStatement(exp, _) => {
+                        assert!(is_infix_expression(
+                            exp,
+                            &left.to_string(),
+                            op,
+                            &right.to_string()
+                        ));
                     }
                     _ => panic!("Expected ExpressionStatement, got {:?}", statement),
                 }
@@ -859,6 +1364,8 @@ return 993322;"#;
             ("a + b - c", "((a + b) - c)"),
             ("a * b * c", "((a * b) * c)"),
             ("a * b / c", "((a * b) / c)"),
+            ("a + b % c", "(a + (b % c))"),
+            ("a % b * c", "((a % b) * c)"),
             ("a + b / c", "(a + (b / c))"),
             ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f)"),
             ("3 + 4; -5 * 5", "(3 + 4)((-5) * 5)"),
@@ -885,6 +1392,14 @@ return 993322;"#;
                 "add(a + b + c * d / f + g)",
                 "add((((a + b) + ((c * d) / f)) + g))",
             ),
+            (
+                "a * [1, 2, 3, 4][b * c] * d",
+                "((a * ([1, 2, 3, 4][(b * c)])) * d)",
+            ),
+            (
+                "add(a * b[2], b[1], 2 * [1, 2][1])",
+                "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])))",
+            ),
         ];
 
         for test in tests {
@@ -898,7 +1413,157 @@ return 993322;"#;
         }
     }
 
-    fn is_integer_literal(exp: &Expression, value: isize) -> bool {
+    #[test]
+    fn test_statement_spans() {
+        let input = "let x = 5;\nreturn add(x,\n  2);\nif (x) { x }";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+
+        assert_eq!(parser.errors.len(), 0);
+        assert_eq!(program.statements.len(), 3);
+
+        let spans: Vec<(usize, usize, usize, usize)> = program
+            .statements
+            .iter()
+            .map(|s| {
+                let span = s.span();
+                (span.start, span.end, span.line, span.column)
+            })
+            .collect();
+        assert_eq!(spans, vec![(0, 10, 1, 1), (11, 30, 2, 1), (31, 43, 4, 1)]);
+
+        match &program.statements[1] {
+            Statement::ReturnStatement(Expression::Call { span, .. }, _) => {
+                assert_eq!(&input[span.start..span.end], "add(x,\n  2)");
+            }
+            statement => panic!("Expected ReturnStatement, got {:?}", statement),
+        }
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let input = "let x = 5;\nlet y 10;";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        parser.parse_program();
+
+        assert_eq!(
+            parser.errors[0].to_string(),
+            "expected next token to be ASSIGN, got INT(10) instead at line 2, column 7"
+        );
+    }
+
+    #[test]
+    fn test_illegal_character_errors() {
+        let input = "let x = 5;\nlet y = @;";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::new(lexer);
+        parser.parse_program();
+
+        assert_eq!(
+            parser.errors[0].to_string(),
+            "unexpected character '@' at line 2, column 9"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let program = parse("let x = 1; x + 2").unwrap();
+        assert_eq!(program.statements.len(), 2);
+        assert_eq!(program.to_string(), "let x = 1;(x + 2)");
+
+        let errors = parse("let = 1; let x 2;").unwrap_err();
+        let kinds: Vec<ParseErrorKind> = errors.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ParseErrorKind::UnexpectedToken,
+                ParseErrorKind::ExpectedToken
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let nested = |open: &str, depth: usize, close: &str| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert!(parse(&nested("(", 200, ")")).is_ok());
+        assert!(parse(&nested("[", 200, "]")).is_ok());
+        assert!(parse(&format!("{}1", "1 + ".repeat(200))).is_ok());
+
+        // chains in parentheses in chains, each short but all of them tall
+        let chains = (0..100).fold("1".to_string(), |inner, _| {
+            format!("({}){}", inner, " + 1".repeat(100))
+        });
+
+        let tests = vec![
+            (nested("(", 100_000, ")"), ParseErrorKind::NestedTooDeeply),
+            (chains, ParseErrorKind::NestedTooDeeply),
+            ("-".repeat(100_000), ParseErrorKind::NestedTooDeeply),
+            (
+                format!("{}1", "1 + ".repeat(100_000)),
+                ParseErrorKind::NestedTooDeeply,
+            ),
+            (
+                format!("f{}", "(1)".repeat(100_000)),
+                ParseErrorKind::NestedTooDeeply,
+            ),
+            (
+                nested("if (true) { ", 1_000, " }"),
+                ParseErrorKind::NestedTooDeeply,
+            ),
+            (
+                "99999999999999999999".to_string(),
+                ParseErrorKind::IntegerTooLarge,
+            ),
+            (
+                "puts(\"no end)".to_string(),
+                ParseErrorKind::UnterminatedString,
+            ),
+        ];
+
+        for (input, kind) in tests {
+            let errors = parse(&input).unwrap_err();
+            assert_eq!(errors[0].kind, kind);
+            // nothing after it is reported
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn test_peek_nth() {
+        let input = "let x = 5;";
+
+        let lexer = Lexer::new(input);
+        let mut parser = Parser::with_lookahead(lexer, 4);
+
+        assert_eq!(parser.current_token(), &Token::LET);
+        assert_eq!(parser.peek_nth(1), &Token::IDENT("x".into()));
+        assert_eq!(parser.peek_nth(3), &Token::INT(5));
+        assert_eq!(parser.peek_nth(4), &Token::SEMICOLON);
+
+        parser.next_token();
+        parser.next_token();
+        assert_eq!(parser.peek_nth(2), &Token::SEMICOLON);
+        assert_eq!(parser.peek_nth(3), &Token::EOF);
+        assert_eq!(parser.peek_nth(4), &Token::EOF);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the parser lookahead")]
+    fn test_peek_nth_beyond_lookahead() {
+        let lexer = Lexer::new("1 + 2");
+        let parser = Parser::new(lexer);
+
+        parser.peek_nth(3);
+    }
+
+    fn is_integer_literal(exp: &Expression, value: i64) -> bool {
         match exp {
             Expression::IntegerLiteral(i) => *i == value,
             _ => false,
@@ -907,7 +1572,7 @@ return 993322;"#;
 
     fn is_identifier(exp: &Expression, value: &str) -> bool {
         match exp {
-            Expression::Identifier(s) => s == value,
+            Expression::Identifier(s, _) => s == value,
             _ => false,
         }
     }
@@ -916,7 +1581,7 @@ return 993322;"#;
         match exp {
             Expression::BooleanLiteral(b) => b.to_string() == expected,
             Expression::IntegerLiteral(_) => is_integer_literal(exp, expected.parse().unwrap()),
-            Expression::Identifier(_) => is_identifier(exp, expected),
+            Expression::Identifier(..) => is_identifier(exp, expected),
             _ => false,
         }
     }
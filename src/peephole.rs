@@ -0,0 +1,476 @@
+use std::rc::Rc;
+
+use crate::ast::Infix;
+use crate::code::{make, Instructions, Opcode};
+use crate::evaluator::{eval_infix_expression, Object};
+use crate::source_map::SourceMap;
+
+// Rewrites short sequences of instructions into shorter ones once a
+// function has been compiled. Each pass looks for one kind of pattern; the
+// pipeline runs them in turn until none of them finds anything more, since
+// one rewrite can make room for another.
+//
+// Instructions that are removed can be jumped to: a jump to one lands on the
+// instruction after it instead. A pattern never spans an instruction that is
+// jumped to, other than its first one, so no jump ends up in the middle of a
+// rewritten sequence.
+pub struct Pass {
+    run: fn(&mut Code, &[Rc<Object>]) -> bool,
+}
+
+pub const PASSES: &[Pass] = &[
+    Pass {
+        run: constant_comparisons,
+    },
+    Pass {
+        run: constant_conditions,
+    },
+    Pass {
+        run: double_negation,
+    },
+    Pass {
+        run: pop_after_constant,
+    },
+    Pass { run: jump_to_next },
+];
+
+#[cfg(test)]
+pub fn optimize(instructions: &Instructions, constants: &[Rc<Object>]) -> Instructions {
+    optimize_with(PASSES, instructions, constants)
+}
+
+#[cfg(test)]
+pub fn optimize_with(
+    passes: &[Pass],
+    instructions: &Instructions,
+    constants: &[Rc<Object>],
+) -> Instructions {
+    let mut code = Code::decode(instructions);
+    code.run(passes, constants);
+    code.encode()
+}
+
+// optimizes the instructions of a function and moves the entries of its
+// source map along with them; the entry of a removed instruction moves to
+// the one after it
+pub fn optimize_mapped(
+    instructions: &Instructions,
+    source_map: &SourceMap,
+    constants: &[Rc<Object>],
+) -> (Instructions, SourceMap) {
+    let mut code = Code::decode(instructions);
+    code.run(PASSES, constants);
+    let old_offsets: Vec<usize> = instructions.iter().map(|(offset, ..)| offset).collect();
+    let new_offsets = code.offsets();
+    let source_map = source_map.remap(|offset| {
+        let index = old_offsets.partition_point(|old| *old < offset);
+        new_offsets[index]
+    });
+    (code.encode(), source_map)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Instruction {
+    op: Opcode,
+    // the operand of a jump is the index of the instruction it jumps to
+    operands: Vec<usize>,
+}
+
+fn is_jump(op: Opcode) -> bool {
+    matches!(op, Opcode::Jump | Opcode::JumpNotTruthy | Opcode::Try)
+}
+
+// Instructions being rewritten, with the removed ones left as holes so that
+// jumps keep their targets.
+pub struct Code {
+    slots: Vec<Option<Instruction>>,
+}
+
+impl Code {
+    fn decode(instructions: &Instructions) -> Code {
+        let decoded: Vec<_> = instructions.iter().collect();
+        let index_of = |offset: usize| {
+            decoded
+                .iter()
+                .position(|(start, ..)| *start == offset)
+                .unwrap_or(decoded.len())
+        };
+        let slots = decoded
+            .iter()
+            .map(|(_, op, operands)| {
+                let mut operands = operands.clone();
+                if is_jump(*op) {
+                    operands[0] = index_of(operands[0]);
+                }
+                Some(Instruction { op: *op, operands })
+            })
+            .collect();
+        Code { slots }
+    }
+
+    fn run(&mut self, passes: &[Pass], constants: &[Rc<Object>]) {
+        loop {
+            let mut changed = false;
+            for pass in passes {
+                changed |= (pass.run)(self, constants);
+            }
+            if !changed {
+                return;
+            }
+        }
+    }
+
+    // the offset each slot is encoded at, and the end of the code; a hole is
+    // at the offset of the instruction after it
+    fn offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.slots.len() + 1);
+        let mut offset = 0;
+        for slot in &self.slots {
+            offsets.push(offset);
+            if let Some(instruction) = slot {
+                offset += instruction.op.width();
+            }
+        }
+        offsets.push(offset);
+        offsets
+    }
+
+    fn encode(&self) -> Instructions {
+        let offsets = self.offsets();
+        let mut bytes = Vec::with_capacity(offsets[self.slots.len()]);
+        for instruction in self.slots.iter().flatten() {
+            let mut operands = instruction.operands.clone();
+            if is_jump(instruction.op) {
+                operands[0] = offsets[operands[0]];
+            }
+            bytes.extend(make(instruction.op, &operands));
+        }
+        Instructions(bytes)
+    }
+
+    fn op(&self, index: usize) -> Opcode {
+        self.slots[index].as_ref().unwrap().op
+    }
+
+    fn operands(&self, index: usize) -> &[usize] {
+        &self.slots[index].as_ref().unwrap().operands
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.slots[index] = None;
+    }
+
+    fn replace(&mut self, index: usize, op: Opcode, operands: &[usize]) {
+        let operands = operands.to_vec();
+        self.slots[index] = Some(Instruction { op, operands });
+    }
+
+    // the instruction a jump to `index` lands on, or the end
+    fn resolve(&self, index: usize) -> usize {
+        (index..self.slots.len())
+            .find(|&index| self.slots[index].is_some())
+            .unwrap_or(self.slots.len())
+    }
+
+    // Runs of `len` instructions in a row that can be rewritten, in order.
+    // A rewrite can only touch the instructions of its own run: runs that
+    // overlap one that was rewritten are skipped.
+    fn rewrite(
+        &mut self,
+        len: usize,
+        mut rewrite: impl FnMut(&mut Code, &[usize]) -> bool,
+    ) -> bool {
+        let mut targets = vec![false; self.slots.len() + 1];
+        for instruction in self.slots.iter().flatten() {
+            if is_jump(instruction.op) {
+                targets[self.resolve(instruction.operands[0])] = true;
+            }
+        }
+        let live: Vec<usize> = (0..self.slots.len())
+            .filter(|&index| self.slots[index].is_some())
+            .collect();
+
+        let mut changed = false;
+        let mut next = 0;
+        for window in live.windows(len) {
+            if window[0] < next || window[1..].iter().any(|&index| targets[index]) {
+                continue;
+            }
+            if rewrite(self, window) {
+                changed = true;
+                next = window[len - 1] + 1;
+            }
+        }
+        changed
+    }
+}
+
+fn boolean(value: bool) -> Opcode {
+    if value {
+        Opcode::True
+    } else {
+        Opcode::False
+    }
+}
+
+// `1 < 2` is true: comparisons of two integer or string constants
+fn constant_comparisons(code: &mut Code, constants: &[Rc<Object>]) -> bool {
+    code.rewrite(3, |code, window| {
+        let operator = match code.op(window[2]) {
+            Opcode::Equal => Infix::EQ,
+            Opcode::NotEqual => Infix::NOT_EQ,
+            Opcode::LessThan => Infix::LT,
+            Opcode::GreaterThan => Infix::GT,
+            _ => return false,
+        };
+        if code.op(window[0]) != Opcode::Constant || code.op(window[1]) != Opcode::Constant {
+            return false;
+        }
+        let left = &constants[code.operands(window[0])[0]];
+        let right = &constants[code.operands(window[1])[0]];
+        if !matches!(
+            (&**left, &**right),
+            (Object::Integer(_), Object::Integer(_)) | (Object::String(_), Object::String(_))
+        ) {
+            return false;
+        }
+        let value = match eval_infix_expression(&operator, left, right).as_deref() {
+            Ok(Object::Boolean(value)) => *value,
+            _ => return false,
+        };
+        code.replace(window[0], boolean(value), &[]);
+        code.remove(window[1]);
+        code.remove(window[2]);
+        true
+    })
+}
+
+// negating a boolean constant, and branching on one
+fn constant_conditions(code: &mut Code, _: &[Rc<Object>]) -> bool {
+    code.rewrite(2, |code, window| {
+        let value = match code.op(window[0]) {
+            Opcode::True => true,
+            Opcode::False => false,
+            _ => return false,
+        };
+        match code.op(window[1]) {
+            Opcode::Bang => {
+                code.replace(window[0], boolean(!value), &[]);
+                code.remove(window[1]);
+            }
+            Opcode::JumpNotTruthy if value => {
+                code.remove(window[0]);
+                code.remove(window[1]);
+            }
+            Opcode::JumpNotTruthy => {
+                let target = code.operands(window[1])[0];
+                code.remove(window[0]);
+                code.replace(window[1], Opcode::Jump, &[target]);
+            }
+            _ => return false,
+        }
+        true
+    })
+}
+
+// `!!` on a value that's already a boolean, like the result of a comparison;
+// on anything else it converts the value to a boolean, so it has to stay
+fn double_negation(code: &mut Code, _: &[Rc<Object>]) -> bool {
+    code.rewrite(3, |code, window| {
+        let is_boolean = matches!(
+            code.op(window[0]),
+            Opcode::Equal
+                | Opcode::NotEqual
+                | Opcode::LessThan
+                | Opcode::GreaterThan
+                | Opcode::Bang
+                | Opcode::True
+                | Opcode::False
+        );
+        if !is_boolean || code.op(window[1]) != Opcode::Bang || code.op(window[2]) != Opcode::Bang {
+            return false;
+        }
+        code.remove(window[1]);
+        code.remove(window[2]);
+        true
+    })
+}
+
+// a constant that is pushed only to be popped again
+fn pop_after_constant(code: &mut Code, _: &[Rc<Object>]) -> bool {
+    code.rewrite(2, |code, window| {
+        let is_constant = matches!(
+            code.op(window[0]),
+            Opcode::Constant | Opcode::True | Opcode::False | Opcode::Null
+        );
+        if !is_constant || code.op(window[1]) != Opcode::Pop {
+            return false;
+        }
+        code.remove(window[0]);
+        code.remove(window[1]);
+        true
+    })
+}
+
+// a jump to the instruction right after it
+fn jump_to_next(code: &mut Code, _: &[Rc<Object>]) -> bool {
+    code.rewrite(1, |code, window| {
+        let index = window[0];
+        if code.op(index) != Opcode::Jump
+            || code.resolve(code.operands(index)[0]) != code.resolve(index + 1)
+        {
+            return false;
+        }
+        code.remove(index);
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::engine::{Engine, Runner};
+    use crate::evaluator::integer_object;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    fn instructions(instructions: Vec<Vec<u8>>) -> Instructions {
+        Instructions(instructions.concat())
+    }
+
+    #[test]
+    fn test_passes() {
+        let constants = vec![
+            integer_object(1),
+            integer_object(2),
+            Object::String("a".to_string()).into(),
+        ];
+        let tests = vec![
+            (
+                vec![
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Constant, &[1]),
+                    make(Opcode::LessThan, &[]),
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Constant, &[2]),
+                    make(Opcode::Equal, &[]),
+                ],
+                // only constants of the same type are compared
+                "\
+0000 OpTrue
+0001 OpConstant 0
+0004 OpConstant 2
+0007 OpEqual
+",
+            ),
+            (
+                vec![
+                    make(Opcode::True, &[]),
+                    make(Opcode::Bang, &[]),
+                    make(Opcode::JumpNotTruthy, &[9]),
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Constant, &[1]),
+                ],
+                "0000 OpConstant 1\n",
+            ),
+            (
+                vec![
+                    make(Opcode::GetLocal, &[0]),
+                    make(Opcode::GetLocal, &[1]),
+                    make(Opcode::Equal, &[]),
+                    make(Opcode::Bang, &[]),
+                    make(Opcode::Bang, &[]),
+                    make(Opcode::GetLocal, &[0]),
+                    make(Opcode::Bang, &[]),
+                    make(Opcode::Bang, &[]),
+                ],
+                "\
+0000 OpGetLocal 0
+0002 OpGetLocal 1
+0004 OpEqual
+0005 OpGetLocal 0
+0007 OpBang
+0008 OpBang
+",
+            ),
+            (
+                vec![
+                    make(Opcode::Null, &[]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Jump, &[5]),
+                    make(Opcode::Null, &[]),
+                    make(Opcode::Return, &[]),
+                ],
+                "\
+0000 OpNull
+0001 OpReturn
+",
+            ),
+            // a jump that lands on the pop keeps it
+            (
+                vec![
+                    make(Opcode::GetLocal, &[0]),
+                    make(Opcode::JumpNotTruthy, &[8]),
+                    make(Opcode::Constant, &[0]),
+                    make(Opcode::Pop, &[]),
+                    make(Opcode::Return, &[]),
+                ],
+                "\
+0000 OpGetLocal 0
+0002 OpJumpNotTruthy 8
+0005 OpConstant 0
+0008 OpPop
+0009 OpReturn
+",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let optimized = optimize(&instructions(input), &constants);
+            assert_eq!(optimized.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let input = instructions(vec![make(Opcode::True, &[]), make(Opcode::Pop, &[])]);
+        let only_jumps = [Pass { run: jump_to_next }];
+        assert_eq!(optimize_with(&only_jumps, &input, &[]), input);
+        assert_eq!(optimize_with(&[], &input, &[]), input);
+        assert!(optimize(&input, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_optimized_programs_run_the_same() {
+        let tests = vec![
+            "if (1 < 2) { 10 } else { 20 }",
+            "let x = 5; if (!!(x > 2)) { x } else { 0 }",
+            r#"if ("a" == "a") { 1 }; 2; "b"; if (false) { 3 }"#,
+            "let f = fn(n) { if (!(n == 0)) { 1; n * f(n - 1) } else { 1 } }; f(10)",
+            "let f = fn() { try { 1 / 0; } catch (e) { if (true) { 2 } } }; f()",
+            "let x = if (!true) { 1 }; x",
+        ];
+
+        for input in tests {
+            let parse = || Parser::new(Lexer::new(input)).parse_program();
+            let expected = Runner::new(Engine::TreeWalker)
+                .run(parse())
+                .unwrap()
+                .to_string();
+            let unoptimized = Compiler::new().fold_constants(false).peephole(false);
+            let optimized = Compiler::new().fold_constants(false);
+            for mut compiler in [unoptimized, optimized] {
+                let bytecode = compiler.compile(&parse()).unwrap();
+                assert_eq!(
+                    Vm::new().run(bytecode).unwrap().to_string(),
+                    expected,
+                    "{}",
+                    input
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::evaluator::{HashPair, Object};
+
+// how wide a collection may get before its elements go on separate lines
+const MAX_WIDTH: usize = 80;
+// elements of a collection shown before the rest is summarized
+const MAX_ELEMENTS: usize = 100;
+// collections nested deeper than this are elided
+const MAX_DEPTH: usize = 32;
+// elements shown in all, so that a value that holds the same collection many
+// times over doesn't render all of its copies
+const MAX_TOTAL_ELEMENTS: usize = 10_000;
+const INDENT: &str = "  ";
+
+// Renders an array or hash. Short collections stay on one line, longer ones
+// get an element per line, indented by nesting level. Strings inside are
+// quoted so that they can be told apart from other values, and functions are
+// shortened to their parameters. A collection that contains itself is shown
+// as `[...]` or `{...}` where it repeats.
+pub fn render(obj: &Object) -> String {
+    Printer::new(true).render(obj, 0)
+}
+
+// like render, but always on a single line
+pub fn render_line(obj: &Object) -> String {
+    Printer::new(false).render(obj, 0)
+}
+
+// Like render, but it stops showing elements once the values in them come
+// to about `bytes`, for when the rendering has to be short rather than
+// whole.
+pub fn render_within(obj: &Object, bytes: usize) -> String {
+    Printer {
+        bytes,
+        ..Printer::new(true)
+    }
+    .render(obj, 0)
+}
+
+struct Printer {
+    // the collections being rendered, outermost first
+    path: Vec<*const Object>,
+    wrap: bool,
+    // how many more elements may be shown, and about how many more bytes
+    // the values in them may take
+    elements: usize,
+    bytes: usize,
+}
+
+impl Printer {
+    fn new(wrap: bool) -> Self {
+        Printer {
+            path: Vec::new(),
+            wrap,
+            elements: MAX_TOTAL_ELEMENTS,
+            bytes: usize::MAX,
+        }
+    }
+
+    fn render(&mut self, obj: &Object, indent: usize) -> String {
+        let rendered = match obj {
+            Object::String(value) => quote(value),
+            Object::Function(function) => {
+                let parameters: Vec<&str> = function.parameters().iter().map(|p| &**p).collect();
+                format!("fn({}) {{...}}", parameters.join(", "))
+            }
+            Object::Memoized(memoized) => {
+                format!("memoized {}", self.render(memoized.function(), indent))
+            }
+            Object::ReturnValue(value) => self.render(value, indent),
+            Object::Array(elements) => {
+                self.collection(obj, indent, ("[", "]"), elements.len(), |printer, index| {
+                    printer.render(&elements[index], indent + 1)
+                })
+            }
+            Object::Hash(pairs) => {
+                let pairs: Vec<&HashPair> = pairs.values().collect();
+                self.collection(obj, indent, ("{", "}"), pairs.len(), |printer, index| {
+                    let pair = pairs[index];
+                    format!(
+                        "{}: {}",
+                        printer.render(&pair.key, indent + 1),
+                        printer.render(&pair.value, indent + 1)
+                    )
+                })
+            }
+            _ => obj.to_string(),
+        };
+        if !matches!(obj, Object::Array(_) | Object::Hash(_)) {
+            self.bytes = self.bytes.saturating_sub(rendered.len());
+        }
+        rendered
+    }
+
+    fn collection(
+        &mut self,
+        obj: &Object,
+        indent: usize,
+        (open, close): (&str, &str),
+        len: usize,
+        mut element: impl FnMut(&mut Printer, usize) -> String,
+    ) -> String {
+        let ptr = obj as *const Object;
+        if len == 0 {
+            return format!("{}{}", open, close);
+        }
+        if self.path.contains(&ptr) || self.path.len() >= MAX_DEPTH {
+            return format!("{}...{}", open, close);
+        }
+
+        self.path.push(ptr);
+        let mut elements = Vec::new();
+        while elements.len() < len.min(MAX_ELEMENTS) && self.elements > 0 && self.bytes > 0 {
+            self.elements -= 1;
+            elements.push(element(self, elements.len()));
+        }
+        self.path.pop();
+        if len > elements.len() {
+            elements.push(format!("... {} more", len - elements.len()));
+        }
+
+        let line = format!("{}{}{}", open, elements.join(", "), close);
+        if !self.wrap || !line.contains('\n') && INDENT.len() * indent + line.len() <= MAX_WIDTH {
+            return line;
+        }
+        let inner = INDENT.repeat(indent + 1);
+        let mut result = format!("{}\n", open);
+        for element in elements {
+            result.push_str(&format!("{}{},\n", inner, element));
+        }
+        result.push_str(&format!("{}{}", INDENT.repeat(indent), close));
+        result
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut result = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::{integer_object, HashPairs};
+    use std::rc::Rc;
+
+    fn string(value: &str) -> Rc<Object> {
+        Object::String(value.into()).into()
+    }
+
+    fn array(elements: Vec<Rc<Object>>) -> Rc<Object> {
+        Object::Array(elements).into()
+    }
+
+    #[test]
+    fn test_render() {
+        let mut pairs = HashPairs::new();
+        for (key, value) in [(string("b"), integer_object(2)), (string("a"), string("x"))] {
+            pairs.insert(key.hash_key().unwrap(), HashPair { key, value });
+        }
+        let hash: Rc<Object> = Object::Hash(pairs).into();
+
+        let tests = vec![
+            (array(vec![]), "[]"),
+            (
+                array(vec![integer_object(1), string("two\n\"2\"")]),
+                r#"[1, "two\n\"2\""]"#,
+            ),
+            (Rc::clone(&hash), r#"{"b": 2, "a": "x"}"#),
+            (
+                array(vec![hash, array(vec![])]),
+                r#"[{"b": 2, "a": "x"}, []]"#,
+            ),
+        ];
+        for (obj, expected) in tests {
+            assert_eq!(render(&obj), expected);
+        }
+    }
+
+    #[test]
+    fn test_render_long_collections() {
+        let words = (0..3).map(|_| string(&"word".repeat(8))).collect();
+        let nested = array(vec![integer_object(1), array(words)]);
+        let expected = format!(
+            "[\n  1,\n  [\n    {0},\n    {0},\n    {0},\n  ],\n]",
+            format_args!("\"{}\"", "word".repeat(8))
+        );
+        assert_eq!(render(&nested), expected);
+
+        let numbers = array((0..150).map(integer_object).collect());
+        let rendered = render(&numbers);
+        assert!(
+            rendered.ends_with("  99,\n  ... 50 more,\n]"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_render_deep_nesting() {
+        let mut nested = array(vec![]);
+        for _ in 0..1_000 {
+            nested = array(vec![nested]);
+        }
+        let rendered = render(&nested);
+        assert!(rendered.contains("[...]"));
+    }
+
+    #[test]
+    fn test_render_shared_collections() {
+        // a collection that holds the one before it twice, which is 2^22
+        // elements deep down but few collections
+        let mut shared = array(vec![integer_object(1)]);
+        for _ in 0..22 {
+            shared = array(vec![Rc::clone(&shared), shared]);
+        }
+        for rendered in [render(&shared), render_line(&shared)] {
+            assert!(rendered.len() < 1024 * 1024, "{}", rendered.len());
+            assert!(rendered.contains("... 1 more"));
+        }
+    }
+
+    #[test]
+    fn test_render_within() {
+        let numbers = array((0..100).map(integer_object).collect());
+        assert_eq!(render_within(&numbers, 5), "[0, 1, 2, 3, 4, ... 95 more]");
+        assert_eq!(render_within(&numbers, 1000), render(&numbers));
+
+        let words = array((0..3).map(|_| string(&"word".repeat(100))).collect());
+        let rendered = render_within(&array(vec![words, integer_object(1)]), 10);
+        assert!(
+            rendered.ends_with("... 2 more,\n  ],\n  ... 1 more,\n]"),
+            "{}",
+            rendered
+        );
+    }
+}
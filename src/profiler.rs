@@ -0,0 +1,177 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::evaluator::{EvalError, Frame, Object};
+use crate::observer::EvalObserver;
+use crate::symbol::Symbol;
+
+// Collects call counts and timings per function while the evaluator runs.
+// It's a shared handle: pass a clone to EvalConfig::observe and read the
+// report from the original once eval returns.
+#[derive(Clone, Default)]
+pub struct Profiler {
+    data: Rc<RefCell<ProfileData>>,
+}
+
+#[derive(Default)]
+struct ProfileData {
+    stats: HashMap<Symbol, Stats>,
+    active: Vec<ActiveCall>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub calls: u64,
+    // time spent in the function including the functions it called; the time
+    // of recursive calls is counted once, for the outermost call
+    pub total: Duration,
+    // time spent in the function itself
+    pub own: Duration,
+}
+
+struct ActiveCall {
+    name: Symbol,
+    start: Instant,
+    children: Duration,
+}
+
+impl Profiler {
+    pub fn enter(&self, name: &Symbol) {
+        let mut data = self.data.borrow_mut();
+        data.stats.entry(name.clone()).or_default().calls += 1;
+        data.active.push(ActiveCall {
+            name: name.clone(),
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    pub fn leave(&self) {
+        let mut data = self.data.borrow_mut();
+        let Some(call) = data.active.pop() else {
+            return;
+        };
+        let elapsed = call.start.elapsed();
+        let recursive = data.active.iter().any(|active| active.name == call.name);
+        if let Some(parent) = data.active.last_mut() {
+            parent.children += elapsed;
+        }
+
+        let stats = data.stats.entry(call.name).or_default();
+        stats.own += elapsed.saturating_sub(call.children);
+        if !recursive {
+            stats.total += elapsed;
+        }
+    }
+
+    // leaves the calls an error unwound, down to the given number of active calls
+    pub fn unwind(&self, depth: usize) {
+        while self.data.borrow().active.len() > depth {
+            self.leave();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn stats(&self, name: &str) -> Option<Stats> {
+        self.data
+            .borrow()
+            .stats
+            .iter()
+            .find(|(symbol, _)| **symbol == name)
+            .map(|(_, stats)| *stats)
+    }
+
+    // one line per function, the most expensive ones first
+    pub fn report(&self) -> String {
+        let data = self.data.borrow();
+        let mut stats: Vec<(&Symbol, &Stats)> = data.stats.iter().collect();
+        stats.sort_by(|a, b| b.1.own.cmp(&a.1.own).then_with(|| str::cmp(a.0, b.0)));
+
+        let mut report = format!(
+            "{:<20} {:>10} {:>12} {:>12}\n",
+            "function", "calls", "total", "self"
+        );
+        for (name, stats) in stats {
+            report.push_str(&format!(
+                "{:<20} {:>10} {:>12} {:>12}\n",
+                name.to_string(),
+                stats.calls,
+                format!("{:.3?}", stats.total),
+                format!("{:.3?}", stats.own)
+            ));
+        }
+        report
+    }
+}
+
+impl EvalObserver for Profiler {
+    fn on_call(&mut self, frame: &Frame, _args: &[Rc<Object>]) {
+        self.enter(&frame.name);
+    }
+
+    fn on_return(&mut self, _frame: &Frame, _value: &Rc<Object>) {
+        self.leave();
+    }
+
+    fn on_error(&mut self, _error: &EvalError, frames: &[Frame]) {
+        self.unwind(frames.len());
+    }
+}
+
+impl Display for Profiler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.report())
+    }
+}
+
+impl Debug for Profiler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Profiler").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_calls_and_splits_time() {
+        let profiler = Profiler::default();
+        let (outer, inner) = (Symbol::intern("outer"), Symbol::intern("inner"));
+
+        profiler.enter(&outer);
+        for _ in 0..2 {
+            profiler.enter(&inner);
+            std::thread::sleep(Duration::from_millis(2));
+            profiler.leave();
+        }
+        profiler.leave();
+
+        let outer = profiler.stats("outer").unwrap();
+        let inner = profiler.stats("inner").unwrap();
+        assert_eq!((outer.calls, inner.calls), (1, 2));
+        assert!(inner.total >= Duration::from_millis(4));
+        assert!(outer.total >= inner.total);
+        assert_eq!(outer.own, outer.total - inner.total);
+        assert!(profiler.report().starts_with("function"));
+    }
+
+    #[test]
+    fn test_recursive_calls_count_once_towards_total() {
+        let profiler = Profiler::default();
+        let name = Symbol::intern("f");
+
+        profiler.enter(&name);
+        profiler.enter(&name);
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.unwind(0);
+
+        let stats = profiler.stats("f").unwrap();
+        assert_eq!(stats.calls, 2);
+        assert!(stats.total >= stats.own);
+        assert!(stats.total < stats.own + Duration::from_millis(2));
+    }
+}
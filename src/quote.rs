@@ -0,0 +1,340 @@
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::evaluator::{ErrorKind, EvalError, Object};
+
+// `quote(expression)` gives back its argument as code instead of evaluating
+// it, and `unquote(expression)` inside of it is evaluated when the quote is,
+// with its value put back into the code. Both are special forms: they are
+// recognized by name at the call site, so they can't be shadowed or passed
+// around like functions.
+pub const QUOTE: &str = "quote";
+pub const UNQUOTE: &str = "unquote";
+
+// the argument of a call to the special form with the given name
+pub fn special_form<'a>(expression: &'a Expression, name: &str) -> Option<&'a Expression> {
+    match expression {
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } if arguments.len() == 1 => match function.as_ref() {
+            Expression::Identifier(function, _) if *function == name => Some(&arguments[0]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The arguments of the unquote calls in a quoted expression, in the order
+// that `splice` expects their values. Unquote calls inside of them belong to
+// them, so they aren't collected.
+pub fn unquoted<'a>(expression: &'a Expression, found: &mut Vec<&'a Expression>) {
+    if let Some(argument) = special_form(expression, UNQUOTE) {
+        found.push(argument);
+        return;
+    }
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_) => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                unquoted(element, found);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                unquoted(key, found);
+                unquoted(value, found);
+            }
+        }
+        Expression::Index { left, index, .. } => {
+            unquoted(left, found);
+            unquoted(index, found);
+        }
+        Expression::Prefix(_, right) => unquoted(right, found),
+        Expression::Infix(_, left, right) => {
+            unquoted(left, found);
+            unquoted(right, found);
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            unquoted(condition, found);
+            unquoted_statement(consequence, found);
+            if let Some(alternative) = alternative {
+                unquoted_statement(alternative, found);
+            }
+        }
+        Expression::FunctionLiteral { body, .. } | Expression::MacroLiteral { body, .. } => {
+            unquoted_statement(body, found)
+        }
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            unquoted(function, found);
+            for argument in arguments {
+                unquoted(argument, found);
+            }
+        }
+        Expression::Try { body, handler, .. } => {
+            unquoted_statement(body, found);
+            unquoted_statement(handler, found);
+        }
+        Expression::For { iterable, body, .. } => {
+            unquoted(iterable, found);
+            unquoted_statement(body, found);
+        }
+    }
+}
+
+fn unquoted_statement<'a>(statement: &'a Statement, found: &mut Vec<&'a Expression>) {
+    match statement {
+        Statement::LetStatement { value, .. } => unquoted(value, found),
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            unquoted(value, found)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                unquoted_statement(statement, found);
+            }
+        }
+    }
+}
+
+// Replaces the unquote calls in a quoted expression with the code for the
+// values of their arguments, which are given in the order `unquoted` found
+// them.
+pub fn splice(expression: &Expression, values: &[Rc<Object>]) -> Result<Expression, EvalError> {
+    let mut expression = expression.clone();
+    let mut values = values.iter();
+    let mut error = None;
+    modify_expression(&mut expression, &mut |expression| {
+        if special_form(expression, UNQUOTE).is_none() {
+            return false;
+        }
+        let value = values.next().expect("a value for every unquote call");
+        match to_expression(value) {
+            Ok(code) => *expression = code,
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+        true
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(expression),
+    }
+}
+
+// the code that evaluates to the given value
+fn to_expression(value: &Object) -> Result<Expression, EvalError> {
+    match value {
+        Object::Integer(value) => Ok(Expression::IntegerLiteral(*value)),
+        Object::Boolean(value) => Ok(Expression::BooleanLiteral(*value)),
+        Object::String(value) => Ok(Expression::StringLiteral(value.clone())),
+        Object::Quote(expression) => Ok(expression.clone()),
+        _ => Err(EvalError::new(
+            ErrorKind::TypeMismatch,
+            format!("can't unquote {}", value.type_of()),
+        )
+        .with_types(&[value.type_of()])),
+    }
+}
+
+// Calls the modifier on every expression in the statement, outermost first.
+// Returns true from the modifier to leave out what's inside the expression it
+// was given, e.g. because it was replaced.
+pub fn modify_statement(
+    statement: &mut Statement,
+    modifier: &mut impl FnMut(&mut Expression) -> bool,
+) {
+    match statement {
+        Statement::LetStatement { value, .. } => modify_expression(value, modifier),
+        Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+            modify_expression(value, modifier)
+        }
+        Statement::BlockStatement(statements, _) => {
+            for statement in statements {
+                modify_statement(statement, modifier);
+            }
+        }
+    }
+}
+
+pub fn modify_expression(
+    expression: &mut Expression,
+    modifier: &mut impl FnMut(&mut Expression) -> bool,
+) {
+    if modifier(expression) {
+        return;
+    }
+    match expression {
+        Expression::Identifier(..)
+        | Expression::IntegerLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::StringLiteral(_) => {}
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                modify_expression(element, modifier);
+            }
+        }
+        Expression::HashLiteral(pairs, _) => {
+            for (key, value) in pairs {
+                modify_expression(key, modifier);
+                modify_expression(value, modifier);
+            }
+        }
+        Expression::Index { left, index, .. } => {
+            modify_expression(left, modifier);
+            modify_expression(index, modifier);
+        }
+        Expression::Prefix(_, right) => modify_expression(right, modifier),
+        Expression::Infix(_, left, right) => {
+            modify_expression(left, modifier);
+            modify_expression(right, modifier);
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            modify_expression(condition, modifier);
+            modify_statement(consequence, modifier);
+            if let Some(alternative) = alternative {
+                modify_statement(alternative, modifier);
+            }
+        }
+        Expression::FunctionLiteral { body, .. } | Expression::MacroLiteral { body, .. } => {
+            modify_statement(Rc::make_mut(body), modifier)
+        }
+        Expression::Call {
+            function,
+            arguments,
+            ..
+        } => {
+            modify_expression(function, modifier);
+            for argument in arguments {
+                modify_expression(argument, modifier);
+            }
+        }
+        Expression::Try { body, handler, .. } => {
+            modify_statement(body, modifier);
+            modify_statement(handler, modifier);
+        }
+        Expression::For { iterable, body, .. } => {
+            modify_expression(iterable, modifier);
+            modify_statement(body, modifier);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::{eval, Environment};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+
+    fn run(input: &str) -> String {
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse_program();
+        assert!(parser.errors.is_empty(), "{:?}", parser.errors);
+        let env = Rc::new(RefCell::new(Environment::new()));
+        match eval(program, &env) {
+            Ok(obj) => obj.to_string(),
+            Err(error) => format!("error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_quote() {
+        let tests = vec![
+            ("quote(5)", "QUOTE(5)"),
+            ("quote(5 + 8)", "QUOTE((5 + 8))"),
+            ("quote(foobar)", "QUOTE(foobar)"),
+            ("quote(foobar + barfoo)", "QUOTE((foobar + barfoo))"),
+            ("quote(fn(x) { x })", "QUOTE(fn(x) x)"),
+            ("let quoted = quote(1 + 2); quoted", "QUOTE((1 + 2))"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(run(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_unquote() {
+        let tests = vec![
+            ("quote(unquote(4))", "QUOTE(4)"),
+            ("quote(unquote(4 + 4))", "QUOTE(8)"),
+            ("quote(8 + unquote(4 + 4))", "QUOTE((8 + 8))"),
+            ("quote(unquote(4 + 4) + 8)", "QUOTE((8 + 8))"),
+            ("let foobar = 8; quote(foobar)", "QUOTE(foobar)"),
+            ("let foobar = 8; quote(unquote(foobar))", "QUOTE(8)"),
+            ("quote(unquote(true))", "QUOTE(true)"),
+            ("quote(unquote(true == false))", "QUOTE(false)"),
+            (r#"quote(unquote("a" + "b"))"#, r#"QUOTE("ab")"#),
+            ("quote(unquote(quote(4 + 4)))", "QUOTE((4 + 4))"),
+            (
+                "let quotedInfix = quote(4 + 4); quote(unquote(4 + 4) + unquote(quotedInfix))",
+                "QUOTE((8 + (4 + 4)))",
+            ),
+            (
+                "let f = fn(x) { quote(unquote(x) * 2) }; f(quote(a + b))",
+                "QUOTE(((a + b) * 2))",
+            ),
+            ("quote([unquote(1), unquote(2)])", "QUOTE([1, 2])"),
+            ("quote(unquote([1]))", "error: can't unquote ARRAY"),
+            (
+                "quote(unquote(missing))",
+                "error: identifier not found: missing",
+            ),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(run(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_modify() {
+        let one = || Expression::IntegerLiteral(1);
+        let two = || Expression::IntegerLiteral(2);
+        let mut turn_one_into_two = |expression: &mut Expression| {
+            if *expression == one() {
+                *expression = two();
+            }
+            false
+        };
+        let boxed = |expression: Expression| Box::new(expression);
+
+        let tests = vec![
+            (one(), two()),
+            (
+                Expression::Infix(Infix::PLUS, boxed(one()), boxed(two())),
+                Expression::Infix(Infix::PLUS, boxed(two()), boxed(two())),
+            ),
+            (
+                Expression::Prefix(Prefix::MINUS, boxed(one())),
+                Expression::Prefix(Prefix::MINUS, boxed(two())),
+            ),
+            (
+                Expression::ArrayLiteral(vec![one(), one()]),
+                Expression::ArrayLiteral(vec![two(), two()]),
+            ),
+        ];
+        for (mut input, expected) in tests {
+            modify_expression(&mut input, &mut turn_one_into_two);
+            assert_eq!(input, expected);
+        }
+    }
+}
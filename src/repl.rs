@@ -1,36 +1,1293 @@
-use std::io::{stdin, stdout, Write};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, stdin, stdout, BufRead, IsTerminal, Read, Write};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::Instant;
 
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use crate::ast::{Program, Statement};
+use crate::builtins;
+use crate::cli::{Dump, ErrorFormat, Options};
+use crate::color::{Colors, Style};
+use crate::compiler::Compiler;
+use crate::config::{self, Config};
+use crate::debugger::Debugger;
+use crate::diagnostic;
+use crate::dump;
+use crate::engine::{Engine, Runner};
 use crate::evaluator::*;
-use crate::lexer::Lexer;
-use crate::parser::Parser;
+use crate::history::{self, History};
+use crate::inspect;
+use crate::interrupt;
+use crate::parser;
+use crate::profiler::Profiler;
+use crate::serialize;
+use crate::symbol::Symbol;
+use crate::token::Span;
+use crate::vm::Vm;
+use crate::warnings::{self, Warning};
+
+// the innermost frames of a stack trace that are printed
+const MAX_TRACE_FRAMES: usize = 10;
+
+// the binding of the last value printed
+const LAST_VALUE: &str = "_";
+
+// the line that ends a :paste
+const END_PASTE: &str = ":end";
+
+pub fn start_repl(colors: Colors, options: &Options) {
+    let (config, error) = match config::default_path().map(|path| Config::load(&path)) {
+        Some(Err(error)) => (Config::default(), Some(error)),
+        Some(Ok(config)) => (config, None),
+        None => (Config::default(), None),
+    };
+    if !config.banner.is_empty() {
+        println!("{}", config.banner);
+    }
+
+    let colors = match stdout().is_terminal() && config.color {
+        true => config
+            .colors
+            .iter()
+            .fold(colors, |colors, (style, code)| colors.set(*style, code)),
+        false => Colors::off(),
+    };
+    let mut repl = Repl::new(colors);
+    if let Some(error) = error {
+        repl.warning(format!("can't load the config: {}", error));
+    }
+    repl.runner
+        .set_engine(options.engine.unwrap_or(config.engine));
+    if let Some(path) = history::default_path() {
+        match History::load(path, config.history_size) {
+            Ok(history) => repl.history = history,
+            Err(error) => repl.warning(format!("can't load the history: {}", error)),
+        }
+    }
+    if let Some(path) = &options.restore {
+        repl.restore(path);
+    }
+    for path in &config.load {
+        repl.load(path);
+    }
 
-pub fn start_repl() {
-    println!("Return to Monk REPL (Ctrl+C to exit)");
-    let mut env = Environment::new();
+    interrupt::install();
+    interrupt::cancel_with(repl.cancel.clone());
+    let mut reader = LineReader::new(colors, &repl.history);
     loop {
-        print!(">> ");
-        stdout().flush().unwrap();
-        let mut input = String::new();
-        match stdin().read_line(&mut input) {
-            Ok(_) => {
-                let lexer = Lexer::new(&input);
-                let mut parser = Parser::new(lexer);
-                let program = parser.parse_program();
-                if parser.errors.len() > 0 {
-                    println!("ya done f'ed up");
-                    for error in parser.errors {
-                        println!("{}", error);
-                    }
-                    continue;
+        match reader.read(&config.prompt) {
+            Ok(Input::Line(input)) => {
+                if let Err(error) = repl.history.add(&input) {
+                    repl.warning(format!("can't save the history: {}", error));
+                }
+                if interrupt::cancellable(|| repl.handle(&input)) == Flow::Quit {
+                    break;
                 }
+            }
+            Ok(Input::Cancelled) => {}
+            Ok(Input::End) => {
+                println!("\ngoodbye");
+                break;
+            }
+            Err(error) => repl.error(error),
+        }
+    }
+}
+
+// Runs all of the input as one program when it isn't typed at a terminal,
+// e.g. `echo "1 + 2" | monk`.
+pub fn run_piped(colors: Colors, options: &Options) -> ExitCode {
+    let mut source = String::new();
+    if let Err(error) = stdin().read_to_string(&mut source) {
+        eprintln!("error: {}", error);
+        return ExitCode::FAILURE;
+    }
+    run_code(colors, &source, options)
+}
+
+// Runs code from the command line, e.g. `monk -e "1 + 2"` or piped input.
+// Only its value and the errors are printed, the errors to stderr, and the
+// exit status tells whether there were any.
+pub fn run_code(colors: Colors, source: &str, options: &Options) -> ExitCode {
+    run_source(colors, source, None, options)
+}
+
+// Runs a script, e.g. `monk script.mk`. It prints what it puts, its
+// diagnostics go to stderr with the name of the file, and the exit status
+// tells whether it ran without errors.
+pub fn run_file(colors: Colors, path: &str, options: &Options) -> ExitCode {
+    let colors = terminal_colors(colors);
+    let mut repl = Repl::new(colors);
+    repl.error_format = options.error_format;
+    // a script in stdin, e.g. `cat script.mk | monk - args`
+    let (path, bytes) = match path {
+        "-" => {
+            let mut bytes = Vec::new();
+            ("<stdin>", stdin().read_to_end(&mut bytes).map(|_| bytes))
+        }
+        _ => (path, fs::read(path)),
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            repl.error(format!("can't read {}: {}", path, error));
+            return ExitCode::FAILURE;
+        }
+    };
+    if serialize::is_compiled(&bytes) {
+        if options.check || options.dump.is_some() {
+            repl.error(format!("{}: a compiled program has no source", path));
+            return ExitCode::FAILURE;
+        }
+        return match repl.run_compiled(&bytes, path, options) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+    match String::from_utf8(bytes) {
+        Ok(source) => run_source(colors, &source, Some(path), options),
+        Err(error) => {
+            repl.error(format!("can't read {}: {}", path, error));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_source(colors: Colors, source: &str, file: Option<&str>, options: &Options) -> ExitCode {
+    let colors = terminal_colors(colors);
+    let mut repl = Repl::new(colors);
+    repl.error_format = options.error_format;
+    if options.check {
+        return match repl.check(source, file) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+    if let Some(dump) = options.dump {
+        return match repl.dump(source, file, dump) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+    match repl.run_program(source, file, options) {
+        Some(obj) => {
+            // a script shows what it puts, code only its value, unless that's
+            // e.g. the null of a puts at its end
+            if file.is_none() && *obj != Object::Null {
+                println!("{}", colors.paint(Style::Result, &obj.to_string()));
+            }
+            ExitCode::SUCCESS
+        }
+        None => ExitCode::FAILURE,
+    }
+}
+
+enum Input {
+    Line(String),
+    Cancelled,
+    End,
+}
+
+// Where the REPL reads what's typed: a line editor that highlights the line
+// as it's typed, with the history to go back through, or stdin as it is when
+// it isn't a terminal.
+enum LineReader {
+    Editor(Box<Editor<Highlight, DefaultHistory>>),
+    Stdin,
+}
+
+impl LineReader {
+    fn new(colors: Colors, history: &History) -> LineReader {
+        if !stdin().is_terminal() {
+            return LineReader::Stdin;
+        }
+        let Ok(mut editor) = Editor::new() else {
+            return LineReader::Stdin;
+        };
+        editor.set_helper(Some(Highlight(colors)));
+        for entry in history.entries() {
+            let _ = editor.add_history_entry(entry.as_str());
+        }
+        LineReader::Editor(Box::new(editor))
+    }
+
+    fn read(&mut self, prompt: &str) -> io::Result<Input> {
+        let editor = match self {
+            LineReader::Editor(editor) => editor,
+            LineReader::Stdin => {
+                print!("{}", prompt);
+                stdout().flush()?;
+                let input = read_line()?;
+                if let Input::Cancelled = input {
+                    // the terminal has echoed ^C
+                    println!();
+                }
+                return Ok(input);
+            }
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                Ok(Input::Line(line + "\n"))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Input::Cancelled),
+            Err(ReadlineError::Eof) => Ok(Input::End),
+            Err(ReadlineError::Io(error)) => Err(error),
+            Err(error) => Err(io::Error::other(error)),
+        }
+    }
+}
+
+// colors the keywords and literals of the line in the line editor as it's
+// typed, but not a command
+struct Highlight(Colors);
+
+impl Highlighter for Highlight {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        match find_command(line) {
+            Some(_) => Cow::Borrowed(line),
+            None => Cow::Owned(self.0.highlight(line)),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+        true
+    }
+}
+
+impl Completer for Highlight {
+    type Candidate = String;
+}
+
+impl Hinter for Highlight {
+    type Hint = String;
+}
+
+impl Validator for Highlight {}
+
+impl Helper for Highlight {}
+
+// The next line of input, like `read_line`, except that Ctrl+C cancels it and
+// that it tells the end of the input apart.
+fn read_line() -> io::Result<Input> {
+    let stdin = stdin();
+    let mut stdin = stdin.lock();
+    let mut line = Vec::new();
+    interrupt::set_waiting_for_input(true);
+    let cancelled = loop {
+        let available = match stdin.fill_buf() {
+            Ok(available) => available,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+                if interrupt::take() {
+                    break Ok(true);
+                }
+                continue;
+            }
+            Err(error) => break Err(error),
+        };
+        // the end of the input, maybe after a last line without a newline
+        if available.is_empty() {
+            break Ok(false);
+        }
+        match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                line.extend_from_slice(&available[..=end]);
+                stdin.consume(end + 1);
+                break Ok(false);
+            }
+            None => {
+                let length = available.len();
+                line.extend_from_slice(available);
+                stdin.consume(length);
+            }
+        }
+    };
+    interrupt::set_waiting_for_input(false);
+
+    if cancelled? {
+        return Ok(Input::Cancelled);
+    }
+    if line.is_empty() {
+        return Ok(Input::End);
+    }
+    String::from_utf8(line)
+        .map(Input::Line)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+// The lines of a paste, up to the line that ends it or the end of the input,
+// or none if it's cancelled.
+fn read_paste(mut read_line: impl FnMut() -> io::Result<Input>) -> io::Result<Option<String>> {
+    let mut source = String::new();
+    loop {
+        match read_line()? {
+            Input::Line(line) if line.trim() == END_PASTE => return Ok(Some(source)),
+            Input::Line(line) => source.push_str(&line),
+            Input::End => return Ok(Some(source)),
+            Input::Cancelled => return Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Quit,
+}
+
+struct Repl {
+    runner: Runner,
+    colors: Colors,
+    history: History,
+    // the statements that completed, up to the one that failed in an input
+    // that failed, which is what :save writes
+    transcript: Vec<String>,
+    // the file each global was last bound in by a let statement, and where
+    // in it, which :doc shows; a global bound by input has none
+    definitions: HashMap<String, String>,
+    error_format: ErrorFormat,
+    // what Ctrl+C cancels the code that runs with
+    cancel: CancelHandle,
+    // what the code that ran last did, which :stats shows
+    metrics: Rc<Cell<Metrics>>,
+}
+
+// An input that starts with `:` is a command: its name, then whatever
+// argument it takes. Every REPL feature that isn't code goes here.
+struct Command {
+    names: &'static [&'static str],
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Repl, &str) -> Flow,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        names: &["help"],
+        usage: "",
+        help: "list the commands",
+        run: Repl::help,
+    },
+    Command {
+        names: &["quit", "exit"],
+        usage: "",
+        help: "leave the REPL",
+        run: |_, _| Flow::Quit,
+    },
+    Command {
+        names: &["env"],
+        usage: "",
+        help: "list the bindings with their types",
+        run: Repl::env,
+    },
+    Command {
+        names: &["reset"],
+        usage: "",
+        help: "forget every binding and macro",
+        run: Repl::reset,
+    },
+    Command {
+        names: &["type"],
+        usage: "<code>",
+        help: "show the type of the code's value",
+        run: Repl::type_of,
+    },
+    Command {
+        names: &["paste"],
+        usage: "",
+        help: "run the lines up to :end or Ctrl+D as one program",
+        run: Repl::paste,
+    },
+    Command {
+        names: &["doc"],
+        usage: "[name]",
+        help: "show how to call a function and the file it's defined in, or list the builtins",
+        run: Repl::doc,
+    },
+    Command {
+        names: &["load"],
+        usage: "<file>",
+        help: "run a file in the current environment",
+        run: Repl::load,
+    },
+    Command {
+        names: &["save"],
+        usage: "<file>",
+        help: "write the code that ran so far to a file",
+        run: Repl::save,
+    },
+    Command {
+        names: &["restore"],
+        usage: "<file>",
+        help: "start over from a saved session",
+        run: Repl::restore,
+    },
+    Command {
+        names: &["history"],
+        usage: "[text]",
+        help: "list the inputs so far, or the ones containing the text",
+        run: Repl::history,
+    },
+    Command {
+        names: &["engine"],
+        usage: "[name]",
+        help: "show the engine code runs on, or switch to another one",
+        run: Repl::engine,
+    },
+    Command {
+        names: &["bytecode"],
+        usage: "<code>",
+        help: "show the instructions the code compiles to",
+        run: Repl::bytecode,
+    },
+    Command {
+        names: &["time"],
+        usage: "<code>",
+        help: "run the code and report how long it took",
+        run: Repl::time,
+    },
+    Command {
+        names: &["stats"],
+        usage: "",
+        help: "show what the code that ran last cost",
+        run: Repl::stats,
+    },
+    Command {
+        names: &["profile"],
+        usage: "<code>",
+        help: "run the code and report where the time went",
+        run: Repl::profile,
+    },
+    Command {
+        names: &["debug"],
+        usage: "<code>",
+        help: "step through the code",
+        run: Repl::debug,
+    },
+];
+
+// the command an input is, and its argument; an error if it names none
+fn find_command(input: &str) -> Option<Result<(&'static Command, &str), String>> {
+    let input = input.trim().strip_prefix(':')?;
+    let (name, argument) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let command = COMMANDS
+        .iter()
+        .find(|command| command.names.contains(&name))
+        .ok_or_else(|| format!("unknown command: :{} (try :help)", name));
+    Some(command.map(|command| (command, argument.trim())))
+}
+
+impl Repl {
+    fn new(colors: Colors) -> Repl {
+        Repl {
+            runner: Runner::new(Engine::default()),
+            colors,
+            history: History::new(),
+            transcript: Vec::new(),
+            definitions: HashMap::new(),
+            error_format: ErrorFormat::Text,
+            cancel: CancelHandle::new(),
+            metrics: Rc::default(),
+        }
+    }
+
+    fn handle(&mut self, input: &str) -> Flow {
+        match find_command(input) {
+            Some(Ok((command, argument))) => (command.run)(self, argument),
+            Some(Err(error)) => {
+                self.error(error);
+                Flow::Continue
+            }
+            None => {
+                self.eval(input, None, EvalConfig::default());
+                Flow::Continue
+            }
+        }
+    }
+
+    // runs the code, which came from the file if there is one, and prints
+    // its value
+    fn eval(&mut self, source: &str, file: Option<&str>, config: EvalConfig) {
+        if let Some(obj) = self.run(source, file, config) {
+            println!("{}", self.colors.paint(Style::Result, &obj.to_string()));
+            self.runner.define(Symbol::intern(LAST_VALUE), obj);
+        }
+    }
+
+    // the value of the code, or none after printing why it has none
+    fn run(&mut self, source: &str, file: Option<&str>, config: EvalConfig) -> Option<Rc<Object>> {
+        self.run_checked(source, file, config, warnings::check_incremental)
+    }
+
+    // Runs the source as a whole program rather than as one more input, with
+    // the flags on the command line. Its bindings that are never read are
+    // warned about, and --profile reports on it afterwards.
+    fn run_program(
+        &mut self,
+        source: &str,
+        file: Option<&str>,
+        options: &Options,
+    ) -> Option<Rc<Object>> {
+        if let Some(engine) = options.engine {
+            self.runner.set_engine(engine);
+        }
+        profiled(options, |config| {
+            self.run_checked(source, file, config, warnings::check)
+        })
+    }
+
+    // runs a program compiled by monk compile on the VM, as a whole program
+    fn run_compiled(&mut self, bytes: &[u8], file: &str, options: &Options) -> bool {
+        let bytecode = match serialize::decode(bytes) {
+            Ok(bytecode) => bytecode,
+            Err(error) => {
+                self.error(located(Some(file), error));
+                return false;
+            }
+        };
+        match profiled(options, |config| {
+            let config = config
+                .cancel_with(self.cancel.clone())
+                .record_metrics(Rc::clone(&self.metrics));
+            Vm::new().run_with_config(bytecode, config)
+        }) {
+            Ok(_) => true,
+            Err(error) => {
+                self.runtime_error(&error, None, Some(file));
+                false
+            }
+        }
+    }
+
+    // Reports the syntax errors and warnings of the source as a whole program,
+    // without running it. Warnings alone don't make it fail.
+    fn check(&self, source: &str, file: Option<&str>) -> bool {
+        let Some(program) = self.parse(source, file) else {
+            return false;
+        };
+        for warning in warnings::check(&program) {
+            self.warning(located(file, warning));
+        }
+        true
+    }
+
+    // prints the tokens or syntax tree of the source, or its syntax errors
+    fn dump(&self, source: &str, file: Option<&str>, dump: Dump) -> bool {
+        let dumped = match dump {
+            Dump::Tokens => dump::tokens(source),
+            Dump::Ast => match self.parse(source, file) {
+                Some(program) => dump::tree(&program),
+                None => return false,
+            },
+            Dump::AstJson => match self.parse(source, file) {
+                Some(program) => dump::json(&program) + "\n",
+                None => return false,
+            },
+        };
+        print!("{}", dumped);
+        true
+    }
+
+    fn run_checked(
+        &mut self,
+        source: &str,
+        file: Option<&str>,
+        config: EvalConfig,
+        check: fn(&Program) -> Vec<Warning>,
+    ) -> Option<Rc<Object>> {
+        let program = self.parse(source, file)?;
+
+        for warning in check(&program) {
+            self.warning(located(file, warning));
+        }
+
+        let config = config
+            .cancel_with(self.cancel.clone())
+            .record_metrics(Rc::clone(&self.metrics));
+        let spans: Vec<Span> = program.statements.iter().map(Statement::span).collect();
+        let definitions: Vec<(Span, String, Option<String>)> = program
+            .statements
+            .iter()
+            .filter_map(|statement| match statement {
+                Statement::LetStatement { name, span, .. } => Some((
+                    *span,
+                    name.to_string(),
+                    file.map(|file| located(Some(file), span)),
+                )),
+                _ => None,
+            })
+            .collect();
+        match self.runner.run_with_config(program, config) {
+            Ok(obj) => {
+                self.record(source, &spans, &definitions);
+                Some(obj)
+            }
+            Err(error) => {
+                // the statements before the one that failed keep what they
+                // did, so they're part of the session
+                let completed = match error.location {
+                    Some(location) => spans
+                        .iter()
+                        .take_while(|span| span.end <= location.start)
+                        .count(),
+                    None => 0,
+                };
+                self.record(source, &spans[..completed], &definitions);
+                let caret = caret(&error, &definitions).map(|span| (source, span));
+                self.runtime_error(&error, caret, file);
+                None
+            }
+        }
+    }
+
+    // adds the statements that completed to the session, along with the
+    // bindings of the let statements among them
+    fn record(
+        &mut self,
+        source: &str,
+        statements: &[Span],
+        definitions: &[(Span, String, Option<String>)],
+    ) {
+        for span in statements {
+            self.transcript
+                .push(source[span.start..span.end].trim().to_string());
+        }
+        let completed = statements.last().map_or(0, |span| span.end);
+        for (span, name, defined) in definitions {
+            if span.end > completed {
+                continue;
+            }
+            match defined {
+                Some(defined) => self.definitions.insert(name.clone(), defined.clone()),
+                None => self.definitions.remove(name),
+            };
+        }
+    }
+
+    fn help(&mut self, _: &str) -> Flow {
+        for command in COMMANDS {
+            let names: Vec<String> = command
+                .names
+                .iter()
+                .map(|name| format!(":{}", name))
+                .collect();
+            let usage = format!("{} {}", names.join(", "), command.usage);
+            println!("  {:<22} {}", usage.trim_end(), command.help);
+        }
+        Flow::Continue
+    }
+
+    fn env(&mut self, _: &str) -> Flow {
+        for (name, value) in self.runner.bindings() {
+            println!("{}", inspect::binding(name, &value));
+        }
+        Flow::Continue
+    }
+
+    fn reset(&mut self, _: &str) -> Flow {
+        self.runner.reset();
+        self.transcript.clear();
+        self.definitions.clear();
+        Flow::Continue
+    }
+
+    fn type_of(&mut self, source: &str) -> Flow {
+        let transcript = self.transcript.len();
+        if let Some(obj) = self.run(source, None, EvalConfig::default()) {
+            println!("{} = {}", obj.type_of(), inspect::preview(&obj));
+        }
+        // the code only ran to look at its value
+        self.transcript.truncate(transcript);
+        Flow::Continue
+    }
+
+    fn paste(&mut self, _: &str) -> Flow {
+        println!(
+            "pasting; end with {} or Ctrl+D on a line of its own",
+            END_PASTE
+        );
+        match read_paste(read_line) {
+            Ok(Some(source)) => self.eval(&source, None, EvalConfig::default()),
+            Ok(None) => {}
+            Err(error) => self.error(error),
+        }
+        Flow::Continue
+    }
+
+    fn doc(&mut self, name: &str) -> Flow {
+        if name.is_empty() {
+            for builtin in builtins::all() {
+                println!("  {:<22} {}", builtin.signature, builtin.doc);
+            }
+            return Flow::Continue;
+        }
+        let defined = self.definitions.get(name).map(String::as_str);
+        match doc(name, self.runner.lookup(name).as_deref(), defined) {
+            Ok(doc) => println!("{}", doc),
+            Err(error) => self.error(error),
+        }
+        Flow::Continue
+    }
+
+    fn load(&mut self, path: &str) -> Flow {
+        match fs::read_to_string(path) {
+            Ok(source) => self.eval(&source, Some(path), EvalConfig::default()),
+            Err(error) => self.error(format!("can't read {}: {}", path, error)),
+        }
+        Flow::Continue
+    }
 
-                let evaluated = eval(program, &mut env);
-                match evaluated {
-                    Ok(obj) => println!("{}", obj),
-                    Err(error) => println!("error: {}", error),
+    fn history(&mut self, text: &str) -> Flow {
+        for (number, entry) in self.history.search(text) {
+            println!("{:>5}  {}", number, self.colors.highlight(entry));
+        }
+        Flow::Continue
+    }
+
+    // Writes the session as a program: the statements that completed, in
+    // order. Restoring it runs them again, side effects and all.
+    fn save(&mut self, path: &str) -> Flow {
+        if let Err(error) = fs::write(path, session(&self.transcript)) {
+            self.error(format!("can't write {}: {}", path, error));
+        }
+        Flow::Continue
+    }
+
+    // Starts over from the file, running a statement at a time so that one
+    // that fails doesn't keep the ones after it from running.
+    fn restore(&mut self, path: &str) -> Flow {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                self.error(format!("can't read {}: {}", path, error));
+                return Flow::Continue;
+            }
+        };
+        let Some(program) = self.parse(&source, Some(path)) else {
+            return Flow::Continue;
+        };
+        self.reset("");
+        for statement in &program.statements {
+            let span = statement.span();
+            // blanks out what comes before the statement, so that its errors
+            // are at its line and column in the file
+            let blank: String = source[..span.start]
+                .chars()
+                .map(|c| if c == '\n' { c } else { ' ' })
+                .collect();
+            let statement = blank + &source[span.start..span.end];
+            // the statements after it may use its value as _
+            if let Some(obj) = self.run(&statement, Some(path), EvalConfig::default()) {
+                self.runner.define(Symbol::intern(LAST_VALUE), obj);
+            }
+        }
+        Flow::Continue
+    }
+
+    fn engine(&mut self, name: &str) -> Flow {
+        match name {
+            "" => println!("{}", self.runner.engine()),
+            name => match name.parse() {
+                Ok(engine) => self.runner.set_engine(engine),
+                Err(error) => self.error(error),
+            },
+        }
+        Flow::Continue
+    }
+
+    fn bytecode(&mut self, source: &str) -> Flow {
+        if let Some(program) = self.parse(source, None) {
+            match Compiler::new().compile(&program) {
+                Ok(bytecode) => print!("{}", bytecode),
+                Err(error) => self.error(error),
+            }
+        }
+        Flow::Continue
+    }
+
+    fn time(&mut self, source: &str) -> Flow {
+        let steps = Rc::new(Cell::new(0));
+        let start = Instant::now();
+        self.eval(
+            source,
+            None,
+            EvalConfig::default().count_steps(steps.clone()),
+        );
+        let elapsed = start.elapsed();
+        let unit = match self.runner.engine() {
+            Engine::TreeWalker => "nodes evaluated",
+            _ => "instructions executed",
+        };
+        println!("took {:?}, {} {}", elapsed, steps.get(), unit);
+        Flow::Continue
+    }
+
+    fn stats(&mut self, _: &str) -> Flow {
+        let metrics = self.metrics.get();
+        let unit = match self.runner.engine() {
+            Engine::TreeWalker => "nodes evaluated",
+            _ => "instructions executed",
+        };
+        println!("  took          {:?}", metrics.elapsed);
+        println!("  steps         {} {}", metrics.steps, unit);
+        println!("  calls         {}", metrics.calls);
+        println!("  deepest       {} calls", metrics.max_depth);
+        println!(
+            "  allocations   {} ({} bytes)",
+            metrics.allocations, metrics.bytes
+        );
+        Flow::Continue
+    }
+
+    fn profile(&mut self, source: &str) -> Flow {
+        let profiler = Profiler::default();
+        self.eval(
+            source,
+            None,
+            EvalConfig::default().observe(profiler.clone()),
+        );
+        print!("{}", profiler.report());
+        Flow::Continue
+    }
+
+    fn debug(&mut self, source: &str) -> Flow {
+        let debugger = Debugger::new(stdin().lock(), stdout());
+        self.eval(source, None, EvalConfig::default().observe(debugger));
+        Flow::Continue
+    }
+
+    // the program in the source, or none after printing its syntax errors
+    fn parse(&self, source: &str, file: Option<&str>) -> Option<Program> {
+        match parser::parse(source) {
+            Ok(program) => Some(program),
+            Err(errors) => {
+                for error in errors {
+                    self.error(located(file, &error));
+                    eprintln!("{}", diagnostic::snippet(source, error.span));
                 }
+                None
             }
-            Err(error) => println!("error: {}", error),
         }
     }
+
+    // the error with the part of the source it's in, when there's source,
+    // and its trace, or else as JSON
+    fn runtime_error(&self, error: &EvalError, source: Option<(&str, Span)>, file: Option<&str>) {
+        if self.error_format == ErrorFormat::Json {
+            eprintln!("{}", error.json());
+            return;
+        }
+        self.error(located(file, error));
+        if let Some((source, span)) = source {
+            eprintln!("{}", diagnostic::snippet(source, span));
+        }
+        print_trace(&error.trace);
+    }
+
+    fn error(&self, message: impl Display) {
+        let error = format!("error: {}", message);
+        eprintln!("{}", self.colors.paint(Style::Error, &error));
+    }
+
+    fn warning(&self, message: impl Display) {
+        let warning = format!("warning: {}", message);
+        eprintln!("{}", self.colors.paint(Style::Warning, &warning));
+    }
+}
+
+// What :doc shows about a name: the builtin or function it's bound to, or
+// the builtin by that name if it isn't bound. For a function that's its
+// parameters and where the let statement that bound the name is, if a
+// loaded file bound it; the language has no comments, so there's no doc
+// comment to show.
+fn doc(name: &str, value: Option<&Object>, defined: Option<&str>) -> Result<String, String> {
+    let signature = |kind: &str, parameters: &[Symbol]| {
+        let parameters: Vec<&str> = parameters.iter().map(|p| &**p).collect();
+        let mut doc = format!("{}{}({})", kind, name, parameters.join(", "));
+        if let Some(defined) = defined {
+            doc.push_str(&format!("\n  defined at {}", defined));
+        }
+        doc
+    };
+    match value {
+        Some(Object::Builtin(builtin)) => Ok(format!("{}\n  {}", builtin.signature, builtin.doc)),
+        Some(Object::Function(function)) => Ok(signature("", function.parameters())),
+        Some(Object::Macro(function)) => Ok(signature("macro ", function.parameters())),
+        Some(Object::Closure(closure)) => Ok(signature("", &closure.function.parameters)),
+        Some(Object::Memoized(memoized)) => {
+            let doc = doc(name, Some(memoized.function()), defined)?;
+            Ok(format!("{}\n  memoized", doc))
+        }
+        Some(value) => Err(format!("{} is {}, not a function", name, value.type_of())),
+        None => match builtins::lookup(name) {
+            Some(builtin) => Ok(format!("{}\n  {}", builtin.signature, builtin.doc)),
+            None => Err(format!("identifier not found: {}", name)),
+        },
+    }
+}
+
+// the statements as one program, a line each; each is ended with a
+// semicolon so that it can't run on into the next one, e.g. `f` and `(1)`
+// into a call
+fn session(transcript: &[String]) -> String {
+    let mut session = String::new();
+    for input in transcript {
+        session.push_str(input);
+        if !input.ends_with(';') {
+            session.push(';');
+        }
+        session.push('\n');
+    }
+    session
+}
+
+// the colors, unless the output doesn't go to a terminal
+fn terminal_colors(colors: Colors) -> Colors {
+    match stdout().is_terminal() {
+        true => colors,
+        false => Colors::off(),
+    }
+}
+
+// runs with the config of the flags, reporting where the time went to stderr
+// afterwards if --profile asks for it
+fn profiled<T>(options: &Options, run: impl FnOnce(EvalConfig) -> T) -> T {
+    let profiler = Profiler::default();
+    let config = match options.profile {
+        true => options.eval_config().observe(profiler.clone()),
+        false => options.eval_config(),
+    };
+    let result = run(config);
+    if options.profile {
+        eprint!("{}", profiler.report());
+    }
+    result
+}
+
+// a diagnostic that says which file it's about, if it isn't about input
+fn located(file: Option<&str>, diagnostic: impl Display) -> String {
+    match file {
+        Some(file) => format!("{}: {}", file, diagnostic),
+        None => diagnostic.to_string(),
+    }
+}
+
+// Where in the source the caret goes: at the code that failed, when that's
+// in the source, which it is at its top level or in a function one of its let
+// statements defined. In a function an earlier input defined it isn't, and
+// the caret goes at the outermost call instead, which the source made.
+fn caret(error: &EvalError, definitions: &[(Span, String, Option<String>)]) -> Option<Span> {
+    let in_source = |span: &Span| {
+        error.trace.is_empty()
+            || definitions.iter().any(|(defined, name, _)| {
+                defined.start <= span.start
+                    && span.end <= defined.end
+                    && error.trace.iter().any(|frame| frame.name == *name)
+            })
+    };
+    let outermost = error.trace.last().map(|frame| frame.span);
+    error.span.filter(in_source).or(outermost)
+}
+
+fn print_trace(trace: &[TraceFrame]) {
+    for frame in trace.iter().take(MAX_TRACE_FRAMES) {
+        eprintln!("    {}", frame);
+    }
+    if trace.len() > MAX_TRACE_FRAMES {
+        eprintln!("    ... {} more", trace.len() - MAX_TRACE_FRAMES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_command() {
+        let tests = vec![
+            ("1 + 2", None),
+            (":help", Some(Ok(("help", "")))),
+            (":exit\n", Some(Ok(("quit", "")))),
+            (":engine  vm ", Some(Ok(("engine", "vm")))),
+            (":type [1, 2]", Some(Ok(("type", "[1, 2]")))),
+            (":profile fib(3); 1", Some(Ok(("profile", "fib(3); 1")))),
+            (":nope", Some(Err("unknown command: :nope (try :help)"))),
+        ];
+        for (input, expected) in tests {
+            let found = find_command(input)
+                .map(|found| found.map(|(command, argument)| (command.names[0], argument)));
+            let expected = expected.map(|expected| expected.map_err(String::from));
+            assert_eq!(found, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut repl = Repl::new(Colors::off());
+        assert_eq!(repl.handle("let x = 5;"), Flow::Continue);
+        assert_eq!(repl.runner.bindings().len(), 2);
+        assert_eq!(repl.metrics.get().steps, 2);
+        assert_eq!(repl.handle(":stats"), Flow::Continue);
+        assert_eq!(repl.handle(":reset"), Flow::Continue);
+        assert!(repl.runner.bindings().is_empty());
+        assert_eq!(repl.handle(":engine vm"), Flow::Continue);
+        assert_eq!(repl.runner.engine(), Engine::Vm);
+        assert_eq!(repl.handle(":quit"), Flow::Quit);
+    }
+
+    #[test]
+    fn test_last_value() {
+        for engine in Engine::ALL {
+            let mut repl = Repl::new(Colors::off());
+            repl.runner.set_engine(engine);
+            repl.handle("1 + 2");
+            repl.handle("_ * 2");
+            repl.handle(":type [_]");
+            let last = repl.runner.run(repl.parse("_", None).unwrap()).unwrap();
+            assert_eq!(last.to_string(), "6", "{}", engine);
+
+            repl.handle("missing");
+            let last = repl.runner.run(repl.parse("_", None).unwrap()).unwrap();
+            assert_eq!(last.to_string(), "6", "{}", engine);
+        }
+    }
+
+    #[test]
+    fn test_caret() {
+        let mut runner = Runner::new(Engine::TreeWalker);
+        let mut run = |input: &str| {
+            let program = parser::parse(input).unwrap();
+            let definitions: Vec<(Span, String, Option<String>)> = program
+                .statements
+                .iter()
+                .filter_map(|statement| match statement {
+                    Statement::LetStatement { name, span, .. } => {
+                        Some((*span, name.to_string(), None))
+                    }
+                    _ => None,
+                })
+                .collect();
+            let error = runner.run(program).unwrap_err();
+            let span = caret(&error, &definitions).unwrap();
+            input[span.start..span.end].to_string()
+        };
+        assert_eq!(run("let f = fn() {\n  1 / 0\n};\nf()"), "1 / 0");
+        assert_eq!(run("1;\nlen(1)"), "len(1)");
+        // the body of f is in the input before
+        assert_eq!(run("let g = 5;\n[g, f()]"), "f()");
+    }
+
+    #[test]
+    fn test_read_paste() {
+        let line = |line: &str| Input::Line(line.to_string());
+        let tests = vec![
+            (
+                vec![
+                    line("let f = fn(x) {\n"),
+                    line("  x\n"),
+                    line("};\n"),
+                    line(":end\n"),
+                ],
+                Some("let f = fn(x) {\n  x\n};\n"),
+            ),
+            (vec![line("1\n"), line("\n"), line("2")], Some("1\n\n2")),
+            (vec![line("1\n"), Input::End, line("2\n")], Some("1\n")),
+            (vec![line("1\n"), Input::Cancelled], None),
+        ];
+        for (inputs, expected) in tests {
+            let mut inputs = inputs.into_iter();
+            let paste = read_paste(|| Ok(inputs.next().unwrap_or(Input::End))).unwrap();
+            assert_eq!(paste.as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join("return_to_monk_test_session.mk");
+        let path = path.display().to_string();
+
+        let names = |repl: &Repl| {
+            let mut names: Vec<String> = repl
+                .runner
+                .bindings()
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut repl = Repl::new(Colors::off());
+        for input in [
+            "let f = fn(x) { x * 2 }\n",
+            "f",
+            "(3)",
+            "",
+            "missing",
+            "let x = 5; let y = x / 0; let z = 3;",
+            ":type x",
+            "let y = f(x); y",
+            "let z = _ + 1;",
+        ] {
+            repl.handle(input);
+        }
+        repl.handle(&format!(":save {}", path));
+        // the statement that failed and the one after it didn't complete
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "let f = fn(x) { x * 2 };\nf;\n(3);\nlet x = 5;\nlet y = f(x);\ny;\nlet z = _ + 1;\n"
+        );
+
+        let mut restored = Repl::new(Colors::off());
+        restored.handle("let w = 1;");
+        restored.handle(&format!(":restore {}", path));
+        assert_eq!(names(&restored), ["_", "f", "x", "y", "z"]);
+        assert_eq!(restored.runner.lookup("z").unwrap().to_string(), "11");
+
+        // a statement that fails doesn't stop the ones after it
+        fs::write(&path, "let a = 1;\nlet b = a / 0;\nlet c = a + 1;\n").unwrap();
+        restored.handle(&format!(":restore {}", path));
+        assert_eq!(names(&restored), ["_", "a", "c"]);
+        assert_eq!(restored.transcript, ["let a = 1;", "let c = a + 1;"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_doc() {
+        let tests = vec![
+            (
+                "len",
+                "",
+                Ok("len(value)\n  the number of characters in a string, elements in an array or pairs in a hash"),
+            ),
+            ("l", "let l = first;", Ok("first(array)\n  the first element of the array, or null if it's empty")),
+            (
+                "add",
+                "1;\n  let add = fn(a, b) {\n  a + b\n};",
+                Ok("add(a, b)"),
+            ),
+            (
+                "fib",
+                "let fib = memoize(fn(n) { n });",
+                Ok("fib(n)\n  memoized"),
+            ),
+            ("m", "let m = macro(x) { x };", Ok("macro m(x)")),
+            ("f", "let f = fn() { 1 }; let g = f;", Ok("f()")),
+            ("g", "let f = fn() { 1 }; let g = f;", Ok("g()")),
+            ("x", "let x = 1;", Err("x is INTEGER, not a function")),
+            ("nope", "", Err("identifier not found: nope")),
+        ];
+        for (name, input, expected) in tests {
+            let mut repl = Repl::new(Colors::off());
+            repl.handle(input);
+            let defined = repl.definitions.get(name).map(String::as_str);
+            let doc = doc(name, repl.runner.lookup(name).as_deref(), defined);
+            assert_eq!(
+                doc,
+                expected.map(String::from).map_err(String::from),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join("return_to_monk_test_load.mk");
+        fs::write(
+            &path,
+            "let double = fn(x) { x * 2 };\nlet four = double(2);\n",
+        )
+        .unwrap();
+
+        let mut repl = Repl::new(Colors::off());
+        repl.handle("let one = 1;");
+        repl.handle(&format!(":load {}", path.display()));
+        let mut names: Vec<String> = repl
+            .runner
+            .bindings()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["_", "double", "four", "one"]);
+
+        let doc_of = |repl: &Repl, name: &str| {
+            let defined = repl.definitions.get(name).map(String::as_str);
+            doc(name, repl.runner.lookup(name).as_deref(), defined)
+        };
+        assert_eq!(
+            doc_of(&repl, "double"),
+            Ok(format!(
+                "double(x)\n  defined at {}: line 1, column 1",
+                path.display()
+            ))
+        );
+        repl.handle("let double = fn(y) { y * 2 };");
+        assert_eq!(doc_of(&repl, "double"), Ok("double(y)".to_string()));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            repl.handle(&format!(":load {}", path.display())),
+            Flow::Continue
+        );
+    }
+
+    #[test]
+    fn test_run_file() {
+        let path = std::env::temp_dir().join("return_to_monk_test_run_file.mk");
+        let path_name = path.display().to_string();
+        fs::write(&path, "let x = 1;\nif (x) { 2 }\n").unwrap();
+
+        let options = Options::default();
+        assert_eq!(
+            run_file(Colors::off(), &path_name, &options),
+            ExitCode::SUCCESS
+        );
+        let options = Options {
+            engine: Some(Engine::Vm),
+            strict_booleans: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            run_file(Colors::off(), &path_name, &options),
+            ExitCode::FAILURE
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            run_file(Colors::off(), &path_name, &options),
+            ExitCode::FAILURE
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let repl = Repl::new(Colors::off());
+        assert!(repl.check("let unused = 1 / 0;", None));
+        assert!(!repl.check("let x = ;", None));
+
+        let options = Options {
+            check: true,
+            ..Options::default()
+        };
+        // it would fail if it ran
+        assert_eq!(
+            run_code(Colors::off(), "len(1)", &options),
+            ExitCode::SUCCESS
+        );
+        assert_eq!(run_code(Colors::off(), "len(", &options), ExitCode::FAILURE);
+    }
 }
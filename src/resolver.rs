@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::symbol::Symbol;
+
+// Fills in the slot of every identifier and let statement, so that the
+// evaluator can find local variables by position instead of by name.
+//
+// Function bodies, catch handlers and the bodies of for loops get an
+// environment of their own at runtime, blocks don't. Each of those scopes
+// numbers its names in the order they are declared, and an identifier refers
+// to the innermost scope that declared its name before it is used. Function
+// bodies are resolved once their enclosing scope is complete, since they run
+// later and can see everything declared in it (e.g. recursive local
+// functions). Anything that isn't found in a local scope is a global, looked
+// up by name at runtime.
+//
+// A program is usually resolved at the top level. It can also be resolved
+// as if it appeared inside local scopes, given the names of their slots
+// (outermost first), e.g. to evaluate an expression in a paused call.
+pub fn resolve(program: &mut Program, enclosing: &[Rc<[Symbol]>]) {
+    let mut resolver = Resolver {
+        scopes: vec![Scope::default()],
+        work: vec![Work::EndScope],
+    };
+    for names in enclosing {
+        let mut scope = Scope::default();
+        scope.slots.extend(names.iter().cloned().zip(0..));
+        resolver.scopes.push(scope);
+        resolver.work.push(Work::EndScope);
+    }
+    resolver.push_statements(&mut program.statements);
+    resolver.run();
+}
+
+// the parameters, statements and slot names of a function body, handler or
+// loop body
+type Body<'a> = (&'a [Symbol], &'a mut [Statement], &'a mut Rc<[Symbol]>);
+
+#[derive(Default)]
+struct Scope<'a> {
+    slots: HashMap<Symbol, usize>,
+    deferred: Vec<Body<'a>>,
+    // where the names of the slots go once the scope is complete
+    locals: Option<&'a mut Rc<[Symbol]>>,
+}
+
+// Like the evaluator, the resolver keeps its own work stack so that deeply
+// nested programs don't overflow the native stack.
+enum Work<'a> {
+    Statement(&'a mut Statement),
+    Expression(&'a mut Expression),
+    Declare(&'a Symbol, &'a mut Slot),
+    EnterScope(Body<'a>),
+    // resolves the function bodies deferred in the current scope, then leaves it
+    EndScope,
+}
+
+struct Resolver<'a> {
+    // the global scope comes first, its names aren't numbered
+    scopes: Vec<Scope<'a>>,
+    work: Vec<Work<'a>>,
+}
+
+impl<'a> Resolver<'a> {
+    fn run(&mut self) {
+        while let Some(work) = self.work.pop() {
+            match work {
+                Work::Statement(statement) => self.resolve_statement(statement),
+                Work::Expression(expression) => self.resolve_expression(expression),
+                Work::Declare(name, slot) => *slot = self.declare(name),
+                Work::EnterScope(body) => self.enter_scope(body),
+                Work::EndScope => {
+                    let scope = self.scopes.last_mut().unwrap();
+                    match scope.deferred.pop() {
+                        Some(body) => {
+                            self.work.push(Work::EndScope);
+                            self.enter_scope(body);
+                        }
+                        None => {
+                            let scope = self.scopes.pop().unwrap();
+                            if let Some(locals) = scope.locals {
+                                let mut names = vec![None; scope.slots.len()];
+                                for (name, index) in scope.slots {
+                                    names[index] = Some(name);
+                                }
+                                *locals = names.into_iter().flatten().collect();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn enter_scope(&mut self, (parameters, statements, locals): Body<'a>) {
+        self.scopes.push(Scope {
+            locals: Some(locals),
+            ..Scope::default()
+        });
+        for parameter in parameters {
+            self.declare(parameter);
+        }
+        self.work.push(Work::EndScope);
+        self.push_statements(statements);
+    }
+
+    fn push_statements(&mut self, statements: &'a mut [Statement]) {
+        for statement in statements.iter_mut().rev() {
+            self.work.push(Work::Statement(statement));
+        }
+    }
+
+    fn declare(&mut self, name: &Symbol) -> Slot {
+        if self.scopes.len() == 1 {
+            return Slot::Global;
+        }
+        let scope = self.scopes.last_mut().unwrap();
+        let next = scope.slots.len();
+        let index = *scope.slots.entry(name.clone()).or_insert(next);
+        Slot::Local { depth: 0, index }
+    }
+
+    fn lookup(&self, name: &Symbol) -> Slot {
+        for (depth, scope) in self.scopes[1..].iter().rev().enumerate() {
+            if let Some(index) = scope.slots.get(name) {
+                return Slot::Local {
+                    depth,
+                    index: *index,
+                };
+            }
+        }
+        Slot::Global
+    }
+
+    fn resolve_statement(&mut self, statement: &'a mut Statement) {
+        match statement {
+            Statement::LetStatement {
+                name, slot, value, ..
+            } => {
+                self.work.push(Work::Declare(name, slot));
+                self.work.push(Work::Expression(value));
+            }
+            Statement::ReturnStatement(value, _) | Statement::ExpressionStatement(value, _) => {
+                self.work.push(Work::Expression(value))
+            }
+            Statement::BlockStatement(statements, _) => self.push_statements(statements),
+        }
+    }
+
+    fn resolve_expression(&mut self, expression: &'a mut Expression) {
+        match expression {
+            Expression::Identifier(name, slot) => *slot = self.lookup(name),
+            Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_) => {}
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                if let Some(alternative) = alternative {
+                    self.work.push(Work::Statement(alternative));
+                }
+                self.work.push(Work::Statement(consequence));
+                self.work.push(Work::Expression(condition));
+            }
+            Expression::FunctionLiteral {
+                parameters,
+                body,
+                locals,
+                ..
+            }
+            | Expression::MacroLiteral {
+                parameters,
+                body,
+                locals,
+                ..
+            } => {
+                let body = match Rc::make_mut(body) {
+                    Statement::BlockStatement(statements, _) => statements.as_mut_slice(),
+                    statement => std::slice::from_mut(statement),
+                };
+                let scope = self.scopes.last_mut().unwrap();
+                scope.deferred.push((parameters, body, locals));
+            }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                locals,
+                ..
+            } => {
+                let handler = match handler.as_mut() {
+                    Statement::BlockStatement(statements, _) => statements.as_mut_slice(),
+                    statement => std::slice::from_mut(statement),
+                };
+                self.work.push(Work::EnterScope((
+                    std::slice::from_ref(name),
+                    handler,
+                    locals,
+                )));
+                self.work.push(Work::Statement(body));
+            }
+            Expression::For {
+                name,
+                iterable,
+                body,
+                locals,
+                ..
+            } => {
+                let body = match body.as_mut() {
+                    Statement::BlockStatement(statements, _) => statements.as_mut_slice(),
+                    statement => std::slice::from_mut(statement),
+                };
+                self.work
+                    .push(Work::EnterScope((std::slice::from_ref(name), body, locals)));
+                self.work.push(Work::Expression(iterable));
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                for argument in arguments.iter_mut().rev() {
+                    self.work.push(Work::Expression(argument));
+                }
+                self.work.push(Work::Expression(function));
+            }
+            Expression::ArrayLiteral(elements) => {
+                for element in elements.iter_mut().rev() {
+                    self.work.push(Work::Expression(element));
+                }
+            }
+            Expression::HashLiteral(pairs, _) => {
+                for (key, value) in pairs.iter_mut().rev() {
+                    self.work.push(Work::Expression(value));
+                    self.work.push(Work::Expression(key));
+                }
+            }
+            Expression::Index { left, index, .. } => {
+                self.work.push(Work::Expression(index));
+                self.work.push(Work::Expression(left));
+            }
+            Expression::Prefix(_, right) => self.work.push(Work::Expression(right)),
+            Expression::Infix(_, left, right) => {
+                self.work.push(Work::Expression(right));
+                self.work.push(Work::Expression(left));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_resolve_slots() {
+        let tests = vec![
+            ("let x = 1; x", "x", Slot::Global),
+            ("fn(a, b) { b }", "b", Slot::Local { depth: 0, index: 1 }),
+            (
+                "fn(a) { let b = 1; b }",
+                "b",
+                Slot::Local { depth: 0, index: 1 },
+            ),
+            (
+                "fn(a) { fn() { a } }",
+                "a",
+                Slot::Local { depth: 1, index: 0 },
+            ),
+            ("fn(a) { x }", "x", Slot::Global),
+            // declared after its use in the same scope, so it's the global
+            ("fn() { y; let y = 1; }", "y", Slot::Global),
+            // function bodies see the whole enclosing scope
+            (
+                "fn() { let f = fn() { g }; let g = 1; }",
+                "g",
+                Slot::Local { depth: 1, index: 1 },
+            ),
+            (
+                "fn(a) { try { a } catch (e) { e } }",
+                "e",
+                Slot::Local { depth: 0, index: 0 },
+            ),
+            ("try { 1 } catch (e) { puts }", "puts", Slot::Global),
+        ];
+
+        for (input, name, expected) in tests {
+            let lexer = Lexer::new(input);
+            let mut parser = Parser::new(lexer);
+            let mut program = parser.parse_program();
+            resolve(&mut program, &[]);
+
+            let mut slots = Vec::new();
+            collect_slots(&program.statements, name, &mut slots);
+            assert_eq!(slots.last(), Some(&expected), "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_resolve_let_slots() {
+        let lexer = Lexer::new("let a = 1; let f = fn(x) { let y = x; let x = y; };");
+        let mut parser = Parser::new(lexer);
+        let mut program = parser.parse_program();
+        resolve(&mut program, &[]);
+
+        let mut slots = Vec::new();
+        collect_let_slots(&program.statements, &mut slots);
+        assert_eq!(
+            slots,
+            vec![
+                Slot::Global,
+                Slot::Global,
+                Slot::Local { depth: 0, index: 1 },
+                Slot::Local { depth: 0, index: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_local_names() {
+        let lexer = Lexer::new("fn(a) { let b = 1; try { b } catch (e) { let c = e; } }");
+        let mut parser = Parser::new(lexer);
+        let mut program = parser.parse_program();
+        resolve(&mut program, &[]);
+
+        let Statement::ExpressionStatement(Expression::FunctionLiteral { body, locals, .. }, _) =
+            &program.statements[0]
+        else {
+            panic!("not a function literal: {}", program);
+        };
+        assert_eq!(locals.as_ref(), [Symbol::intern("a"), Symbol::intern("b")]);
+
+        let Statement::BlockStatement(statements, _) = body.as_ref() else {
+            panic!("not a block: {}", body);
+        };
+        let Statement::ExpressionStatement(Expression::Try { locals, .. }, _) = &statements[1]
+        else {
+            panic!("not a try expression: {}", statements[1]);
+        };
+        assert_eq!(locals.as_ref(), [Symbol::intern("e"), Symbol::intern("c")]);
+    }
+
+    #[test]
+    fn test_resolve_in_enclosing_scopes() {
+        let lexer = Lexer::new("a; x; let y = b;");
+        let mut parser = Parser::new(lexer);
+        let mut program = parser.parse_program();
+        let outer: Rc<[Symbol]> = vec![Symbol::intern("a")].into();
+        let inner: Rc<[Symbol]> = vec![Symbol::intern("x"), Symbol::intern("b")].into();
+        resolve(&mut program, &[outer, inner]);
+
+        let tests = vec![
+            ("a", Slot::Local { depth: 1, index: 0 }),
+            ("x", Slot::Local { depth: 0, index: 0 }),
+            ("b", Slot::Local { depth: 0, index: 1 }),
+        ];
+        for (name, expected) in tests {
+            let mut slots = Vec::new();
+            collect_slots(&program.statements, name, &mut slots);
+            assert_eq!(slots, vec![expected], "name: {}", name);
+        }
+
+        let mut slots = Vec::new();
+        collect_let_slots(&program.statements, &mut slots);
+        assert_eq!(slots, vec![Slot::Local { depth: 0, index: 2 }]);
+    }
+
+    // the slots of every identifier with the given name, in source order
+    fn collect_slots(statements: &[Statement], name: &str, slots: &mut Vec<Slot>) {
+        for statement in statements {
+            match statement {
+                Statement::LetStatement { value, .. }
+                | Statement::ReturnStatement(value, _)
+                | Statement::ExpressionStatement(value, _) => {
+                    collect_expression_slots(value, name, slots)
+                }
+                Statement::BlockStatement(statements, _) => collect_slots(statements, name, slots),
+            }
+        }
+    }
+
+    fn collect_expression_slots(expression: &Expression, name: &str, slots: &mut Vec<Slot>) {
+        match expression {
+            Expression::Identifier(identifier, slot) if *identifier == name => slots.push(*slot),
+            Expression::FunctionLiteral { body, .. } => {
+                collect_slots(std::slice::from_ref(body.as_ref()), name, slots)
+            }
+            Expression::Try { body, handler, .. } => {
+                collect_slots(std::slice::from_ref(body.as_ref()), name, slots);
+                collect_slots(std::slice::from_ref(handler.as_ref()), name, slots);
+            }
+            _ => {}
+        }
+    }
+
+    fn collect_let_slots(statements: &[Statement], slots: &mut Vec<Slot>) {
+        for statement in statements {
+            if let Statement::LetStatement { slot, value, .. } = statement {
+                slots.push(*slot);
+                if let Expression::FunctionLiteral { body, .. } = value {
+                    collect_let_slots(std::slice::from_ref(body.as_ref()), slots);
+                }
+            }
+            if let Statement::BlockStatement(statements, _) = statement {
+                collect_let_slots(statements, slots);
+            }
+        }
+    }
+}
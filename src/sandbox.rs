@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::fmt::{self, Display, Formatter};
+
+use crate::evaluator::{ErrorKind, EvalError};
+
+// What code may do outside of the interpreter, through the builtins that
+// reach out of it. Code that's embedded may do none of it unless the host
+// allows it, while the monk binary allows everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Filesystem,
+    Network,
+    Process,
+    Env,
+    Stdin,
+    Threads,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::Filesystem,
+        Capability::Network,
+        Capability::Process,
+        Capability::Env,
+        Capability::Stdin,
+        Capability::Threads,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let description = match self {
+            Capability::Filesystem => "filesystem access",
+            Capability::Network => "network access",
+            Capability::Process => "running processes",
+            Capability::Env => "environment variables",
+            Capability::Stdin => "reading stdin",
+            Capability::Threads => "starting threads",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    allowed: u8,
+}
+
+impl Capabilities {
+    // fully sandboxed
+    pub fn none() -> Capabilities {
+        Capabilities::default()
+    }
+
+    pub fn all() -> Capabilities {
+        Capability::ALL
+            .into_iter()
+            .fold(Capabilities::none(), Capabilities::allow)
+    }
+
+    pub fn allow(self, capability: Capability) -> Self {
+        Capabilities {
+            allowed: self.allowed | capability.bit(),
+        }
+    }
+
+    pub fn deny(self, capability: Capability) -> Self {
+        Capabilities {
+            allowed: self.allowed & !capability.bit(),
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed & capability.bit() != 0
+    }
+}
+
+thread_local! {
+    // what the builtins that run on this thread may do
+    static CAPABILITIES: Cell<Capabilities> = const { Cell::new(Capabilities { allowed: 0 }) };
+}
+
+// sets what the builtins may do, returning what they could before
+pub fn set_capabilities(capabilities: Capabilities) -> Capabilities {
+    CAPABILITIES.with(|current| current.replace(capabilities))
+}
+
+// what the builtins may do, e.g. to let a thread they start do the same
+pub fn capabilities() -> Capabilities {
+    CAPABILITIES.with(Cell::get)
+}
+
+// an error for the builtin unless it may use the capability
+pub fn require(builtin: &str, capability: Capability) -> Result<(), EvalError> {
+    match CAPABILITIES.with(Cell::get).allows(capability) {
+        true => Ok(()),
+        false => Err(EvalError::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "`{}` needs {}, which the sandbox denies",
+                builtin, capability
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::none().allow(Capability::Env);
+        assert!(capabilities.allows(Capability::Env));
+        assert!(!capabilities.allows(Capability::Stdin));
+        let all = Capabilities::all();
+        assert!(Capability::ALL.into_iter().all(|c| all.allows(c)));
+        assert!(!all.deny(Capability::Network).allows(Capability::Network));
+        assert!(all.deny(Capability::Network).allows(Capability::Process));
+
+        let before = set_capabilities(capabilities);
+        assert_eq!(before, Capabilities::none());
+        assert!(require("getenv", Capability::Env).is_ok());
+        let error = require("read_file", Capability::Filesystem).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::PermissionDenied);
+        assert_eq!(
+            error.message,
+            "`read_file` needs filesystem access, which the sandbox denies"
+        );
+        set_capabilities(before);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt::{self, Formatter};
+use std::rc::Rc;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use crate::evaluator::{HashPair, HashPairs, Object};
+
+// Values as the data they hold, with the serde feature: integers, booleans,
+// strings, arrays, hashes and null, which round-trip through any format that
+// has them. Values that aren't data, like functions, fail to serialize.
+// Value is an Rc, which serde's rc feature covers.
+
+impl Serialize for Object {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Object::Integer(value) => serializer.serialize_i64(*value),
+            Object::Boolean(value) => serializer.serialize_bool(*value),
+            Object::String(value) => serializer.serialize_str(value),
+            Object::Array(elements) => {
+                let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+                for element in elements {
+                    seq.serialize_element(&**element)?;
+                }
+                seq.end()
+            }
+            Object::Hash(pairs) => {
+                // in the order they were inserted, like they're displayed
+                let mut map = serializer.serialize_map(Some(pairs.len()))?;
+                for pair in pairs.values() {
+                    map.serialize_entry(&*pair.key, &*pair.value)?;
+                }
+                map.end()
+            }
+            Object::ReturnValue(value) => value.serialize(serializer),
+            Object::Null => serializer.serialize_unit(),
+            _ => Err(ser::Error::custom(format!(
+                "can't serialize a value of type {}",
+                self.type_of()
+            ))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Object {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Object, D::Error> {
+        deserializer.deserialize_any(ObjectVisitor)
+    }
+}
+
+struct ObjectVisitor;
+
+impl<'de> Visitor<'de> for ObjectVisitor {
+    type Value = Object;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "an integer, boolean, string, array, hash or null")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Object, E> {
+        Ok(Object::Boolean(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Object, E> {
+        Ok(Object::Integer(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Object, E> {
+        i64::try_from(value)
+            .map(Object::Integer)
+            .map_err(|_| E::custom(format!("integer out of range: {}", value)))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Object, E> {
+        Ok(Object::String(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Object, E> {
+        Ok(Object::String(value))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Object, E> {
+        Ok(Object::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Object, E> {
+        Ok(Object::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Object, D::Error> {
+        Object::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Object, A::Error> {
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(element) = seq.next_element::<Object>()? {
+            elements.push(Rc::new(element));
+        }
+        Ok(Object::Array(elements))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Object, A::Error> {
+        let mut pairs = HashPairs::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, value)) = map.next_entry::<Object, Object>()? {
+            let key = Rc::new(key);
+            let hash_key = key
+                .hash_key()
+                .map_err(|error| de::Error::custom(error.message))?;
+            let value = Rc::new(value);
+            pairs.insert(hash_key, HashPair { key, value });
+        }
+        Ok(Object::Hash(pairs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::value::{Error, MapDeserializer, SeqDeserializer};
+    use serde::de::IntoDeserializer;
+    use serde_test::{assert_ser_tokens, assert_ser_tokens_error, assert_tokens, Token};
+
+    use crate::evaluator::integer_object;
+    use crate::interpreter::Interpreter;
+
+    fn deserialize<'de>(deserializer: impl Deserializer<'de, Error = Error>) -> String {
+        match Object::deserialize(deserializer) {
+            Ok(obj) => obj.to_string(),
+            Err(error) => format!("error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(deserialize(42i64.into_deserializer()), "42");
+        assert_eq!(deserialize(7u8.into_deserializer()), "7");
+        assert_eq!(deserialize(true.into_deserializer()), "true");
+        assert_eq!(deserialize("monk".into_deserializer()), "monk");
+        assert_eq!(deserialize(().into_deserializer()), "null");
+        assert_eq!(
+            deserialize(SeqDeserializer::new(vec![1i64, 2].into_iter())),
+            "[1, 2]"
+        );
+        assert_eq!(
+            deserialize(MapDeserializer::new(
+                vec![("b", 2i64), ("a", 1)].into_iter()
+            )),
+            "{\"b\": 2, \"a\": 1}"
+        );
+        assert_eq!(
+            deserialize(1.5f64.into_deserializer()),
+            "error: invalid type: floating point `1.5`, expected an integer, boolean, \
+             string, array, hash or null"
+        );
+        assert_eq!(
+            deserialize(u64::MAX.into_deserializer()),
+            format!("error: integer out of range: {}", u64::MAX)
+        );
+
+        let value = Rc::<Object>::deserialize("shared".into_deserializer());
+        let value: Result<_, Error> = value;
+        assert_eq!(*value.unwrap(), Object::String("shared".to_string()));
+    }
+
+    fn eval(input: &str) -> Rc<Object> {
+        Interpreter::new().eval(input).unwrap()
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_ser_tokens(&Object::Integer(42), &[Token::I64(42)]);
+        assert_ser_tokens(&Object::Boolean(false), &[Token::Bool(false)]);
+        assert_ser_tokens(&Object::String("monk".to_string()), &[Token::Str("monk")]);
+        assert_ser_tokens(&Object::Null, &[Token::Unit]);
+        assert_ser_tokens(&Object::ReturnValue(integer_object(1)), &[Token::I64(1)]);
+        assert_ser_tokens(
+            &*eval(r#"{"b": 2, "a": [1]}"#),
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("b"),
+                Token::I64(2),
+                Token::Str("a"),
+                Token::Seq { len: Some(1) },
+                Token::I64(1),
+                Token::SeqEnd,
+                Token::MapEnd,
+            ],
+        );
+
+        assert_ser_tokens_error(
+            &*eval("fn(x) { x }"),
+            &[],
+            "can't serialize a value of type FUNCTION",
+        );
+        assert_ser_tokens_error(
+            &*eval("len"),
+            &[],
+            "can't serialize a value of type BUILTIN",
+        );
+        // the error of an element stops the array around it
+        assert_ser_tokens_error(
+            &*eval("[1, puts]"),
+            &[Token::Seq { len: Some(2) }, Token::I64(1)],
+            "can't serialize a value of type BUILTIN",
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = eval(r#"[1, {"a": [true, if (false) { 1 }], 2: {"b": "c"}}, [[]], "d"]"#);
+        assert_tokens(
+            &*value,
+            &[
+                Token::Seq { len: Some(4) },
+                Token::I64(1),
+                Token::Map { len: Some(2) },
+                Token::Str("a"),
+                Token::Seq { len: Some(2) },
+                Token::Bool(true),
+                Token::Unit,
+                Token::SeqEnd,
+                Token::I64(2),
+                Token::Map { len: Some(1) },
+                Token::Str("b"),
+                Token::Str("c"),
+                Token::MapEnd,
+                Token::MapEnd,
+                Token::Seq { len: Some(1) },
+                Token::Seq { len: Some(0) },
+                Token::SeqEnd,
+                Token::SeqEnd,
+                Token::Str("d"),
+                Token::SeqEnd,
+            ],
+        );
+    }
+}
@@ -0,0 +1,684 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::code::{read_operands, Instructions, Opcode};
+use crate::compiler::Bytecode;
+use crate::evaluator::{integer_object, CompiledFunction, Object};
+use crate::source_map::SourceMap;
+use crate::symbol::Symbol;
+use crate::token::Span;
+
+// The file format of compiled programs, so a script can be compiled once and
+// run without parsing it again:
+//
+//   magic     b"MONK" and a version byte
+//   globals   count, then each name
+//   locals    the number of locals of the top level
+//   constants count, then each one as a tag byte and its value
+//   code      the instructions of the top level and their source map
+//
+// Counts and lengths are u32 and integers i64, all big-endian. Strings and
+// instructions are a length followed by their bytes. A function constant is
+// its number of locals, its name (a flag byte, then the name if it's set),
+// its parameters, its instructions and their source map. A source map is a
+// count, then each entry as the offset and the start, end, line and column
+// of its span.
+//
+// Files of another version are rejected rather than guessed at, so the
+// version has to change whenever the opcodes or this layout do.
+const MAGIC: &[u8; 4] = b"MONK";
+pub const VERSION: u8 = 2;
+
+// the most locals a function can have, as the instructions that get and set
+// them take a u8
+const MAX_LOCALS: usize = 256;
+
+const TAG_INTEGER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatError {
+    pub message: String,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+fn error<T>(message: impl Into<String>) -> Result<T, FormatError> {
+    Err(FormatError {
+        message: message.into(),
+    })
+}
+
+pub fn encode(bytecode: &Bytecode) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    write_u32(&mut bytes, bytecode.globals.len());
+    for name in &bytecode.globals {
+        write_str(&mut bytes, name);
+    }
+    write_u32(&mut bytes, bytecode.num_locals);
+    write_u32(&mut bytes, bytecode.constants.len());
+    for constant in &bytecode.constants {
+        match &**constant {
+            Object::Integer(value) => {
+                bytes.push(TAG_INTEGER);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            Object::String(value) => {
+                bytes.push(TAG_STRING);
+                write_str(&mut bytes, value);
+            }
+            Object::CompiledFunction(function) => {
+                bytes.push(TAG_FUNCTION);
+                write_u32(&mut bytes, function.num_locals);
+                match &function.name {
+                    Some(name) => {
+                        bytes.push(1);
+                        write_str(&mut bytes, name);
+                    }
+                    None => bytes.push(0),
+                }
+                write_u32(&mut bytes, function.parameters.len());
+                for parameter in &function.parameters {
+                    write_str(&mut bytes, parameter);
+                }
+                write_instructions(&mut bytes, &function.instructions);
+                write_source_map(&mut bytes, &function.source_map);
+            }
+            obj => unreachable!("not a constant: {}", obj),
+        }
+    }
+    write_instructions(&mut bytes, &bytecode.instructions);
+    write_source_map(&mut bytes, &bytecode.source_map);
+    bytes
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_be_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, value: &str) {
+    write_u32(bytes, value.len());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+fn write_instructions(bytes: &mut Vec<u8>, instructions: &Instructions) {
+    write_u32(bytes, instructions.len());
+    bytes.extend_from_slice(&instructions.0);
+}
+
+fn write_source_map(bytes: &mut Vec<u8>, source_map: &SourceMap) {
+    write_u32(bytes, source_map.entries().len());
+    for (offset, span) in source_map.entries() {
+        for value in [*offset, span.start, span.end, span.line, span.column] {
+            write_u32(bytes, value);
+        }
+    }
+}
+
+// whether the bytes are a compiled program, of any version
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Decodes a compiled program, checking that its instructions only refer to
+// constants, globals, builtins and locals that exist and never take more
+// from the stack than there is, so the VM can run it as is even when the
+// file is corrupt.
+pub fn decode(bytes: &[u8]) -> Result<Bytecode, FormatError> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return error("not a compiled program");
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return error(format!(
+            "unsupported bytecode version: {} (expected {})",
+            version, VERSION
+        ));
+    }
+
+    let globals = (0..reader.u32()?)
+        .map(|_| reader.str().map(|name| Symbol::intern(&name)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let num_locals = reader.locals()?;
+    let mut constants = Vec::new();
+    for _ in 0..reader.u32()? {
+        let constant = match reader.u8()? {
+            TAG_INTEGER => {
+                let value = i64::from_be_bytes(reader.take(8)?.try_into().unwrap());
+                integer_object(value)
+            }
+            TAG_STRING => Object::String(reader.str()?).into(),
+            TAG_FUNCTION => {
+                let num_locals = reader.locals()?;
+                let name = match reader.u8()? {
+                    0 => None,
+                    _ => Some(Symbol::intern(&reader.str()?)),
+                };
+                let parameters = (0..reader.u32()?)
+                    .map(|_| reader.str().map(|name| Symbol::intern(&name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let instructions = reader.instructions()?;
+                let source_map = reader.source_map()?;
+                let function = CompiledFunction {
+                    instructions,
+                    num_locals,
+                    parameters,
+                    name,
+                    source_map,
+                    body: None,
+                };
+                Object::CompiledFunction(Rc::new(function)).into()
+            }
+            tag => return error(format!("unknown constant tag: {}", tag)),
+        };
+        constants.push(constant);
+    }
+    let instructions = reader.instructions()?;
+    let source_map = reader.source_map()?;
+    if reader.offset != bytes.len() {
+        return error("trailing bytes after the program");
+    }
+
+    let bytecode = Bytecode {
+        instructions,
+        constants,
+        num_locals,
+        globals,
+        source_map,
+    };
+    let free = free_counts(&bytecode);
+    check(&bytecode.instructions, num_locals, None, &bytecode)?;
+    for (index, constant) in bytecode.constants.iter().enumerate() {
+        if let Object::CompiledFunction(function) = &**constant {
+            check(
+                &function.instructions,
+                function.num_locals,
+                free[index],
+                &bytecode,
+            )?;
+        }
+    }
+    Ok(bytecode)
+}
+
+// The fewest free variables that the closures of each function constant
+// capture. A function that no closure is made of can't run, so it can read
+// any it likes.
+fn free_counts(bytecode: &Bytecode) -> Vec<Option<usize>> {
+    let mut free = vec![Some(usize::MAX); bytecode.constants.len()];
+    let functions = bytecode.constants.iter().filter_map(|obj| match &**obj {
+        Object::CompiledFunction(function) => Some(&function.instructions),
+        _ => None,
+    });
+    for instructions in std::iter::once(&bytecode.instructions).chain(functions) {
+        let mut offset = 0;
+        while let Some(op) = instructions
+            .0
+            .get(offset)
+            .and_then(|&byte| Opcode::from_byte(byte))
+        {
+            if offset + op.width() > instructions.len() {
+                break;
+            }
+            if op == Opcode::Closure {
+                let operands = read_operands(op, &instructions.0[offset + 1..]);
+                if let Some(Some(count)) = free.get_mut(operands[0]) {
+                    *count = (*count).min(operands[1]);
+                }
+            }
+            offset += op.width();
+        }
+    }
+    free
+}
+
+// The operands that index into something have to be in range, the jumps
+// have to land on an instruction, and no instruction may take more values
+// from the stack than the ones before it left there, on any path to it. The
+// free variables are those of the function's closures, none for the top
+// level.
+fn check(
+    instructions: &Instructions,
+    num_locals: usize,
+    free: Option<usize>,
+    bytecode: &Bytecode,
+) -> Result<(), FormatError> {
+    let mut starts = vec![false; instructions.len()];
+    let mut offset = 0;
+    while offset < instructions.len() {
+        let Some(op) = Opcode::from_byte(instructions.0[offset]) else {
+            return error(format!("unknown opcode at {}", offset));
+        };
+        if offset + op.width() > instructions.len() {
+            return error(format!("truncated instruction at {}", offset));
+        }
+        let operands = read_operands(op, &instructions.0[offset + 1..]);
+        let in_range = match op {
+            Opcode::Constant => operands[0] < bytecode.constants.len(),
+            Opcode::Closure => matches!(
+                bytecode.constants.get(operands[0]).map(|obj| &**obj),
+                Some(Object::CompiledFunction(_))
+            ),
+            Opcode::GetGlobal | Opcode::SetGlobal => operands[0] < bytecode.globals.len(),
+            Opcode::GetLocal | Opcode::SetLocal => operands[0] < num_locals,
+            Opcode::GetBuiltin => crate::builtins::by_position(operands[0]).is_some(),
+            Opcode::GetFree => free.is_some_and(|free| operands[0] < free),
+            Opcode::CurrentClosure => free.is_some(),
+            _ => true,
+        };
+        if !in_range {
+            return error(format!("invalid operand of {} at {}", op.name(), offset));
+        }
+        starts[offset] = true;
+        offset += op.width();
+    }
+    check_stack(instructions, &starts)
+}
+
+// what the stack holds at an instruction: how many values the function put
+// there, and the try expressions it's in, innermost last, each with where
+// its handler is and how many values were there when it started
+#[derive(Clone, PartialEq)]
+struct StackState {
+    depth: usize,
+    handlers: Vec<(usize, usize)>,
+}
+
+// follows every path through the instructions, giving each one the state of
+// the stack it runs with, which has to be the same on every path
+fn check_stack(instructions: &Instructions, starts: &[bool]) -> Result<(), FormatError> {
+    let mut states: Vec<Option<StackState>> = vec![None; instructions.len()];
+    let mut pending = vec![(
+        0,
+        StackState {
+            depth: 0,
+            handlers: Vec::new(),
+        },
+    )];
+    while let Some((offset, state)) = pending.pop() {
+        if offset >= instructions.len() {
+            return error("instructions run past the end");
+        }
+        if !starts[offset] {
+            return error(format!(
+                "jump into the middle of an instruction at {}",
+                offset
+            ));
+        }
+        match &states[offset] {
+            Some(seen) if *seen == state => continue,
+            Some(_) => return error(format!("inconsistent stack at {}", offset)),
+            None => states[offset] = Some(state.clone()),
+        }
+
+        let op = Opcode::from_byte(instructions.0[offset]).unwrap();
+        let operands = read_operands(op, &instructions.0[offset + 1..]);
+        let (pops, pushes) = stack_effect(op, &operands);
+        // values below the start of a try body are the ones its handler
+        // unwinds to, so the body can't take them
+        let floor = state.handlers.last().map_or(0, |&(_, depth)| depth);
+        if state.depth < floor + pops {
+            return error(format!("stack underflow at {}", offset));
+        }
+        let mut next = StackState {
+            depth: state.depth - pops + pushes,
+            handlers: state.handlers.clone(),
+        };
+        let after = offset + op.width();
+        match op {
+            Opcode::Return | Opcode::ReturnValue => {}
+            Opcode::Jump => pending.push((operands[0], next)),
+            Opcode::JumpNotTruthy => {
+                pending.push((operands[0], next.clone()));
+                pending.push((after, next));
+            }
+            Opcode::Try => {
+                // the handler starts with the error on the stack
+                let handler = StackState {
+                    depth: state.depth + 1,
+                    handlers: state.handlers.clone(),
+                };
+                pending.push((operands[0], handler));
+                next.handlers.push((operands[0], state.depth));
+                pending.push((after, next));
+            }
+            Opcode::EndTry => {
+                if next.handlers.pop().is_none() {
+                    return error(format!("end of a try that didn't start at {}", offset));
+                }
+                pending.push((after, next));
+            }
+            _ => pending.push((after, next)),
+        }
+    }
+    Ok(())
+}
+
+// how many values the instruction takes from the stack and puts back on it
+fn stack_effect(op: Opcode, operands: &[usize]) -> (usize, usize) {
+    match op {
+        Opcode::Constant
+        | Opcode::True
+        | Opcode::False
+        | Opcode::Null
+        | Opcode::GetGlobal
+        | Opcode::GetLocal
+        | Opcode::GetBuiltin
+        | Opcode::GetFree
+        | Opcode::CurrentClosure
+        | Opcode::Globals => (0, 1),
+        Opcode::Pop | Opcode::JumpNotTruthy | Opcode::ReturnValue => (1, 0),
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::Equal
+        | Opcode::NotEqual
+        | Opcode::LessThan
+        | Opcode::GreaterThan
+        | Opcode::Index => (2, 1),
+        // the setters leave the value on the stack
+        Opcode::Minus | Opcode::Bang | Opcode::SetGlobal | Opcode::SetLocal => (1, 1),
+        Opcode::Array => (operands[0], 1),
+        Opcode::Hash => (2 * operands[0], 1),
+        // the callee and its arguments
+        Opcode::Call => (operands[0] + 1, 1),
+        Opcode::Closure => (operands[1], 1),
+        Opcode::Jump | Opcode::Return | Opcode::Try | Opcode::EndTry => (0, 0),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+        let Some(bytes) = self.bytes.get(self.offset..self.offset + len) else {
+            return error("unexpected end of file");
+        };
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, FormatError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_be_bytes(bytes) as usize)
+    }
+
+    fn locals(&mut self) -> Result<usize, FormatError> {
+        match self.u32()? {
+            count if count > MAX_LOCALS => error(format!("too many locals: {}", count)),
+            count => Ok(count),
+        }
+    }
+
+    fn str(&mut self) -> Result<String, FormatError> {
+        let len = self.u32()?;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(value) => Ok(value.to_string()),
+            Err(_) => error("string isn't valid utf-8"),
+        }
+    }
+
+    fn instructions(&mut self) -> Result<Instructions, FormatError> {
+        let len = self.u32()?;
+        Ok(Instructions(self.take(len)?.to_vec()))
+    }
+
+    fn source_map(&mut self) -> Result<SourceMap, FormatError> {
+        let mut source_map = SourceMap::default();
+        for _ in 0..self.u32()? {
+            let offset = self.u32()?;
+            let span = Span {
+                start: self.u32()?,
+                end: self.u32()?,
+                line: self.u32()?,
+                column: self.u32()?,
+            };
+            if matches!(source_map.entries().last(), Some((last, _)) if *last >= offset) {
+                return error("source map entries out of order");
+            }
+            source_map.add(offset, span);
+        }
+        Ok(source_map)
+    }
+}
+
+pub fn write_file(path: impl AsRef<Path>, bytecode: &Bytecode) -> Result<(), FormatError> {
+    let path = path.as_ref();
+    fs::write(path, encode(bytecode))
+        .or_else(|err| error(format!("could not write {}: {}", path.display(), err)))
+}
+
+#[cfg(test)]
+pub fn read_file(path: impl AsRef<Path>) -> Result<Bytecode, FormatError> {
+    let path = path.as_ref();
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes),
+        Err(err) => error(format!("could not read {}: {}", path.display(), err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::make;
+    use crate::compiler::compile;
+    use crate::evaluator::EvalConfig;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    fn test_compile(input: &str) -> Bytecode {
+        compile(&Parser::new(Lexer::new(input)).parse_program()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tests = vec![
+            ("1 + 2 * x", "identifier not found: x"),
+            (r#"let s = "hello"; len(s) - 7"#, "-2"),
+            (
+                "let newAdder = fn(a) { fn(b) { a + b } }; let addTwo = newAdder(2); addTwo(3)",
+                "5",
+            ),
+            (
+                "let f = fn() { let g = fn(n) { if (n == 0) { 0 } else { g(n - 1) } }; g(2) }; f()",
+                "0",
+            ),
+            (r#"try { error("boom") } catch (e) { message(e) }"#, "boom"),
+        ];
+
+        for (input, expected) in tests {
+            let bytecode = test_compile(input);
+            let decoded = decode(&encode(&bytecode)).unwrap();
+            assert_eq!(decoded, bytecode, "{}", input);
+
+            let result = Vm::new().run(decoded);
+            let result = match result {
+                Ok(obj) => obj.to_string(),
+                Err(error) => error.to_string(),
+            };
+            assert_eq!(result, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let bytecode = test_compile("let f = fn(x) { x }; f(1)");
+        let bytes = encode(&bytecode);
+        let mut version = bytes.clone();
+        version[4] = VERSION + 1;
+        // the top level's instructions end right before its source map of
+        // two entries, a count and five u32s each
+        let code_end = bytes.len() - 4 - 2 * 20;
+        assert_eq!(bytecode.source_map.entries().len(), 2);
+        let mut opcode = bytes.clone();
+        opcode[code_end - 1] = 255;
+        let mut source_map = bytes.clone();
+        source_map[code_end + 24..code_end + 28].fill(0);
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+
+        let tests = vec![
+            (
+                b"#!/usr/bin/env monkey".to_vec(),
+                "not a compiled program".to_string(),
+            ),
+            (
+                version,
+                format!(
+                    "unsupported bytecode version: {} (expected {})",
+                    VERSION + 1,
+                    VERSION
+                ),
+            ),
+            (
+                bytes[..bytes.len() - 1].to_vec(),
+                "unexpected end of file".to_string(),
+            ),
+            (
+                opcode,
+                format!("unknown opcode at {}", bytecode.instructions.len() - 1),
+            ),
+            (source_map, "source map entries out of order".to_string()),
+            (trailing, "trailing bytes after the program".to_string()),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(decode(&input).unwrap_err().message, expected);
+        }
+    }
+
+    fn bytecode(instructions: Vec<Vec<u8>>, num_locals: usize) -> Bytecode {
+        Bytecode {
+            instructions: Instructions(instructions.concat()),
+            constants: vec![integer_object(1)],
+            num_locals,
+            globals: Vec::new(),
+            source_map: SourceMap::default(),
+        }
+    }
+
+    #[test]
+    fn test_unsafe_programs() {
+        let tests = vec![
+            (
+                bytecode(vec![make(Opcode::Pop, &[]), make(Opcode::Return, &[])], 0),
+                "stack underflow at 0",
+            ),
+            (
+                bytecode(
+                    vec![
+                        make(Opcode::Constant, &[0]),
+                        make(Opcode::Array, &[2]),
+                        make(Opcode::ReturnValue, &[]),
+                    ],
+                    0,
+                ),
+                "stack underflow at 3",
+            ),
+            (
+                bytecode(
+                    vec![make(Opcode::Constant, &[0]), make(Opcode::Call, &[1])],
+                    0,
+                ),
+                "stack underflow at 3",
+            ),
+            (
+                bytecode(vec![make(Opcode::Constant, &[0])], 0),
+                "instructions run past the end",
+            ),
+            (
+                bytecode(vec![make(Opcode::Jump, &[1]), make(Opcode::Return, &[])], 0),
+                "jump into the middle of an instruction at 1",
+            ),
+            (
+                // one path to the return leaves a value, the other doesn't
+                bytecode(
+                    vec![
+                        make(Opcode::True, &[]),
+                        make(Opcode::JumpNotTruthy, &[7]),
+                        make(Opcode::Constant, &[0]),
+                        make(Opcode::Return, &[]),
+                    ],
+                    0,
+                ),
+                "inconsistent stack at 7",
+            ),
+            (
+                bytecode(
+                    vec![make(Opcode::EndTry, &[]), make(Opcode::Return, &[])],
+                    0,
+                ),
+                "end of a try that didn't start at 0",
+            ),
+            (
+                bytecode(
+                    vec![make(Opcode::CurrentClosure, &[]), make(Opcode::Return, &[])],
+                    0,
+                ),
+                "invalid operand of OpCurrentClosure at 0",
+            ),
+            (
+                bytecode(vec![make(Opcode::Return, &[])], MAX_LOCALS + 1),
+                "too many locals: 257",
+            ),
+        ];
+        for (bytecode, expected) in tests {
+            let error = decode(&encode(&bytecode)).unwrap_err();
+            assert_eq!(error.message, expected);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_files() {
+        let bytecode = test_compile(
+            "let f = fn(n) { try { [n, {n: n}][0] } catch (e) { 0 } }; let g = fn(x) { fn() { f(x) } }; g(1)()",
+        );
+        let bytes = encode(&bytecode);
+        // every file that decodes has to run, to a value or an error
+        for len in 0..bytes.len() {
+            let _ = decode(&bytes[..len]);
+        }
+        for index in 0..bytes.len() {
+            for bit in 0..8 {
+                let mut corrupted = bytes.clone();
+                corrupted[index] ^= 1 << bit;
+                if let Ok(bytecode) = decode(&corrupted) {
+                    let config = EvalConfig::default().fuel(1000);
+                    let _ = Vm::new().run_with_config(bytecode, config);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        let path = std::env::temp_dir().join(format!("monk-{}.monkc", std::process::id()));
+        let bytecode = test_compile("let x = 40; x + 2");
+        write_file(&path, &bytecode).unwrap();
+        assert_eq!(read_file(&path).unwrap(), bytecode);
+        fs::remove_file(&path).unwrap();
+
+        let error = read_file(&path).unwrap_err();
+        assert!(error.message.starts_with("could not read"), "{}", error);
+    }
+}
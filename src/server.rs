@@ -0,0 +1,457 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::cli::ServeOptions;
+use crate::evaluator::{EvalConfig, Object};
+use crate::interpreter::Interpreter;
+use crate::{inspect, printer};
+
+// `monk serve`: the REPL over TCP. A client sends a line of code at a time,
+// and gets back what the code wrote, a line each after "| ", then its value
+// after "= " or its error after "! ". Newlines in a value or an error are
+// sent as \n, so that either is one line and ends the answer:
+//
+//   let name = "monk"; puts("hello " + name); len(name)
+//   | hello monk
+//   = 4
+//
+// When the server has a token, the first line has to be the token, which is
+// answered with "= ok", or else "! wrong token" before the server hangs up.
+// :reset forgets the globals of the connection, and :quit closes it.
+//
+// Each connection has its own thread and interpreter, in the sandbox of an
+// embedded interpreter, and each line runs with the fuel, time, memory and
+// depth limits. What a client can make the server hold is bounded too: the
+// connections at once, the length of a line, which is checked before the
+// token, and the number of globals of a connection and what they hold. A
+// value longer than the limit on replies is cut off.
+pub fn run(options: &ServeOptions) -> ExitCode {
+    let listener = match TcpListener::bind((options.host.as_str(), options.port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!(
+                "error: can't listen on {}:{}: {}",
+                options.host, options.port, error
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Ok(address) = listener.local_addr() {
+        if options.token.is_none() && !address.ip().is_loopback() {
+            eprintln!("warning: anyone who can reach {} can run code", address);
+        }
+        eprintln!("listening on {}", address);
+    }
+    listen(listener, options);
+    ExitCode::SUCCESS
+}
+
+// serves each connection on a thread of its own, turning away the ones past
+// the limit
+fn listen(listener: TcpListener, options: &ServeOptions) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                continue;
+            }
+        };
+        let Some(slot) = Slot::take(&connections, options.max_connections) else {
+            let _ = writeln!(stream, "! too many connections");
+            continue;
+        };
+        let options = options.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let peer = stream.peer_addr().map(|peer| peer.to_string());
+            if let Err(error) = serve(stream, &options) {
+                eprintln!("error: {}: {}", peer.unwrap_or_default(), error);
+            }
+        });
+    }
+}
+
+// one of the connections that may be open at once, given back when the
+// connection's thread is done with it
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(connections: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| Slot(Arc::clone(connections)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Answers the lines of the connection until it's closed. A line that's too
+// long or isn't valid UTF-8 is answered with the error before the server
+// hangs up, as the lines after it can't be told apart.
+pub fn serve(stream: TcpStream, options: &ServeOptions) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut refusal = stream.try_clone()?;
+    let mut next_line = || match read_line(&mut reader, options.max_line) {
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+            writeln!(refusal, "! {}", error)?;
+            Ok(None)
+        }
+        line => line,
+    };
+    if let Some(token) = &options.token {
+        match next_line()? {
+            Some(line) if same(line.trim_end().as_bytes(), token.as_bytes()) => {
+                writeln!(writer, "= ok")?
+            }
+            Some(_) => return writeln!(writer, "! wrong token"),
+            None => return Ok(()),
+        }
+    }
+    let line_start = Rc::new(Cell::new(true));
+    let config = EvalConfig::default()
+        .fuel(options.fuel)
+        .timeout(options.timeout)
+        .memory_limit(options.memory_limit)
+        .max_depth(options.max_depth);
+    let mut interpreter = Interpreter::new()
+        .engine(options.engine)
+        .config(config)
+        .output(Output {
+            stream: stream.try_clone()?,
+            line_start: Rc::clone(&line_start),
+        });
+    while let Some(line) = next_line()? {
+        let answer = match line.trim() {
+            ":quit" => return Ok(()),
+            ":reset" => {
+                interpreter.reset();
+                Ok("null".to_string())
+            }
+            command if command.starts_with(':') => Err(format!("unknown command: {}", command)),
+            source => eval(&mut interpreter, source, options),
+        };
+        // what the code wrote last may not end its line
+        if !line_start.replace(true) {
+            writeln!(writer)?;
+        }
+        match answer {
+            Ok(value) => writeln!(writer, "= {}", escape(&value))?,
+            Err(error) => writeln!(writer, "! {}", escape(&error))?,
+        }
+    }
+    Ok(())
+}
+
+// Runs a line, undoing what it did to the globals when it leaves more of
+// them than a connection may have, or globals that hold more than a line
+// may.
+fn eval(
+    interpreter: &mut Interpreter,
+    source: &str,
+    options: &ServeOptions,
+) -> Result<String, String> {
+    let snapshot = interpreter.snapshot();
+    let answer = interpreter
+        .eval(source)
+        .map(|value| reply(&value, options.max_reply))
+        .map_err(|error| error.to_string());
+    let globals = interpreter.globals();
+    let error = if globals.len() > options.max_globals {
+        format!("a connection may have {} globals", options.max_globals)
+    } else {
+        let values: Vec<_> = globals.into_iter().map(|(_, value)| value).collect();
+        match inspect::held(&values) {
+            bytes if bytes > options.memory_limit => format!(
+                "the globals would hold more than {} bytes",
+                options.memory_limit
+            ),
+            _ => return answer,
+        }
+    };
+    interpreter.restore(&snapshot);
+    Err(format!("{}, so the line was undone", error))
+}
+
+// The next line without its newline, none at the end of the stream. A line
+// longer than the limit is an error rather than read in full.
+fn read_line(reader: &mut impl BufRead, max: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.take(max as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > max {
+        let message = format!("line longer than {} bytes", max);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line isn't valid UTF-8"))
+}
+
+// The value as the answer shows it, cut off past `max` bytes. The elements
+// of a collection stop being rendered about there, as one that holds the
+// same collection over and over can be far longer than what it holds.
+fn reply(value: &Object, max: usize) -> String {
+    let text = match value {
+        Object::Array(_) | Object::Hash(_) => printer::render_within(value, max),
+        value => value.to_string(),
+    };
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... (cut off at {} bytes)", &text[..end], max)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+// compares the token in the same time wherever they differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// where puts writes for a connection, starting each line with "| "
+struct Output {
+    stream: TcpStream,
+    // whether the next byte starts a line
+    line_start: Rc<Cell<bool>>,
+}
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+            if self.line_start.get() {
+                self.stream.write_all(b"| ")?;
+            }
+            self.stream.write_all(line)?;
+            self.line_start.set(line.ends_with(b"\n"));
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // connects a client to a server of one connection, and returns the answers
+    // to the lines it sends
+    fn session(options: ServeOptions, lines: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &options).unwrap();
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        // the server may hang up before it has read every line
+        for line in lines {
+            let _ = writeln!(client, "{}", line);
+        }
+        let _ = client.shutdown(std::net::Shutdown::Write);
+        let answers = BufReader::new(client).lines().map(Result::unwrap).collect();
+        server.join().unwrap();
+        answers
+    }
+
+    #[test]
+    fn test_serve() {
+        let lines = [
+            "let greet = fn(name) { puts(\"hello \" + name); len(name) };",
+            "greet(\"monk\")",
+            "read_file(\"Cargo.toml\")",
+            ":reset",
+            "greet",
+            ":what",
+        ];
+        let answers = session(ServeOptions::default(), &lines);
+        // the function is printed on more than one line
+        assert!(answers[0].starts_with("= fn(name) {\\n"), "{}", answers[0]);
+        assert_eq!(answers[1..3], ["| hello monk", "= 4"]);
+        assert!(answers[3].starts_with("! "), "{}", answers[3]);
+        assert_eq!(answers[4], "= null");
+        assert!(answers[5].contains("greet"), "{}", answers[5]);
+        assert_eq!(answers[6], "! unknown command: :what");
+    }
+
+    #[test]
+    fn test_serve_token() {
+        let options = ServeOptions {
+            token: Some("secret".to_string()),
+            ..ServeOptions::default()
+        };
+        let answers = session(options.clone(), &["secret", "1 + 2"]);
+        assert_eq!(answers, ["= ok", "= 3"]);
+        let answers = session(options, &["guess", "1 + 2"]);
+        assert_eq!(answers, ["! wrong token"]);
+    }
+
+    #[test]
+    fn test_serve_limits() {
+        let options = ServeOptions {
+            fuel: 1000,
+            ..ServeOptions::default()
+        };
+        let answers = session(options, &["let f = fn() { f() }; f()", "1"]);
+        assert!(answers[0].starts_with("! "), "{}", answers[0]);
+        assert_eq!(answers[1], "= 1");
+
+        let options = ServeOptions {
+            memory_limit: 4096,
+            max_depth: 10,
+            ..ServeOptions::default()
+        };
+        let lines = [
+            "let grow = fn(s, n) { if (n == 0) { s } else { grow(s + s, n - 1) } }; len(grow(\"ab\", 5))",
+            "len(grow(\"ab\", 12))",
+            "let down = fn(n) { if (n == 0) { 0 } else { down(n - 1) } }; down(20)",
+        ];
+        let answers = session(options, &lines);
+        assert_eq!(answers[0], "= 64");
+        assert!(answers[1].starts_with("! memory limit"), "{}", answers[1]);
+        assert!(
+            answers[2].starts_with("! maximum recursion depth"),
+            "{}",
+            answers[2]
+        );
+    }
+
+    #[test]
+    fn test_serve_bounds() {
+        let options = ServeOptions {
+            max_line: 16,
+            max_globals: 2,
+            ..ServeOptions::default()
+        };
+        let lines = [
+            "let a = 1;",
+            "let b = 2;",
+            "let c = 3; c",
+            "a + b",
+            "c",
+            "\"a line that's too long\"",
+            "a",
+        ];
+        let answers = session(options, &lines);
+        assert_eq!(answers[..2], ["= 1", "= 2"]);
+        assert_eq!(
+            answers[2],
+            "! a connection may have 2 globals, so the line was undone"
+        );
+        assert_eq!(answers[3], "= 3");
+        assert!(
+            answers[4].starts_with("! identifier not found"),
+            "{}",
+            answers[4]
+        );
+        assert_eq!(answers[5..], ["! line longer than 16 bytes"]);
+
+        let options = ServeOptions {
+            memory_limit: 4096,
+            ..ServeOptions::default()
+        };
+        let a = format!("let a = \"{}\"; len(a)", "x".repeat(1024));
+        let lines = [
+            &a,
+            "let b = a + \"\"; len(b)",
+            "let c = a + \"\"; len(c)",
+            "let d = a + \"\"; len(d)",
+            "len(a) + len(b) + len(c)",
+        ];
+        let answers = session(options, &lines);
+        assert_eq!(answers[..3], ["= 1024", "= 1024", "= 1024"]);
+        assert_eq!(
+            answers[3],
+            "! the globals would hold more than 4096 bytes, so the line was undone"
+        );
+        assert_eq!(answers[4], "= 3072");
+    }
+
+    #[test]
+    fn test_serve_replies() {
+        let options = ServeOptions {
+            max_reply: 100,
+            ..ServeOptions::default()
+        };
+        let chain = format!("1{}", " + 1".repeat(10_000));
+        let lines = [
+            &chain,
+            "let g = fn(x, n) { if (n == 0) { x } else { g([x, x], n - 1) } }; g(1, 60)",
+            "[1, 2, 3]",
+        ];
+        let answers = session(options, &lines);
+        assert!(
+            answers[0].starts_with("! expressions nested more than"),
+            "{}",
+            answers[0]
+        );
+        assert!(
+            answers[1].ends_with("... (cut off at 100 bytes)"),
+            "{}",
+            answers[1]
+        );
+        assert!(answers[1].len() < 200, "{}", answers[1]);
+        assert_eq!(answers[2], "= [1, 2, 3]");
+    }
+
+    #[test]
+    fn test_serve_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            max_connections: 1,
+            ..ServeOptions::default()
+        };
+        thread::spawn(move || listen(listener, &options));
+        let mut first = TcpStream::connect(address).unwrap();
+        writeln!(first, "1").unwrap();
+        let mut answers = BufReader::new(first.try_clone().unwrap()).lines();
+        assert_eq!(answers.next().unwrap().unwrap(), "= 1");
+        let second = TcpStream::connect(address).unwrap();
+        let mut refused = BufReader::new(second).lines();
+        assert_eq!(refused.next().unwrap().unwrap(), "! too many connections");
+        assert!(refused.next().is_none());
+        // the slot is given back when the first connection closes
+        writeln!(first, ":quit").unwrap();
+        assert!(answers.next().is_none());
+        // the server may not have given the slot back yet
+        let answer = loop {
+            let mut third = TcpStream::connect(address).unwrap();
+            writeln!(third, "2").unwrap();
+            let answer = BufReader::new(third).lines().next().unwrap().unwrap();
+            if answer != "! too many connections" {
+                break answer;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(answer, "= 2");
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use crate::convert::Data;
+use crate::evaluator::{CancelHandle, ErrorKind, EvalError};
+use crate::interpreter::{Interpreter, MonkError};
+
+// An interpreter that can be moved to other threads and shared between them,
+// e.g. behind an Arc in a server. Objects and environments are Rc's and so
+// stay on one thread: the interpreter runs on a thread of its own, and the
+// code to run goes to it while its values come back as data. The code of
+// every thread runs one piece at a time, in the order it's sent.
+//
+//   let interpreter = Arc::new(SharedInterpreter::new(Interpreter::new));
+//   interpreter.eval("let n = 21;")?;
+//   interpreter.eval("n * 2")?; // Data::Integer(42)
+pub struct SharedInterpreter {
+    requests: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+    cancel: CancelHandle,
+}
+
+enum Request {
+    Eval(String, Sender<Result<Data, MonkError>>),
+    Reset,
+}
+
+impl SharedInterpreter {
+    // starts the thread with the interpreter that new makes there, which is
+    // where the interpreter has to be made as it can't be sent
+    pub fn new(new: impl FnOnce() -> Interpreter + Send + 'static) -> SharedInterpreter {
+        let (requests, receiver) = mpsc::channel();
+        let (cancel, handle) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let interpreter = new();
+            let _ = cancel.send(interpreter.cancel_handle());
+            serve(interpreter, receiver)
+        });
+        SharedInterpreter {
+            requests: Some(requests),
+            thread: Some(thread),
+            // if making the interpreter panicked, there's nothing to cancel
+            // and every eval gives the error that the thread stopped
+            cancel: handle.recv().unwrap_or_default(),
+        }
+    }
+
+    // like Interpreter::eval, with the value as data; once code on the
+    // interpreter's thread has panicked, every eval gives an error instead
+    pub fn eval(&self, source: &str) -> Result<Data, MonkError> {
+        let (reply, result) = mpsc::channel();
+        self.send(Request::Eval(source.to_string(), reply))?;
+        result.recv().map_err(|_| stopped())?
+    }
+
+    // like Interpreter::cancel_handle
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    // like Interpreter::reset, which does nothing once the thread stopped
+    pub fn reset(&self) {
+        let _ = self.send(Request::Reset);
+    }
+
+    fn send(&self, request: Request) -> Result<(), MonkError> {
+        let requests = self.requests.as_ref().unwrap();
+        requests.send(request).map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> MonkError {
+    let error = EvalError::new(
+        ErrorKind::InterpreterStopped,
+        "the interpreter thread stopped",
+    );
+    MonkError::Runtime(error)
+}
+
+impl Drop for SharedInterpreter {
+    // stops the thread once the code that was sent has run
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(mut interpreter: Interpreter, requests: Receiver<Request>) {
+    for request in requests {
+        match request {
+            Request::Eval(source, reply) => {
+                let result = interpreter.eval(&source).map(|value| Data::from(&*value));
+                // the thread that sent it may not wait for it anymore
+                let _ = reply.send(result);
+            }
+            Request::Reset => interpreter.reset(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use std::error::Error;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_shared_interpreter() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedInterpreter>();
+        assert_send_sync::<MonkError>();
+
+        let interpreter = Arc::new(SharedInterpreter::new(|| {
+            Interpreter::new().engine(Engine::Vm)
+        }));
+        interpreter.eval("let n = 21; let f = fn() { n };").unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let interpreter = Arc::clone(&interpreter);
+                thread::spawn(move || interpreter.eval(&format!("f() * 2 + {}", i)))
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let result = thread.join().unwrap();
+            assert_eq!(result.unwrap(), Data::Integer(42 + i as i64));
+        }
+
+        assert!(matches!(interpreter.eval("f"), Ok(Data::Other(_))));
+        let Err(MonkError::Runtime(error)) = interpreter.eval("let g = fn() { 1 / 0 }; g()") else {
+            panic!("not a runtime error");
+        };
+        assert_eq!(error.kind, ErrorKind::DivisionByZero);
+        assert_eq!(error.to_string(), "division by zero: 1 / 0");
+        assert_eq!(error.trace[0].name, "g");
+        let error: Box<dyn Error + Send + Sync> = MonkError::Runtime(error).into();
+        let error = thread::spawn(move || error.to_string()).join().unwrap();
+        assert_eq!(error, "division by zero: 1 / 0");
+        assert!(matches!(
+            interpreter.eval("let = 1"),
+            Err(MonkError::Parse(_))
+        ));
+
+        let message = |result: Result<Data, MonkError>| result.unwrap_err().to_string();
+        // nothing runs to be cancelled
+        interpreter.cancel_handle().cancel();
+        assert_eq!(interpreter.eval("n").unwrap(), Data::Integer(21));
+        interpreter.reset();
+        assert_eq!(message(interpreter.eval("n")), "identifier not found: n");
+    }
+
+    #[test]
+    fn test_stopped_interpreter() {
+        let interpreter = Arc::new(SharedInterpreter::new(|| panic!("no interpreter")));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let interpreter = Arc::clone(&interpreter);
+                thread::spawn(move || interpreter.eval("1"))
+            })
+            .collect();
+        for thread in threads {
+            let Err(MonkError::Runtime(error)) = thread.join().unwrap() else {
+                panic!("not a runtime error");
+            };
+            assert_eq!(error.kind, ErrorKind::InterpreterStopped);
+            assert_eq!(error.to_string(), "the interpreter thread stopped");
+        }
+        interpreter.reset();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::token::Span;
+
+// Where the instructions of a compiled function came from in the source.
+// Each entry is the offset of an instruction and the span of the statement or
+// call expression it starts, in order of offset; the instructions after it up
+// to the next entry belong to the same span. The VM looks up the spans of its
+// call instructions, so compiled programs have the same stack traces as
+// evaluated ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    entries: Vec<(usize, Span)>,
+}
+
+impl SourceMap {
+    // entries have to be added in order of offset; a later one at the same
+    // offset, e.g. of a statement nested in a block, replaces the earlier one
+    pub fn add(&mut self, offset: usize, span: Span) {
+        if let Some(last) = self.entries.last_mut() {
+            assert!(last.0 <= offset, "source map entries out of order");
+            if last.0 == offset {
+                last.1 = span;
+                return;
+            }
+        }
+        self.entries.push((offset, span));
+    }
+
+    // the span of the instruction at the offset
+    pub fn lookup(&self, offset: usize) -> Option<Span> {
+        let index = self.entries.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|index| self.entries[index].1)
+    }
+
+    pub fn entries(&self) -> &[(usize, Span)] {
+        &self.entries
+    }
+
+    // drops the entries of instructions from the offset on, once they've
+    // been removed
+    pub fn truncate(&mut self, offset: usize) {
+        self.entries.retain(|(start, _)| *start < offset);
+    }
+
+    // the same map for instructions that were moved to other offsets
+    pub fn remap(&self, new_offset: impl Fn(usize) -> usize) -> SourceMap {
+        let mut remapped = SourceMap::default();
+        for (offset, span) in &self.entries {
+            remapped.add(new_offset(*offset), *span);
+        }
+        remapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize) -> Span {
+        Span {
+            line,
+            ..Span::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut map = SourceMap::default();
+        map.add(0, span(1));
+        map.add(4, span(2));
+        map.add(4, span(3));
+        map.add(9, span(4));
+
+        let tests = vec![
+            (0, Some(1)),
+            (3, Some(1)),
+            (4, Some(3)),
+            (8, Some(3)),
+            (9, Some(4)),
+            (100, Some(4)),
+        ];
+        for (offset, expected) in tests {
+            let line = map.lookup(offset).map(|span| span.line);
+            assert_eq!(line, expected, "offset {}", offset);
+        }
+        assert_eq!(SourceMap::default().lookup(0), None);
+
+        map.truncate(9);
+        assert_eq!(map.entries().len(), 2);
+        let remapped = map.remap(|offset| offset / 2);
+        assert_eq!(remapped.entries(), [(0, span(1)), (2, span(3))]);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use crate::builtins;
+use crate::symbol::Symbol;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolScope {
+    Global,
+    // a slot in the locals of the current call frame
+    Local,
+    Builtin,
+    // a variable of an enclosing function, captured by the closure
+    Free,
+    // the name of the function itself
+    Function,
+}
+
+// What a name refers to: the kind of storage and the index into it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Binding {
+    pub name: Symbol,
+    pub scope: SymbolScope,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Global,
+    Function,
+    // a scope that shares the call frame of the enclosing function or
+    // program, like a catch handler
+    Block,
+}
+
+// The names visible while compiling, one table per scope with the enclosing
+// one as its outer table. Entering a function or block moves the current
+// table into a new one, leaving it moves it back out.
+//
+// Names that are used in a function but defined as locals of an enclosing
+// one are captured: the function's table records them as free variables, in
+// the order the closure has to copy them.
+#[derive(Debug)]
+pub struct SymbolTable {
+    outer: Option<Box<SymbolTable>>,
+    kind: Kind,
+    store: HashMap<Symbol, Binding>,
+    // the global slots in the global table
+    globals: Vec<Symbol>,
+    // the locals of the call frame: a function's own, or the program's for
+    // the global table; blocks allocate theirs from the enclosing frame
+    locals: usize,
+    free: Vec<Binding>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        SymbolTable::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            outer: None,
+            kind: Kind::Global,
+            store: HashMap::new(),
+            globals: Vec::new(),
+            locals: 0,
+            free: Vec::new(),
+        }
+    }
+
+    // a table for the body of a function inside this one
+    pub fn enclose(self) -> SymbolTable {
+        self.push(Kind::Function)
+    }
+
+    // a table for a scope that keeps its locals in the current call frame
+    pub fn enclose_block(self) -> SymbolTable {
+        self.push(Kind::Block)
+    }
+
+    fn push(self, kind: Kind) -> SymbolTable {
+        SymbolTable {
+            outer: Some(Box::new(self)),
+            kind,
+            ..SymbolTable::new()
+        }
+    }
+
+    // leaves the scope, giving back the enclosing table
+    pub fn into_outer(self) -> Option<SymbolTable> {
+        self.outer.map(|outer| *outer)
+    }
+
+    // Defines the name in this scope. Defining a name again in the same
+    // scope reuses its slot.
+    pub fn define(&mut self, name: &Symbol) -> Binding {
+        if let Some(binding) = self.store.get(name) {
+            if matches!(binding.scope, SymbolScope::Global | SymbolScope::Local) {
+                return binding.clone();
+            }
+        }
+        let binding = match self.kind {
+            Kind::Global => {
+                self.globals.push(name.clone());
+                Binding {
+                    name: name.clone(),
+                    scope: SymbolScope::Global,
+                    index: self.globals.len() - 1,
+                }
+            }
+            Kind::Function | Kind::Block => Binding {
+                name: name.clone(),
+                scope: SymbolScope::Local,
+                index: self.allocate_local(),
+            },
+        };
+        self.store.insert(name.clone(), binding.clone());
+        binding
+    }
+
+    // Makes the name refer to the function this table is for, so that a local
+    // function can call itself before the let statement binding it has run.
+    pub fn define_function_name(&mut self, name: &Symbol) -> Binding {
+        let binding = Binding {
+            name: name.clone(),
+            scope: SymbolScope::Function,
+            index: 0,
+        };
+        self.store.insert(name.clone(), binding.clone());
+        binding
+    }
+
+    // whether let statements in this scope define locals rather than globals
+    pub fn is_local(&self) -> bool {
+        self.kind != Kind::Global
+    }
+
+    // Finds what the name refers to, looking outwards from this scope and
+    // falling back to the builtins. Locals of an enclosing function become
+    // free variables of this one.
+    pub fn resolve(&mut self, name: &Symbol) -> Option<Binding> {
+        if let Some(binding) = self.store.get(name) {
+            return Some(binding.clone());
+        }
+        let Some(outer) = self.outer.as_mut() else {
+            let index = builtins::position(name)?;
+            return Some(Binding {
+                name: name.clone(),
+                scope: SymbolScope::Builtin,
+                index,
+            });
+        };
+        let binding = outer.resolve(name)?;
+        match (self.kind, binding.scope) {
+            (Kind::Function, SymbolScope::Local | SymbolScope::Free | SymbolScope::Function) => {
+                Some(self.define_free(binding))
+            }
+            _ => Some(binding),
+        }
+    }
+
+    // Declares a global that hasn't been defined yet, e.g. one that a
+    // function refers to before the let statement for it. Globals are only
+    // checked for a value at runtime.
+    pub fn declare_global(&mut self, name: &Symbol) -> Binding {
+        match self.outer.as_mut() {
+            Some(outer) => outer.declare_global(name),
+            None => self.define(name),
+        }
+    }
+
+    fn define_free(&mut self, original: Binding) -> Binding {
+        let binding = Binding {
+            name: original.name.clone(),
+            scope: SymbolScope::Free,
+            index: self.free.len(),
+        };
+        self.free.push(original);
+        self.store.insert(binding.name.clone(), binding.clone());
+        binding
+    }
+
+    // takes the next local slot of the call frame this scope runs in
+    // a slot in the current call frame for the compiler's own use, which no
+    // name refers to
+    pub fn define_hidden(&mut self) -> usize {
+        self.allocate_local()
+    }
+
+    fn allocate_local(&mut self) -> usize {
+        if let (Kind::Block, Some(outer)) = (self.kind, self.outer.as_mut()) {
+            return outer.allocate_local();
+        }
+        self.locals += 1;
+        self.locals - 1
+    }
+
+    // the number of local slots the call frame needs
+    pub fn num_locals(&self) -> usize {
+        self.locals
+    }
+
+    // the bindings in the enclosing scope of the variables this function
+    // captures, in capture order
+    pub fn free_symbols(&self) -> &[Binding] {
+        &self.free
+    }
+
+    // the names of the locals and free variables visible from this scope,
+    // sorted by name
+    pub fn local_names(&self) -> Vec<Symbol> {
+        let mut names = Vec::new();
+        let mut table = Some(self);
+        while let Some(current) = table.filter(|table| table.kind != Kind::Global) {
+            names.extend(current.store.keys().cloned());
+            table = current.outer.as_deref();
+        }
+        names.sort_by(|a, b| str::cmp(a, b));
+        names.dedup();
+        names
+    }
+
+    // the names of the global slots, in slot order
+    pub fn globals(&self) -> &[Symbol] {
+        match &self.outer {
+            Some(outer) => outer.globals(),
+            None => &self.globals,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(name: &str, scope: SymbolScope, index: usize) -> Binding {
+        Binding {
+            name: Symbol::intern(name),
+            scope,
+            index,
+        }
+    }
+
+    fn define(table: &mut SymbolTable, name: &str) -> Binding {
+        table.define(&Symbol::intern(name))
+    }
+
+    fn resolve(table: &mut SymbolTable, name: &str) -> Option<Binding> {
+        table.resolve(&Symbol::intern(name))
+    }
+
+    #[test]
+    fn test_define_and_resolve() {
+        let mut global = SymbolTable::new();
+        assert_eq!(
+            define(&mut global, "a"),
+            binding("a", SymbolScope::Global, 0)
+        );
+        assert_eq!(
+            define(&mut global, "b"),
+            binding("b", SymbolScope::Global, 1)
+        );
+        // defining again keeps the slot
+        assert_eq!(
+            define(&mut global, "a"),
+            binding("a", SymbolScope::Global, 0)
+        );
+
+        let mut local = global.enclose();
+        assert_eq!(define(&mut local, "c"), binding("c", SymbolScope::Local, 0));
+        assert_eq!(define(&mut local, "b"), binding("b", SymbolScope::Local, 1));
+
+        let tests = vec![
+            ("a", Some(binding("a", SymbolScope::Global, 0))),
+            ("b", Some(binding("b", SymbolScope::Local, 1))),
+            ("c", Some(binding("c", SymbolScope::Local, 0))),
+            ("len", Some(binding("len", SymbolScope::Builtin, 0))),
+            ("d", None),
+        ];
+        for (name, expected) in tests {
+            assert_eq!(resolve(&mut local, name), expected);
+        }
+        assert_eq!(local.num_locals(), 2);
+
+        let global = local.into_outer().unwrap();
+        assert!(global.into_outer().is_none());
+    }
+
+    #[test]
+    fn test_resolve_free() {
+        let mut global = SymbolTable::new();
+        define(&mut global, "a");
+        let mut first = global.enclose();
+        define(&mut first, "b");
+        define(&mut first, "c");
+        let mut second = first.enclose();
+        define(&mut second, "d");
+
+        let tests = vec![
+            ("a", binding("a", SymbolScope::Global, 0)),
+            ("c", binding("c", SymbolScope::Free, 0)),
+            ("b", binding("b", SymbolScope::Free, 1)),
+            ("d", binding("d", SymbolScope::Local, 0)),
+            // resolving again doesn't capture twice
+            ("c", binding("c", SymbolScope::Free, 0)),
+        ];
+        for (name, expected) in tests {
+            assert_eq!(resolve(&mut second, name).unwrap(), expected);
+        }
+        assert_eq!(
+            second.free_symbols(),
+            &[
+                binding("c", SymbolScope::Local, 1),
+                binding("b", SymbolScope::Local, 0)
+            ]
+        );
+
+        // a free variable of a function in between is captured through it
+        let mut third = second.enclose();
+        assert_eq!(
+            resolve(&mut third, "b").unwrap(),
+            binding("b", SymbolScope::Free, 0)
+        );
+        assert_eq!(third.free_symbols(), &[binding("b", SymbolScope::Free, 1)]);
+    }
+
+    #[test]
+    fn test_blocks_share_the_frame() {
+        let mut global = SymbolTable::new();
+        define(&mut global, "a");
+        let mut block = global.enclose_block();
+        assert_eq!(define(&mut block, "a"), binding("a", SymbolScope::Local, 0));
+        let mut global = block.into_outer().unwrap();
+        assert_eq!(global.num_locals(), 1);
+        assert_eq!(
+            resolve(&mut global, "a").unwrap(),
+            binding("a", SymbolScope::Global, 0)
+        );
+
+        let mut function = global.enclose();
+        define(&mut function, "x");
+        let mut block = function.enclose_block();
+        define(&mut block, "e");
+        // the block's names are locals of the function, not free variables
+        assert_eq!(
+            resolve(&mut block, "x").unwrap(),
+            binding("x", SymbolScope::Local, 0)
+        );
+        let function = block.into_outer().unwrap();
+        assert_eq!(function.num_locals(), 2);
+    }
+
+    #[test]
+    fn test_define_function_name() {
+        let global = SymbolTable::new();
+        assert!(!global.is_local());
+        let mut outer = global.enclose();
+        assert!(outer.is_local());
+        outer.define_function_name(&Symbol::intern("f"));
+        assert_eq!(
+            resolve(&mut outer, "f").unwrap(),
+            binding("f", SymbolScope::Function, 0)
+        );
+
+        // a parameter of the same name shadows the function
+        define(&mut outer, "f");
+        assert_eq!(
+            resolve(&mut outer, "f").unwrap(),
+            binding("f", SymbolScope::Local, 0)
+        );
+
+        // nested functions capture it like any other local
+        let mut inner = SymbolTable::new().enclose();
+        inner.define_function_name(&Symbol::intern("g"));
+        let mut nested = inner.enclose();
+        assert_eq!(
+            resolve(&mut nested, "g").unwrap(),
+            binding("g", SymbolScope::Free, 0)
+        );
+        assert_eq!(
+            nested.free_symbols(),
+            &[binding("g", SymbolScope::Function, 0)]
+        );
+    }
+
+    #[test]
+    fn test_declare_global() {
+        let mut function = SymbolTable::new().enclose();
+        let declared = function.declare_global(&Symbol::intern("later"));
+        assert_eq!(declared, binding("later", SymbolScope::Global, 0));
+
+        let mut global = function.into_outer().unwrap();
+        assert_eq!(define(&mut global, "later"), declared);
+        assert_eq!(global.globals(), &[Symbol::intern("later")]);
+    }
+}
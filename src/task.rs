@@ -0,0 +1,538 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::ast::{Expression, Program, Slot, Statement};
+use crate::builtins;
+use crate::compiler::{Bytecode, Compiler};
+use crate::convert::Data;
+use crate::evaluator::{self, CancelHandle, ErrorKind, EvalConfig, EvalError, Object, TraceFrame};
+use crate::sandbox;
+use crate::serialize;
+use crate::symbol::Symbol;
+use crate::token::Span;
+use crate::vm::Vm;
+
+// Tasks are functions that spawn runs on threads of their own, each in a VM
+// of its own as objects are Rc's and stay on the thread that made them. So a
+// task shares nothing with the code that spawned it: its function is
+// compiled again from its body and sent as bytecode, and its arguments, its
+// value and whatever goes through a channel are sent as `Data`, which has
+// to be data or channels. A function that uses a variable from outside of it
+// has to be given the value as an argument instead, and as functions can't
+// be sent, the functions it calls have to be defined inside of it, except
+// for the function itself, which is bound to its name on the task's thread
+// too so that it can call itself.
+//
+// What a task puts goes where puts writes on the thread that spawned it.
+// When that's an interpreter's output rather than stdout, it can only be
+// written there from that thread, so it's kept until the task is joined, or
+// dropped while the interpreter runs code.
+//
+// A task runs with the limits of the eval that spawned it, and is cancelled
+// when its task object is dropped. Waiting for a task or a message gives up
+// when the eval that waits runs out of time or is cancelled.
+//
+//   let results = channel();
+//   let task = spawn(fn(out, n) { send(out, n * 2); n }, results, 21);
+//   [recv(results), join(task)] // [42, 21]
+
+// A queue of messages that every thread with the channel can send to and
+// receive from.
+#[derive(Clone, Default)]
+pub struct Channel(Arc<(Mutex<VecDeque<Data>>, Condvar)>);
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel::default()
+    }
+
+    pub fn send(&self, message: Data) {
+        let (queue, ready) = &*self.0;
+        lock(queue).push_back(message);
+        ready.notify_one();
+    }
+
+    // waits until there's a message, or the eval that waits for it can't
+    // wait any longer
+    pub fn recv(&self) -> Result<Data, EvalError> {
+        let limits = evaluator::limits();
+        let (queue, ready) = &*self.0;
+        let mut queue = lock(queue);
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Ok(message);
+            }
+            limits.check()?;
+            queue = match ready.wait_timeout(queue, POLL_INTERVAL) {
+                Ok((queue, _)) => queue,
+                Err(error) => error.into_inner().0,
+            };
+        }
+    }
+}
+
+// a channel is the same channel on every thread
+impl PartialEq for Channel {
+    fn eq(&self, other: &Channel) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for Channel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Channel({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "channel")
+    }
+}
+
+// messages are only pushed and popped, so a queue is whole even if a thread
+// panicked while it held the lock
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+// how long a wait goes between checks of whether it should give up
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// an error of a task, as it's sent back to the thread that joins it
+type Failure = (ErrorKind, String, Vec<(String, Span)>);
+
+// A task that spawn started, which join waits for. It's only joined once,
+// and its outcome is kept for joining it again.
+pub struct Task {
+    thread: RefCell<Option<JoinHandle<Result<Data, Failure>>>>,
+    // gets a message once the thread is done, even if it panicked
+    finished: Channel,
+    cancel: CancelHandle,
+    outcome: RefCell<Option<Result<Data, EvalError>>>,
+    // what the task put, if it doesn't go to stdout
+    printed: Option<Printed>,
+}
+
+// What a task puts, kept until the thread that spawned it writes it to the
+// output puts has there.
+#[derive(Clone, Default)]
+struct Printed(Arc<Mutex<Vec<u8>>>);
+
+impl Printed {
+    fn forward(&self) {
+        let bytes = mem::take(&mut *lock(&self.0));
+        // like an output that fails, whoever spawned the task doesn't hear of it
+        let _ = builtins::write_output(&bytes);
+    }
+}
+
+impl Write for Printed {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        lock(&self.0).extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// tells the task's finished channel when the thread is done
+struct Finished(Channel);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.send(Data::Null);
+    }
+}
+
+impl Task {
+    // Compiles the function from its parameters and body, and starts running
+    // it with the arguments on a thread of its own, with the capabilities of
+    // this one. itself tells whether a name it uses from outside of it is
+    // its own.
+    pub fn spawn(
+        parameters: &[Symbol],
+        body: &Rc<Statement>,
+        args: &[Rc<Object>],
+        itself: impl Fn(&Symbol) -> bool,
+    ) -> Result<Task, EvalError> {
+        let names: Vec<Symbol> = (0..args.len())
+            .map(|index| Symbol::intern(&format!("${}", index)))
+            .collect();
+        let span = body.span();
+        let function = Expression::FunctionLiteral {
+            parameters: parameters.to_vec(),
+            body: Rc::clone(body),
+            locals: Rc::default(),
+            span,
+        };
+        let mut bytecode = compile(&function, None, &names, span)?;
+        let own = bytecode
+            .globals
+            .iter()
+            .find(|name| !names.contains(name) && itself(name))
+            .cloned();
+        if let Some(own) = &own {
+            bytecode = compile(&function, Some(own), &names, span)?;
+        }
+        let outside = bytecode
+            .globals
+            .iter()
+            .find(|name| !names.contains(name) && Some(*name) != own.as_ref());
+        if let Some(name) = outside {
+            return Err(EvalError::new(
+                ErrorKind::WrongArguments,
+                format!(
+                    "a spawned function can't use `{}` from outside of it, \
+                     pass data as an argument and define functions inside of it",
+                    name
+                ),
+            ));
+        }
+        let mut globals = Vec::new();
+        for (index, name) in bytecode.globals.iter().enumerate() {
+            if let Some(arg) = names.iter().position(|arg| arg == name) {
+                let message = Data::exact(&args[arg]).map_err(|type_name| {
+                    EvalError::new(
+                        ErrorKind::WrongArguments,
+                        format!("can't send {} to another thread", type_name),
+                    )
+                    .with_types(&[type_name])
+                })?;
+                globals.push((index, name.to_string(), message));
+            }
+        }
+        let code = serialize::encode(&bytecode);
+        let capabilities = sandbox::capabilities();
+        let limits = evaluator::limits();
+        let cancel = CancelHandle::new();
+        let finished = Channel::new();
+        let printed = builtins::has_output().then(Printed::default);
+        let thread = thread::spawn({
+            let cancel = cancel.clone();
+            let finished = Finished(finished.clone());
+            let printed = printed.clone();
+            move || {
+                let _finished = finished;
+                sandbox::set_capabilities(capabilities);
+                builtins::set_output(printed.map(|printed| Box::new(printed) as Box<dyn Write>));
+                run(&code, globals, limits.config(cancel))
+            }
+        });
+        Ok(Task {
+            thread: RefCell::new(Some(thread)),
+            finished,
+            cancel,
+            outcome: RefCell::new(None),
+            printed,
+        })
+    }
+
+    // Waits for the task to finish, and gives back its value or its error.
+    // If the eval that waits is cancelled, so is the task.
+    pub fn join(&self) -> Result<Rc<Object>, EvalError> {
+        if self.thread.borrow().is_some() {
+            if let Err(error) = self.finished.recv() {
+                if error.kind == ErrorKind::Cancelled {
+                    self.cancel.cancel();
+                }
+                return Err(error);
+            }
+        }
+        if let Some(thread) = self.thread.borrow_mut().take() {
+            let outcome = match thread.join() {
+                Ok(Ok(message)) => Ok(message),
+                Ok(Err((kind, message, trace))) => {
+                    let mut error = EvalError::new(kind, message);
+                    error.trace = trace
+                        .into_iter()
+                        .map(|(name, span)| TraceFrame { name, span })
+                        .collect();
+                    Err(error)
+                }
+                Err(panic) => Err(EvalError::new(
+                    ErrorKind::TaskPanicked,
+                    format!("the task panicked: {}", panic_message(&*panic)),
+                )),
+            };
+            *self.outcome.borrow_mut() = Some(outcome);
+            if let Some(printed) = &self.printed {
+                printed.forward();
+            }
+        }
+        match self.outcome.borrow().as_ref() {
+            Some(Ok(message)) => Ok(message.clone().into_object()),
+            Some(Err(error)) => Err(error.clone()),
+            None => unreachable!("a task has an outcome once it's joined"),
+        }
+    }
+}
+
+// a task that nothing can join anymore has no reason to go on; what it put
+// so far is still written if there's an output to write it to
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Some(printed) = self.printed.as_ref().filter(|_| builtins::has_output()) {
+            printed.forward();
+        }
+    }
+}
+
+// tasks are only the same as themselves
+impl PartialEq for Task {
+    fn eq(&self, other: &Task) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Debug for Task {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Task").finish_non_exhaustive()
+    }
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "task")
+    }
+}
+
+// the program of a task, which calls the function with the arguments in the
+// globals, after binding it to its name if it calls itself by it
+fn compile(
+    function: &Expression,
+    name: Option<&Symbol>,
+    args: &[Symbol],
+    span: Span,
+) -> Result<Bytecode, EvalError> {
+    let mut statements = Vec::new();
+    let callee = match name {
+        Some(name) => {
+            statements.push(Statement::LetStatement {
+                name: name.clone(),
+                slot: Slot::Global,
+                value: function.clone(),
+                span,
+            });
+            Expression::Identifier(name.clone(), Slot::Global)
+        }
+        None => function.clone(),
+    };
+    let call = Expression::Call {
+        function: Box::new(callee),
+        arguments: args
+            .iter()
+            .map(|arg| Expression::Identifier(arg.clone(), Slot::Global))
+            .collect(),
+        span,
+    };
+    statements.push(Statement::ExpressionStatement(call, Span::default()));
+    Compiler::new()
+        .inline_functions(false)
+        .compile(&Program { statements })
+        .map_err(|error| EvalError::new(ErrorKind::Compile, error.message))
+}
+
+// runs the code of a task on its thread, with its arguments in the globals
+fn run(
+    code: &[u8],
+    globals: Vec<(usize, String, Data)>,
+    config: EvalConfig,
+) -> Result<Data, Failure> {
+    let failure = |error: EvalError| {
+        let trace = error
+            .trace
+            .iter()
+            .map(|frame| (frame.name.to_string(), frame.span))
+            .collect();
+        (error.kind, error.message, trace)
+    };
+    let bytecode = serialize::decode(code)
+        .map_err(|error| failure(EvalError::new(ErrorKind::Compile, error.to_string())))?;
+    let mut vm = Vm::new();
+    for (index, name, message) in globals {
+        vm.set_global(index, Symbol::intern(&name), message.into_object());
+    }
+    let value = vm.run_with_config(bytecode, config).map_err(failure)?;
+    Data::exact(&value).map_err(|type_name| {
+        failure(
+            EvalError::new(
+                ErrorKind::WrongArguments,
+                format!("a task can't return {} to another thread", type_name),
+            )
+            .with_types(&[type_name]),
+        )
+    })
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown cause", String::as_str),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::engine::{assert_outcomes, outcome, Engine, Runner};
+    use crate::evaluator::{CancelHandle, ErrorKind, EvalConfig};
+    use crate::parser;
+    use crate::sandbox::{self, Capabilities, Capability};
+
+    #[test]
+    fn test_tasks() {
+        let tests = vec![
+            ("join(spawn(fn() { 1 + 2 }))", "3"),
+            (
+                "let fib = fn(n) { let f = fn(n) { if (n < 2) { n } else { f(n - 1) + f(n - 2) } }; f(n) };
+                 let tasks = [spawn(fib, 15), spawn(fib, 16)];
+                 join(tasks[0]) + join(tasks[1])",
+                "1597",
+            ),
+            (
+                r#"let results = channel();
+                   let task = spawn(fn(out, n) { send(out, [n, {"twice": n * 2}]); n }, results, 21);
+                   [recv(results), join(task), join(task)]"#,
+                r#"[[21, {"twice": 42}], 21, 21]"#,
+            ),
+            (
+                // the channel is the same one on both threads
+                "let requests = channel(); let replies = channel();
+                 let worker = spawn(fn(requests, replies) { send(replies, recv(requests) * 2) }, requests, replies);
+                 send(requests, 5); [recv(replies), join(worker)]",
+                "[10, null]",
+            ),
+            (
+                // the task's error is raised by join, and can be caught
+                r#"let task = spawn(fn() { error("boom") });
+                   try { join(task) } catch (e) { [kind(e), message(e)] }"#,
+                r#"["USER", "boom"]"#,
+            ),
+            ("join(spawn(fn(x) { x / 0 }, 1))", "division by zero: 1 / 0"),
+            (
+                // a function can call itself by the name it's bound to
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+                 join(spawn(fib, 15))",
+                "610",
+            ),
+            (
+                "let g = fn() { let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; join(spawn(f, 5)) }; g()",
+                "0",
+            ),
+            (
+                "let h = fn() { 1 }; let f = fn() { h() }; spawn(f)",
+                "a spawned function can't use `h` from outside of it, \
+                 pass data as an argument and define functions inside of it",
+            ),
+            (
+                "let n = 1; spawn(fn() { n })",
+                "a spawned function can't use `n` from outside of it, \
+                 pass data as an argument and define functions inside of it",
+            ),
+            ("spawn(fn(f) { f() }, len)", "can't send BUILTIN to another thread"),
+            ("join(spawn(fn() { fn() { 1 } }))", "a task can't return FUNCTION to another thread"),
+            ("send(channel(), fn() { 1 })", "can't send FUNCTION to another thread"),
+            ("spawn(1)", "argument to `spawn` must be FUNCTION, got INTEGER"),
+            ("spawn()", "wrong number of arguments to `spawn`: got=0, want=1"),
+            ("join(channel())", "argument to `join` must be TASK, got CHANNEL"),
+            ("let c = channel(); [c == c, c == channel()]", "[true, false]"),
+        ];
+        let before = sandbox::set_capabilities(Capabilities::none().allow(Capability::Threads));
+        assert_outcomes(&tests);
+        sandbox::set_capabilities(Capabilities::none());
+        assert_eq!(
+            outcome(Engine::TreeWalker, "spawn(fn() { 1 })"),
+            "`spawn` needs starting threads, which the sandbox denies"
+        );
+        sandbox::set_capabilities(before);
+    }
+
+    #[test]
+    fn test_tasks_keep_the_limits() {
+        let before = sandbox::set_capabilities(Capabilities::none().allow(Capability::Threads));
+        let tests = vec![
+            (EvalConfig::default().fuel(1_000), "join(spawn(fn() { let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(500) }))", "fuel exhausted"),
+            (EvalConfig::default().max_depth(50), "join(spawn(fn() { let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(100) }))", "maximum recursion depth exceeded: 50"),
+            (EvalConfig::default().timeout(Duration::from_millis(50)), "recv(channel())", "evaluation timed out after 50ms"),
+            (EvalConfig::default().timeout(Duration::from_millis(50)), "join(spawn(fn(c) { recv(c) }, channel()))", "evaluation timed out after 50ms"),
+        ];
+        for engine in Engine::ALL {
+            for (config, input, expected) in &tests {
+                let program = parser::parse(input).unwrap();
+                let result = Runner::new(engine).run_with_config(program, config.clone());
+                assert_eq!(
+                    result.map_or_else(|error| error.to_string(), |obj| obj.to_string()),
+                    *expected,
+                    "{} on {}",
+                    input,
+                    engine
+                );
+            }
+
+            // a cancel stops the wait, and the task that was waited for
+            let cancel = CancelHandle::new();
+            let waiter = thread::spawn({
+                let cancel = cancel.clone();
+                move || {
+                    thread::sleep(Duration::from_millis(50));
+                    cancel.cancel();
+                }
+            });
+            let program = parser::parse("join(spawn(fn(c) { recv(c) }, channel()))").unwrap();
+            let config = EvalConfig::default().cancel_with(cancel);
+            let result = Runner::new(engine).run_with_config(program, config);
+            assert_eq!(result.unwrap_err().kind, ErrorKind::Cancelled);
+            waiter.join().unwrap();
+        }
+
+        sandbox::set_capabilities(Capabilities::none());
+        for input in ["channel()", "send(1, 2)", "recv(1)"] {
+            let name = input.split('(').next().unwrap();
+            assert_eq!(
+                outcome(Engine::TreeWalker, input),
+                format!(
+                    "`{}` needs starting threads, which the sandbox denies",
+                    name
+                )
+            );
+        }
+        sandbox::set_capabilities(before);
+    }
+
+    #[test]
+    fn test_tasks_keep_the_sandbox() {
+        let threads = Capabilities::none().allow(Capability::Threads);
+        let before = sandbox::set_capabilities(threads);
+        let input = r#"join(spawn(fn() { getenv("RETURN_TO_MONK_UNSET") }))"#;
+        assert_eq!(
+            outcome(Engine::TreeWalker, input),
+            "`getenv` needs environment variables, which the sandbox denies"
+        );
+        sandbox::set_capabilities(threads.allow(Capability::Env));
+        assert_eq!(outcome(Engine::TreeWalker, input), "null");
+
+        // the function of a task is only there as bytecode
+        let input = "join(spawn(fn() { join(spawn(fn() { 1 })) }))";
+        assert_eq!(
+            outcome(Engine::Vm, input),
+            "can't spawn a function without its source, like one of a compiled file or a task"
+        );
+        sandbox::set_capabilities(before);
+    }
+}
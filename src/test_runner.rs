@@ -0,0 +1,312 @@
+use std::fs;
+use std::io::{self, stdout, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::ast::{Expression, Program, Statement};
+use crate::builtins::{self, Test};
+use crate::cli::TestOptions;
+use crate::color::{Colors, Style};
+use crate::coverage::Coverage;
+use crate::engine::{Engine, Runner};
+use crate::evaluator::{ErrorKind, EvalConfig, EvalError};
+use crate::parser;
+use crate::symbol::Symbol;
+
+// what a file has to end with to be found in a directory
+const TEST_SUFFIX: &str = "_test.mk";
+// what a function has to start with to be a test
+const TEST_PREFIX: &str = "test_";
+// the global a test registered with test() is called through
+const TEST_FUNCTION: &str = "__test";
+
+// `monk test`: runs the tests of every test file under the paths and prints
+// how each went, then a summary. A test is a top-level function named
+// test_..., or one that the top level registers with test("name", fn), called
+// without arguments, and it fails when it raises an error, e.g. through
+// assert. A file without any tests is one test itself.
+pub fn run(options: &TestOptions) -> ExitCode {
+    let colors = match stdout().is_terminal() {
+        true => Colors::new(options.color),
+        false => Colors::off(),
+    };
+    let mut files = Vec::new();
+    for path in &options.paths {
+        if let Err(error) = find(Path::new(path), true, &mut files) {
+            eprintln!("error: can't read {}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let coverage = options.coverage.then(Coverage::new);
+    let start = Instant::now();
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        println!("running {}", file.display());
+        for test in run_file(file, options.engine, coverage.as_ref()) {
+            match &test.error {
+                None => {
+                    passed += 1;
+                    println!("test {} ... ok ({:?})", test.name, test.duration);
+                }
+                Some(error) => {
+                    failed += 1;
+                    let label = colors.paint(Style::Error, "FAILED");
+                    println!("test {} ... {} ({:?})", test.name, label, test.duration);
+                    for line in error.message.lines() {
+                        println!("    {}", line);
+                    }
+                    for frame in &error.trace {
+                        println!("      {}", frame);
+                    }
+                }
+            }
+        }
+    }
+
+    let result = match failed {
+        0 => "ok".to_string(),
+        _ => colors.paint(Style::Error, "FAILED"),
+    };
+    println!(
+        "\ntest result: {}. {} passed; {} failed; finished in {:?}",
+        result,
+        passed,
+        failed,
+        start.elapsed()
+    );
+    if let Some(coverage) = &coverage {
+        println!("\n{}", coverage.summary());
+        if let Some(path) = &options.coverage_output {
+            let report = match path.ends_with(".json") {
+                true => coverage.json(),
+                false => coverage.lcov(),
+            };
+            if let Err(error) = fs::write(path, report) {
+                eprintln!("error: can't write {}: {}", path, error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+// Adds the test files at the path, sorted, searching directories for the
+// files whose names end in _test.mk. A file that's named explicitly is a
+// test file whatever its name.
+fn find(path: &Path, explicit: bool, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        let name = path.file_name().and_then(|name| name.to_str());
+        if explicit || name.is_some_and(|name| name.ends_with(TEST_SUFFIX)) {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        let hidden = entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if !hidden {
+            find(&entry, false, files)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct TestResult {
+    name: String,
+    duration: Duration,
+    error: Option<EvalError>,
+}
+
+// Runs every test of the file, each on a runner of its own that has run the
+// top level of the file first, so tests can't see what the others did. The
+// coverage, if any, counts the statements of the file that every run ran.
+fn run_file(path: &Path, engine: Engine, coverage: Option<&Coverage>) -> Vec<TestResult> {
+    let file = path.display().to_string();
+    let failed = |name: &str, message: String| TestResult {
+        name: name.to_string(),
+        duration: Duration::ZERO,
+        error: Some(EvalError::new(ErrorKind::User, message)),
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => return vec![failed(&file, format!("can't read the file: {}", error))],
+    };
+    let program = match parse(&source) {
+        Ok(program) => program,
+        Err(error) => return vec![failed(&file, error)],
+    };
+
+    let mut config = EvalConfig::default();
+    if let Some(coverage) = coverage {
+        coverage.add_file(&file, &program);
+        config = config.observe(coverage.clone());
+    }
+
+    let names = functions(&program, TEST_PREFIX);
+    let start = Instant::now();
+    let (_, error, registered) = run_top_level(program, engine, &config);
+    if names.is_empty() && registered.is_empty() {
+        return vec![TestResult {
+            name: file,
+            duration: start.elapsed(),
+            error,
+        }];
+    }
+    // the functions first, then the registered tests by their index
+    let tests = names.into_iter().map(|name| (name, None)).chain(
+        registered
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name, Some(i))),
+    );
+    tests
+        .map(|(name, registered)| {
+            let (mut runner, error, registered_tests) =
+                run_top_level(parse(&source).unwrap(), engine, &config);
+            if let Some(error) = error {
+                return TestResult {
+                    name,
+                    duration: Duration::ZERO,
+                    error: Some(error),
+                };
+            }
+            let function = match registered {
+                Some(i) => {
+                    let function = Rc::clone(&registered_tests[i].1);
+                    runner.define(Symbol::intern(TEST_FUNCTION), function);
+                    TEST_FUNCTION
+                }
+                None => &name,
+            };
+            let call = parse(&format!("{}()", function)).unwrap();
+            let start = Instant::now();
+            let error = runner
+                .run_with_config(call, config.clone())
+                .err()
+                .map(|mut error| {
+                    // the call above, which isn't in the file
+                    error.trace.pop();
+                    error
+                });
+            TestResult {
+                name,
+                duration: start.elapsed(),
+                error,
+            }
+        })
+        .collect()
+}
+
+// a runner that has run the top level of the file, with its error if it
+// failed and the tests that it registered
+fn run_top_level(
+    program: Program,
+    engine: Engine,
+    config: &EvalConfig,
+) -> (Runner, Option<EvalError>, Vec<Test>) {
+    let mut runner = Runner::new(engine);
+    let tests = builtins::set_tests(Some(Vec::new()));
+    let error = runner.run_with_config(program, config.clone()).err();
+    let registered = builtins::set_tests(tests).unwrap_or_default();
+    (runner, error, registered)
+}
+
+// the program in the source, or its first syntax error
+pub fn parse(source: &str) -> Result<Program, String> {
+    parser::parse(source).map_err(|errors| errors[0].to_string())
+}
+
+// the top-level functions whose names start with the prefix, in the order
+// they're defined
+pub fn functions(program: &Program, prefix: &str) -> Vec<String> {
+    program
+        .statements
+        .iter()
+        .filter_map(|statement| match statement {
+            Statement::LetStatement {
+                name,
+                value: Expression::FunctionLiteral { .. },
+                ..
+            } if name.starts_with(prefix) => Some(name.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_find_and_run() {
+        let dir = env::temp_dir().join("return_to_monk_test_runner");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        let math = "let double = fn(x) { x * 2 };\n\
+                    let test_double = fn() { assert_eq(double(2), 4) };\n\
+                    let test_wrong = fn() { assert_eq(double(2), 5) };\n\
+                    let helper = fn() { 1 };\n\
+                    test(\"doubles zero\", fn() { assert_eq(double(0), 0) });\n\
+                    test(\"doubles a string\", fn() { double(\"a\") });";
+        fs::write(dir.join("math_test.mk"), math).unwrap();
+        fs::write(dir.join("nested").join("plain_test.mk"), "1 / 0;").unwrap();
+        fs::write(dir.join("nested").join("helper.mk"), "1;").unwrap();
+        fs::write(dir.join(".hidden").join("skipped_test.mk"), "1;").unwrap();
+
+        let mut files = Vec::new();
+        find(&dir, true, &mut files).unwrap();
+        assert_eq!(
+            files,
+            [
+                dir.join("math_test.mk"),
+                dir.join("nested").join("plain_test.mk")
+            ]
+        );
+
+        for engine in Engine::ALL {
+            let results: Vec<(String, Option<String>)> = run_file(&files[0], engine, None)
+                .into_iter()
+                .map(|test| (test.name, test.error.map(|error| error.message)))
+                .collect();
+            assert_eq!(
+                results,
+                [
+                    ("test_double".to_string(), None),
+                    (
+                        "test_wrong".to_string(),
+                        Some(
+                            "assertion failed: 4 != 5\n  expected: 5\n    actual: 4\n            ^"
+                                .to_string()
+                        )
+                    ),
+                    ("doubles zero".to_string(), None),
+                    (
+                        "doubles a string".to_string(),
+                        Some("type mismatch: STRING * INTEGER".to_string())
+                    ),
+                ]
+            );
+
+            let results = run_file(&files[1], engine, None);
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].name, files[1].display().to_string());
+            assert!(results[0].error.is_some());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
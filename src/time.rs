@@ -0,0 +1,160 @@
+use std::fmt::Write;
+use std::rc::Rc;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, Timelike, Utc};
+
+use crate::builtins::{check_arity, wrong_type};
+use crate::evaluator::{integer_object, ErrorKind, EvalError, Object};
+
+// The time_ builtins of the time feature. A time is an integer of the
+// milliseconds since the Unix epoch, so that times can be compared and
+// subtracted like any integer, and is read, written and taken apart in UTC.
+// Formats are chrono's strftime formats, like "%Y-%m-%d %H:%M:%S".
+
+pub fn now(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("time_now", args, 0)?;
+    Ok(integer_object(Utc::now().timestamp_millis()))
+}
+
+// A time without an offset is taken to be in UTC, and a date without a time
+// of day is taken to be at midnight.
+pub fn parse(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("time_parse", args, 2)?;
+    let format = string(&args[0], "time_parse")?;
+    let text = string(&args[1], "time_parse")?;
+    let time = DateTime::parse_from_str(text, format)
+        .map(|time| time.to_utc())
+        .or_else(|_| NaiveDateTime::parse_from_str(text, format).map(|time| time.and_utc()))
+        .or_else(|error| {
+            NaiveDate::parse_from_str(text, format)
+                .map(|date| date.and_time(Default::default()).and_utc())
+                .map_err(|_| error)
+        })
+        .map_err(|error| {
+            EvalError::new(
+                ErrorKind::WrongArguments,
+                format!("can't parse {:?} as {:?}: {}", text, format, error),
+            )
+        })?;
+    Ok(integer_object(time.timestamp_millis()))
+}
+
+pub fn format(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    check_arity("time_format", args, 2)?;
+    let format = string(&args[0], "time_format")?;
+    let time = time(&args[1], "time_format")?;
+    // an invalid format fails to write rather than panicking like to_string
+    let mut formatted = String::new();
+    write!(formatted, "{}", time.format(format)).map_err(|_| {
+        EvalError::new(
+            ErrorKind::WrongArguments,
+            format!("invalid time format: {:?}", format),
+        )
+    })?;
+    Ok(Object::String(formatted).into())
+}
+
+pub fn year(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_year", args, |time| time.year() as i64)
+}
+
+// from 1 for January
+pub fn month(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_month", args, |time| time.month() as i64)
+}
+
+pub fn day(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_day", args, |time| time.day() as i64)
+}
+
+pub fn hour(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_hour", args, |time| time.hour() as i64)
+}
+
+pub fn minute(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_minute", args, |time| time.minute() as i64)
+}
+
+pub fn second(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_second", args, |time| time.second() as i64)
+}
+
+// from 1 for Monday to 7 for Sunday, like ISO 8601
+pub fn weekday(args: &[Rc<Object>]) -> Result<Rc<Object>, EvalError> {
+    component("time_weekday", args, |time| {
+        time.weekday().number_from_monday() as i64
+    })
+}
+
+fn component(
+    name: &str,
+    args: &[Rc<Object>],
+    part: fn(DateTime<Utc>) -> i64,
+) -> Result<Rc<Object>, EvalError> {
+    check_arity(name, args, 1)?;
+    Ok(integer_object(part(time(&args[0], name)?)))
+}
+
+fn string<'a>(arg: &'a Object, name: &str) -> Result<&'a str, EvalError> {
+    match arg {
+        Object::String(value) => Ok(value),
+        arg => Err(wrong_type(name, "STRING", arg)),
+    }
+}
+
+fn time(arg: &Object, name: &str) -> Result<DateTime<Utc>, EvalError> {
+    match arg {
+        Object::Integer(millis) => DateTime::from_timestamp_millis(*millis).ok_or_else(|| {
+            EvalError::new(
+                ErrorKind::WrongArguments,
+                format!("time out of range: {}", millis),
+            )
+        }),
+        arg => Err(wrong_type(name, "INTEGER", arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::assert_outcomes;
+
+    #[test]
+    fn test_time_builtins() {
+        let tests = vec![
+            (r#"time_parse("%Y-%m-%d", "1970-01-02")"#, "86400000"),
+            (
+                r#"time_parse("%Y-%m-%d %H:%M:%S%z", "2024-02-29 13:45:30+0100")"#,
+                "1709210730000",
+            ),
+            (
+                r#"time_format("%Y-%m-%dT%H:%M:%S", time_parse("%d/%m/%Y %H:%M", "29/02/2024 12:45"))"#,
+                "2024-02-29T12:45:00",
+            ),
+            (
+                r#"let t = time_parse("%Y-%m-%d %H:%M:%S", "2024-02-29 12:45:30");
+                   [time_year(t), time_month(t), time_day(t), time_hour(t), time_minute(t),
+                    time_second(t), time_weekday(t)]"#,
+                "[2024, 2, 29, 12, 45, 30, 4]",
+            ),
+            ("time_now() > 1700000000000", "true"),
+            (
+                r#"time_parse("%Y-%m-%d", "yesterday")"#,
+                r#"can't parse "yesterday" as "%Y-%m-%d": input contains invalid characters"#,
+            ),
+            (r#"time_format("%Q", 0)"#, r#"invalid time format: "%Q""#),
+            (
+                r#"time_format("%Y", "now")"#,
+                "argument to `time_format` must be INTEGER, got STRING",
+            ),
+            (
+                "time_year(9223372036854775807)",
+                "time out of range: 9223372036854775807",
+            ),
+            (
+                "time_now(1)",
+                "wrong number of arguments to `time_now`: got=1, want=0",
+            ),
+        ];
+        assert_outcomes(&tests);
+    }
+}
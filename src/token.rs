@@ -1,12 +1,19 @@
 use std::fmt::{Display, Formatter};
 
-#[allow(non_camel_case_types)]
+use crate::symbol::Symbol;
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    ILLEGAL,
+    ILLEGAL(char),
     EOF,
-    IDENT(String),
-    INT(isize),
+    IDENT(Symbol),
+    INT(i64),
+    // an integer literal that doesn't fit in an i64
+    INT_TOO_LARGE(String),
+    STRING(String),
+    // a string literal that runs until the end of the input, as it's written
+    UNTERMINATED_STRING(String),
     // Operators
     ASSIGN,
     PLUS,
@@ -14,70 +21,126 @@ pub enum Token {
     BANG,
     ASTERISK,
     SLASH,
+    PERCENT,
     LT,
     GT,
     // Delimiters
     COMMA,
     SEMICOLON,
+    COLON,
     LPAREN,
     RPAREN,
     LBRACE,
     RBRACE,
+    LBRACKET,
+    RBRACKET,
     EQ,
     NOT_EQ,
     // Keywords
     FUNCTION,
+    MACRO,
     LET,
     IF,
     ELSE,
     TRUE,
     FALSE,
     RETURN,
+    TRY,
+    CATCH,
+    FOR,
+    IN,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // a span covering both self and other, which must come after self
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..*self
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
 }
 
 pub fn lookup_ident(ident: &str) -> Token {
     match ident {
         "fn" => Token::FUNCTION,
+        "macro" => Token::MACRO,
         "let" => Token::LET,
         "if" => Token::IF,
         "else" => Token::ELSE,
         "true" => Token::TRUE,
         "false" => Token::FALSE,
         "return" => Token::RETURN,
-        _ => Token::IDENT(ident.into()),
+        "try" => Token::TRY,
+        "catch" => Token::CATCH,
+        "for" => Token::FOR,
+        "in" => Token::IN,
+        _ => Token::IDENT(Symbol::intern(ident)),
     }
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
-            Token::ILLEGAL => write!(f, "ILLEGAL"),
+            Token::ILLEGAL(c) => write!(f, "ILLEGAL({})", c),
             Token::EOF => write!(f, "EOF"),
             Token::IDENT(s) => write!(f, "IDENT({})", s),
             Token::INT(i) => write!(f, "INT({})", i),
+            Token::INT_TOO_LARGE(s) => write!(f, "INT_TOO_LARGE({})", s),
+            Token::STRING(s) => write!(f, "STRING({})", s),
+            Token::UNTERMINATED_STRING(s) => write!(f, "UNTERMINATED_STRING({})", s),
             Token::ASSIGN => write!(f, "="),
             Token::PLUS => write!(f, "+"),
             Token::MINUS => write!(f, "-"),
             Token::BANG => write!(f, "!"),
             Token::ASTERISK => write!(f, "*"),
             Token::SLASH => write!(f, "/"),
+            Token::PERCENT => write!(f, "%"),
             Token::LT => write!(f, "<"),
             Token::GT => write!(f, ">"),
             Token::COMMA => write!(f, ","),
             Token::SEMICOLON => write!(f, ";"),
+            Token::COLON => write!(f, ":"),
             Token::LPAREN => write!(f, "("),
             Token::RPAREN => write!(f, ")"),
             Token::LBRACE => write!(f, "{{"),
             Token::RBRACE => write!(f, "}}"),
+            Token::LBRACKET => write!(f, "["),
+            Token::RBRACKET => write!(f, "]"),
             Token::EQ => write!(f, "=="),
             Token::NOT_EQ => write!(f, "!="),
             Token::FUNCTION => write!(f, "fn"),
+            Token::MACRO => write!(f, "macro"),
             Token::LET => write!(f, "let"),
             Token::IF => write!(f, "if"),
             Token::ELSE => write!(f, "else"),
             Token::TRUE => write!(f, "true"),
             Token::FALSE => write!(f, "false"),
             Token::RETURN => write!(f, "return"),
+            Token::TRY => write!(f, "try"),
+            Token::CATCH => write!(f, "catch"),
+            Token::FOR => write!(f, "for"),
+            Token::IN => write!(f, "in"),
         }
     }
 }
@@ -0,0 +1,834 @@
+use std::rc::Rc;
+
+use crate::ast::{Infix, Prefix};
+use crate::builtins::{self, NativeFn};
+use crate::code::{read_u16, Opcode};
+use crate::compiler::Bytecode;
+use crate::evaluator::{
+    eval_index_expression, eval_infix_expression, eval_prefix_expression,
+    native_bool_to_boolean_object, null_object, set_limits, Budget, Closure, CompiledFunction,
+    Environment, ErrorKind, EvalConfig, EvalError, Frame, HashKey, HashPair, HashPairs, Object,
+    TraceFrame,
+};
+use crate::gc;
+use crate::iter::{self, Step};
+use crate::symbol::Symbol;
+use crate::token::Span;
+
+// Runs compiled programs on a stack of objects. The operations themselves
+// are the tree-walker's, so both engines give the same results and errors
+// for the same program. Fuel is counted per instruction rather than per
+// statement and expression.
+//
+// Globals are kept from one run to the next, so a REPL can compile and run
+// its inputs one at a time with the same compiler and VM.
+#[derive(Default)]
+pub struct Vm {
+    config: EvalConfig,
+    budget: Budget,
+    constants: Vec<Rc<Object>>,
+    globals: Vec<Option<Rc<Object>>>,
+    global_names: Vec<Symbol>,
+    stack: Vec<Rc<Object>>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    // runs hot functions as native code, for the jit engine
+    #[cfg(feature = "jit")]
+    jit: Option<crate::jit::Jit>,
+}
+
+struct CallFrame {
+    function: Rc<CompiledFunction>,
+    // the closure being run, none at the top level
+    closure: Option<Rc<Object>>,
+    ip: usize,
+    // where the locals start on the stack, right after the callee
+    base: usize,
+    // the call as it shows up in traces, none at the top level
+    call: Option<Frame>,
+    // the memoized function to store the result in, under its arguments
+    remember: Option<(Rc<Object>, Vec<HashKey>)>,
+    // what the builtin that made this call does with its result
+    resume: Option<Resume>,
+}
+
+// a builtin waiting on a call it needed to work out its value
+struct Resume {
+    then: Box<dyn FnOnce(Rc<Object>) -> Step<Rc<Object>>>,
+    builtin: Frame,
+}
+
+impl CallFrame {
+    // the call frames of functions are counted as the environments they'd
+    // be in the tree-walker, and freed again when they return or unwind
+    fn free(&self) {
+        if self.closure.is_some() {
+            gc::free(Environment::size(self.function.num_locals));
+        }
+    }
+
+    fn read_op(&mut self) -> Opcode {
+        let byte = self.function.instructions.0[self.ip];
+        self.ip += 1;
+        Opcode::from_byte(byte).expect("not an opcode")
+    }
+
+    fn read_u16(&mut self) -> usize {
+        let operand = read_u16(&self.function.instructions.0[self.ip..]);
+        self.ip += 2;
+        operand
+    }
+
+    fn read_u8(&mut self) -> usize {
+        let operand = self.function.instructions.0[self.ip] as usize;
+        self.ip += 1;
+        operand
+    }
+}
+
+// a try expression whose body is running
+struct Handler {
+    frame: usize,
+    stack: usize,
+    ip: usize,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::default()
+    }
+
+    // Whether to compile hot functions to native code, which the jit engine
+    // does. Turning it off drops what was compiled.
+    #[cfg(feature = "jit")]
+    pub fn use_jit(&mut self, on: bool) {
+        if !on {
+            self.jit = None;
+        } else if self.jit.is_none() {
+            self.jit = crate::jit::Jit::new();
+        }
+    }
+
+    #[cfg(all(test, feature = "jit"))]
+    pub(crate) fn jit(&self) -> Option<&crate::jit::Jit> {
+        self.jit.as_ref()
+    }
+
+    // runs the program to the end, returning the value of its last
+    // statement like the tree-walker
+    #[cfg(test)]
+    pub fn run(&mut self, bytecode: Bytecode) -> Result<Rc<Object>, EvalError> {
+        self.run_with_config(bytecode, EvalConfig::default())
+    }
+
+    pub fn run_with_config(
+        &mut self,
+        bytecode: Bytecode,
+        config: EvalConfig,
+    ) -> Result<Rc<Object>, EvalError> {
+        self.config = config;
+        self.constants = bytecode.constants;
+        self.global_names = bytecode.globals;
+        if self.globals.len() < self.global_names.len() {
+            self.globals.resize(self.global_names.len(), None);
+        }
+
+        let main = CompiledFunction {
+            instructions: bytecode.instructions,
+            num_locals: bytecode.num_locals,
+            parameters: Vec::new(),
+            name: None,
+            source_map: bytecode.source_map,
+            body: None,
+        };
+        self.stack.clear();
+        self.stack.resize(main.num_locals, null_object());
+        self.handlers.clear();
+        self.frames.clear();
+        self.frames.push(CallFrame {
+            function: main.into(),
+            closure: None,
+            ip: 0,
+            base: 0,
+            call: None,
+            remember: None,
+            resume: None,
+        });
+
+        self.budget = self.config.budget();
+        let outer = set_limits(self.config.limits(&self.budget));
+        let result = self.run_frames();
+        set_limits(outer);
+        // done with the budget, which hands over the metrics of the run
+        self.budget = Budget::default();
+        result
+    }
+
+    fn run_frames(&mut self) -> Result<Rc<Object>, EvalError> {
+        loop {
+            match self.budget.step().and_then(|_| self.execute()) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {}
+                Err(error) => {
+                    let error = self.attach_trace(error, None);
+                    self.unwind(error)?;
+                }
+            }
+        }
+    }
+
+    // sets a global from outside of the programs, in the slot the compiler
+    // gave it
+    pub fn set_global(&mut self, index: usize, name: Symbol, value: Rc<Object>) {
+        if self.globals.len() <= index {
+            self.globals.resize(index + 1, None);
+            self.global_names.resize(index + 1, name.clone());
+        }
+        self.global_names[index] = name;
+        self.globals[index] = Some(value);
+    }
+
+    // unsets every global, keeping the slots the compiler gave them
+    pub fn clear_globals(&mut self) {
+        self.globals.fill(None);
+    }
+
+    // the globals that have been set, in the order they were defined
+    pub fn globals(&self) -> Vec<(Symbol, Rc<Object>)> {
+        self.global_names
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| Some((name.clone(), Rc::clone(value.as_ref()?))))
+            .collect()
+    }
+
+    // executes one instruction, returning the program's value once the top
+    // level returns
+    fn execute(&mut self) -> Result<Option<Rc<Object>>, EvalError> {
+        let frame = self.frames.last_mut().unwrap();
+        let op = frame.read_op();
+        match op {
+            Opcode::Constant => {
+                let index = frame.read_u16();
+                self.stack.push(Rc::clone(&self.constants[index]));
+            }
+            Opcode::Pop => {
+                self.pop();
+            }
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::Equal
+            | Opcode::NotEqual
+            | Opcode::LessThan
+            | Opcode::GreaterThan => {
+                let right = self.pop();
+                let left = self.pop();
+                let operator = match op {
+                    Opcode::Add => Infix::PLUS,
+                    Opcode::Sub => Infix::MINUS,
+                    Opcode::Mul => Infix::ASTERISK,
+                    Opcode::Div => Infix::SLASH,
+                    Opcode::Mod => Infix::PERCENT,
+                    Opcode::Equal => Infix::EQ,
+                    Opcode::NotEqual => Infix::NOT_EQ,
+                    Opcode::LessThan => Infix::LT,
+                    _ => Infix::GT,
+                };
+                let result = eval_infix_expression(&operator, &left, &right)?;
+                self.budget.allocate_object(&result)?;
+                self.stack.push(result);
+            }
+            Opcode::True => self.stack.push(native_bool_to_boolean_object(true)),
+            Opcode::False => self.stack.push(native_bool_to_boolean_object(false)),
+            Opcode::Null => self.stack.push(null_object()),
+            Opcode::Minus => {
+                let right = self.pop();
+                self.stack
+                    .push(eval_prefix_expression(&Prefix::MINUS, right)?);
+            }
+            Opcode::Bang => {
+                let right = self.pop();
+                self.config.check_boolean(&right, "!")?;
+                self.stack
+                    .push(eval_prefix_expression(&Prefix::BANG, right)?);
+            }
+            Opcode::JumpNotTruthy => {
+                let target = frame.read_u16();
+                let condition = self.pop();
+                self.config.check_boolean(&condition, "if")?;
+                if !condition.is_truthy() {
+                    self.frames.last_mut().unwrap().ip = target;
+                }
+            }
+            Opcode::Jump => frame.ip = frame.read_u16(),
+            Opcode::GetGlobal => {
+                let index = frame.read_u16();
+                let value = self.globals[index].clone().ok_or_else(|| {
+                    EvalError::new(
+                        ErrorKind::IdentifierNotFound,
+                        format!("identifier not found: {}", self.global_names[index]),
+                    )
+                })?;
+                self.stack.push(value);
+            }
+            Opcode::SetGlobal => {
+                let index = frame.read_u16();
+                self.globals[index] = self.stack.last().cloned();
+            }
+            Opcode::GetLocal => {
+                let index = frame.base + frame.read_u8();
+                self.stack.push(Rc::clone(&self.stack[index]));
+            }
+            Opcode::SetLocal => {
+                let index = frame.base + frame.read_u8();
+                self.stack[index] = Rc::clone(self.stack.last().unwrap());
+            }
+            Opcode::GetBuiltin => {
+                let builtin = builtins::by_position(frame.read_u8()).expect("not a builtin");
+                self.stack.push(Object::Builtin(builtin).into());
+            }
+            Opcode::GetFree => {
+                let index = frame.read_u8();
+                let value = match frame.closure.as_deref() {
+                    Some(Object::Closure(closure)) => Rc::clone(&closure.free[index]),
+                    _ => unreachable!("free variable outside of a closure"),
+                };
+                self.stack.push(value);
+            }
+            Opcode::CurrentClosure => {
+                let closure = frame.closure.clone().expect("no closure at the top level");
+                self.stack.push(closure);
+            }
+            Opcode::Array => {
+                let len = frame.read_u16();
+                let elements = self.stack.split_off(self.stack.len() - len);
+                let array = Object::Array(elements).into();
+                self.budget.allocate_object(&array)?;
+                self.stack.push(array);
+            }
+            Opcode::Hash => {
+                let len = frame.read_u16();
+                let values = self.stack.split_off(self.stack.len() - 2 * len);
+                let mut pairs = HashPairs::with_capacity(len);
+                for pair in values.chunks_exact(2) {
+                    let (key, value) = (Rc::clone(&pair[0]), Rc::clone(&pair[1]));
+                    pairs.insert(key.hash_key()?, HashPair { key, value });
+                }
+                let hash = Object::Hash(pairs).into();
+                self.budget.allocate_object(&hash)?;
+                self.stack.push(hash);
+            }
+            Opcode::Globals => {
+                let globals = builtins::variables(self.globals());
+                self.budget.allocate_object(&globals)?;
+                self.stack.push(globals);
+            }
+            Opcode::Index => {
+                let index = self.pop();
+                let left = self.pop();
+                self.stack.push(eval_index_expression(&left, &index)?);
+            }
+            Opcode::Call => {
+                let argc = frame.read_u8();
+                self.budget.call(self.frames.len());
+                self.call(argc)?;
+            }
+            Opcode::ReturnValue => {
+                let value = self.pop();
+                return self.return_from_call(value);
+            }
+            Opcode::Return => return self.return_from_call(null_object()),
+            Opcode::Closure => {
+                let index = frame.read_u16();
+                let count = frame.read_u8();
+                let function = match &*self.constants[index] {
+                    Object::CompiledFunction(function) => Rc::clone(function),
+                    obj => unreachable!("not a function: {}", obj),
+                };
+                let free = self.stack.split_off(self.stack.len() - count);
+                self.stack
+                    .push(Object::Closure(Closure { function, free }).into());
+            }
+            Opcode::Try => {
+                let ip = frame.read_u16();
+                self.handlers.push(Handler {
+                    frame: self.frames.len() - 1,
+                    stack: self.stack.len(),
+                    ip,
+                });
+            }
+            Opcode::EndTry => {
+                self.handlers.pop();
+            }
+        }
+        Ok(None)
+    }
+
+    fn pop(&mut self) -> Rc<Object> {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    // the callee and its arguments are on top of the stack
+    fn call(&mut self, argc: usize) -> Result<(), EvalError> {
+        let callee = Rc::clone(&self.stack[self.stack.len() - 1 - argc]);
+        let span = self.call_site();
+        match &*callee {
+            Object::Closure(_) => self.call_closure(callee, argc, span, None),
+            Object::Builtin(_) | Object::Native(_) => {
+                let (name, func): (Symbol, &NativeFn) = match &*callee {
+                    Object::Builtin(builtin) => (Symbol::intern(builtin.name), &builtin.func),
+                    Object::Native(native) => (native.name.clone(), &*native.func),
+                    _ => unreachable!(),
+                };
+                let frame = Frame { name, span };
+                let args = self.stack.split_off(self.stack.len() - argc);
+                self.config
+                    .notify(|observer| observer.on_call(&frame, &args));
+                if let Object::Builtin(builtin) = &*callee {
+                    if let Some(step) = iter::step(builtin.name, &args) {
+                        self.pop();
+                        return self.resolve(step, frame);
+                    }
+                }
+                let result = match func(&args)
+                    .and_then(|result| self.budget.allocate_object(&result).map(|_| result))
+                {
+                    Ok(result) => result,
+                    Err(error) => return Err(self.attach_trace(error, Some(&frame))),
+                };
+                self.pop();
+                self.config
+                    .notify(|observer| observer.on_return(&frame, &result));
+                self.stack.push(result);
+                Ok(())
+            }
+            Object::Memoized(memoized) => {
+                let key = self.stack[self.stack.len() - argc..]
+                    .iter()
+                    .map(|arg| arg.hash_key())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(result) = memoized.lookup(&key) {
+                    self.stack.truncate(self.stack.len() - argc - 1);
+                    self.stack.push(result);
+                    return Ok(());
+                }
+                let function = Rc::clone(memoized.function());
+                let callee_index = self.stack.len() - 1 - argc;
+                self.stack[callee_index] = Rc::clone(&function);
+                self.call_closure(function, argc, span, Some((callee, key)))
+            }
+            _ => Err(EvalError::new(
+                ErrorKind::NotAFunction,
+                format!("not a function: {}", callee),
+            )
+            .with_types(&[callee.type_of()])),
+        }
+    }
+
+    // works out a value that can take calls of functions, making each call
+    // as if the builtin that needs it was the one calling, and pushes it
+    fn resolve(&mut self, mut step: Step<Rc<Object>>, builtin: Frame) -> Result<(), EvalError> {
+        loop {
+            match step {
+                Step::Value(value) => {
+                    self.budget.allocate_object(&value)?;
+                    self.config
+                        .notify(|observer| observer.on_return(&builtin, &value));
+                    self.stack.push(value);
+                    return Ok(());
+                }
+                Step::Call(function, arg, then) => {
+                    let frames = self.frames.len();
+                    self.stack.push(function);
+                    self.stack.push(arg);
+                    self.budget.call(frames);
+                    self.call(1)
+                        .map_err(|error| self.attach_trace(error, Some(&builtin)))?;
+                    if self.frames.len() > frames {
+                        let frame = self.frames.last_mut().unwrap();
+                        frame.resume = Some(Resume { then, builtin });
+                        return Ok(());
+                    }
+                    // builtins, remembered results and native code are done
+                    // right away
+                    step = then(self.pop());
+                }
+            }
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        callee: Rc<Object>,
+        argc: usize,
+        span: Span,
+        remember: Option<(Rc<Object>, Vec<HashKey>)>,
+    ) -> Result<(), EvalError> {
+        let Object::Closure(closure) = &*callee else {
+            return Err(EvalError::new(
+                ErrorKind::NotAFunction,
+                format!("not a function: {}", callee),
+            )
+            .with_types(&[callee.type_of()]));
+        };
+        // the top level isn't a call
+        self.config.check_depth(self.frames.len() - 1)?;
+        self.budget
+            .allocate(Environment::size(closure.function.num_locals))?;
+
+        #[cfg(feature = "jit")]
+        if let (Some(jit), None) = (&mut self.jit, &remember) {
+            let base = self.stack.len() - argc;
+            let value = jit.call(
+                &closure.function,
+                &self.stack[base..],
+                &self.globals,
+                &self.constants,
+                &self.config,
+                &mut self.budget,
+                self.frames.len() + 1,
+            );
+            if let Some(value) = value {
+                gc::free(Environment::size(closure.function.num_locals));
+                self.stack.truncate(base - 1);
+                self.stack.push(value);
+                return Ok(());
+            }
+        }
+
+        let function = Rc::clone(&closure.function);
+        let frame = Frame {
+            name: function
+                .name
+                .clone()
+                .unwrap_or_else(|| Symbol::intern("<anonymous>")),
+            span,
+        };
+        // missing arguments are null and extra ones are dropped
+        let base = self.stack.len() - argc;
+        self.stack
+            .truncate(base + argc.min(function.parameters.len()));
+        self.config
+            .notify(|observer| observer.on_call(&frame, &self.stack[base..]));
+        self.stack.resize(base + function.num_locals, null_object());
+
+        self.frames.push(CallFrame {
+            function,
+            closure: Some(callee),
+            ip: 0,
+            base,
+            call: Some(frame),
+            remember,
+            resume: None,
+        });
+        Ok(())
+    }
+
+    // the span of the call instruction that was just read, from the source
+    // map of the function it's in
+    fn call_site(&self) -> Span {
+        let frame = self.frames.last().unwrap();
+        let offset = frame.ip - Opcode::Call.width();
+        frame.function.source_map.lookup(offset).unwrap_or_default()
+    }
+
+    // pops the current call frame, giving back the value when it's the top
+    // level and pushing it for the caller otherwise
+    fn return_from_call(&mut self, value: Rc<Object>) -> Result<Option<Rc<Object>>, EvalError> {
+        let frame = self.frames.pop().unwrap();
+        if self.frames.is_empty() {
+            return Ok(Some(value));
+        }
+        frame.free();
+        while matches!(self.handlers.last(), Some(handler) if handler.frame >= self.frames.len()) {
+            self.handlers.pop();
+        }
+        self.stack.truncate(frame.base - 1);
+
+        if let Some((memoized, key)) = frame.remember {
+            if let Object::Memoized(memoized) = &*memoized {
+                memoized.remember(key, Rc::clone(&value));
+            }
+        }
+        if let Some(call) = &frame.call {
+            self.config
+                .notify(|observer| observer.on_return(call, &value));
+        }
+        match frame.resume {
+            Some(Resume { then, builtin }) => self.resolve(then(value), builtin)?,
+            None => self.stack.push(value),
+        }
+        Ok(None)
+    }
+
+    // the calls in progress, innermost last, with the builtins that made
+    // calls of their own
+    fn calls(&self) -> Vec<Frame> {
+        self.frames
+            .iter()
+            .flat_map(|frame| {
+                let builtin = frame.resume.as_ref().map(|resume| resume.builtin.clone());
+                builtin.into_iter().chain(frame.call.clone())
+            })
+            .collect()
+    }
+
+    // errors keep the trace of the innermost call they were raised in, which
+    // can be a builtin that has no call frame, and the span of the
+    // instruction that failed
+    fn attach_trace(&self, mut error: EvalError, builtin: Option<&Frame>) -> EvalError {
+        if error.trace.is_empty() {
+            let calls = self.calls().into_iter().chain(builtin.cloned());
+            error.trace = calls.rev().map(|frame| TraceFrame::from(&frame)).collect();
+        }
+        if error.span.is_none() {
+            error.span = self.frames.last().and_then(|frame| {
+                let offset = frame.ip.checked_sub(1)?;
+                frame.function.source_map.lookup(offset)
+            });
+        }
+        error
+    }
+
+    // unwinds to the innermost try expression and jumps to its handler with
+    // the error, or gives the error back if nothing can catch it
+    fn unwind(&mut self, error: EvalError) -> Result<(), EvalError> {
+        if error.kind.is_catchable() {
+            if let Some(handler) = self.handlers.pop() {
+                self.frames
+                    .drain(handler.frame + 1..)
+                    .for_each(|frame| frame.free());
+                self.stack.truncate(handler.stack);
+                let calls = self.calls();
+                self.config
+                    .notify(|observer| observer.on_error(&error, &calls));
+
+                self.stack.push(Object::Error(error).into());
+                self.frames.last_mut().unwrap().ip = handler.ip;
+                return Ok(());
+            }
+        }
+
+        // the instruction of the top level that was running, just before
+        // where its frame would go on from
+        let location = self.frames.first().and_then(|frame| {
+            let offset = frame.ip.checked_sub(1)?;
+            frame.function.source_map.lookup(offset)
+        });
+        self.frames.drain(..).for_each(|frame| frame.free());
+        self.config
+            .notify(|observer| observer.on_error(&error, &[]));
+        Err(EvalError {
+            location: location.or(error.location),
+            ..error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::evaluator::{eval, Environment};
+    use crate::parser;
+    use crate::profiler::Profiler;
+    use std::cell::RefCell;
+
+    fn test_run_with_config(input: &str, config: EvalConfig) -> Result<Rc<Object>, EvalError> {
+        let program = parser::parse(input).unwrap();
+        // every call is kept, so that it shows up in traces
+        let mut compiler = Compiler::new().inline_functions(false);
+        let bytecode = compiler.compile(&program).unwrap();
+        // what the compiler makes passes the checks of a compiled file
+        let decoded = crate::serialize::decode(&crate::serialize::encode(&bytecode));
+        assert_eq!(decoded.as_ref(), Ok(&bytecode), "{}", input);
+        Vm::new().run_with_config(bytecode, config)
+    }
+
+    fn test_run(input: &str) -> Result<Rc<Object>, EvalError> {
+        test_run_with_config(input, EvalConfig::default())
+    }
+
+    fn test_eval(input: &str) -> Result<Rc<Object>, EvalError> {
+        let program = parser::parse(input).unwrap();
+        eval(program, &Rc::new(RefCell::new(Environment::new())))
+    }
+
+    fn display(result: Result<Rc<Object>, EvalError>) -> String {
+        match result {
+            Ok(obj) => obj.to_string(),
+            Err(error) => format!("error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_matches_tree_walker() {
+        let tests = vec![
+            "1 + 2 * 3 - -4 / 2 % 3",
+            "(5 + 10 * 2 + 15 / 3) * 2 + -10",
+            "1 < 2 == true; !(1 > 2) != false",
+            r#""a" < "b"; "Hello" + " " + "World!""#,
+            "if (1 > 2) { 10 }",
+            "if (1) { 10 } else { 20 }",
+            "if (false) { 10 } else { let x = 20; }",
+            "let a = 5; let b = a * 2; let a = b + a; a",
+            "return 1; 2",
+            "let f = fn(x) { if (x > 1) { return x; } 0 }; [f(5), f(0)]",
+            "let f = fn(a, b) { a }; [f(1), f(1, 2, 3)]",
+            r#"[1, "two", [3]][2]; {"a": 1, true: 2, 3: [4]}"#,
+            r#"let h = {"a": 1}; [h["a"], h["b"], [1, 2][5], [1][-1]]"#,
+            r#"len("four"); first([1, 2]); rest([1, 2, 3]); push([1], 2)"#,
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            "let f = fn() { g() }; let g = fn() { 5 }; f()",
+            "let newAdder = fn(a) { fn(b) { a + b } }; let addTwo = newAdder(2); addTwo(3)",
+            "let adder = fn(a, b) { fn(c) { fn(d) { a + b + c + d } } }; adder(1, 2)(3)(4)",
+            "let f = fn() { let n = 10; let inner = fn(k) { if (k == 0) { n } else { inner(k - 1) } }; inner(3) }; f()",
+            "let countdown = fn(x) { if (x == 0) { 0 } else { countdown(x - 1) } }; let wrapper = fn() { countdown(1) }; wrapper()",
+            "let fib = memoize(fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }); fib(60)",
+            r#"try { 1 / 0 } catch (e) { [kind(e), message(e)] }"#,
+            r#"let f = fn() { error("boom") }; try { f(); 1 } catch (e) { message(e) }"#,
+            r#"let e = 1; let r = try { error("x") } catch (e) { let y = e; message(y) }; [e, r]"#,
+            r#"let f = fn(x) { try { x / 0 } catch (e) { return -1; }; 2 }; f(1)"#,
+            "try { 1 } catch (e) { 2 }",
+            r#"[[1, 2] == [1, 2], {"a": [1]} == {"a": [1]}, {1: 2} != {1: 3}]"#,
+            "let f = fn(x) { x }; [f == f, [f] == [f], fn() { 1 } == fn() { 1 }, len == len]",
+            "let f = fn(a) { fn() { a } }; [f(1) == f(1), memoize(f) == memoize(f)]",
+            "foobar",
+            "5 + true",
+            "-true",
+            r#"{"a": 1}[fn(x) { x }]"#,
+            "1(2)",
+            "len(1)",
+        ];
+
+        for input in tests {
+            assert_eq!(
+                display(test_run(input)),
+                display(test_eval(input)),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_closures() {
+        let tests = vec![
+            ("let newClosure = fn(a) { fn() { a; }; }; let closure = newClosure(99); closure();", "99"),
+            (
+                "let newAdderOuter = fn(a, b) { let c = a + b; fn(d) { let e = d + c; fn(f) { e + f; }; }; };
+                let newAdderInner = newAdderOuter(1, 2);
+                let adder = newAdderInner(3);
+                adder(8);",
+                "14",
+            ),
+            (
+                "let a = 1; let newAdderOuter = fn(b) { fn(c) { fn(d) { a + b + c + d }; }; };
+                newAdderOuter(2)(3)(8);",
+                "14",
+            ),
+            (
+                "let newClosure = fn(a, b) { let one = fn() { a; }; let two = fn() { b; }; fn() { one() + two(); }; };
+                newClosure(9, 90)();",
+                "99",
+            ),
+            // a local function calling itself, also from a closure nested in it
+            (
+                "let wrapper = fn() { let countDown = fn(x) { if (x == 0) { return 0; } else { countDown(x - 1); } }; countDown(1); }; wrapper();",
+                "0",
+            ),
+            (
+                "let wrapper = fn() { let f = fn(x) { let g = fn() { f(x - 1) }; if (x == 0) { 7 } else { g() } }; f(3) }; wrapper();",
+                "7",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(test_run(input).unwrap().to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_globals_persist_between_runs() {
+        let mut compiler = Compiler::new();
+        let mut vm = Vm::new();
+        let tests = vec![
+            ("let x = 5;", "5"),
+            ("let add = fn(a) { a + x };", "fn(a) {...}"),
+            ("add(x)", "10"),
+            ("let x = 1; add(x)", "2"),
+        ];
+        for (input, expected) in tests {
+            let program = parser::parse(input).unwrap();
+            let bytecode = compiler.compile(&program).unwrap();
+            assert_eq!(vm.run(bytecode).unwrap().to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let tests = vec![
+            (
+                EvalConfig::default().max_depth(20),
+                "let f = fn(n) { f(n + 1) }; f(0)",
+                "maximum recursion depth exceeded: 20",
+            ),
+            (
+                EvalConfig::default().fuel(100),
+                "let f = fn(n) { f(n + 1) }; try { f(0) } catch (e) { 0 }",
+                "fuel exhausted",
+            ),
+            (
+                EvalConfig::default().strict_booleans(true),
+                "if (1) { 2 }",
+                "if expects a BOOLEAN, got INTEGER",
+            ),
+            (
+                EvalConfig::default().strict_booleans(true),
+                "!0",
+                "! expects a BOOLEAN, got INTEGER",
+            ),
+        ];
+        for (config, input, expected) in tests {
+            let error = test_run_with_config(input, config).unwrap_err();
+            assert_eq!(error.to_string(), expected, "{}", input);
+        }
+
+        let profiler = Profiler::default();
+        let config = EvalConfig::default().observe(profiler.clone());
+        let input = "let f = fn(n) { if (n == 0) { len([]) } else { f(n - 1) } }; f(3)";
+        test_run_with_config(input, config).unwrap();
+        assert_eq!(profiler.stats("f").unwrap().calls, 4);
+        assert_eq!(profiler.stats("len").unwrap().calls, 1);
+    }
+
+    #[test]
+    fn test_error_trace() {
+        let input = r#"let inner = fn() { error("boom") };
+let outer = fn() { inner() };
+outer()"#;
+        let error = test_run(input).unwrap_err();
+        let names: Vec<&str> = error.trace.iter().map(|frame| &*frame.name).collect();
+        assert_eq!(names, vec!["error", "inner", "outer"]);
+        // the calls are found in the source maps, even after optimizing
+        let lines: Vec<usize> = error.trace.iter().map(|frame| frame.span.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+        assert_eq!(error.trace, test_eval(input).unwrap_err().trace);
+
+        let input = "let f = fn(x) {
+  if (true) { x + 1 } else { 0 };
+  [1, 2][0] + len(x)
+};
+let a = 1;
+f(a)";
+        let error = test_run(input).unwrap_err();
+        assert_eq!(error.trace, test_eval(input).unwrap_err().trace);
+        assert_eq!(error.trace[0].to_string(), "at len (line 3, column 15)");
+
+        let error = test_run("let f = fn() { f() }; f()").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::RecursionLimit);
+    }
+}
@@ -0,0 +1,696 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::ast::*;
+use crate::builtins;
+use crate::symbol::Symbol;
+use crate::token::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    UnusedBinding,
+    UnreachableCode,
+    ConstantCondition,
+    Shadowing,
+}
+
+impl WarningKind {
+    pub const ALL: [WarningKind; 4] = [
+        WarningKind::UnusedBinding,
+        WarningKind::UnreachableCode,
+        WarningKind::ConstantCondition,
+        WarningKind::Shadowing,
+    ];
+
+    // the name of its lint rule, e.g. for `monk lint --deny unused`
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::UnusedBinding => "unused",
+            WarningKind::UnreachableCode => "unreachable",
+            WarningKind::ConstantCondition => "constant-condition",
+            WarningKind::Shadowing => "shadowing",
+        }
+    }
+
+    // shadowing is usually on purpose, so it's only reported when asked for
+    pub fn default_severity(self) -> Severity {
+        match self {
+            WarningKind::Shadowing => Severity::Allow,
+            _ => Severity::Warn,
+        }
+    }
+}
+
+// What becomes of a warning: nothing, a warning, or an error that fails
+// `monk lint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Severity, String> {
+        match name {
+            "allow" => Ok(Severity::Allow),
+            "warn" => Ok(Severity::Warn),
+            "deny" => Ok(Severity::Deny),
+            _ => Err(format!(
+                "unknown severity: {} (expected allow, warn or deny)",
+                name
+            )),
+        }
+    }
+}
+
+// Reports everything that's reported by default, including top-level
+// bindings that are never read.
+pub fn check(program: &Program) -> Vec<Warning> {
+    by_default(lint(program))
+}
+
+// Used by the REPL, where a top-level binding is usually read by a later input.
+pub fn check_incremental(program: &Program) -> Vec<Warning> {
+    by_default(run(program, rules(false)))
+}
+
+// Every warning of every kind, for `monk lint` to pick from.
+pub fn lint(program: &Program) -> Vec<Warning> {
+    run(program, rules(true))
+}
+
+fn by_default(warnings: Vec<Warning>) -> Vec<Warning> {
+    warnings
+        .into_iter()
+        .filter(|warning| warning.kind.default_severity() != Severity::Allow)
+        .collect()
+}
+
+// A check of the lint pass. The pass walks the program once, resolving the
+// names in it, and calls the hooks of every rule on the way, which report
+// what they find to the warnings. A hook does nothing unless the rule has it.
+pub trait LintRule {
+    // the kind of the warnings it reports
+    fn kind(&self) -> WarningKind;
+
+    // the statements of a block or program, before they're walked
+    fn visit_statements(&mut self, _statements: &[Statement], _warnings: &mut Vec<Warning>) {}
+
+    // a let, after its value is walked and before its name is declared
+    fn visit_let(
+        &mut self,
+        _name: &Symbol,
+        _span: Span,
+        _scopes: &Scopes,
+        _warnings: &mut Vec<Warning>,
+    ) {
+    }
+
+    // an expression, before the ones in it are walked
+    fn visit_expression(&mut self, _expression: &Expression, _warnings: &mut Vec<Warning>) {}
+
+    // the bindings of a scope that's ending, which is still the innermost
+    fn leave_scope(
+        &mut self,
+        _bindings: &[Binding],
+        _scopes: &Scopes,
+        _warnings: &mut Vec<Warning>,
+    ) {
+    }
+}
+
+// The rules of the lint pass, one of each kind. Top-level bindings that are
+// never read are reported unless it's for the REPL.
+pub fn rules(report_top_level: bool) -> Vec<Box<dyn LintRule>> {
+    vec![
+        Box::new(UnusedBindings { report_top_level }),
+        Box::new(UnreachableCode),
+        Box::new(ConstantConditions),
+        Box::new(Shadowing),
+    ]
+}
+
+// the warnings of the rules, in the order of where they are in the program;
+// the rules report them as they find them, which for unused bindings is once
+// their scope is done
+pub fn run(program: &Program, rules: Vec<Box<dyn LintRule>>) -> Vec<Warning> {
+    let mut checker = Checker {
+        scopes: Scopes(Vec::new()),
+        rules,
+        warnings: Vec::new(),
+    };
+    checker.check_scope(&[], &program.statements);
+    checker.warnings.sort_by_key(|warning| warning.span.start);
+    checker.warnings
+}
+
+pub struct Binding {
+    pub name: Symbol,
+    pub span: Span,
+    pub used: bool,
+}
+
+#[derive(Default)]
+struct Scope<'a> {
+    bindings: Vec<Binding>,
+    lookup: HashMap<Symbol, usize>,
+    // function bodies are checked once their defining scope is complete, so
+    // that they can refer to bindings declared after them (e.g. recursion)
+    deferred: Vec<(&'a [Symbol], &'a [Statement])>,
+}
+
+// the scopes the walk is in, innermost last
+pub struct Scopes<'a>(Vec<Scope<'a>>);
+
+impl Scopes<'_> {
+    pub fn is_top_level(&self) -> bool {
+        self.0.len() == 1
+    }
+
+    // whether the name is bound in a scope around the innermost one
+    pub fn is_bound_outside(&self, name: &Symbol) -> bool {
+        let (_, outer) = self.0.split_last().unwrap();
+        outer.iter().any(|scope| scope.lookup.contains_key(name))
+    }
+}
+
+struct Checker<'a> {
+    scopes: Scopes<'a>,
+    rules: Vec<Box<dyn LintRule>>,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Checker<'a> {
+    fn check_scope(&mut self, parameters: &'a [Symbol], statements: &'a [Statement]) {
+        self.scopes.0.push(Scope::default());
+        for parameter in parameters {
+            self.declare(parameter, Span::default());
+            self.resolve(parameter);
+        }
+
+        self.walk_statements(statements);
+
+        while let Some((parameters, body)) = self.current_scope().deferred.pop() {
+            self.check_scope(parameters, body);
+        }
+
+        let bindings = &self.scopes.0.last().unwrap().bindings;
+        for rule in &mut self.rules {
+            rule.leave_scope(bindings, &self.scopes, &mut self.warnings);
+        }
+        self.scopes.0.pop();
+    }
+
+    fn current_scope(&mut self) -> &mut Scope<'a> {
+        self.scopes.0.last_mut().unwrap()
+    }
+
+    fn declare(&mut self, name: &Symbol, span: Span) {
+        let scope = self.current_scope();
+        scope.bindings.push(Binding {
+            name: name.clone(),
+            span,
+            used: false,
+        });
+        let index = scope.bindings.len() - 1;
+        scope.lookup.insert(name.clone(), index);
+    }
+
+    fn resolve(&mut self, name: &Symbol) {
+        for scope in self.scopes.0.iter_mut().rev() {
+            if let Some(index) = scope.lookup.get(name) {
+                scope.bindings[*index].used = true;
+                return;
+            }
+        }
+        // locals() and globals() read every variable they can see
+        let scopes = match &**name {
+            builtins::LOCALS => &mut self.scopes.0[1..],
+            builtins::GLOBALS => &mut self.scopes.0[..1],
+            _ => return,
+        };
+        for binding in scopes.iter_mut().flat_map(|scope| &mut scope.bindings) {
+            binding.used = true;
+        }
+    }
+
+    fn walk_statements(&mut self, statements: &'a [Statement]) {
+        for rule in &mut self.rules {
+            rule.visit_statements(statements, &mut self.warnings);
+        }
+        for statement in statements {
+            self.walk_statement(statement);
+        }
+    }
+
+    fn walk_statement(&mut self, statement: &'a Statement) {
+        match statement {
+            Statement::LetStatement {
+                name, value, span, ..
+            } => {
+                self.walk_expression(value);
+                for rule in &mut self.rules {
+                    rule.visit_let(name, *span, &self.scopes, &mut self.warnings);
+                }
+                self.declare(name, *span);
+            }
+            Statement::ReturnStatement(value, _) => self.walk_expression(value),
+            Statement::BlockStatement(statements, _) => self.walk_statements(statements),
+            Statement::ExpressionStatement(expression, _) => self.walk_expression(expression),
+        }
+    }
+
+    fn walk_expression(&mut self, expression: &'a Expression) {
+        for rule in &mut self.rules {
+            rule.visit_expression(expression, &mut self.warnings);
+        }
+        match expression {
+            Expression::Identifier(name, _) => self.resolve(name),
+            Expression::IntegerLiteral(_)
+            | Expression::BooleanLiteral(_)
+            | Expression::StringLiteral(_) => {}
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.walk_expression(condition);
+                self.walk_statement(consequence);
+                if let Some(alternative) = alternative {
+                    self.walk_statement(alternative);
+                }
+            }
+            Expression::FunctionLiteral {
+                parameters, body, ..
+            }
+            | Expression::MacroLiteral {
+                parameters, body, ..
+            } => {
+                let body = match body.as_ref() {
+                    Statement::BlockStatement(statements, _) => statements.as_slice(),
+                    statement => std::slice::from_ref(statement),
+                };
+                self.current_scope().deferred.push((parameters, body));
+            }
+            Expression::Try {
+                body,
+                name,
+                handler,
+                ..
+            } => {
+                self.walk_statement(body);
+                let handler = match handler.as_ref() {
+                    Statement::BlockStatement(statements, _) => statements.as_slice(),
+                    statement => std::slice::from_ref(statement),
+                };
+                self.check_scope(std::slice::from_ref(name), handler);
+            }
+            Expression::For {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.walk_expression(iterable);
+                let body = match body.as_ref() {
+                    Statement::BlockStatement(statements, _) => statements.as_slice(),
+                    statement => std::slice::from_ref(statement),
+                };
+                self.check_scope(std::slice::from_ref(name), body);
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                self.walk_expression(function);
+                for argument in arguments {
+                    self.walk_expression(argument);
+                }
+            }
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.walk_expression(element);
+                }
+            }
+            Expression::HashLiteral(pairs, _) => {
+                for (key, value) in pairs {
+                    self.walk_expression(key);
+                    self.walk_expression(value);
+                }
+            }
+            Expression::Index { left, index, .. } => {
+                self.walk_expression(left);
+                self.walk_expression(index);
+            }
+            Expression::Prefix(_, right) => self.walk_expression(right),
+            Expression::Infix(_, left, right) => {
+                self.walk_expression(left);
+                self.walk_expression(right);
+            }
+        }
+    }
+}
+
+struct UnusedBindings {
+    report_top_level: bool,
+}
+
+impl LintRule for UnusedBindings {
+    fn kind(&self) -> WarningKind {
+        WarningKind::UnusedBinding
+    }
+
+    fn leave_scope(&mut self, bindings: &[Binding], scopes: &Scopes, warnings: &mut Vec<Warning>) {
+        if scopes.is_top_level() && !self.report_top_level {
+            return;
+        }
+        for binding in bindings {
+            if !binding.used && !binding.name.starts_with('_') {
+                warnings.push(Warning {
+                    kind: self.kind(),
+                    message: format!("unused variable: {}", binding.name),
+                    span: binding.span,
+                });
+            }
+        }
+    }
+}
+
+struct UnreachableCode;
+
+impl LintRule for UnreachableCode {
+    fn kind(&self) -> WarningKind {
+        WarningKind::UnreachableCode
+    }
+
+    // only the first statement after a return is reported
+    fn visit_statements(&mut self, statements: &[Statement], warnings: &mut Vec<Warning>) {
+        for pair in statements.windows(2) {
+            if let [Statement::ReturnStatement(..), statement] = pair {
+                warnings.push(Warning {
+                    kind: self.kind(),
+                    message: format!("unreachable code after return: {}", statement),
+                    span: statement.span(),
+                });
+            }
+        }
+    }
+}
+
+struct ConstantConditions;
+
+impl LintRule for ConstantConditions {
+    fn kind(&self) -> WarningKind {
+        WarningKind::ConstantCondition
+    }
+
+    fn visit_expression(&mut self, expression: &Expression, warnings: &mut Vec<Warning>) {
+        let Expression::If {
+            condition, span, ..
+        } = expression
+        else {
+            return;
+        };
+        if let Some(value) = constant_value(condition) {
+            warnings.push(Warning {
+                kind: self.kind(),
+                message: format!(
+                    "constant condition: {} is always {}",
+                    condition,
+                    value.is_truthy()
+                ),
+                span: *span,
+            });
+        }
+    }
+}
+
+struct Shadowing;
+
+impl LintRule for Shadowing {
+    fn kind(&self) -> WarningKind {
+        WarningKind::Shadowing
+    }
+
+    fn visit_let(
+        &mut self,
+        name: &Symbol,
+        span: Span,
+        scopes: &Scopes,
+        warnings: &mut Vec<Warning>,
+    ) {
+        if !name.starts_with('_') && scopes.is_bound_outside(name) {
+            warnings.push(Warning {
+                kind: self.kind(),
+                message: format!("{} shadows a binding of an outer scope", name),
+                span,
+            });
+        }
+    }
+}
+
+enum Constant {
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Constant {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Constant::Integer(_) => true,
+            Constant::Boolean(value) => *value,
+        }
+    }
+}
+
+fn constant_value(expression: &Expression) -> Option<Constant> {
+    match expression {
+        Expression::IntegerLiteral(value) => Some(Constant::Integer(*value)),
+        Expression::BooleanLiteral(value) => Some(Constant::Boolean(*value)),
+        Expression::Prefix(operator, right) => match (operator, constant_value(right)?) {
+            (Prefix::BANG, value) => Some(Constant::Boolean(!value.is_truthy())),
+            (Prefix::MINUS, Constant::Integer(value)) => value.checked_neg().map(Constant::Integer),
+            _ => None,
+        },
+        Expression::Infix(operator, left, right) => {
+            match (operator, constant_value(left)?, constant_value(right)?) {
+                (Infix::PLUS, Constant::Integer(l), Constant::Integer(r)) => {
+                    l.checked_add(r).map(Constant::Integer)
+                }
+                (Infix::MINUS, Constant::Integer(l), Constant::Integer(r)) => {
+                    l.checked_sub(r).map(Constant::Integer)
+                }
+                (Infix::ASTERISK, Constant::Integer(l), Constant::Integer(r)) => {
+                    l.checked_mul(r).map(Constant::Integer)
+                }
+                (Infix::SLASH, Constant::Integer(l), Constant::Integer(r)) => {
+                    l.checked_div(r).map(Constant::Integer)
+                }
+                (Infix::PERCENT, Constant::Integer(l), Constant::Integer(r)) => {
+                    l.checked_rem(r).map(Constant::Integer)
+                }
+                (Infix::LT, Constant::Integer(l), Constant::Integer(r)) => {
+                    Some(Constant::Boolean(l < r))
+                }
+                (Infix::GT, Constant::Integer(l), Constant::Integer(r)) => {
+                    Some(Constant::Boolean(l > r))
+                }
+                (Infix::EQ, Constant::Integer(l), Constant::Integer(r)) => {
+                    Some(Constant::Boolean(l == r))
+                }
+                (Infix::NOT_EQ, Constant::Integer(l), Constant::Integer(r)) => {
+                    Some(Constant::Boolean(l != r))
+                }
+                (Infix::EQ, Constant::Boolean(l), Constant::Boolean(r)) => {
+                    Some(Constant::Boolean(l == r))
+                }
+                (Infix::NOT_EQ, Constant::Boolean(l), Constant::Boolean(r)) => {
+                    Some(Constant::Boolean(l != r))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_unused_bindings() {
+        let tests = vec![
+            ("let x = 5;", vec!["unused variable: x"]),
+            ("let x = 5; x;", vec![]),
+            ("let _x = 5;", vec![]),
+            ("let x = 5; let y = x;", vec!["unused variable: y"]),
+            (
+                "let f = fn(a) { let b = 1; a }; f(1);",
+                vec!["unused variable: b"],
+            ),
+            ("let f = fn() { g() }; let g = fn() { 1 }; f();", vec![]),
+            ("let x = 1; let x = 2; x;", vec!["unused variable: x"]),
+            ("let f = fn(a) { let b = 1; locals() }; f(1);", vec![]),
+            ("let x = 5; globals();", vec![]),
+            (
+                "let x = 5; let f = fn() { let y = 1; globals() }; f();",
+                vec!["unused variable: y"],
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let warnings = test_check(input);
+            let messages: Vec<&str> = warnings.iter().map(|w| w.message.as_str()).collect();
+            assert_eq!(messages, expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_incremental_ignores_top_level_bindings() {
+        let program = parser::parse("let x = 5; let f = fn() { let y = 1; 2 };").unwrap();
+        let warnings = check_incremental(&program);
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "unused variable: y");
+    }
+
+    #[test]
+    fn test_warning_spans() {
+        let warnings = test_check("let x = 1;\nlet f = fn() {\n  return 1;\n  x;\n};\nf();");
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::UnreachableCode);
+        assert_eq!(warnings[0].span.line, 4);
+        assert_eq!(warnings[0].span.column, 3);
+        assert_eq!(
+            warnings[0].to_string(),
+            "unreachable code after return: x at line 4, column 3"
+        );
+    }
+
+    #[test]
+    fn test_unreachable_code() {
+        let tests = vec![
+            ("fn() { return 1; 2; }", 1),
+            ("fn() { return 1; 2; 3; }", 1),
+            ("fn() { if (x) { return 1; } 2; }", 0),
+            ("return 1; 2;", 1),
+        ];
+
+        for (input, expected) in tests {
+            let warnings = test_check(input);
+            let count = warnings
+                .iter()
+                .filter(|w| w.kind == WarningKind::UnreachableCode)
+                .count();
+            assert_eq!(count, expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let tests = vec![
+            (
+                "if (true) { 1 }",
+                Some("constant condition: true is always true"),
+            ),
+            (
+                "if (1 > 2) { 1 }",
+                Some("constant condition: (1 > 2) is always false"),
+            ),
+            (
+                "if (!0) { 1 }",
+                Some("constant condition: (!0) is always false"),
+            ),
+            ("if (1 / 0) { 1 }", None),
+            ("let x = 1; if (x > 2) { 1 }", None),
+        ];
+
+        for (input, expected) in tests {
+            let warnings = test_check(input);
+            let message = warnings
+                .iter()
+                .find(|w| w.kind == WarningKind::ConstantCondition)
+                .map(|w| w.message.as_str());
+            assert_eq!(message, expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let tests = vec![
+            ("let x = 1; let f = fn() { let x = 2; x }; f() + x;", 1),
+            ("let f = fn(x) { fn() { let x = 2; x }() + x }; f(1);", 1),
+            // a let in the same scope replaces the binding instead
+            ("let f = fn(x) { let x = x + 1; x }; f(1);", 0),
+            ("let x = 1; let x = x + 1; x;", 0),
+            ("let _x = 1; let f = fn() { let _x = 2; _x }; f();", 0),
+            ("let f = fn() { let y = 1; y }; let y = 2; f() + y;", 1),
+        ];
+
+        for (input, expected) in tests {
+            let warnings = lint(&parser::parse(input).unwrap());
+            let count = warnings
+                .iter()
+                .filter(|w| w.kind == WarningKind::Shadowing)
+                .count();
+            assert_eq!(count, expected, "input: {}", input);
+            // off unless it's asked for
+            assert!(test_check(input)
+                .iter()
+                .all(|w| w.kind != WarningKind::Shadowing));
+        }
+    }
+
+    #[test]
+    fn test_run_rules() {
+        let program =
+            parser::parse("let x = 1; let f = fn() { let x = 2; if (true) { x } }; f();").unwrap();
+        let kinds = |rules| -> Vec<WarningKind> {
+            run(&program, rules)
+                .iter()
+                .map(|warning| warning.kind)
+                .collect()
+        };
+        // in the order of where they are, though the unused x is only found
+        // once the program is walked
+        assert_eq!(
+            kinds(rules(true)),
+            [
+                WarningKind::UnusedBinding,
+                WarningKind::Shadowing,
+                WarningKind::ConstantCondition
+            ]
+        );
+        assert_eq!(kinds(vec![Box::new(Shadowing)]), [WarningKind::Shadowing]);
+        assert!(kinds(Vec::new()).is_empty());
+
+        assert_eq!("deny".parse(), Ok(Severity::Deny));
+        assert!("error".parse::<Severity>().is_err());
+    }
+
+    fn test_check(input: &str) -> Vec<Warning> {
+        check(&parser::parse(input).unwrap())
+    }
+}
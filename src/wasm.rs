@@ -0,0 +1,987 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::compiler::CompileError;
+use crate::symbol::Symbol;
+
+// Compiles a program to a standalone WebAssembly module. Only part of the
+// language can be compiled: integers, booleans, if expressions, functions
+// defined by let statements at the top level, and puts. Everything else is
+// rejected with an error rather than compiled into something that behaves
+// differently.
+//
+// Values are i64s, booleans being 0 or 1. There is no room to tag them with
+// their type, so types are worked out when compiling instead: the
+// parameters of a function get the types of the arguments it's called with,
+// and a function returns the type of its body. Mixing types where the
+// tree-walker would raise a type mismatch is a compile error, and so are if
+// expressions whose branches have different types.
+//
+// Errors the engines raise at runtime, like dividing by zero or
+// integer overflow, trap. Top-level lets are globals, which read as 0 before
+// their let statement has run.
+//
+// The module imports `monk.puts(value: i64, type: i32)` to print values,
+// where type is 0 for integers, 1 for booleans and 2 for null, and exports
+// `main`, which runs the program and returns the value of its last statement.
+// RUNTIME is a shim that provides puts and runs a module under node.
+pub fn compile(program: &Program) -> Result<Vec<u8>, CompileError> {
+    let mut module = Module::new(program)?;
+    // the types of functions, parameters and globals that are used before
+    // they're known start out as anything and are filled in by compiling
+    // everything again until nothing changes
+    loop {
+        let types = module.types.clone();
+        let code = module.compile_functions(program)?;
+        if module.types == types {
+            return Ok(module.encode(code));
+        }
+    }
+}
+
+pub const RUNTIME: &str = r#"// node monk.js program.wasm
+const fs = require("fs");
+
+const puts = (value, type) => {
+  if (type === 0) console.log(value.toString());
+  else if (type === 1) console.log(value !== 0n ? "true" : "false");
+  else console.log("null");
+};
+
+const bytes = fs.readFileSync(process.argv[2]);
+WebAssembly.instantiate(bytes, { monk: { puts } }).then(({ instance }) => {
+  instance.exports.main();
+});
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Integer,
+    Boolean,
+    Null,
+    // not known yet, or an expression that never produces a value like a
+    // return statement; fits wherever a value is expected
+    Any,
+}
+
+impl Type {
+    fn unify(self, other: Type) -> Option<Type> {
+        match (self, other) {
+            (Type::Any, other) | (other, Type::Any) => Some(other),
+            _ if self == other => Some(self),
+            _ => None,
+        }
+    }
+
+    fn is(self, expected: Type) -> bool {
+        self == expected || self == Type::Any
+    }
+
+    // the type argument of puts
+    fn tag(self) -> i64 {
+        match self {
+            Type::Integer => 0,
+            Type::Boolean => 1,
+            Type::Null | Type::Any => 2,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Integer => "INTEGER",
+            Type::Boolean => "BOOLEAN",
+            Type::Null => "NULL",
+            Type::Any => "ANY",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn error<T>(message: impl Display) -> Result<T, CompileError> {
+    Err(CompileError {
+        message: format!("can't compile to wasm: {}", message),
+    })
+}
+
+// the value types of WebAssembly
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+
+// the instructions used, by opcode
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0b;
+    pub const RETURN: u8 = 0x0f;
+    pub const CALL: u8 = 0x10;
+    pub const DROP: u8 = 0x1a;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const GLOBAL_GET: u8 = 0x23;
+    pub const GLOBAL_SET: u8 = 0x24;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I64_EQZ: u8 = 0x50;
+    pub const I64_EQ: u8 = 0x51;
+    pub const I64_NE: u8 = 0x52;
+    pub const I64_LT_S: u8 = 0x53;
+    pub const I64_GT_S: u8 = 0x55;
+    pub const I64_ADD: u8 = 0x7c;
+    pub const I64_SUB: u8 = 0x7d;
+    pub const I64_MUL: u8 = 0x7e;
+    pub const I64_DIV_S: u8 = 0x7f;
+    pub const I64_REM_S: u8 = 0x81;
+    pub const I64_AND: u8 = 0x83;
+    pub const I64_XOR: u8 = 0x85;
+    pub const I32_WRAP_I64: u8 = 0xa7;
+    pub const I64_EXTEND_I32_U: u8 = 0xad;
+}
+
+// the function indices: the import first, then the helpers, then the
+// functions of the program and finally main
+const PUTS: u32 = 0;
+const CHECKED_ADD: u32 = 1;
+const CHECKED_SUB: u32 = 2;
+const CHECKED_MUL: u32 = 3;
+const FIRST_FUNCTION: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Types {
+    returns: Vec<Type>,
+    parameters: Vec<Vec<Type>>,
+    globals: Vec<Type>,
+}
+
+struct Function {
+    parameters: Vec<Symbol>,
+    body: Rc<Statement>,
+}
+
+struct Module {
+    functions: Vec<Function>,
+    function_names: HashMap<Symbol, usize>,
+    global_names: HashMap<Symbol, usize>,
+    types: Types,
+}
+
+impl Module {
+    // finds the functions and globals defined at the top level
+    fn new(program: &Program) -> Result<Module, CompileError> {
+        let mut module = Module {
+            functions: Vec::new(),
+            function_names: HashMap::new(),
+            global_names: HashMap::new(),
+            types: Types {
+                returns: Vec::new(),
+                parameters: Vec::new(),
+                globals: Vec::new(),
+            },
+        };
+        for statement in &program.statements {
+            let Statement::LetStatement { name, value, .. } = statement else {
+                continue;
+            };
+            let defined =
+                module.function_names.contains_key(name) || module.global_names.contains_key(name);
+            match value {
+                Expression::FunctionLiteral {
+                    parameters, body, ..
+                } => {
+                    if defined {
+                        return error(format!("{} is defined more than once", name));
+                    }
+                    module
+                        .function_names
+                        .insert(name.clone(), module.functions.len());
+                    module.functions.push(Function {
+                        parameters: parameters.clone(),
+                        body: Rc::clone(body),
+                    });
+                    module.types.returns.push(Type::Any);
+                    module
+                        .types
+                        .parameters
+                        .push(vec![Type::Any; parameters.len()]);
+                }
+                _ if module.function_names.contains_key(name) => {
+                    return error(format!("{} is defined more than once", name));
+                }
+                _ if !defined => {
+                    module
+                        .global_names
+                        .insert(name.clone(), module.types.globals.len());
+                    module.types.globals.push(Type::Any);
+                }
+                _ => {}
+            }
+        }
+        Ok(module)
+    }
+
+    // the code of every function and of main, updating the types on the way
+    fn compile_functions(&mut self, program: &Program) -> Result<Vec<Body>, CompileError> {
+        let mut bodies = Vec::new();
+        for index in 0..self.functions.len() {
+            let parameters = self.functions[index].parameters.clone();
+            let types = self.types.parameters[index].clone();
+            let body = Rc::clone(&self.functions[index].body);
+
+            let mut function = Body::new(false);
+            for (parameter, ty) in parameters.iter().zip(types) {
+                function.define_local(parameter, ty)?;
+            }
+            function.num_parameters = parameters.len() as u32;
+            let value = function.block(self, &body)?;
+            let Some(returns) = function.returns.unify(value) else {
+                return error(format!(
+                    "{} returns a {} and a {}",
+                    self.name_of(index),
+                    function.returns,
+                    value
+                ));
+            };
+            self.types.returns[index] = returns;
+            bodies.push(function);
+        }
+
+        let mut main = Body::new(true);
+        main.statements(self, &program.statements)?;
+        bodies.push(main);
+        Ok(bodies)
+    }
+
+    fn name_of(&self, index: usize) -> &Symbol {
+        let names = self.function_names.iter();
+        names
+            .filter(|(_, &i)| i == index)
+            .map(|(name, _)| name)
+            .next()
+            .unwrap()
+    }
+
+    fn encode(&self, bodies: Vec<Body>) -> Vec<u8> {
+        let mut signatures: Vec<(Vec<u8>, Vec<u8>)> = vec![(vec![I64, I32], vec![])];
+        let mut signature = |parameters: usize| {
+            let signature = (vec![I64; parameters], vec![I64]);
+            match signatures.iter().position(|known| *known == signature) {
+                Some(index) => index as u32,
+                None => {
+                    signatures.push(signature);
+                    signatures.len() as u32 - 1
+                }
+            }
+        };
+        let helper = signature(2);
+        let mut functions = vec![helper; 3];
+        for function in &self.functions {
+            functions.push(signature(function.parameters.len()));
+        }
+        functions.push(signature(0));
+        let main = FIRST_FUNCTION + self.functions.len() as u32;
+
+        let mut module = b"\0asm".to_vec();
+        module.extend_from_slice(&1u32.to_le_bytes());
+
+        section(&mut module, 1, signatures.len(), |bytes| {
+            for (parameters, results) in &signatures {
+                bytes.push(0x60);
+                vector(bytes, parameters);
+                vector(bytes, results);
+            }
+        });
+        section(&mut module, 2, 1, |bytes| {
+            name(bytes, "monk");
+            name(bytes, "puts");
+            bytes.push(0x00);
+            uleb(bytes, 0);
+        });
+        section(&mut module, 3, functions.len(), |bytes| {
+            for signature in &functions {
+                uleb(bytes, *signature as u64);
+            }
+        });
+        if !self.types.globals.is_empty() {
+            section(&mut module, 6, self.types.globals.len(), |bytes| {
+                for _ in &self.types.globals {
+                    bytes.extend_from_slice(&[I64, 0x01, op::I64_CONST, 0x00, op::END]);
+                }
+            });
+        }
+        section(&mut module, 7, 1, |bytes| {
+            name(bytes, "main");
+            bytes.push(0x00);
+            uleb(bytes, main as u64);
+        });
+        section(&mut module, 10, functions.len(), |bytes| {
+            for helper in [CHECKED_ADD, CHECKED_SUB, CHECKED_MUL] {
+                code(bytes, 1, &checked(helper));
+            }
+            for body in &bodies {
+                code(bytes, body.num_locals - body.num_parameters, &body.code);
+            }
+        });
+        module
+    }
+}
+
+// Arithmetic that traps on overflow, over the parameters 0 and 1 with the
+// result in local 2.
+fn checked(helper: u32) -> Vec<u8> {
+    let mut bytes = vec![op::LOCAL_GET, 0, op::LOCAL_GET, 1];
+    let overflows: &[u8] = match helper {
+        // the result has a different sign than both operands
+        CHECKED_ADD => &[
+            op::I64_ADD,
+            op::LOCAL_SET,
+            2,
+            op::LOCAL_GET,
+            0,
+            op::LOCAL_GET,
+            2,
+            op::I64_XOR,
+            op::LOCAL_GET,
+            1,
+            op::LOCAL_GET,
+            2,
+            op::I64_XOR,
+            op::I64_AND,
+            op::I64_CONST,
+            0,
+            op::I64_LT_S,
+        ],
+        // the operands have different signs and the result the sign of the
+        // subtrahend
+        CHECKED_SUB => &[
+            op::I64_SUB,
+            op::LOCAL_SET,
+            2,
+            op::LOCAL_GET,
+            0,
+            op::LOCAL_GET,
+            1,
+            op::I64_XOR,
+            op::LOCAL_GET,
+            0,
+            op::LOCAL_GET,
+            2,
+            op::I64_XOR,
+            op::I64_AND,
+            op::I64_CONST,
+            0,
+            op::I64_LT_S,
+        ],
+        // dividing the result by one operand doesn't give the other; the
+        // division traps by itself for i64::MIN * -1
+        _ => &[
+            op::I64_MUL,
+            op::LOCAL_SET,
+            2,
+            op::LOCAL_GET,
+            0,
+            op::I64_EQZ,
+            op::I32_EQZ,
+            op::IF,
+            0x40,
+            op::LOCAL_GET,
+            2,
+            op::LOCAL_GET,
+            0,
+            op::I64_DIV_S,
+            op::LOCAL_GET,
+            1,
+            op::I64_NE,
+            op::IF,
+            0x40,
+            op::UNREACHABLE,
+            op::END,
+            op::END,
+            op::I32_CONST,
+            0,
+        ],
+    };
+    bytes.extend_from_slice(overflows);
+    bytes.extend_from_slice(&[op::IF, 0x40, op::UNREACHABLE, op::END, op::LOCAL_GET, 2]);
+    bytes
+}
+
+// The code of a function being compiled. Every statement and expression
+// leaves one i64 on the stack.
+struct Body {
+    main: bool,
+    locals: HashMap<Symbol, (u32, Type)>,
+    // the type of the values of its return statements
+    returns: Type,
+    num_parameters: u32,
+    num_locals: u32,
+    code: Vec<u8>,
+}
+
+impl Body {
+    fn new(main: bool) -> Body {
+        Body {
+            main,
+            locals: HashMap::new(),
+            returns: Type::Any,
+            num_parameters: 0,
+            num_locals: 0,
+            code: Vec::new(),
+        }
+    }
+
+    fn define_local(&mut self, name: &Symbol, ty: Type) -> Result<u32, CompileError> {
+        match self.locals.get(name) {
+            Some(&(index, known)) => {
+                let Some(ty) = known.unify(ty) else {
+                    return error(format!("{} is a {} and a {}", name, known, ty));
+                };
+                self.locals.insert(name.clone(), (index, ty));
+                Ok(index)
+            }
+            None => {
+                self.locals.insert(name.clone(), (self.num_locals, ty));
+                self.num_locals += 1;
+                Ok(self.num_locals - 1)
+            }
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_with(&mut self, op: u8, operand: u64) {
+        self.code.push(op);
+        uleb(&mut self.code, operand);
+    }
+
+    fn integer(&mut self, value: i64) {
+        self.code.push(op::I64_CONST);
+        sleb(&mut self.code, value);
+    }
+
+    // the value of the last statement, or null
+    fn statements(
+        &mut self,
+        module: &mut Module,
+        statements: &[Statement],
+    ) -> Result<Type, CompileError> {
+        let Some((last, rest)) = statements.split_last() else {
+            self.integer(0);
+            return Ok(Type::Null);
+        };
+        for statement in rest {
+            self.statement(module, statement)?;
+            self.emit(&[op::DROP]);
+        }
+        self.statement(module, last)
+    }
+
+    fn block(&mut self, module: &mut Module, block: &Statement) -> Result<Type, CompileError> {
+        match block {
+            Statement::BlockStatement(statements, _) => self.statements(module, statements),
+            statement => self.statement(module, statement),
+        }
+    }
+
+    fn statement(
+        &mut self,
+        module: &mut Module,
+        statement: &Statement,
+    ) -> Result<Type, CompileError> {
+        match statement {
+            Statement::LetStatement { name, value, .. } if self.main => {
+                if module.function_names.contains_key(name) {
+                    self.integer(0);
+                    return Ok(Type::Null);
+                }
+                let ty = self.expression(module, value)?;
+                let index = module.global_names[name];
+                let known = module.types.globals[index];
+                let Some(ty) = known.unify(ty) else {
+                    return error(format!("{} is a {} and a {}", name, known, ty));
+                };
+                module.types.globals[index] = ty;
+                self.emit_with(op::GLOBAL_SET, index as u64);
+                self.emit_with(op::GLOBAL_GET, index as u64);
+                Ok(ty)
+            }
+            Statement::LetStatement { name, value, .. } => {
+                let ty = self.expression(module, value)?;
+                let index = self.define_local(name, ty)?;
+                self.emit_with(op::LOCAL_TEE, index as u64);
+                Ok(ty)
+            }
+            Statement::ReturnStatement(value, _) => {
+                let ty = self.expression(module, value)?;
+                let Some(returns) = self.returns.unify(ty) else {
+                    return error(format!("return values are a {} and a {}", self.returns, ty));
+                };
+                self.returns = returns;
+                self.emit(&[op::RETURN]);
+                Ok(Type::Any)
+            }
+            Statement::ExpressionStatement(expression, _) => self.expression(module, expression),
+            Statement::BlockStatement(statements, _) => self.statements(module, statements),
+        }
+    }
+
+    fn expression(
+        &mut self,
+        module: &mut Module,
+        expression: &Expression,
+    ) -> Result<Type, CompileError> {
+        match expression {
+            Expression::IntegerLiteral(value) => {
+                self.integer(*value);
+                Ok(Type::Integer)
+            }
+            Expression::BooleanLiteral(value) => {
+                self.integer(*value as i64);
+                Ok(Type::Boolean)
+            }
+            Expression::Identifier(name, _) => {
+                if let Some(&(index, ty)) = self.locals.get(name) {
+                    self.emit_with(op::LOCAL_GET, index as u64);
+                    Ok(ty)
+                } else if let Some(&index) = module.global_names.get(name) {
+                    self.emit_with(op::GLOBAL_GET, index as u64);
+                    Ok(module.types.globals[index])
+                } else if module.function_names.contains_key(name) {
+                    error(format!("{} is a function, which can only be called", name))
+                } else {
+                    error(format!("identifier not found: {}", name))
+                }
+            }
+            Expression::Prefix(Prefix::MINUS, right) => {
+                self.integer(0);
+                let ty = self.expression(module, right)?;
+                if !ty.is(Type::Integer) {
+                    return error(format!("unknown operator: -{}", ty));
+                }
+                self.emit_with(op::CALL, CHECKED_SUB as u64);
+                Ok(Type::Integer)
+            }
+            Expression::Prefix(Prefix::BANG, right) => {
+                match self.expression(module, right)? {
+                    Type::Boolean | Type::Any => {
+                        self.emit(&[op::I64_EQZ, op::I64_EXTEND_I32_U]);
+                    }
+                    // integers are always truthy and null never is
+                    ty => {
+                        self.emit(&[op::DROP]);
+                        self.integer((ty == Type::Null) as i64);
+                    }
+                }
+                Ok(Type::Boolean)
+            }
+            Expression::Infix(operator, left, right) => {
+                let left = self.expression(module, left)?;
+                let right = self.expression(module, right)?;
+                self.infix(operator, left, right)
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                match self.expression(module, condition)? {
+                    Type::Boolean | Type::Any => self.emit(&[op::I32_WRAP_I64]),
+                    ty => {
+                        self.emit(&[op::DROP, op::I32_CONST]);
+                        sleb(&mut self.code, (ty == Type::Integer) as i64);
+                    }
+                }
+                self.emit(&[op::IF, I64]);
+                let consequence = self.block(module, consequence)?;
+                self.emit(&[op::ELSE]);
+                let alternative = match alternative {
+                    Some(alternative) => self.block(module, alternative)?,
+                    None => {
+                        self.integer(0);
+                        Type::Null
+                    }
+                };
+                self.emit(&[op::END]);
+                match consequence.unify(alternative) {
+                    Some(ty) => Ok(ty),
+                    None => error(format!(
+                        "the branches of an if are a {} and a {}",
+                        consequence, alternative
+                    )),
+                }
+            }
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => self.call(module, function, arguments),
+            Expression::StringLiteral(_) => error("strings aren't supported"),
+            Expression::ArrayLiteral(_) => error("arrays aren't supported"),
+            Expression::HashLiteral(..) => error("hashes aren't supported"),
+            Expression::Index { .. } => error("index expressions aren't supported"),
+            Expression::Try { .. } => error("try expressions aren't supported"),
+            Expression::For { .. } => error("for loops aren't supported"),
+            Expression::MacroLiteral { .. } => error("macros aren't supported"),
+            Expression::FunctionLiteral { .. } => {
+                error("functions have to be bound by a let statement at the top level")
+            }
+        }
+    }
+
+    fn infix(&mut self, operator: &Infix, left: Type, right: Type) -> Result<Type, CompileError> {
+        let mismatch = || error(format!("type mismatch: {} {} {}", left, operator, right));
+        let integers = left.is(Type::Integer) && right.is(Type::Integer);
+        match operator {
+            Infix::PLUS | Infix::MINUS | Infix::ASTERISK | Infix::SLASH | Infix::PERCENT => {
+                if !integers {
+                    return mismatch();
+                }
+                match operator {
+                    Infix::PLUS => self.emit_with(op::CALL, CHECKED_ADD as u64),
+                    Infix::MINUS => self.emit_with(op::CALL, CHECKED_SUB as u64),
+                    Infix::ASTERISK => self.emit_with(op::CALL, CHECKED_MUL as u64),
+                    Infix::SLASH => self.emit(&[op::I64_DIV_S]),
+                    _ => self.emit(&[op::I64_REM_S]),
+                }
+                Ok(Type::Integer)
+            }
+            Infix::LT | Infix::GT => {
+                if !integers {
+                    return mismatch();
+                }
+                let op = if *operator == Infix::LT {
+                    op::I64_LT_S
+                } else {
+                    op::I64_GT_S
+                };
+                self.emit(&[op, op::I64_EXTEND_I32_U]);
+                Ok(Type::Boolean)
+            }
+            Infix::EQ | Infix::NOT_EQ => {
+                // values of different types are unequal
+                if left.unify(right).is_none() {
+                    let unequal = (*operator == Infix::NOT_EQ) as u8;
+                    self.emit(&[op::DROP, op::DROP, op::I64_CONST, unequal]);
+                    return Ok(Type::Boolean);
+                }
+                let op = if *operator == Infix::EQ {
+                    op::I64_EQ
+                } else {
+                    op::I64_NE
+                };
+                self.emit(&[op, op::I64_EXTEND_I32_U]);
+                Ok(Type::Boolean)
+            }
+        }
+    }
+
+    fn call(
+        &mut self,
+        module: &mut Module,
+        function: &Expression,
+        arguments: &[Expression],
+    ) -> Result<Type, CompileError> {
+        let Expression::Identifier(name, _) = function else {
+            return error("only functions defined at the top level can be called");
+        };
+        if self.locals.contains_key(name) || module.global_names.contains_key(name) {
+            return error(format!("not a function: {}", name));
+        }
+        let Some(&index) = module.function_names.get(name) else {
+            if &**name == "puts" {
+                for argument in arguments {
+                    let ty = self.expression(module, argument)?;
+                    self.emit(&[op::I32_CONST]);
+                    sleb(&mut self.code, ty.tag());
+                    self.emit_with(op::CALL, PUTS as u64);
+                }
+                self.integer(0);
+                return Ok(Type::Null);
+            }
+            return error(format!("{} isn't a function that can be compiled", name));
+        };
+
+        let expected = module.functions[index].parameters.len();
+        if arguments.len() != expected {
+            return error(format!(
+                "wrong number of arguments to {}: got={}, want={}",
+                name,
+                arguments.len(),
+                expected
+            ));
+        }
+        for (position, argument) in arguments.iter().enumerate() {
+            let ty = self.expression(module, argument)?;
+            let known = module.types.parameters[index][position];
+            let Some(ty) = known.unify(ty) else {
+                let parameter = &module.functions[index].parameters[position];
+                return error(format!(
+                    "{} of {} is a {} and a {}",
+                    parameter, name, known, ty
+                ));
+            };
+            module.types.parameters[index][position] = ty;
+        }
+        self.emit_with(op::CALL, (FIRST_FUNCTION as usize + index) as u64);
+        Ok(module.types.returns[index])
+    }
+}
+
+fn uleb(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn sleb(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    uleb(bytes, name.len() as u64);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn vector(bytes: &mut Vec<u8>, items: &[u8]) {
+    uleb(bytes, items.len() as u64);
+    bytes.extend_from_slice(items);
+}
+
+fn section(module: &mut Vec<u8>, id: u8, count: usize, contents: impl FnOnce(&mut Vec<u8>)) {
+    let mut bytes = Vec::new();
+    uleb(&mut bytes, count as u64);
+    contents(&mut bytes);
+    module.push(id);
+    uleb(module, bytes.len() as u64);
+    module.extend(bytes);
+}
+
+fn code(bytes: &mut Vec<u8>, locals: u32, code: &[u8]) {
+    let mut body = Vec::new();
+    if locals > 0 {
+        uleb(&mut body, 1);
+        uleb(&mut body, locals as u64);
+        body.push(I64);
+    } else {
+        uleb(&mut body, 0);
+    }
+    body.extend_from_slice(code);
+    body.push(op::END);
+    uleb(bytes, body.len() as u64);
+    bytes.extend(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn test_compile(input: &str) -> Result<Vec<u8>, CompileError> {
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse_program();
+        assert!(parser.errors.is_empty(), "{:?}", parser.errors);
+        compile(&program)
+    }
+
+    #[test]
+    fn test_leb128() {
+        let tests: Vec<(i64, Vec<u8>, Vec<u8>)> = vec![
+            (0, vec![0x00], vec![0x00]),
+            (63, vec![0x3f], vec![0x3f]),
+            (64, vec![0x40], vec![0xc0, 0x00]),
+            (624485, vec![0xe5, 0x8e, 0x26], vec![0xe5, 0x8e, 0x26]),
+        ];
+        for (value, unsigned, signed) in tests {
+            let mut bytes = Vec::new();
+            uleb(&mut bytes, value as u64);
+            assert_eq!(bytes, unsigned, "{}", value);
+            bytes.clear();
+            sleb(&mut bytes, value);
+            assert_eq!(bytes, signed, "{}", value);
+        }
+        let mut bytes = Vec::new();
+        sleb(&mut bytes, -123456);
+        assert_eq!(bytes, vec![0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn test_module() {
+        let module = test_compile("puts(1)").unwrap();
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        // type, import, function, export and code sections in order
+        let mut ids = Vec::new();
+        let mut offset = 8;
+        while offset < module.len() {
+            ids.push(module[offset]);
+            let mut size = 0;
+            let mut shift = 0;
+            loop {
+                offset += 1;
+                size |= ((module[offset] & 0x7f) as usize) << shift;
+                shift += 7;
+                if module[offset] & 0x80 == 0 {
+                    break;
+                }
+            }
+            offset += 1 + size;
+        }
+        assert_eq!(offset, module.len());
+        assert_eq!(ids, vec![1, 2, 3, 7, 10]);
+
+        let module = test_compile("let x = 1; puts(x)").unwrap();
+        assert!(module.windows(2).any(|section| section == [6, 6]));
+    }
+
+    #[test]
+    fn test_types() {
+        let tests = vec![
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; puts(fib(10))",
+            "let f = fn(flag) { if (flag) { 1 } else { 2 } }; let g = fn() { f(true) }; puts(g())",
+            "let x = 1; let f = fn() { x + 1 }; let x = 5; f()",
+            "let f = fn(n) { if (n > 0) { return true; } false }; puts(f(1), !f(0))",
+            "return 1; 2",
+            "if (1 > 2) { puts(1) }",
+        ];
+        for input in tests {
+            if let Err(error) = test_compile(input) {
+                panic!("{}: {}", input, error);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let tests = vec![
+            (r#"puts("hello")"#, "strings aren't supported"),
+            (
+                "let f = fn() { fn(x) { x } }; f()",
+                "functions have to be bound by a let statement at the top level",
+            ),
+            ("1 + true", "type mismatch: INTEGER + BOOLEAN"),
+            (
+                "let x = if (true) { 1 };",
+                "the branches of an if are a INTEGER and a NULL",
+            ),
+            (
+                "let f = fn(x) { x }; f(1); f(true)",
+                "x of f is a INTEGER and a BOOLEAN",
+            ),
+            (
+                "let f = fn(n) { if (n > 0) { return 1; } false }; f(1)",
+                "f returns a INTEGER and a BOOLEAN",
+            ),
+            (
+                "let f = fn(x) { x }; f(1, 2)",
+                "wrong number of arguments to f: got=2, want=1",
+            ),
+            ("let x = 1; let x = true;", "x is a INTEGER and a BOOLEAN"),
+            (
+                "let f = fn() { 1 }; let f = fn() { 2 };",
+                "f is defined more than once",
+            ),
+            (
+                "let f = fn() { 1 }; puts(f)",
+                "f is a function, which can only be called",
+            ),
+            ("len(1)", "len isn't a function that can be compiled"),
+            ("y", "identifier not found: y"),
+        ];
+        for (input, expected) in tests {
+            let error = test_compile(input).unwrap_err();
+            assert_eq!(
+                error.message,
+                format!("can't compile to wasm: {}", expected),
+                "{}",
+                input
+            );
+        }
+    }
+
+    // runs the module with a puts that writes to a string, giving back what
+    // it printed or none if it traps
+    #[cfg(feature = "wasm-test")]
+    fn run(module: &[u8]) -> Option<String> {
+        use wasmtime::{Caller, Engine, Linker, Module, Store};
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, module).unwrap();
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "monk",
+                "puts",
+                |mut caller: Caller<'_, String>, value: i64, kind: i32| {
+                    let line = match kind {
+                        0 => value.to_string(),
+                        1 => (value != 0).to_string(),
+                        _ => "null".to_string(),
+                    };
+                    caller.data_mut().push_str(&line);
+                    caller.data_mut().push('\n');
+                },
+            )
+            .unwrap();
+        let mut store = Store::new(&engine, String::new());
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let main = instance.get_func(&mut store, "main").unwrap();
+        let mut results = vec![wasmtime::Val::I64(0); main.ty(&store).results().len()];
+        main.call(&mut store, &[], &mut results).ok()?;
+        Some(store.into_data())
+    }
+
+    // cargo test --features wasm-test
+    #[cfg(feature = "wasm-test")]
+    #[test]
+    fn test_run() {
+        // the output, or none if the module traps
+        let tests = vec![
+            (
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+                let limit = 20;
+                let even = fn(n) { n % 2 == 0 };
+                puts(fib(limit), even(limit), !even(3), if (false) { 1 } else { -7 / 2 });
+                let nothing = if (limit < 0) { puts(0) };
+                puts(nothing);",
+                Some("6765\ntrue\ntrue\n-3\nnull\n"),
+            ),
+            (
+                "let f = fn(a, b) { a - b * 2 }; puts(f(-9223372036854775807, -4611686018427387904))",
+                Some("1\n"),
+            ),
+            ("puts(1); puts(9223372036854775807 + 1)", None),
+            ("puts(-9223372036854775807 - 2)", None),
+            ("puts(4611686018427387904 * 2)", None),
+            ("let zero = 0; puts(1 / zero)", None),
+            ("puts(1 == true, 1 != false)", Some("false\ntrue\n")),
+        ];
+
+        for (input, expected) in tests {
+            let output = run(&test_compile(input).unwrap());
+            assert_eq!(output.as_deref(), expected, "{}", input);
+        }
+    }
+}